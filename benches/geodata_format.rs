@@ -0,0 +1,75 @@
+// Measures what the compact geodata encoding costs and saves: the size of the imported file, the time
+// to load it and the time to decode all of its coordinates and reference lists.
+//
+// Run with `cargo bench --bench geodata_format [-- <input.osm>]`.
+
+use renderer::coords::Coords;
+use renderer::geodata::importer::import;
+use renderer::geodata::reader::GeodataReader;
+use std::env;
+use std::fs;
+use std::time::Instant;
+
+const ITERATIONS: u32 = 10;
+
+fn main() {
+    let input = env::args()
+        .skip(1)
+        .find(|arg| !arg.starts_with('-'))
+        .unwrap_or_else(|| concat!(env!("CARGO_MANIFEST_DIR"), "/tests/osm/nano_moscow.osm").to_string());
+    let output = env::temp_dir().join("geodata_format_bench.bin");
+    let output = output.to_str().unwrap();
+
+    let start = Instant::now();
+    import(&input, output).unwrap();
+    println!("import: {:?}", start.elapsed());
+
+    let start = Instant::now();
+    let bytes = fs::read(output).unwrap();
+    println!("file size: {} bytes, read in {:?}", bytes.len(), start.elapsed());
+
+    let start = Instant::now();
+    let reader = GeodataReader::load(output).unwrap();
+    println!("load: {:?}", start.elapsed());
+
+    let mut node_count = 0;
+    let mut ref_count = 0;
+    let mut checksum = 0.0;
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        let entities = reader.get_entities_in_bbox(-85.0, -180.0, 85.0, 180.0);
+        node_count = entities.nodes.len();
+        ref_count = 0;
+        for way in &entities.ways {
+            ref_count += way.node_count();
+            for idx in 0..way.node_count() {
+                checksum += way.get_node(idx).lat();
+            }
+        }
+        for multipolygon in &entities.multipolygons {
+            ref_count += multipolygon.polygon_count();
+            for polygon_idx in 0..multipolygon.polygon_count() {
+                let polygon = multipolygon.get_polygon(polygon_idx);
+                ref_count += polygon.node_count();
+                for idx in 0..polygon.node_count() {
+                    checksum += polygon.get_node(idx).lon();
+                }
+            }
+        }
+    }
+    println!(
+        "decoding {} node references: {:?} per pass (checksum {})",
+        ref_count,
+        start.elapsed() / ITERATIONS,
+        checksum
+    );
+
+    // For comparison with the previous encoding, which stored f64 coordinates and plain u32 references.
+    println!(
+        "f64 coordinates would add {} bytes, the decoded references take {} bytes as plain u32 numbers",
+        node_count * 8,
+        ref_count * 4
+    );
+
+    fs::remove_file(output).unwrap();
+}
@@ -0,0 +1,163 @@
+use renderer;
+
+use renderer::geodata::clip::ClipArea;
+use renderer::geodata::inspect::{dump, get_stats, DumpFormat, DumpSelection, OsmId};
+use renderer::geodata::reader::GeodataReader;
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+
+fn usage(bin_name: &str) -> ! {
+    eprintln!("Usage: {} stats FILE", bin_name);
+    eprintln!(
+        "       {} dump [--format osm|geojson] (--bbox MIN_LON,MIN_LAT,MAX_LON,MAX_LAT | --poly FILE | --ids n1,w2,r3) FILE [OUTPUT]",
+        bin_name
+    );
+    eprintln!(
+        "       {} extract (--bbox MIN_LON,MIN_LAT,MAX_LON,MAX_LAT | --poly FILE) INPUT OUTPUT",
+        bin_name
+    );
+    std::process::exit(1);
+}
+
+fn exit_with_error(err: &failure::Error) -> ! {
+    for cause in err.iter_chain() {
+        eprintln!("{}", cause);
+    }
+    std::process::exit(1);
+}
+
+fn load(file_name: &str) -> GeodataReader<'static> {
+    GeodataReader::load(file_name).unwrap_or_else(|err| exit_with_error(&err))
+}
+
+fn stats(bin_name: &str, args: &[String]) {
+    if args.len() != 1 {
+        usage(bin_name);
+    }
+
+    let reader = load(&args[0]);
+    if let Err(err) = reader.verify() {
+        exit_with_error(&err.context(format!("Failed to verify {}", args[0])).into());
+    }
+    if let Ok(metadata) = fs::metadata(&args[0]) {
+        println!("File size: {} bytes", metadata.len());
+    }
+    print!("{}", get_stats(&reader));
+}
+
+// Parses the options that select the entities, returning the remaining arguments.
+fn parse_selection<'a>(
+    bin_name: &str,
+    args: &'a [String],
+    format: Option<&mut DumpFormat>,
+) -> (DumpSelection, Vec<&'a String>) {
+    let mut selection = None;
+    let mut format = format;
+    let mut positional_args = Vec::new();
+
+    let mut arg_iter = args.iter();
+    while let Some(arg) = arg_iter.next() {
+        let new_selection = match arg.as_str() {
+            "--bbox" => match arg_iter.next() {
+                Some(bbox) => ClipArea::from_bbox_str(bbox).map(DumpSelection::Area),
+                None => usage(bin_name),
+            },
+            "--poly" => match arg_iter.next() {
+                Some(file) => ClipArea::from_poly_file(file).map(DumpSelection::Area),
+                None => usage(bin_name),
+            },
+            "--ids" if format.is_some() => match arg_iter.next() {
+                Some(ids) => ids
+                    .split(',')
+                    .map(|id| id.trim().parse::<OsmId>())
+                    .collect::<Result<Vec<_>, _>>()
+                    .map(DumpSelection::Ids),
+                None => usage(bin_name),
+            },
+            "--format" if format.is_some() => {
+                let new_format = match arg_iter.next().map(String::as_str) {
+                    Some("osm") => DumpFormat::OsmXml,
+                    Some("geojson") => DumpFormat::GeoJson,
+                    _ => usage(bin_name),
+                };
+                if let Some(ref mut format) = format {
+                    **format = new_format;
+                }
+                continue;
+            }
+            _ if arg.starts_with("--") => {
+                eprintln!("Unknown option: {}", arg);
+                usage(bin_name);
+            }
+            _ => {
+                positional_args.push(arg);
+                continue;
+            }
+        };
+        if selection.is_some() {
+            eprintln!("Only one of --bbox, --poly and --ids can be used");
+            usage(bin_name);
+        }
+        selection = Some(new_selection.unwrap_or_else(|err| exit_with_error(&err)));
+    }
+
+    match selection {
+        Some(selection) => (selection, positional_args),
+        None => usage(bin_name),
+    }
+}
+
+fn dump_entities(bin_name: &str, args: &[String]) {
+    let mut format = DumpFormat::OsmXml;
+    let (selection, positional_args) = parse_selection(bin_name, args, Some(&mut format));
+    if positional_args.is_empty() || positional_args.len() > 2 {
+        usage(bin_name);
+    }
+
+    let reader = load(positional_args[0]);
+    let result = match positional_args.get(1) {
+        Some(output) => File::create(output).map_err(failure::Error::from).and_then(|file| {
+            let mut writer = BufWriter::new(file);
+            dump(&reader, &selection, format, &mut writer).and_then(|_| Ok(writer.flush()?))
+        }),
+        None => {
+            let stdout = io::stdout();
+            let mut writer = BufWriter::new(stdout.lock());
+            dump(&reader, &selection, format, &mut writer).and_then(|_| Ok(writer.flush()?))
+        }
+    };
+    if let Err(err) = result {
+        exit_with_error(&err);
+    }
+}
+
+fn extract(bin_name: &str, args: &[String]) {
+    let (selection, positional_args) = parse_selection(bin_name, args, None);
+    let area = match selection {
+        DumpSelection::Area(area) => area,
+        DumpSelection::Ids(_) => usage(bin_name),
+    };
+    if positional_args.len() != 2 {
+        usage(bin_name);
+    }
+
+    let (input, output) = (positional_args[0], positional_args[1]);
+    eprintln!("Extracting the area from {} to {}", input, output);
+    match renderer::geodata::extract::extract(input, &area, output) {
+        Ok(_) => eprintln!("All good"),
+        Err(err) => exit_with_error(&err),
+    }
+}
+
+fn main() {
+    let args: Vec<_> = env::args().collect();
+    let bin_name = args.first().map(String::as_str).unwrap_or("geodata-tool");
+
+    match args.get(1).map(String::as_str) {
+        Some("stats") => stats(bin_name, &args[2..]),
+        Some("dump") => dump_entities(bin_name, &args[2..]),
+        Some("extract") => extract(bin_name, &args[2..]),
+        _ => usage(bin_name),
+    }
+}
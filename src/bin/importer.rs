@@ -1,28 +1,143 @@
 use renderer;
 
+use renderer::geodata::area_rules::AreaRules;
+use renderer::geodata::clip::ClipArea;
+use renderer::geodata::diff::DiffOptions;
+use renderer::geodata::history::Timestamp;
+use renderer::geodata::importer::{ImportOptions, TruncatedWays};
 use std::env;
+use std::path::PathBuf;
+
+fn usage(bin_name: &str) -> ! {
+    eprintln!(
+        "Usage: {} [--low-memory] [--temp-dir DIR] [--stylesheet FILE] [--index-zoom ZOOM] [--validation-report FILE] [--repair-multipolygons] [--truncated-ways keep|drop|close] [--area-rules FILE] [--at TIMESTAMP] [--bbox MIN_LON,MIN_LAT,MAX_LON,MAX_LAT | --poly FILE] INPUT OUTPUT",
+        bin_name
+    );
+    eprintln!("       {} apply-diff [--stylesheet FILE] BASE DIFF OUTPUT", bin_name);
+    std::process::exit(1);
+}
+
+fn exit_with_error(err: &failure::Error) -> ! {
+    for cause in err.iter_chain() {
+        eprintln!("{}", cause);
+    }
+    std::process::exit(1);
+}
+
+fn apply_diff(bin_name: &str, args: &[String]) {
+    let mut options = DiffOptions::default();
+    let args = match args {
+        [flag, stylesheet, rest @ ..] if flag == "--stylesheet" => {
+            options.stylesheet = Some(stylesheet.clone());
+            rest
+        }
+        _ => args,
+    };
+    if args.len() != 3 {
+        usage(bin_name);
+    }
+
+    eprintln!(
+        "Applying {} to {} and saving the result to {}",
+        args[1], args[0], args[2]
+    );
+
+    match renderer::geodata::diff::apply_diff_with_options(&args[0], &args[1], &args[2], &options) {
+        Ok(changed_tiles) => {
+            for tile in changed_tiles {
+                println!("{}/{}/{}", tile.zoom, tile.x, tile.y);
+            }
+        }
+        Err(err) => exit_with_error(&err),
+    }
+}
 
 fn main() {
     let args: Vec<_> = env::args().collect();
+    let bin_name = args.first().map(String::as_str).unwrap_or("importer");
 
-    if args.len() != 3 {
-        let bin_name = args.first().map(String::as_str).unwrap_or("importer");
-        eprintln!("Usage: {} INPUT OUTPUT", bin_name);
-        std::process::exit(1);
+    if args.get(1).map(String::as_str) == Some("apply-diff") {
+        apply_diff(bin_name, &args[2..]);
+        return;
     }
 
-    let input = &args[1];
-    let output = &args[2];
+    let mut options = ImportOptions::default();
+    let mut positional_args = Vec::new();
+
+    let mut arg_iter = args.iter().skip(1);
+    while let Some(arg) = arg_iter.next() {
+        match arg.as_str() {
+            "--low-memory" => options.low_memory = true,
+            "--temp-dir" => match arg_iter.next() {
+                Some(dir) => options.temp_dir = Some(PathBuf::from(dir)),
+                None => usage(bin_name),
+            },
+            "--bbox" | "--poly" if options.clip_area.is_some() => {
+                eprintln!("Only one of --bbox and --poly can be used");
+                usage(bin_name);
+            }
+            "--bbox" => match arg_iter.next() {
+                Some(bbox) => {
+                    let clip_area = ClipArea::from_bbox_str(bbox).unwrap_or_else(|err| exit_with_error(&err));
+                    options.clip_area = Some(clip_area);
+                }
+                None => usage(bin_name),
+            },
+            "--poly" => match arg_iter.next() {
+                Some(file) => {
+                    let clip_area = ClipArea::from_poly_file(file).unwrap_or_else(|err| exit_with_error(&err));
+                    options.clip_area = Some(clip_area);
+                }
+                None => usage(bin_name),
+            },
+            "--stylesheet" => match arg_iter.next() {
+                Some(file) => options.stylesheet = Some(file.clone()),
+                None => usage(bin_name),
+            },
+            "--validation-report" => match arg_iter.next() {
+                Some(file) => options.validation_report = Some(file.clone()),
+                None => usage(bin_name),
+            },
+            "--repair-multipolygons" => options.repair_multipolygons = true,
+            "--area-rules" => match arg_iter.next() {
+                Some(file) => {
+                    options.area_rules = AreaRules::from_file(file).unwrap_or_else(|err| exit_with_error(&err))
+                }
+                None => usage(bin_name),
+            },
+            "--at" => match arg_iter.next() {
+                Some(at) => options.history_at = Some(Timestamp::parse(at).unwrap_or_else(|err| exit_with_error(&err))),
+                None => usage(bin_name),
+            },
+            "--truncated-ways" => match arg_iter.next().map(String::as_str) {
+                Some("keep") => options.truncated_ways = TruncatedWays::Keep,
+                Some("drop") => options.truncated_ways = TruncatedWays::Drop,
+                Some("close") => options.truncated_ways = TruncatedWays::CloseAreas,
+                _ => usage(bin_name),
+            },
+            "--index-zoom" => match arg_iter.next().map(|zoom| zoom.parse()) {
+                Some(Ok(zoom)) => options.index_zoom = Some(zoom),
+                _ => usage(bin_name),
+            },
+            _ if arg.starts_with("--") => {
+                eprintln!("Unknown option: {}", arg);
+                usage(bin_name);
+            }
+            _ => positional_args.push(arg),
+        }
+    }
+
+    if positional_args.len() != 2 {
+        usage(bin_name);
+    }
+
+    let input = positional_args[0];
+    let output = positional_args[1];
 
     println!("Importing from {} to {}", input, output);
 
-    match renderer::geodata::importer::import(input, output) {
+    match renderer::geodata::importer::import_with_options(input, output, &options) {
         Ok(_) => println!("All good"),
-        Err(err) => {
-            for cause in err.iter_chain() {
-                eprintln!("{}", cause);
-            }
-            std::process::exit(1);
-        }
+        Err(err) => exit_with_error(&err),
     }
 }
@@ -36,7 +36,12 @@ fn main() {
     };
 
     let server_address = get_value_from_config(&config, "http", "address");
-    let geodata_file = get_value_from_config(&config, "geodata", "file");
+    // Several files can be listed, separated by commas.
+    let geodata_files = get_value_from_config(&config, "geodata", "file")
+        .split(',')
+        .map(|file| file.trim().to_string())
+        .filter(|file| !file.is_empty())
+        .collect::<Vec<_>>();
 
     let style_section = "style";
     let stylesheet_file = get_value_from_config(&config, style_section, "file");
@@ -72,7 +77,7 @@ fn main() {
 
     let res = run_server(
         &server_address,
-        &geodata_file,
+        &geodata_files,
         &stylesheet_file,
         &stylesheet_type,
         font_size_multiplier,
@@ -23,13 +23,15 @@ macro_rules! implement_to_point_pairs {
 
 impl<'w> PointPairCollection<'w> for Way<'w> {
     fn to_point_pairs(&'w self, tile: &'w Tile, scale: f64) -> PointPairIter<'w> {
-        implement_to_point_pairs!(self, tile, scale)
+        let way = self.simplified_for_zoom(tile.zoom);
+        implement_to_point_pairs!(way, tile, scale)
     }
 }
 
 impl<'p> Polygon<'p> {
     fn into_point_pairs(self, tile: &'p Tile, scale: f64) -> PointPairIter<'p> {
-        implement_to_point_pairs!(self, tile, scale)
+        let polygon = self.simplified_for_zoom(tile.zoom);
+        implement_to_point_pairs!(polygon, tile, scale)
     }
 }
 
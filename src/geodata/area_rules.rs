@@ -0,0 +1,181 @@
+use crate::geodata::importer::RawTags;
+use failure::{bail, Error, ResultExt};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+
+// The keys that make a closed way an area, loosely following the osm2pgsql and JOSM defaults.
+// The format is the same as for the files given to `AreaRules::from_file`.
+const DEFAULT_AREA_RULES: &str = "
+aeroway!=taxiway,runway,parking_position
+amenity
+area:highway
+boundary=protected_area,national_park
+building
+building:part
+craft
+highway=services,rest_area
+historic
+landuse
+leisure!=track,slipway
+man_made!=cutline,embankment,pipeline,dyke,breakwater,groyne
+military
+natural!=coastline,cliff,ridge,arete,tree_row
+office
+place
+power=plant,substation,generator,transformer
+public_transport=station
+railway=station,turntable,roundhouse,platform
+shop
+tourism
+water
+waterway=riverbank,dock,boatyard,dam
+wetland
+";
+
+/// Tells which closed ways are areas (e.g. `building=*`) and which are just closed lines (e.g. roundabouts),
+/// judging by their tags. `area=yes` and `area=no` always take precedence.
+///
+/// The rules are read from a text file with a rule per line:
+///
+/// ```text
+/// # Every closed way with this key is an area.
+/// building
+/// # Only these values make an area.
+/// highway=services,rest_area
+/// # All values but these make an area.
+/// natural!=coastline,cliff
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct AreaRules {
+    rules: HashMap<String, AreaValues>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum AreaValues {
+    Only(HashSet<String>),
+    Except(HashSet<String>),
+}
+
+impl Default for AreaRules {
+    fn default() -> AreaRules {
+        AreaRules::parse(DEFAULT_AREA_RULES).unwrap()
+    }
+}
+
+impl AreaRules {
+    pub fn from_file(file_name: &str) -> Result<AreaRules, Error> {
+        let text = fs::read_to_string(file_name).context(format!("Failed to read {}", file_name))?;
+        let rules = AreaRules::parse(&text).context(format!("Failed to parse the area rules from {}", file_name))?;
+        Ok(rules)
+    }
+
+    pub(super) fn parse(text: &str) -> Result<AreaRules, Error> {
+        let mut rules = HashMap::new();
+        for (line_idx, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (key, values) = match line.find('=') {
+                Some(pos) if pos > 0 && line[..pos].ends_with('!') => {
+                    (&line[..pos - 1], AreaValues::Except(parse_values(&line[pos + 1..])))
+                }
+                Some(pos) => (&line[..pos], AreaValues::Only(parse_values(&line[pos + 1..]))),
+                None => (line, AreaValues::Except(HashSet::new())),
+            };
+            let key = key.trim();
+            if key.is_empty() {
+                bail!("Line {} has no tag key: {}", line_idx + 1, line);
+            }
+            rules.insert(key.to_string(), values);
+        }
+        Ok(AreaRules { rules })
+    }
+
+    // Writes the rules in the format that `parse` reads, so that they can be stored in the geodata file.
+    pub(super) fn to_text(&self) -> String {
+        let join_values = |values: &HashSet<String>| {
+            let mut values = values.iter().map(String::as_str).collect::<Vec<_>>();
+            values.sort_unstable();
+            values.join(",")
+        };
+        let mut lines = self
+            .rules
+            .iter()
+            .map(|(key, values)| match values {
+                AreaValues::Only(values) => format!("{}={}", key, join_values(values)),
+                AreaValues::Except(values) if values.is_empty() => key.clone(),
+                AreaValues::Except(values) => format!("{}!={}", key, join_values(values)),
+            })
+            .collect::<Vec<_>>();
+        lines.sort_unstable();
+        lines.join("\n")
+    }
+
+    pub(super) fn is_area(&self, tags: &RawTags) -> bool {
+        match tags.get("area").map(String::as_str) {
+            Some("yes") => return true,
+            Some("no") => return false,
+            _ => {}
+        }
+        tags.iter().any(|(k, v)| match self.rules.get(k) {
+            Some(AreaValues::Only(values)) => values.contains(v),
+            Some(AreaValues::Except(values)) => !values.contains(v) && v != "no",
+            None => false,
+        })
+    }
+}
+
+fn parse_values(values: &str) -> HashSet<String> {
+    values
+        .split(',')
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tags(pairs: &[(&str, &str)]) -> RawTags {
+        pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn test_default_rules() {
+        let rules = AreaRules::default();
+        assert!(rules.is_area(&tags(&[("building", "yes")])));
+        assert!(rules.is_area(&tags(&[("natural", "water")])));
+        assert!(!rules.is_area(&tags(&[("natural", "coastline")])));
+        assert!(!rules.is_area(&tags(&[("building", "no")])));
+        assert!(!rules.is_area(&tags(&[("highway", "primary"), ("junction", "roundabout")])));
+        assert!(!rules.is_area(&tags(&[("highway", "pedestrian")])));
+        assert!(rules.is_area(&tags(&[("highway", "pedestrian"), ("area", "yes")])));
+        assert!(!rules.is_area(&tags(&[("leisure", "park"), ("area", "no")])));
+    }
+
+    #[test]
+    fn test_parse() {
+        let rules =
+            AreaRules::parse("# comment\n\nbuilding\nhighway = pedestrian, footway\nnatural!=tree_row\n").unwrap();
+        assert!(rules.is_area(&tags(&[("building", "house")])));
+        assert!(rules.is_area(&tags(&[("highway", "footway")])));
+        assert!(!rules.is_area(&tags(&[("highway", "primary")])));
+        assert!(rules.is_area(&tags(&[("natural", "wood")])));
+        assert!(!rules.is_area(&tags(&[("natural", "tree_row")])));
+        assert!(!rules.is_area(&tags(&[("landuse", "grass")])));
+
+        assert!(AreaRules::parse("=yes").is_err());
+    }
+
+    #[test]
+    fn test_to_text() {
+        let rules = AreaRules::parse("natural!=tree_row,cliff\nbuilding\nhighway=services").unwrap();
+        assert_eq!(rules.to_text(), "building\nhighway=services\nnatural!=cliff,tree_row");
+
+        let default_rules = AreaRules::default();
+        assert_eq!(AreaRules::parse(&default_rules.to_text()).unwrap(), default_rules);
+    }
+}
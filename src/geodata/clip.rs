@@ -0,0 +1,426 @@
+use crate::coords::Coords;
+use crate::geodata::history::Timestamp;
+use crate::geodata::importer::{
+    is_multipolygon, is_stored_relation, parse_input, OsmEntityHandler, OsmInput, ParsedMember, ParsedRelation,
+    ParsedWay, RawNode,
+};
+use crate::geodata::reader::{GeodataReader, Member, MemberKind, Node, OsmEntity};
+use failure::{bail, format_err, Error, ResultExt};
+use std::collections::HashSet;
+use std::fs;
+
+/// An area that the imported data is clipped to.
+pub struct ClipArea {
+    rings: Vec<Ring>,
+    bbox: BoundingBox,
+}
+
+impl ClipArea {
+    pub fn from_bbox(min_lon: f64, min_lat: f64, max_lon: f64, max_lat: f64) -> Result<ClipArea, Error> {
+        if !(min_lon < max_lon && min_lat < max_lat) {
+            bail!(
+                "Invalid bounding box {},{},{},{}: the minimum coordinates must be less than the maximum ones",
+                min_lon,
+                min_lat,
+                max_lon,
+                max_lat
+            );
+        }
+        let ring = vec![
+            (min_lat, min_lon),
+            (min_lat, max_lon),
+            (max_lat, max_lon),
+            (max_lat, min_lon),
+            (min_lat, min_lon),
+        ];
+        Ok(ClipArea::from_rings(vec![Ring::new(ring, false)]))
+    }
+
+    /// Parses a bounding box written as `MIN_LON,MIN_LAT,MAX_LON,MAX_LAT`.
+    pub fn from_bbox_str(bbox: &str) -> Result<ClipArea, Error> {
+        let coords = bbox
+            .split(',')
+            .map(|c| c.trim().parse::<f64>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| format_err!("Failed to parse the bounding box: {}", bbox))?;
+        match coords[..] {
+            [min_lon, min_lat, max_lon, max_lat] => ClipArea::from_bbox(min_lon, min_lat, max_lon, max_lat),
+            _ => bail!("The bounding box should have 4 coordinates: {}", bbox),
+        }
+    }
+
+    /// Loads an area from a file in the [Osmosis polygon format](https://wiki.openstreetmap.org/wiki/Osmosis/Polygon_Filter_File_Format).
+    pub fn from_poly_file(file_name: &str) -> Result<ClipArea, Error> {
+        let content = fs::read_to_string(file_name).context(format!("Failed to read {}", file_name))?;
+        let rings = parse_poly(&content).context(format!("Failed to parse {}", file_name))?;
+        Ok(ClipArea::from_rings(rings))
+    }
+
+    fn from_rings(rings: Vec<Ring>) -> ClipArea {
+        let bbox = rings
+            .iter()
+            .filter(|r| !r.is_hole)
+            .fold(BoundingBox::empty(), |bbox, r| bbox.union(&r.bbox));
+        ClipArea { rings, bbox }
+    }
+
+    // Returns (min_lat, min_lon, max_lat, max_lon) of the area.
+    pub(super) fn bounds(&self) -> (f64, f64, f64, f64) {
+        (
+            self.bbox.min_lat,
+            self.bbox.min_lon,
+            self.bbox.max_lat,
+            self.bbox.max_lon,
+        )
+    }
+
+    pub(super) fn contains(&self, lat: f64, lon: f64) -> bool {
+        if !self.bbox.contains(lat, lon) {
+            return false;
+        }
+        let is_inside = |is_hole| self.rings.iter().any(|r| r.is_hole == is_hole && r.contains(lat, lon));
+        is_inside(false) && !is_inside(true)
+    }
+}
+
+#[derive(Clone, Copy)]
+struct BoundingBox {
+    min_lat: f64,
+    min_lon: f64,
+    max_lat: f64,
+    max_lon: f64,
+}
+
+impl BoundingBox {
+    fn empty() -> BoundingBox {
+        BoundingBox {
+            min_lat: f64::INFINITY,
+            min_lon: f64::INFINITY,
+            max_lat: f64::NEG_INFINITY,
+            max_lon: f64::NEG_INFINITY,
+        }
+    }
+
+    fn union(&self, other: &BoundingBox) -> BoundingBox {
+        BoundingBox {
+            min_lat: self.min_lat.min(other.min_lat),
+            min_lon: self.min_lon.min(other.min_lon),
+            max_lat: self.max_lat.max(other.max_lat),
+            max_lon: self.max_lon.max(other.max_lon),
+        }
+    }
+
+    fn contains(&self, lat: f64, lon: f64) -> bool {
+        self.min_lat <= lat && lat <= self.max_lat && self.min_lon <= lon && lon <= self.max_lon
+    }
+}
+
+struct Ring {
+    // (lat, lon) pairs, the first one is repeated at the end.
+    points: Vec<(f64, f64)>,
+    is_hole: bool,
+    bbox: BoundingBox,
+}
+
+impl Ring {
+    fn new(mut points: Vec<(f64, f64)>, is_hole: bool) -> Ring {
+        if points.first() != points.last() {
+            points.push(points[0]);
+        }
+        let bbox = points.iter().fold(BoundingBox::empty(), |bbox, &(lat, lon)| {
+            bbox.union(&BoundingBox {
+                min_lat: lat,
+                min_lon: lon,
+                max_lat: lat,
+                max_lon: lon,
+            })
+        });
+        Ring { points, is_hole, bbox }
+    }
+
+    fn contains(&self, lat: f64, lon: f64) -> bool {
+        if !self.bbox.contains(lat, lon) {
+            return false;
+        }
+        let mut inside = false;
+        for idx in 1..self.points.len() {
+            let ((lat1, lon1), (lat2, lon2)) = (self.points[idx - 1], self.points[idx]);
+            if (lat1 > lat) != (lat2 > lat) {
+                let crossing_lon = lon1 + (lat - lat1) / (lat2 - lat1) * (lon2 - lon1);
+                if lon < crossing_lon {
+                    inside = !inside;
+                }
+            }
+        }
+        inside
+    }
+}
+
+// The file starts with a name line and consists of sections, each of which is a name line (starting
+// with `!` for holes), a list of "lon lat" lines and an END line. The last section is followed by
+// one more END line.
+fn parse_poly(content: &str) -> Result<Vec<Ring>, Error> {
+    let mut lines = content
+        .lines()
+        .enumerate()
+        .map(|(idx, line)| (idx + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty());
+
+    if lines.next().is_none() {
+        bail!("The file is empty");
+    }
+
+    let mut rings = Vec::new();
+    loop {
+        let is_hole = match lines.next() {
+            Some((_, "END")) => break,
+            Some((_, section_name)) => section_name.starts_with('!'),
+            None => bail!("Unexpected end of file, expected a section or END"),
+        };
+
+        let mut points = Vec::new();
+        loop {
+            match lines.next() {
+                Some((_, "END")) => break,
+                Some((line_number, line)) => points.push(parse_point(line).context(format!("Line {}", line_number))?),
+                None => bail!("Unexpected end of file inside a section"),
+            }
+        }
+        if points.len() < 3 {
+            bail!("Section #{} has less than 3 points", rings.len() + 1);
+        }
+        rings.push(Ring::new(points, is_hole));
+    }
+
+    if !rings.iter().any(|r| !r.is_hole) {
+        bail!("The file doesn't have any sections that are not holes");
+    }
+    Ok(rings)
+}
+
+fn parse_point(line: &str) -> Result<(f64, f64), Error> {
+    let coords = line
+        .split_whitespace()
+        .map(|c| c.parse::<f64>().map_err(|_| format_err!("Invalid coordinate: {}", c)))
+        .collect::<Result<Vec<_>, _>>()?;
+    match coords[..] {
+        [lon, lat] => Ok((lat, lon)),
+        _ => bail!("Expected a longitude and a latitude, got {}", line),
+    }
+}
+
+// Global ids of the entities that should be imported.
+#[derive(Default)]
+pub(super) struct SelectedEntities {
+    pub(super) nodes: HashSet<u64>,
+    pub(super) ways: HashSet<u64>,
+    pub(super) relations: HashSet<u64>,
+}
+
+/// Finds the entities of `input` that should be imported when clipping it to `area`: the nodes inside
+/// the area, the ways that have at least one of these nodes, the multipolygons that have at least one
+/// of these ways, and all members of the selected ways and multipolygons. Other relations are selected
+/// if they have a selected node or way, but their members outside of the area are not.
+///
+/// Only the ids are kept in memory, so the memory usage depends on the size of the result. The input is
+/// read twice, or three times if some multipolygons have member ways that don't cross the area.
+pub(super) fn select_entities(
+    input: &OsmInput<'_>,
+    history_at: Option<Timestamp>,
+    area: &ClipArea,
+) -> Result<SelectedEntities, Error> {
+    println!("Looking for entities inside the area");
+    let scanner = AreaScanner {
+        area,
+        selected: SelectedEntities::default(),
+        outside_nodes: HashSet::new(),
+        missing_ways: HashSet::new(),
+    };
+    let scanner = parse_input(input, history_at, scanner)?;
+    let AreaScanner {
+        mut selected,
+        outside_nodes,
+        missing_ways,
+        ..
+    } = scanner;
+    selected.nodes.extend(outside_nodes);
+
+    if !missing_ways.is_empty() {
+        println!("Looking for the remaining multipolygon members");
+        let way_scanner = parse_input(
+            input,
+            history_at,
+            MemberWayScanner {
+                ways: missing_ways,
+                nodes: HashSet::new(),
+            },
+        )?;
+        selected.nodes.extend(way_scanner.nodes);
+        selected.ways.extend(way_scanner.ways);
+    }
+
+    Ok(selected)
+}
+
+/// Same as `select_entities`, but for an imported geodata file. Multipolygons are selected if one of their
+/// nodes is inside the area. The selected nodes are only the ones inside the area: the other nodes of
+/// the selected ways and multipolygons are not included.
+pub(super) fn select_geodata_entities(reader: &GeodataReader<'_>, area: &ClipArea) -> SelectedEntities {
+    let (min_lat, min_lon, max_lat, max_lon) = area.bounds();
+    let candidates = reader.get_entities_in_bbox(min_lat, min_lon, max_lat, max_lon);
+
+    let mut selected = SelectedEntities::default();
+    for node in candidates.nodes.iter().filter(|n| area.contains(n.lat(), n.lon())) {
+        selected.nodes.insert(node.global_id());
+    }
+    let is_selected = |node: Node<'_>| selected.nodes.contains(&node.global_id());
+    let ways = candidates
+        .ways
+        .iter()
+        .filter(|w| (0..w.node_count()).any(|idx| is_selected(w.get_node(idx))))
+        .map(|w| w.global_id())
+        .collect::<Vec<_>>();
+    let multipolygons = candidates
+        .multipolygons
+        .iter()
+        .filter(|mp| {
+            (0..mp.polygon_count()).any(|poly_idx| {
+                let polygon = mp.get_polygon(poly_idx);
+                (0..polygon.node_count()).any(|idx| is_selected(polygon.get_node(idx)))
+            })
+        })
+        .map(|mp| mp.global_id())
+        .collect::<Vec<_>>();
+    selected.ways.extend(ways);
+    selected.relations.extend(multipolygons);
+
+    for relation in &candidates.relations {
+        let is_selected = relation.members().any(|m| match m.member {
+            Member::Node(node) => selected.nodes.contains(&node.global_id()),
+            Member::Way(way) => selected.ways.contains(&way.global_id()),
+            Member::Relation(_) => false,
+        });
+        if is_selected {
+            selected.relations.insert(relation.global_id());
+        }
+    }
+
+    selected
+}
+
+struct AreaScanner<'a> {
+    area: &'a ClipArea,
+    selected: SelectedEntities,
+    // The nodes of the selected ways that are outside of the area. They are kept separately so that
+    // the ways that only share these nodes with the selected ways are not selected.
+    outside_nodes: HashSet<u64>,
+    // The members of the selected multipolygons that are not selected themselves.
+    missing_ways: HashSet<u64>,
+}
+
+impl<'a> OsmEntityHandler for AreaScanner<'a> {
+    fn handle_node(&mut self, node: RawNode) -> Result<(), Error> {
+        if self.area.contains(node.lat, node.lon) {
+            self.selected.nodes.insert(node.global_id);
+        }
+        Ok(())
+    }
+
+    fn handle_way(&mut self, way: ParsedWay) -> Result<(), Error> {
+        if way.node_ids.iter().any(|id| self.selected.nodes.contains(id)) {
+            self.selected.ways.insert(way.global_id);
+            let nodes = &self.selected.nodes;
+            self.outside_nodes
+                .extend(way.node_ids.iter().filter(|id| !nodes.contains(id)));
+        }
+        Ok(())
+    }
+
+    fn handle_relation(&mut self, relation: ParsedRelation) -> Result<(), Error> {
+        if is_multipolygon(&relation.tags) && relation.way_ids().any(|id| self.selected.ways.contains(&id)) {
+            self.selected.relations.insert(relation.global_id);
+            let ways = &self.selected.ways;
+            self.missing_ways
+                .extend(relation.way_ids().filter(|id| !ways.contains(id)));
+        } else if is_stored_relation(&relation.tags) {
+            let is_selected = |m: &ParsedMember| match m.kind {
+                MemberKind::Node => self.selected.nodes.contains(&m.global_id),
+                MemberKind::Way => self.selected.ways.contains(&m.global_id),
+                MemberKind::Relation => false,
+            };
+            if relation.members.iter().any(is_selected) {
+                self.selected.relations.insert(relation.global_id);
+            }
+        }
+        Ok(())
+    }
+
+    fn dump_state(&self) -> String {
+        format!(
+            "{} nodes, {} ways and {} multipolygon relations inside the area",
+            self.selected.nodes.len(),
+            self.selected.ways.len(),
+            self.selected.relations.len()
+        )
+    }
+}
+
+struct MemberWayScanner {
+    ways: HashSet<u64>,
+    nodes: HashSet<u64>,
+}
+
+impl OsmEntityHandler for MemberWayScanner {
+    fn handle_node(&mut self, _: RawNode) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn handle_way(&mut self, way: ParsedWay) -> Result<(), Error> {
+        if self.ways.contains(&way.global_id) {
+            self.nodes.extend(way.node_ids);
+        }
+        Ok(())
+    }
+
+    fn handle_relation(&mut self, _: ParsedRelation) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn dump_state(&self) -> String {
+        format!("{} nodes of {} multipolygon members", self.nodes.len(), self.ways.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_poly_with_hole() {
+        let poly = "city\n\
+                    outer\n   3.0E+01  5.0E+01\n   32  50\n   32  52\n   30  52\nEND\n\
+                    !hole\n 30.5 50.5\n 31 50.5\n 31 51\n 30.5 51\nEND\n\
+                    END\n";
+        let area = ClipArea::from_rings(parse_poly(poly).unwrap());
+        assert!(area.contains(51.5, 31.5));
+        assert!(!area.contains(50.75, 30.75));
+        assert!(!area.contains(49.0, 31.0));
+        assert!(!area.contains(51.0, 33.0));
+    }
+
+    #[test]
+    fn test_broken_poly() {
+        assert!(parse_poly("").is_err());
+        assert!(parse_poly("name\n1\n30 50\n32 50\n32 52\n").is_err());
+        assert!(parse_poly("name\n1\n30 50\n32\n32 52\nEND\nEND\n").is_err());
+        assert!(parse_poly("name\n!1\n30 50\n32 50\n32 52\nEND\nEND\n").is_err());
+    }
+
+    #[test]
+    fn test_bbox() {
+        let area = ClipArea::from_bbox(37.6, 55.7, 37.7, 55.8).unwrap();
+        assert!(area.contains(55.75, 37.65));
+        assert!(!area.contains(55.65, 37.65));
+        assert!(ClipArea::from_bbox(37.7, 55.7, 37.6, 55.8).is_err());
+    }
+}
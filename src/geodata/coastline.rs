@@ -0,0 +1,330 @@
+use crate::geodata::find_polygons::{find_polygons_in_multipolygon, NodeDesc, NodeDescPair};
+use crate::geodata::importer::{EntityStorages, NodeStorage, RawTags};
+use failure::Error;
+use std::collections::HashMap;
+
+// The tag of the synthetic multipolygon that covers the water along the coastlines. The bundled
+// osmosnimki-minimal.mapcss already fills `area[natural=ocean]` with the sea color, so no stylesheet changes
+// are needed to draw it.
+const WATER_TAG: (&str, &str) = ("natural", "ocean");
+
+// The global id of the synthetic multipolygon. Real OSM ids start from 1.
+const WATER_MULTIPOLYGON_ID: u64 = 0;
+
+// (min_lat, min_lon, max_lat, max_lon)
+pub(super) type Bounds = (f64, f64, f64, f64);
+
+/// Joins `natural=coastline` ways into rings and adds a multipolygon tagged with `WATER_TAG` that covers
+/// the water side of them. The coastlines that are cut off by the boundaries of the extract are closed
+/// along `bounds` (or the bounding box of all nodes, if `bounds` are not given).
+///
+/// Coastline ways are directed so that the land is on the left and the water is on the right. Thus
+/// closed rings that go counterclockwise are islands, and the rest of the water polygons are made
+/// by walking from the end of every open coastline clockwise along the boundary to the start of the
+/// next one.
+pub(super) fn process_coastlines(storages: &mut EntityStorages, bounds: Option<Bounds>) -> Result<(), Error> {
+    let coastlines = storages
+        .way_storage
+        .get_entities()
+        .iter()
+        .filter(|w| w.tags.get("natural").map(String::as_str) == Some("coastline"))
+        .map(|w| w.node_ids.to_vec())
+        .collect::<Vec<_>>();
+    if coastlines.is_empty() {
+        return Ok(());
+    }
+    println!("Building water polygons from {} coastline ways", coastlines.len());
+
+    let nodes = &mut storages.node_storage;
+    let bounds = bounds.unwrap_or_else(|| nodes.bounds());
+
+    let (closed, open): (Vec<_>, Vec<_>) = join_ways(coastlines)
+        .into_iter()
+        .partition(|chain| chain.first() == chain.last());
+    let mut rings = closed.into_iter().filter(|ring| ring.len() > 3).collect::<Vec<_>>();
+
+    if open.is_empty() {
+        // Without open coastlines, the extract is either an island in the sea or a piece of land
+        // with lakes of sea water; the largest ring tells which one.
+        let largest_ring = rings.iter().max_by(|r1, r2| {
+            let area = |r: &[usize]| signed_area(&ring_coords(r, nodes)).abs();
+            area(r1).partial_cmp(&area(r2)).unwrap()
+        });
+        match largest_ring {
+            Some(ring) if signed_area(&ring_coords(ring, nodes)) > 0.0 => {
+                let boundary = Boundary::new(bounds);
+                let mut ring = (0..4)
+                    .map(|idx| {
+                        let (lat, lon) = boundary.corner(idx);
+                        nodes.add_synthetic(lat, lon)
+                    })
+                    .collect::<Vec<_>>();
+                ring.push(ring[0]);
+                rings.push(ring);
+            }
+            Some(_) => {}
+            None => return Ok(()),
+        }
+    } else {
+        rings.extend(close_along_boundary(&open, &Boundary::new(bounds), nodes));
+    }
+
+    let segments = rings
+        .iter()
+        .flat_map(|ring| ring.windows(2))
+        .map(|pair| {
+            let node_desc = |id| {
+                let (lat, lon) = nodes.get_coords(id);
+                NodeDesc::new(id, lat, lon)
+            };
+            NodeDescPair::new(node_desc(pair[0]), node_desc(pair[1]))
+        })
+        .collect::<Vec<_>>();
+    if let Some(polygons) = find_polygons_in_multipolygon(WATER_MULTIPOLYGON_ID, &segments, None) {
+        let mut tags = RawTags::default();
+        tags.insert(WATER_TAG.0.to_string(), WATER_TAG.1.to_string());
+        storages.add_multipolygon(WATER_MULTIPOLYGON_ID, polygons, tags)?;
+    }
+    Ok(())
+}
+
+fn ring_coords(ring: &[usize], nodes: &NodeStorage) -> Vec<(f64, f64)> {
+    ring.iter().map(|id| nodes.get_coords(*id)).collect()
+}
+
+// Joins the ways that end where other ways start. Every way is used only once, so a node
+// where several coastlines meet doesn't make a mess of the result.
+fn join_ways(ways: Vec<Vec<usize>>) -> Vec<Vec<usize>> {
+    let mut chains = ways.into_iter().filter(|w| w.len() >= 2).map(Some).collect::<Vec<_>>();
+    let mut starts = HashMap::new();
+    for (idx, chain) in chains.iter().enumerate() {
+        if let Some(chain) = chain {
+            starts.entry(chain[0]).or_insert(idx);
+        }
+    }
+
+    for idx in 0..chains.len() {
+        while let Some(ref chain) = chains[idx] {
+            let (first, last) = (chain[0], chain[chain.len() - 1]);
+            if first == last {
+                break;
+            }
+            let next = match starts.remove(&last) {
+                Some(next) if next != idx => next,
+                _ => break,
+            };
+            if let Some(next_chain) = chains[next].take() {
+                chains[idx].as_mut().unwrap().extend_from_slice(&next_chain[1..]);
+            }
+        }
+    }
+
+    chains.into_iter().flatten().collect()
+}
+
+// Twice the area of a ring, positive if the ring goes counterclockwise.
+fn signed_area(coords: &[(f64, f64)]) -> f64 {
+    coords
+        .windows(2)
+        .map(|pair| {
+            let ((lat1, lon1), (lat2, lon2)) = (pair[0], pair[1]);
+            lon1 * lat2 - lon2 * lat1
+        })
+        .sum()
+}
+
+// Positions on the boundary are measured clockwise from the north-west corner.
+pub(super) struct Boundary {
+    bounds: Bounds,
+    width: f64,
+    height: f64,
+}
+
+impl Boundary {
+    pub(super) fn new(bounds: Bounds) -> Boundary {
+        let (min_lat, min_lon, max_lat, max_lon) = bounds;
+        Boundary {
+            bounds,
+            width: max_lon - min_lon,
+            height: max_lat - min_lat,
+        }
+    }
+
+    fn perimeter(&self) -> f64 {
+        2.0 * (self.width + self.height)
+    }
+
+    // Corners go clockwise starting from the north-west one.
+    pub(super) fn corner(&self, idx: usize) -> (f64, f64) {
+        let (min_lat, min_lon, max_lat, max_lon) = self.bounds;
+        [
+            (max_lat, min_lon),
+            (max_lat, max_lon),
+            (min_lat, max_lon),
+            (min_lat, min_lon),
+        ][idx]
+    }
+
+    pub(super) fn corner_position(&self, idx: usize) -> f64 {
+        [
+            0.0,
+            self.width,
+            self.width + self.height,
+            2.0 * self.width + self.height,
+        ][idx]
+    }
+
+    // Returns the closest point on the boundary and its position.
+    pub(super) fn project(&self, (lat, lon): (f64, f64)) -> ((f64, f64), f64) {
+        let (min_lat, min_lon, max_lat, max_lon) = self.bounds;
+        let (lat, lon) = (lat.max(min_lat).min(max_lat), lon.max(min_lon).min(max_lon));
+        let distances = [max_lat - lat, max_lon - lon, lat - min_lat, lon - min_lon];
+        let edge = (0..4)
+            .min_by(|e1, e2| distances[*e1].partial_cmp(&distances[*e2]).unwrap())
+            .unwrap();
+        match edge {
+            0 => ((max_lat, lon), lon - min_lon),
+            1 => ((lat, max_lon), self.width + max_lat - lat),
+            2 => ((min_lat, lon), self.width + self.height + max_lon - lon),
+            _ => ((lat, min_lon), 2.0 * self.width + self.height + lat - min_lat),
+        }
+    }
+
+    // How far one needs to go clockwise along the boundary to get from one position to another.
+    pub(super) fn distance(&self, from: f64, to: f64) -> f64 {
+        (to - from).rem_euclid(self.perimeter())
+    }
+
+    // The corners that one passes when going clockwise from one position to another, in that order.
+    pub(super) fn corners_clockwise(&self, from: f64, to: f64) -> Vec<usize> {
+        let corner_distance = |idx: &usize| self.distance(from, self.corner_position(*idx));
+        let mut corners = (0..4)
+            .filter(|idx| corner_distance(idx) > 0.0 && corner_distance(idx) < self.distance(from, to))
+            .collect::<Vec<_>>();
+        corners.sort_by(|c1, c2| corner_distance(c1).partial_cmp(&corner_distance(c2)).unwrap());
+        corners
+    }
+}
+
+fn close_along_boundary(open: &[Vec<usize>], boundary: &Boundary, nodes: &mut NodeStorage) -> Vec<Vec<usize>> {
+    let project = |id| boundary.project(nodes.get_coords(id));
+    let starts = open.iter().map(|chain| project(chain[0])).collect::<Vec<_>>();
+    let ends = open
+        .iter()
+        .map(|chain| project(chain[chain.len() - 1]))
+        .collect::<Vec<_>>();
+
+    let mut rings = Vec::new();
+    let mut used = vec![false; open.len()];
+    for first in 0..open.len() {
+        if used[first] {
+            continue;
+        }
+        let mut ring = Vec::new();
+        let mut current = first;
+        loop {
+            used[current] = true;
+            ring.extend_from_slice(&open[current]);
+            let (end_point, end_pos) = ends[current];
+            let next = (0..open.len())
+                .min_by(|c1, c2| {
+                    let distance = |c: &usize| boundary.distance(end_pos, starts[*c].1);
+                    distance(c1).partial_cmp(&distance(c2)).unwrap()
+                })
+                .unwrap();
+            let (start_point, start_pos) = starts[next];
+
+            add_boundary_point(&mut ring, end_point, nodes);
+            for corner in boundary.corners_clockwise(end_pos, start_pos) {
+                add_boundary_point(&mut ring, boundary.corner(corner), nodes);
+            }
+            if nodes.get_coords(open[next][0]) != start_point {
+                add_boundary_point(&mut ring, start_point, nodes);
+            }
+
+            if next == first {
+                ring.push(open[first][0]);
+                rings.push(ring);
+                break;
+            }
+            if used[next] {
+                eprintln!("Coastlines cross each other, some of the water won't be drawn");
+                break;
+            }
+            current = next;
+        }
+    }
+    rings
+}
+
+// Joins the pieces of a closed way whose nodes outside of the data were dropped (in the order of the way,
+// starting right after a missing node) into a ring. The pieces are connected along the boundary the shorter
+// way round, because the missing parts of the way are usually just beyond it.
+pub(super) fn close_way_along_boundary(
+    pieces: &[Vec<usize>],
+    boundary: &Boundary,
+    nodes: &mut NodeStorage,
+) -> Vec<usize> {
+    let mut ring = Vec::new();
+    for (idx, piece) in pieces.iter().enumerate() {
+        ring.extend_from_slice(piece);
+        let next = &pieces[(idx + 1) % pieces.len()];
+        let (end_point, end_pos) = boundary.project(nodes.get_coords(piece[piece.len() - 1]));
+        let (start_point, start_pos) = boundary.project(nodes.get_coords(next[0]));
+
+        add_boundary_point(&mut ring, end_point, nodes);
+        let corners = if boundary.distance(end_pos, start_pos) <= boundary.distance(start_pos, end_pos) {
+            boundary.corners_clockwise(end_pos, start_pos)
+        } else {
+            let mut corners = boundary.corners_clockwise(start_pos, end_pos);
+            corners.reverse();
+            corners
+        };
+        for corner in corners {
+            add_boundary_point(&mut ring, boundary.corner(corner), nodes);
+        }
+        if nodes.get_coords(next[0]) != start_point {
+            add_boundary_point(&mut ring, start_point, nodes);
+        }
+    }
+    ring.push(pieces[0][0]);
+    ring
+}
+
+// The coastlines often end right on the boundary, so the points are added only if they don't
+// duplicate the previous one.
+fn add_boundary_point(ring: &mut Vec<usize>, (lat, lon): (f64, f64), nodes: &mut NodeStorage) {
+    if ring.last().map(|id| nodes.get_coords(*id)) != Some((lat, lon)) {
+        ring.push(nodes.add_synthetic(lat, lon));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_join_ways() {
+        let chains = join_ways(vec![
+            vec![3, 4, 5],
+            vec![1, 2, 3],
+            vec![5, 1],
+            vec![7, 8],
+            vec![6, 7],
+            vec![9],
+        ]);
+        assert_eq!(chains, vec![vec![3, 4, 5, 1, 2, 3], vec![6, 7, 8]]);
+    }
+
+    #[test]
+    fn test_boundary() {
+        let boundary = Boundary::new((0.0, 0.0, 1.0, 2.0));
+        assert_eq!(boundary.project((1.5, 0.5)), ((1.0, 0.5), 0.5));
+        assert_eq!(boundary.project((0.5, 2.1)), ((0.5, 2.0), 2.5));
+        assert_eq!(boundary.project((0.1, 1.0)), ((0.0, 1.0), 4.0));
+        assert_eq!(boundary.project((0.5, -1.0)), ((0.5, 0.0), 5.5));
+        assert_eq!(boundary.distance(5.5, 0.5), 1.0);
+        assert_eq!(boundary.corners_clockwise(5.5, 2.5), vec![0, 1]);
+        assert_eq!(boundary.corners_clockwise(2.5, 5.5), vec![2, 3]);
+    }
+}
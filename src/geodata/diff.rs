@@ -0,0 +1,712 @@
+use crate::coords::Coords;
+use crate::geodata::area_rules::AreaRules;
+use crate::geodata::clip::SelectedEntities;
+use crate::geodata::encoding::coord_to_fixed;
+use crate::geodata::find_polygons::{find_polygons_in_multipolygon, NodeDesc, NodeDescPair};
+use crate::geodata::importer::{
+    get_id, is_stored_relation, load_stylesheet, parse_required_attr, postprocess_node_refs, process_node_subelement,
+    process_relation_subelement, process_subelements, process_way_subelement, EntityStorages, ParsedMember,
+    ParsedRelation, ParsedWay, Polygon, RawNode, RawRefs, RawTags, RawWay,
+};
+use crate::geodata::reader::{GeodataReader, Member, MemberKind, OsmEntity, Tags, WAY_AREA, WAY_TRUNCATED};
+use crate::geodata::saver::{
+    save_to_internal_format_with_tiles, TileReference, MULTIPOLYGON_REFS_IDX, NODE_REFS_IDX, RELATION_REFS_IDX,
+    WAY_REFS_IDX,
+};
+use crate::mapcss::styler::get_used_tag_keys;
+use crate::tile;
+use failure::{bail, Error, ResultExt};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::{BufReader, BufWriter};
+use xml::attribute::OwnedAttribute;
+use xml::reader::{EventReader, XmlEvent};
+
+/// Applies an OsmChange file (`.osc`) to a geodata file created by the importer and saves the result
+/// to `output`. Returns the tiles of the tile index whose content has changed. These are usually max zoom tiles,
+/// but the entities that cover many tiles are indexed at lower zoom levels, and so are the tiles that contain them.
+///
+/// The base file is loaded into memory entirely. Multipolygons are rebuilt when the relation itself
+/// is changed, when one of its member ways is changed, or when one of its nodes is moved. Other relations
+/// are replaced as a whole when they are changed.
+pub fn apply_diff(base: &str, diff: &str, output: &str) -> Result<Vec<tile::Tile>, Error> {
+    apply_diff_with_options(base, diff, output, &DiffOptions::default())
+}
+
+/// Tweaks for applying a diff.
+#[derive(Default)]
+pub struct DiffOptions {
+    /// The stylesheet that the base file was imported with (see `ImportOptions::stylesheet`). It is required
+    /// for such files, so that the changed entities get their minimum zoom levels, and it has to use the same
+    /// tags, so that the changed entities keep the same tags as the rest.
+    pub stylesheet: Option<String>,
+}
+
+pub fn apply_diff_with_options(
+    base: &str,
+    diff: &str,
+    output: &str,
+    options: &DiffOptions,
+) -> Result<Vec<tile::Tile>, Error> {
+    let diff_file = File::open(diff).context(format!("Failed to open {} for reading", diff))?;
+    let change =
+        parse_osm_change(EventReader::new(BufReader::new(diff_file))).context(format!("Failed to parse {}", diff))?;
+
+    let (entity_storages, known_tile_refs, is_known, mut changed_tiles) = {
+        let reader = GeodataReader::load(base)?;
+        let mut geodata = Geodata::load(&reader)?;
+        let rules = match (&options.stylesheet, &geodata.tag_filter) {
+            (Some(stylesheet), Some(tag_filter)) => {
+                let rules = load_stylesheet(stylesheet)?;
+                if get_used_tag_keys(&rules) != *tag_filter {
+                    bail!(
+                        "{} uses other tags than the stylesheet that {} was imported with",
+                        stylesheet,
+                        base
+                    );
+                }
+                Some(rules)
+            }
+            (None, None) => None,
+            (Some(_), None) => bail!("{} was imported without a stylesheet", base),
+            (None, Some(_)) => bail!(
+                "{} was imported with a stylesheet, the same stylesheet is needed to apply a diff to it",
+                base
+            ),
+        };
+        geodata.apply(change);
+        let (mut entity_storages, new_local_ids) = geodata.to_entity_storages()?;
+        entity_storages.index_zoom = reader.index_zoom();
+        if let Some(ref rules) = rules {
+            entity_storages.compute_min_zooms(rules);
+        }
+
+        let mut known_tile_refs = Vec::new();
+        let mut changed_tiles = BTreeSet::new();
+        for tile_idx in 0..reader.tile_count() {
+            let tile = reader.tile(tile_idx);
+            for refs_idx in &[NODE_REFS_IDX, WAY_REFS_IDX, MULTIPOLYGON_REFS_IDX, RELATION_REFS_IDX] {
+                for local_id in reader.tile_local_ids(tile_idx, *refs_idx as usize) {
+                    let local_id = *local_id as usize;
+                    match geodata.states(*refs_idx)[local_id] {
+                        EntityState::Unchanged => known_tile_refs.push(TileReference::new(
+                            &tile,
+                            *refs_idx,
+                            reader.min_zoom(*refs_idx as usize, local_id),
+                            new_local_ids[*refs_idx as usize][local_id].unwrap() as u32,
+                        )),
+                        _ => {
+                            changed_tiles.insert((tile.zoom, tile.x, tile.y));
+                        }
+                    }
+                }
+            }
+        }
+
+        // Without a stylesheet, all entities are visible at all zoom levels.
+        let mut is_known = Vec::new();
+        for refs_idx in &[NODE_REFS_IDX, WAY_REFS_IDX, MULTIPOLYGON_REFS_IDX, RELATION_REFS_IDX] {
+            let states = geodata.states(*refs_idx);
+            let mut known = Vec::new();
+            let mut min_zooms = Vec::new();
+            for (old_id, new_id) in new_local_ids[*refs_idx as usize].iter().enumerate() {
+                if let Some(new_id) = new_id {
+                    let is_unchanged = states[old_id] == EntityState::Unchanged;
+                    known.push(is_unchanged);
+                    min_zooms.push(if is_unchanged {
+                        reader.min_zoom(*refs_idx as usize, old_id)
+                    } else {
+                        entity_storages.min_zoom(*refs_idx, *new_id)
+                    });
+                }
+            }
+            is_known.push(known);
+            entity_storages.min_zooms[*refs_idx as usize] = min_zooms;
+        }
+
+        (entity_storages, known_tile_refs, is_known, changed_tiles)
+    };
+
+    let output_file = File::create(output).context(format!("Failed to open {} for writing", output))?;
+    let mut writer = BufWriter::new(output_file);
+    let computed_tiles =
+        save_to_internal_format_with_tiles(&mut writer, &entity_storages, known_tile_refs, &|refs_idx, local_id| {
+            is_known[refs_idx as usize][local_id]
+        })
+        .context("Failed to write the updated data to the output file")?;
+
+    changed_tiles.extend(computed_tiles);
+    Ok(changed_tiles
+        .into_iter()
+        .map(|(zoom, x, y)| tile::Tile { zoom, x, y })
+        .collect())
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Action {
+    Create,
+    Modify,
+    Delete,
+}
+
+#[derive(Default)]
+struct OsmChange {
+    nodes: Vec<(Action, RawNode)>,
+    ways: Vec<(Action, ParsedWay)>,
+    relations: Vec<(Action, ParsedRelation)>,
+}
+
+fn parse_osm_change<R: Read>(mut parser: EventReader<R>) -> Result<OsmChange, Error> {
+    let mut change = OsmChange::default();
+    let mut action = None;
+
+    loop {
+        match parser.next()? {
+            XmlEvent::EndDocument => break,
+            XmlEvent::StartElement { name, attributes, .. } => match name.local_name.as_str() {
+                "create" => action = Some(Action::Create),
+                "modify" => action = Some(Action::Modify),
+                "delete" => action = Some(Action::Delete),
+                elem_name @ "node" | elem_name @ "way" | elem_name @ "relation" => match action {
+                    Some(action) => process_changed_element(elem_name, &attributes, action, &mut change, &mut parser)?,
+                    None => bail!("Element {} is outside of create, modify or delete blocks", elem_name),
+                },
+                _ => {}
+            },
+            XmlEvent::EndElement { name } if ["create", "modify", "delete"].contains(&name.local_name.as_str()) => {
+                action = None
+            }
+            _ => {}
+        }
+    }
+
+    Ok(change)
+}
+
+fn process_changed_element<R: Read>(
+    name: &str,
+    attrs: &[OwnedAttribute],
+    action: Action,
+    change: &mut OsmChange,
+    parser: &mut EventReader<R>,
+) -> Result<(), Error> {
+    match name {
+        "node" => {
+            let mut node = RawNode {
+                global_id: get_id(name, attrs)?,
+                ..Default::default()
+            };
+            // Deleted nodes don't always have coordinates.
+            if action != Action::Delete {
+                node.lat = parse_required_attr(name, attrs, "lat")?;
+                node.lon = parse_required_attr(name, attrs, "lon")?;
+            }
+            process_subelements(name, &mut node, &(), process_node_subelement, parser)?;
+            change.nodes.push((action, node));
+        }
+        "way" => {
+            let mut way = ParsedWay {
+                global_id: get_id(name, attrs)?,
+                ..Default::default()
+            };
+            process_subelements(name, &mut way, &(), process_way_subelement, parser)?;
+            change.ways.push((action, way));
+        }
+        "relation" => {
+            let mut relation = ParsedRelation {
+                global_id: get_id(name, attrs)?,
+                ..Default::default()
+            };
+            process_subelements(name, &mut relation, &(), process_relation_subelement, parser)?;
+            change.relations.push((action, relation));
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum EntityState {
+    Unchanged,
+    Changed,
+    Deleted,
+}
+
+// Entities of a single type, indexed by their local ids in the base file. New entities are appended
+// to the end, deleted ones are only marked as such to keep the local ids stable.
+struct EntityList<E> {
+    entities: Vec<E>,
+    states: Vec<EntityState>,
+    global_id_to_local_id: HashMap<u64, usize>,
+}
+
+impl<E> EntityList<E> {
+    fn new(entities: Vec<E>, global_id: impl Fn(&E) -> u64) -> EntityList<E> {
+        let global_id_to_local_id = entities
+            .iter()
+            .enumerate()
+            .map(|(idx, e)| (global_id(e), idx))
+            .collect();
+        EntityList {
+            states: vec![EntityState::Unchanged; entities.len()],
+            entities,
+            global_id_to_local_id,
+        }
+    }
+
+    fn translate_id(&self, global_id: u64) -> Option<usize> {
+        self.global_id_to_local_id
+            .get(&global_id)
+            .cloned()
+            .filter(|idx| self.states[*idx] != EntityState::Deleted)
+    }
+
+    fn upsert(&mut self, global_id: u64, entity: E) -> usize {
+        match self.global_id_to_local_id.get(&global_id) {
+            Some(idx) => {
+                self.entities[*idx] = entity;
+                self.states[*idx] = EntityState::Changed;
+                *idx
+            }
+            None => {
+                let idx = self.entities.len();
+                self.global_id_to_local_id.insert(global_id, idx);
+                self.entities.push(entity);
+                self.states.push(EntityState::Changed);
+                idx
+            }
+        }
+    }
+
+    fn delete(&mut self, global_id: u64) -> Option<usize> {
+        let idx = self.translate_id(global_id)?;
+        self.states[idx] = EntityState::Deleted;
+        Some(idx)
+    }
+
+    fn mark_changed(&mut self, idx: usize) {
+        if self.states[idx] == EntityState::Unchanged {
+            self.states[idx] = EntityState::Changed;
+        }
+    }
+
+    fn alive(&self) -> impl Iterator<Item = (usize, &E)> {
+        self.entities
+            .iter()
+            .enumerate()
+            .filter(move |(idx, _)| self.states[*idx] != EntityState::Deleted)
+    }
+}
+
+type LocalIdMapping = Vec<Option<usize>>;
+
+struct OwnedMultipolygon {
+    global_id: u64,
+    polygons: Vec<Polygon>,
+    tags: RawTags,
+}
+
+// A way that was modified or deleted, so the multipolygons that contain it have to be rebuilt.
+struct ReplacedWay {
+    old_node_ids: RawRefs,
+    new_node_ids: RawRefs,
+}
+
+// All entities of a geodata file in memory, in a form that can be changed and saved again.
+pub(super) struct Geodata {
+    nodes: EntityList<RawNode>,
+    ways: EntityList<RawWay>,
+    multipolygons: EntityList<OwnedMultipolygon>,
+    relations: EntityList<ParsedRelation>,
+    // The rules that the importer used, so that the changed ways are classified the same way.
+    area_rules: AreaRules,
+    // The keys of the tags that the importer kept for the stylesheet, so that the changed entities keep the same.
+    tag_filter: Option<HashSet<String>>,
+}
+
+impl Geodata {
+    pub(super) fn load(reader: &GeodataReader<'_>) -> Result<Geodata, Error> {
+        let to_raw_tags = |tags: Tags<'_>| {
+            tags.iter()
+                .map(|(k, v)| (k.str.to_string(), v.str.to_string()))
+                .collect::<RawTags>()
+        };
+        let to_raw_refs = |ids: &[u32]| ids.iter().map(|id| *id as usize).collect::<RawRefs>();
+
+        let nodes = (0..reader.node_count())
+            .map(|idx| {
+                let node = reader.get_node(idx);
+                RawNode {
+                    global_id: node.global_id(),
+                    lat: node.lat(),
+                    lon: node.lon(),
+                    tags: to_raw_tags(node.tags()),
+                }
+            })
+            .collect();
+
+        let ways = (0..reader.way_count())
+            .map(|idx| {
+                let way = reader.get_way(idx);
+                RawWay {
+                    global_id: way.global_id(),
+                    node_ids: to_raw_refs(way.local_node_ids()),
+                    tags: to_raw_tags(way.tags()),
+                    flags: way.flags(),
+                }
+            })
+            .collect();
+
+        let multipolygons = (0..reader.multipolygon_count())
+            .map(|idx| {
+                let multipolygon = reader.get_multipolygon(idx);
+                OwnedMultipolygon {
+                    global_id: multipolygon.global_id(),
+                    polygons: (0..multipolygon.polygon_count())
+                        .map(|poly_idx| to_raw_refs(multipolygon.get_polygon(poly_idx).local_node_ids()))
+                        .collect(),
+                    tags: to_raw_tags(multipolygon.tags()),
+                }
+            })
+            .collect();
+
+        let relations = (0..reader.relation_count())
+            .map(|idx| {
+                let relation = reader.get_relation(idx);
+                ParsedRelation {
+                    global_id: relation.global_id(),
+                    members: relation
+                        .members()
+                        .map(|m| {
+                            let (kind, global_id) = match m.member {
+                                Member::Node(node) => (MemberKind::Node, node.global_id()),
+                                Member::Way(way) => (MemberKind::Way, way.global_id()),
+                                Member::Relation(relation) => (MemberKind::Relation, relation.global_id()),
+                            };
+                            ParsedMember {
+                                kind,
+                                global_id,
+                                role: m.role.to_string(),
+                            }
+                        })
+                        .collect(),
+                    tags: to_raw_tags(relation.tags()),
+                }
+            })
+            .collect();
+
+        let area_rules =
+            AreaRules::parse(reader.area_rules()).context("Failed to parse the area rules stored in the geodata")?;
+        let tag_filter = reader
+            .tag_filter()
+            .map(|keys| keys.lines().map(str::to_string).collect());
+
+        Ok(Geodata {
+            nodes: EntityList::new(nodes, |n| n.global_id),
+            ways: EntityList::new(ways, |w| w.global_id),
+            multipolygons: EntityList::new(multipolygons, |mp| mp.global_id),
+            relations: EntityList::new(relations, |r| r.global_id),
+            area_rules,
+            tag_filter,
+        })
+    }
+
+    fn states(&self, refs_idx: u8) -> &[EntityState] {
+        match refs_idx {
+            NODE_REFS_IDX => &self.nodes.states,
+            WAY_REFS_IDX => &self.ways.states,
+            MULTIPOLYGON_REFS_IDX => &self.multipolygons.states,
+            _ => &self.relations.states,
+        }
+    }
+
+    fn apply(&mut self, change: OsmChange) {
+        let moved_nodes = self.apply_node_changes(change.nodes);
+        let replaced_ways = self.apply_way_changes(change.ways);
+        for idx in 0..self.ways.entities.len() {
+            if self.ways.entities[idx].node_ids.iter().any(|n| moved_nodes.contains(n)) {
+                self.ways.mark_changed(idx);
+            }
+        }
+
+        let changed_relations = self.apply_relation_changes(change.relations);
+        self.rebuild_multipolygons(&replaced_ways, &changed_relations);
+        for idx in 0..self.multipolygons.entities.len() {
+            let polygons = &self.multipolygons.entities[idx].polygons;
+            if polygons.iter().flat_map(|p| p.iter()).any(|n| moved_nodes.contains(n)) {
+                self.multipolygons.mark_changed(idx);
+            }
+        }
+
+        // The tiles of a relation depend on the positions of its members.
+        for idx in 0..self.relations.entities.len() {
+            let has_changed_members = self.relations.entities[idx].members.iter().any(|m| {
+                let (states, local_id) = match m.kind {
+                    MemberKind::Node => (&self.nodes.states, self.nodes.global_id_to_local_id.get(&m.global_id)),
+                    MemberKind::Way => (&self.ways.states, self.ways.global_id_to_local_id.get(&m.global_id)),
+                    MemberKind::Relation => return false,
+                };
+                local_id.is_some_and(|id| states[*id] != EntityState::Unchanged)
+            });
+            if has_changed_members {
+                self.relations.mark_changed(idx);
+            }
+        }
+    }
+
+    // Returns the local ids of the nodes that were moved or deleted.
+    fn apply_node_changes(&mut self, changes: Vec<(Action, RawNode)>) -> HashSet<usize> {
+        let mut moved_nodes = HashSet::new();
+        for (action, node) in changes {
+            if action == Action::Delete {
+                moved_nodes.extend(self.nodes.delete(node.global_id));
+                continue;
+            }
+            if let Some(idx) = self.nodes.translate_id(node.global_id) {
+                let old_node = &self.nodes.entities[idx];
+                // Stored coordinates are rounded to the fixed-point precision, so the new ones are rounded too.
+                let to_fixed = |lat, lon| (coord_to_fixed(lat), coord_to_fixed(lon));
+                if to_fixed(old_node.lat, old_node.lon) != to_fixed(node.lat, node.lon) {
+                    moved_nodes.insert(idx);
+                }
+            }
+            self.nodes.upsert(node.global_id, node);
+        }
+        moved_nodes
+    }
+
+    fn apply_way_changes(&mut self, changes: Vec<(Action, ParsedWay)>) -> Vec<ReplacedWay> {
+        let area_rules = &self.area_rules;
+        let mut replaced_ways = Vec::new();
+        for (action, way) in changes {
+            let mut node_ids = RawRefs::new();
+            let mut flags = if area_rules.is_area(&way.tags) { WAY_AREA } else { 0 };
+            if action != Action::Delete {
+                node_ids.extend(way.node_ids.iter().filter_map(|id| self.nodes.translate_id(*id)));
+                // Diffs don't close the truncated ways, whatever the import options were.
+                if node_ids.len() != way.node_ids.len() {
+                    flags |= WAY_TRUNCATED;
+                }
+                postprocess_node_refs(&mut node_ids);
+            }
+
+            if let Some(idx) = self.ways.translate_id(way.global_id) {
+                replaced_ways.push(ReplacedWay {
+                    old_node_ids: self.ways.entities[idx].node_ids.clone(),
+                    new_node_ids: node_ids.clone(),
+                });
+            }
+
+            if action == Action::Delete {
+                self.ways.delete(way.global_id);
+            } else {
+                let raw_way = RawWay {
+                    global_id: way.global_id,
+                    node_ids,
+                    tags: way.tags,
+                    flags,
+                };
+                self.ways.upsert(way.global_id, raw_way);
+            }
+        }
+        replaced_ways
+    }
+
+    // Returns the global ids of all relations that were mentioned in the diff.
+    fn apply_relation_changes(&mut self, changes: Vec<(Action, ParsedRelation)>) -> HashSet<u64> {
+        let mut changed_relations = HashSet::new();
+        for (action, relation) in changes {
+            changed_relations.insert(relation.global_id);
+
+            // The type of a relation can change, so it's also removed from the list it no longer belongs to.
+            if action != Action::Delete && is_stored_relation(&relation.tags) {
+                self.multipolygons.delete(relation.global_id);
+                self.relations.upsert(relation.global_id, relation);
+                continue;
+            }
+            self.relations.delete(relation.global_id);
+
+            let is_multipolygon = relation.tags.get("type").map(String::as_str) == Some("multipolygon");
+            let polygons = if action != Action::Delete && is_multipolygon {
+                let segments = relation
+                    .way_ids()
+                    .filter_map(|id| self.ways.translate_id(id))
+                    .flat_map(|idx| self.to_segments(&self.ways.entities[idx].node_ids))
+                    .collect::<Vec<_>>();
+                find_polygons_in_multipolygon(relation.global_id, &segments, None)
+            } else {
+                None
+            };
+
+            match polygons {
+                Some(polygons) => {
+                    let multipolygon = OwnedMultipolygon {
+                        global_id: relation.global_id,
+                        polygons,
+                        tags: relation.tags,
+                    };
+                    self.multipolygons.upsert(relation.global_id, multipolygon);
+                }
+                None => {
+                    self.multipolygons.delete(relation.global_id);
+                }
+            }
+        }
+        changed_relations
+    }
+
+    // The geodata file doesn't know which ways the multipolygons were built from, so we look for
+    // the multipolygons that contain all segments of a replaced way, swap these segments for the new
+    // ones and assemble the rings again.
+    fn rebuild_multipolygons(&mut self, replaced_ways: &[ReplacedWay], changed_relations: &HashSet<u64>) {
+        if replaced_ways.is_empty() {
+            return;
+        }
+
+        let mut segment_to_replaced_ways = HashMap::<_, Vec<usize>>::new();
+        for (way_idx, way) in replaced_ways.iter().enumerate() {
+            for segment in way_segments(&way.old_node_ids) {
+                segment_to_replaced_ways.entry(segment).or_default().push(way_idx);
+            }
+        }
+
+        for idx in 0..self.multipolygons.entities.len() {
+            let multipolygon = &self.multipolygons.entities[idx];
+            if self.multipolygons.states[idx] == EntityState::Deleted
+                || changed_relations.contains(&multipolygon.global_id)
+            {
+                continue;
+            }
+
+            let mut segments = multipolygon
+                .polygons
+                .iter()
+                .flat_map(|p| way_segments(p))
+                .collect::<Vec<_>>();
+            let segment_set = segments.iter().cloned().collect::<HashSet<_>>();
+
+            let mut candidates = segments
+                .iter()
+                .filter_map(|s| segment_to_replaced_ways.get(s))
+                .flatten()
+                .cloned()
+                .collect::<Vec<_>>();
+            candidates.sort();
+            candidates.dedup();
+            let contained_ways = candidates
+                .into_iter()
+                .map(|way_idx| &replaced_ways[way_idx])
+                .filter(|way| way_segments(&way.old_node_ids).all(|s| segment_set.contains(&s)))
+                .collect::<Vec<_>>();
+            if contained_ways.is_empty() {
+                continue;
+            }
+
+            for way in contained_ways {
+                let old_segments = way_segments(&way.old_node_ids).collect::<HashSet<_>>();
+                segments.retain(|s| !old_segments.contains(s));
+                segments.extend(way_segments(&way.new_node_ids));
+            }
+
+            let node_segments = segments
+                .iter()
+                .flat_map(|(n1, n2)| self.to_segments(&[*n1, *n2]))
+                .collect::<Vec<_>>();
+            let global_id = multipolygon.global_id;
+            match find_polygons_in_multipolygon(global_id, &node_segments, None) {
+                Some(polygons) => {
+                    self.multipolygons.entities[idx].polygons = polygons;
+                    self.multipolygons.mark_changed(idx);
+                }
+                None => {
+                    self.multipolygons.delete(global_id);
+                }
+            }
+        }
+    }
+
+    fn to_segments(&self, node_ids: &[usize]) -> Vec<NodeDescPair> {
+        let create_node_desc = |node_id: usize| {
+            let node = &self.nodes.entities[node_id];
+            NodeDesc::new(node_id, node.lat, node.lon)
+        };
+        (1..node_ids.len())
+            .map(|idx| NodeDescPair::new(create_node_desc(node_ids[idx - 1]), create_node_desc(node_ids[idx])))
+            .collect()
+    }
+
+    // Deletes all entities except the selected ones. Multipolygons are selected by their relation ids.
+    pub(super) fn retain(&mut self, selected: &SelectedEntities) {
+        fn retain_list<E>(list: &mut EntityList<E>, ids: &HashSet<u64>, global_id: impl Fn(&E) -> u64) {
+            for (entity, state) in list.entities.iter().zip(list.states.iter_mut()) {
+                if !ids.contains(&global_id(entity)) {
+                    *state = EntityState::Deleted;
+                }
+            }
+        }
+
+        retain_list(&mut self.nodes, &selected.nodes, |n| n.global_id);
+        retain_list(&mut self.ways, &selected.ways, |w| w.global_id);
+        retain_list(&mut self.multipolygons, &selected.relations, |mp| mp.global_id);
+        retain_list(&mut self.relations, &selected.relations, |r| r.global_id);
+    }
+
+    // Returns the new storages and the mapping from the old local ids to the new ones
+    // (for nodes, ways, multipolygons and relations).
+    pub(super) fn to_entity_storages(&self) -> Result<(EntityStorages, [LocalIdMapping; 4]), Error> {
+        let mut entity_storages = EntityStorages::new(None)?;
+        entity_storages.area_rules = self.area_rules.clone();
+        entity_storages.tag_filter = self.tag_filter.clone();
+
+        let mut new_node_ids = vec![None; self.nodes.entities.len()];
+        for (new_id, (old_id, node)) in self.nodes.alive().enumerate() {
+            new_node_ids[old_id] = Some(new_id);
+            entity_storages.add_node(node.clone())?;
+        }
+        entity_storages.finish_ways()?;
+
+        let remap_nodes = |node_ids: &RawRefs| node_ids.iter().filter_map(|n| new_node_ids[*n]).collect::<RawRefs>();
+
+        let mut new_way_ids = vec![None; self.ways.entities.len()];
+        for (new_id, (old_id, way)) in self.ways.alive().enumerate() {
+            new_way_ids[old_id] = Some(new_id);
+            entity_storages.add_way(RawWay {
+                global_id: way.global_id,
+                node_ids: remap_nodes(&way.node_ids),
+                tags: way.tags.clone(),
+                flags: way.flags,
+            })?;
+        }
+
+        let mut new_multipolygon_ids = vec![None; self.multipolygons.entities.len()];
+        for (new_id, (old_id, multipolygon)) in self.multipolygons.alive().enumerate() {
+            new_multipolygon_ids[old_id] = Some(new_id);
+            let polygons = multipolygon.polygons.iter().map(remap_nodes).collect();
+            entity_storages.add_multipolygon(multipolygon.global_id, polygons, multipolygon.tags.clone())?;
+        }
+
+        let mut new_relation_ids = vec![None; self.relations.entities.len()];
+        for (new_id, (old_id, relation)) in self.relations.alive().enumerate() {
+            new_relation_ids[old_id] = Some(new_id);
+            entity_storages.add_stored_relation(relation.clone())?;
+        }
+
+        Ok((
+            entity_storages,
+            [new_node_ids, new_way_ids, new_multipolygon_ids, new_relation_ids],
+        ))
+    }
+}
+
+// Segments as unordered pairs of local node ids.
+fn way_segments(node_ids: &[usize]) -> impl Iterator<Item = (usize, usize)> + '_ {
+    (1..node_ids.len()).map(move |idx| {
+        let (n1, n2) = (node_ids[idx - 1], node_ids[idx]);
+        if n1 < n2 {
+            (n1, n2)
+        } else {
+            (n2, n1)
+        }
+    })
+}
@@ -0,0 +1,90 @@
+// Compact encodings shared by the saver and the reader.
+//
+// Coordinates are stored as fixed-point i32 numbers with 7 decimal digits, which is the precision
+// of the coordinates in OSM data.
+//
+// Lists of local ids (the nodes of ways and polygons, the polygons of multipolygons and the parents
+// of entities) are stored as packed blocks: every value is replaced with its difference from the value
+// `stride` positions before it (or from 0 for the first `stride` values), and the differences are
+// written as zigzag-encoded LEB128 varints. Neighboring nodes of a way usually have close local ids,
+// so most of the values take one or two bytes instead of four. For lists of tuples (e.g. parents,
+// which are triples of a kind, a local id and an index), the stride is the size of the tuple, so that
+// the deltas are computed between the same fields.
+
+const COORD_SCALE: f64 = 1e7;
+
+pub(super) fn coord_to_fixed(coord: f64) -> i32 {
+    (coord * COORD_SCALE).round() as i32
+}
+
+pub(super) fn fixed_to_coord(fixed: i32) -> f64 {
+    f64::from(fixed) / COORD_SCALE
+}
+
+pub(super) fn pack_refs(refs: &[u32], stride: usize, output: &mut Vec<u8>) {
+    for (idx, value) in refs.iter().enumerate() {
+        let prev = if idx >= stride { refs[idx - stride] } else { 0 };
+        let delta = i64::from(*value) - i64::from(prev);
+        let mut zigzag = ((delta << 1) ^ (delta >> 63)) as u64;
+        loop {
+            let byte = (zigzag & 0x7f) as u8;
+            zigzag >>= 7;
+            if zigzag == 0 {
+                output.push(byte);
+                break;
+            }
+            output.push(byte | 0x80);
+        }
+    }
+}
+
+// Decodes `count` values from the start of `bytes`.
+pub(super) fn unpack_refs(bytes: &[u8], count: usize, stride: usize) -> Vec<u32> {
+    let mut result = Vec::with_capacity(count);
+    let mut pos = 0;
+    while result.len() < count {
+        let mut zigzag = 0u64;
+        let mut shift = 0;
+        loop {
+            let byte = bytes[pos];
+            pos += 1;
+            zigzag |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                break;
+            }
+            shift += 7;
+        }
+        let delta = (zigzag >> 1) as i64 ^ -((zigzag & 1) as i64);
+        let idx = result.len();
+        let prev = if idx >= stride { result[idx - stride] } else { 0 };
+        result.push((i64::from(prev) + delta) as u32);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_packed_refs() {
+        let refs = [5, 6, 7, 3, 100_000, 99_999, 0, u32::MAX, 0, 1];
+        for stride in 1..=3 {
+            let mut packed = Vec::new();
+            pack_refs(&refs, stride, &mut packed);
+            assert_eq!(unpack_refs(&packed, refs.len(), stride), refs);
+        }
+
+        let mut packed = Vec::new();
+        pack_refs(&[1000, 1001, 1002, 1001], 1, &mut packed);
+        assert_eq!(packed.len(), 2 + 3);
+        assert!(unpack_refs(&packed, 0, 1).is_empty());
+    }
+
+    #[test]
+    fn test_fixed_point_coords() {
+        for coord in &[55.7512345, -179.9999999, 0.0, 85.0511287] {
+            assert_eq!(fixed_to_coord(coord_to_fixed(*coord)), *coord);
+        }
+    }
+}
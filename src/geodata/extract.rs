@@ -0,0 +1,58 @@
+use crate::geodata::clip::{select_geodata_entities, ClipArea};
+use crate::geodata::diff::Geodata;
+use crate::geodata::reader::{GeodataReader, OsmEntity};
+use crate::geodata::saver::{
+    save_to_internal_format, MULTIPOLYGON_REFS_IDX, NODE_REFS_IDX, RELATION_REFS_IDX, WAY_REFS_IDX,
+};
+use failure::{Error, ResultExt};
+use std::fs::File;
+use std::io::BufWriter;
+
+/// Saves the part of the geodata file `input` that is inside `area` to `output`. The entities are selected
+/// the same way as when the data is clipped during the import (see `ImportOptions::clip_area`), and keep
+/// their minimum zoom levels. The only difference is that geodata files don't know which ways were
+/// the members of a multipolygon, so the member ways that are completely outside of the area are dropped
+/// even if the multipolygon is kept. Like `apply_diff`, this loads the whole input file into memory.
+pub fn extract(input: &str, area: &ClipArea, output: &str) -> Result<(), Error> {
+    let entity_storages = {
+        let reader = GeodataReader::load(input)?;
+        let mut selected = select_geodata_entities(&reader, area);
+
+        // The ways and the multipolygons are kept whole, along with their nodes outside of the area.
+        let mut complete_nodes = Vec::new();
+        for way_id in &selected.ways {
+            let way = reader.get_way_by_id(*way_id).unwrap();
+            complete_nodes.extend((0..way.node_count()).map(|idx| way.get_node(idx).global_id()));
+        }
+        for relation_id in &selected.relations {
+            if let Some(multipolygon) = reader.get_multipolygon_by_id(*relation_id) {
+                for poly_idx in 0..multipolygon.polygon_count() {
+                    let polygon = multipolygon.get_polygon(poly_idx);
+                    complete_nodes.extend((0..polygon.node_count()).map(|idx| polygon.get_node(idx).global_id()));
+                }
+            }
+        }
+        selected.nodes.extend(complete_nodes);
+
+        let mut geodata = Geodata::load(&reader)?;
+        geodata.retain(&selected);
+        let (mut entity_storages, new_local_ids) = geodata.to_entity_storages()?;
+        entity_storages.index_zoom = reader.index_zoom();
+        for refs_idx in &[NODE_REFS_IDX, WAY_REFS_IDX, MULTIPOLYGON_REFS_IDX, RELATION_REFS_IDX] {
+            let refs_idx = *refs_idx as usize;
+            entity_storages.min_zooms[refs_idx] = new_local_ids[refs_idx]
+                .iter()
+                .enumerate()
+                .filter(|(_, new_id)| new_id.is_some())
+                .map(|(old_id, _)| reader.min_zoom(refs_idx, old_id))
+                .collect();
+        }
+        entity_storages
+    };
+
+    let output_file = File::create(output).context(format!("Failed to open {} for writing", output))?;
+    let mut writer = BufWriter::new(output_file);
+    save_to_internal_format(&mut writer, &entity_storages)
+        .context("Failed to write the extracted data to the output file")?;
+    Ok(())
+}
@@ -1,226 +1,855 @@
+use crate::geodata::coastline::{Boundary, Bounds};
 use crate::geodata::importer::Polygon;
+use crate::geodata::validation::{MultipolygonProblem, ValidationReport};
+use std::cmp::Ordering;
 use std::collections::{HashMap, HashSet};
 
 type NodePos = (u64, u64);
 
+#[derive(Clone)]
 pub(super) struct NodeDesc {
     id: usize,
-    pos: NodePos,
+    lat: f64,
+    lon: f64,
 }
 
 impl NodeDesc {
     pub(super) fn new(id: usize, lat: f64, lon: f64) -> NodeDesc {
-        NodeDesc {
-            id,
-            pos: (lat.to_bits(), lon.to_bits()),
-        }
+        NodeDesc { id, lat, lon }
+    }
+
+    fn pos(&self) -> NodePos {
+        (self.lat.to_bits(), self.lon.to_bits())
     }
 }
 
 pub(super) struct NodeDescPair {
     node1: NodeDesc,
     node2: NodeDesc,
-    is_inner: bool,
 }
 
 impl NodeDescPair {
-    pub(super) fn new(node1: NodeDesc, node2: NodeDesc, is_inner: bool) -> NodeDescPair {
-        NodeDescPair { node1, node2, is_inner }
+    pub(super) fn new(node1: NodeDesc, node2: NodeDesc) -> NodeDescPair {
+        NodeDescPair { node1, node2 }
+    }
+
+    fn other_side(&self, pos: NodePos) -> &NodeDesc {
+        if self.node1.pos() == pos {
+            &self.node2
+        } else {
+            &self.node1
+        }
     }
 }
 
+// The largest gap between the ends of the ways that is closed when repairing a multipolygon.
+const MAX_REPAIRED_GAP_METERS: f64 = 20.0;
+
+// A rough length of a degree of latitude, which is enough to compare small distances.
+const METERS_PER_DEGREE: f64 = 111_320.0;
+
+/// How to repair the multipolygons with unclosed rings.
+pub(super) struct MultipolygonRepair<'r> {
+    // The bounds of the imported data. The rings that are cut off by them are closed along them.
+    pub(super) bounds: Bounds,
+    // Adds a node at the given coordinates and returns its local id. Used for the points along the bounds.
+    pub(super) add_node: &'r mut dyn FnMut(f64, f64) -> usize,
+}
+
+// Segments are expected to come in the order of relation members, with consecutive segments of
+// the same way following each other. The rings are built as follows:
+//   * segments that occur an even number of times cancel each other out (this happens when
+//     two rings share an edge, and the union of these rings is what the mapper meant);
+//   * the remaining segments are walked along, and every time the walk comes to a vertex it has
+//     already visited, the loop is cut off as a separate ring, so that rings touching each other
+//     at a vertex become separate simple rings;
+//   * the roles of the members are ignored, and each ring is classified as outer or inner
+//     depending on how many other rings contain it.
+// If `report` is present, the problems with the relation are added to it instead of being printed, and
+// the rings are also checked for self-intersections.
 pub(super) fn find_polygons_in_multipolygon(
     relation_id: u64,
     relation_segments: &[NodeDescPair],
+    report: Option<&mut ValidationReport>,
 ) -> Option<Vec<Polygon>> {
-    let connections = get_connections(relation_segments);
-    let mut unmatched_count = relation_segments.len();
-    let mut available_segments = vec![true; relation_segments.len()];
-    let mut all_rings = Vec::new();
-
-    while unmatched_count > 0 {
-        match find_ring(relation_segments, &connections, &mut available_segments) {
-            Some(ring) => {
-                unmatched_count -= ring.len();
-                all_rings.push(ring);
+    find_polygons(relation_id, relation_segments, report, None).map(|(polygons, _)| polygons)
+}
+
+// Same as `find_polygons_in_multipolygon`, but the relations with unclosed rings are repaired instead of
+// being dropped:
+//   * the ends that are at most MAX_REPAIRED_GAP_METERS apart are connected, the closest ones first;
+//   * the ends that are outside of the bounds or close to them are connected along the bounds. The ends are
+//     paired in the order of their positions along the bounds, and of the two ways to pair them, the one
+//     that adds less of the bounds is chosen;
+//   * the chains of segments that still lead nowhere are dropped, and the rings are built from the rest.
+// Returns the polygons and whether the relation had to be repaired.
+pub(super) fn repair_polygons_in_multipolygon(
+    relation_id: u64,
+    relation_segments: &[NodeDescPair],
+    report: Option<&mut ValidationReport>,
+    repair: &mut MultipolygonRepair<'_>,
+) -> Option<(Vec<Polygon>, bool)> {
+    find_polygons(relation_id, relation_segments, report, Some(repair))
+}
+
+fn find_polygons(
+    relation_id: u64,
+    relation_segments: &[NodeDescPair],
+    mut report: Option<&mut ValidationReport>,
+    repair: Option<&mut MultipolygonRepair<'_>>,
+) -> Option<(Vec<Polygon>, bool)> {
+    let added_segments;
+    let mut segments = remove_duplicate_segments(relation_segments);
+    if let Some(ref mut report) = report {
+        report_duplicate_segments(relation_id, relation_segments, report);
+    }
+
+    let mut connections = get_connections(&segments);
+    let unconnected_ends = find_unconnected_ends(&segments, &connections);
+    let first_end_location = unconnected_ends.first().map(|n| (n.lat, n.lon));
+    if !unconnected_ends.is_empty() {
+        match repair {
+            Some(repair) => {
+                added_segments = repair.close_rings(&unconnected_ends);
+                segments.extend(added_segments.iter());
+                remove_dangling_segments(&mut segments);
+                connections = get_connections(&segments);
             }
             None => {
-                eprintln!(
-                    "Relation #{} is not a valid multipolygon (built {} complete rings, but {} segments are unmatched)",
-                    relation_id,
-                    all_rings.len(),
-                    unmatched_count,
-                );
+                match report {
+                    Some(report) => report.add(
+                        relation_id,
+                        MultipolygonProblem::UnclosedRing,
+                        unconnected_ends.len(),
+                        first_end_location,
+                        true,
+                    ),
+                    None => eprintln!(
+                        "Relation #{} is not a valid multipolygon ({} ring ends are not connected to anything)",
+                        relation_id,
+                        unconnected_ends.len(),
+                    ),
+                }
                 return None;
             }
         }
     }
 
-    let mut polygons = Vec::new();
-    for ring in all_rings {
-        let mut polygon = Polygon::default();
-        for idx in 0..ring.len() {
-            let seg = &relation_segments[ring[idx]];
-            if idx == 0 {
-                polygon.push(seg.node1.id);
-            }
-            let last_node = polygon[polygon.len() - 1];
-            polygon.push(if last_node == seg.node1.id {
-                seg.node2.id
-            } else {
-                seg.node1.id
-            });
+    let rings = find_rings(&segments, &connections);
+    let repaired = !unconnected_ends.is_empty();
+    if let Some(report) = report {
+        if repaired {
+            report.add(
+                relation_id,
+                MultipolygonProblem::UnclosedRing,
+                unconnected_ends.len(),
+                first_end_location,
+                rings.is_empty(),
+            );
+        }
+        let (intersection_count, first_intersection) = find_intersections(&rings);
+        if intersection_count > 0 {
+            report.add(
+                relation_id,
+                MultipolygonProblem::SelfIntersection,
+                intersection_count,
+                first_intersection,
+                false,
+            );
         }
-        polygons.push(polygon);
     }
-    Some(polygons)
+    if repaired && rings.is_empty() {
+        return None;
+    }
+    Some((order_rings(rings), repaired))
 }
 
-struct SearchParams {
-    first_pos: NodePos,
-    is_inner: bool,
+fn get_connections(segments: &[&NodeDescPair]) -> SegmentConnections {
+    let mut connections = SegmentConnections::new();
+    for (idx, seg) in segments.iter().enumerate() {
+        connections.entry(seg.node1.pos()).or_default().push(idx);
+        connections.entry(seg.node2.pos()).or_default().push(idx);
+    }
+    connections
 }
 
-struct ConnectedSegment {
-    other_side: NodePos,
-    segment_index: usize,
-    is_inner: bool,
+// Returns the vertices with an odd number of segments in the order of the segments.
+fn find_unconnected_ends(segments: &[&NodeDescPair], connections: &SegmentConnections) -> Vec<NodeDesc> {
+    let mut seen = HashSet::new();
+    segments
+        .iter()
+        .flat_map(|seg| vec![&seg.node1, &seg.node2])
+        .filter(|n| connections[&n.pos()].len() % 2 == 1 && seen.insert(n.pos()))
+        .cloned()
+        .collect()
 }
 
-type SegmentConnections = HashMap<NodePos, Vec<ConnectedSegment>>;
+// Repeatedly drops the segments that have a loose end, which leaves only the segments that are
+// a part of some ring.
+fn remove_dangling_segments(segments: &mut Vec<&NodeDescPair>) {
+    let connections = get_connections(segments);
+    let mut degrees = connections
+        .iter()
+        .map(|(pos, segs)| (*pos, segs.len()))
+        .collect::<HashMap<_, _>>();
+    let mut removed = vec![false; segments.len()];
+    let mut loose_ends = degrees
+        .iter()
+        .filter(|(_, degree)| **degree == 1)
+        .map(|(pos, _)| *pos)
+        .collect::<Vec<_>>();
+    while let Some(pos) = loose_ends.pop() {
+        if let Some(idx) = connections[&pos].iter().cloned().find(|idx| !removed[*idx]) {
+            removed[idx] = true;
+            let other_pos = segments[idx].other_side(pos).pos();
+            *degrees.get_mut(&pos).unwrap() -= 1;
+            let other_degree = degrees.get_mut(&other_pos).unwrap();
+            *other_degree -= 1;
+            if *other_degree == 1 {
+                loose_ends.push(other_pos);
+            }
+        }
+    }
 
-fn get_connections(relation_segments: &[NodeDescPair]) -> SegmentConnections {
-    let mut connections = SegmentConnections::new();
+    let mut idx = 0;
+    segments.retain(|_| {
+        idx += 1;
+        !removed[idx - 1]
+    });
+}
 
-    for (idx, seg) in relation_segments.iter().enumerate() {
-        add_to_connections(&mut connections, seg.node1.pos, seg.node2.pos, idx, seg.is_inner);
-        add_to_connections(&mut connections, seg.node2.pos, seg.node1.pos, idx, seg.is_inner);
+fn distance_meters(n1: &NodeDesc, n2: &NodeDesc) -> f64 {
+    let lat_meters = (n1.lat - n2.lat) * METERS_PER_DEGREE;
+    let lon_meters = (n1.lon - n2.lon) * METERS_PER_DEGREE * n1.lat.to_radians().cos();
+    lat_meters.hypot(lon_meters)
+}
+
+impl<'r> MultipolygonRepair<'r> {
+    // Returns the segments that connect the unconnected ends.
+    fn close_rings(&mut self, ends: &[NodeDesc]) -> Vec<NodeDescPair> {
+        let mut added = Vec::new();
+
+        let mut gaps = Vec::new();
+        for i in 0..ends.len() {
+            for j in i + 1..ends.len() {
+                let distance = distance_meters(&ends[i], &ends[j]);
+                if distance <= MAX_REPAIRED_GAP_METERS {
+                    gaps.push((distance, i, j));
+                }
+            }
+        }
+        gaps.sort_by(|g1, g2| g1.partial_cmp(g2).unwrap_or(Ordering::Equal));
+        let mut used = vec![false; ends.len()];
+        for (_, i, j) in gaps {
+            if !used[i] && !used[j] {
+                used[i] = true;
+                used[j] = true;
+                added.push(NodeDescPair::new(ends[i].clone(), ends[j].clone()));
+            }
+        }
+
+        let boundary = Boundary::new(self.bounds);
+        let (min_lat, min_lon, max_lat, max_lon) = self.bounds;
+        let mut boundary_ends = ends
+            .iter()
+            .zip(used)
+            .filter(|(_, used)| !used)
+            .map(|(end, _)| {
+                let (point, pos) = boundary.project((end.lat, end.lon));
+                (end, NodeDesc::new(end.id, point.0, point.1), pos)
+            })
+            .filter(|(end, point, _)| {
+                let is_outside = end.lat < min_lat || end.lat > max_lat || end.lon < min_lon || end.lon > max_lon;
+                is_outside || distance_meters(end, point) <= MAX_REPAIRED_GAP_METERS
+            })
+            .collect::<Vec<_>>();
+        if boundary_ends.len() % 2 == 1 {
+            let farthest = (0..boundary_ends.len()).max_by(|i1, i2| {
+                let distance = |idx: &usize| distance_meters(boundary_ends[*idx].0, &boundary_ends[*idx].1);
+                distance(i1).partial_cmp(&distance(i2)).unwrap_or(Ordering::Equal)
+            });
+            boundary_ends.remove(farthest.unwrap());
+        }
+        if boundary_ends.is_empty() {
+            return added;
+        }
+        boundary_ends.sort_by(|e1, e2| e1.2.partial_cmp(&e2.2).unwrap_or(Ordering::Equal));
+
+        let count = boundary_ends.len();
+        let pair = |offset: usize, idx: usize| (offset + 2 * idx) % count;
+        let added_length = |offset: usize| {
+            (0..count / 2)
+                .map(|idx| {
+                    let (from, to) = (&boundary_ends[pair(offset, idx)], &boundary_ends[pair(offset + 1, idx)]);
+                    boundary.distance(from.2, to.2)
+                })
+                .sum::<f64>()
+        };
+        let offset = if added_length(1) < added_length(0) { 1 } else { 0 };
+        for idx in 0..count / 2 {
+            let (from, to) = (&boundary_ends[pair(offset, idx)], &boundary_ends[pair(offset + 1, idx)]);
+            let mut path = vec![from.0.clone()];
+            let mut add_point = |path: &mut Vec<NodeDesc>, lat: f64, lon: f64| {
+                let last = &path[path.len() - 1];
+                if (last.lat, last.lon) != (lat, lon) {
+                    path.push(NodeDesc::new((self.add_node)(lat, lon), lat, lon));
+                }
+            };
+            add_point(&mut path, from.1.lat, from.1.lon);
+            for corner in boundary.corners_clockwise(from.2, to.2) {
+                let (lat, lon) = boundary.corner(corner);
+                add_point(&mut path, lat, lon);
+            }
+            // The end may be right on the boundary, and then it is its own projection.
+            if (to.1.lat, to.1.lon) != (to.0.lat, to.0.lon) {
+                add_point(&mut path, to.1.lat, to.1.lon);
+            }
+            path.push(to.0.clone());
+            added.extend(path.windows(2).map(|p| NodeDescPair::new(p[0].clone(), p[1].clone())));
+        }
+        added
     }
+}
 
-    connections
+fn segment_key(seg: &NodeDescPair) -> (NodePos, NodePos) {
+    let (pos1, pos2) = (seg.node1.pos(), seg.node2.pos());
+    if pos1 < pos2 {
+        (pos1, pos2)
+    } else {
+        (pos2, pos1)
+    }
 }
 
-fn add_to_connections(
-    connections: &mut SegmentConnections,
-    pos1: NodePos,
-    pos2: NodePos,
-    segment_index: usize,
-    is_inner: bool,
-) {
-    connections.entry(pos1).or_default().push(ConnectedSegment {
-        other_side: pos2,
-        segment_index,
-        is_inner,
-    });
+fn remove_duplicate_segments(relation_segments: &[NodeDescPair]) -> Vec<&NodeDescPair> {
+    let mut occurrences = HashMap::<_, usize>::new();
+    for seg in relation_segments {
+        *occurrences.entry(segment_key(seg)).or_default() += 1;
+    }
+
+    let mut seen = HashSet::new();
+    relation_segments
+        .iter()
+        .filter(|seg| {
+            let k = segment_key(seg);
+            k.0 != k.1 && occurrences[&k] % 2 == 1 && seen.insert(k)
+        })
+        .collect()
 }
 
-struct CurrentRing<'a> {
-    available_segments: &'a mut Vec<bool>,
-    used_segments: Vec<usize>,
-    used_vertices: HashSet<NodePos>,
+fn report_duplicate_segments(relation_id: u64, relation_segments: &[NodeDescPair], report: &mut ValidationReport) {
+    let mut seen = HashSet::new();
+    let mut duplicates = HashSet::new();
+    let mut first_duplicate = None;
+    for seg in relation_segments {
+        let k = segment_key(seg);
+        if k.0 != k.1 && !seen.insert(k) && duplicates.insert(k) && first_duplicate.is_none() {
+            first_duplicate = Some(seg);
+        }
+    }
+    if let Some(seg) = first_duplicate {
+        let location = (
+            (seg.node1.lat + seg.node2.lat) / 2.0,
+            (seg.node1.lon + seg.node2.lon) / 2.0,
+        );
+        report.add(
+            relation_id,
+            MultipolygonProblem::DuplicateSegment,
+            duplicates.len(),
+            Some(location),
+            false,
+        );
+    }
 }
 
-impl<'a> CurrentRing<'a> {
-    fn include_segment(&mut self, seg: &ConnectedSegment) {
-        self.available_segments[seg.segment_index] = false;
-        self.used_segments.push(seg.segment_index);
-        self.used_vertices.insert(seg.other_side);
+type SegmentConnections = HashMap<NodePos, Vec<usize>>;
+
+struct Walk<'a> {
+    segments: &'a [&'a NodeDescPair],
+    connections: &'a SegmentConnections,
+    used_segments: Vec<bool>,
+    path: Vec<NodeDesc>,
+    path_indices: HashMap<NodePos, usize>,
+    rings: Vec<Vec<NodeDesc>>,
+}
+
+impl<'a> Walk<'a> {
+    fn visit(&mut self, node: &NodeDesc) {
+        let pos = node.pos();
+        match self.path_indices.get(&pos).cloned() {
+            Some(start_idx) => {
+                let mut ring = self.path.split_off(start_idx);
+                for n in ring.iter().skip(1) {
+                    self.path_indices.remove(&n.pos());
+                }
+                ring.push(ring[0].clone());
+                self.path.push(ring[0].clone());
+                self.rings.push(ring);
+            }
+            None => {
+                self.path_indices.insert(pos, self.path.len());
+                self.path.push(node.clone());
+            }
+        }
     }
 
-    fn exclude_segment(&mut self, seg: &ConnectedSegment) {
-        self.available_segments[seg.segment_index] = true;
-        self.used_segments.pop();
-        self.used_vertices.remove(&seg.other_side);
+    // Prefer to continue along the same way, so that the rings follow the member ways
+    // when there is a choice.
+    fn next_segment(&self, pos: NodePos, prev_segment: Option<usize>) -> Option<usize> {
+        let is_good = |idx: usize| !self.used_segments[idx] && self.connections[&pos].contains(&idx);
+        if let Some(prev) = prev_segment {
+            let neighbors = [prev.checked_add(1), prev.checked_sub(1)];
+            if let Some(idx) = neighbors
+                .iter()
+                .flatten()
+                .find(|idx| **idx < self.segments.len() && is_good(**idx))
+            {
+                return Some(*idx);
+            }
+        }
+        self.connections[&pos]
+            .iter()
+            .find(|idx| !self.used_segments[**idx])
+            .cloned()
     }
 }
 
-fn find_ring(
-    relation_segments: &[NodeDescPair],
-    connections: &SegmentConnections,
-    available_segments: &mut Vec<bool>,
-) -> Option<Vec<usize>> {
-    for start_idx in 0..available_segments.len() {
-        if !available_segments[start_idx] {
+fn find_rings(segments: &[&NodeDescPair], connections: &SegmentConnections) -> Vec<Vec<NodeDesc>> {
+    let mut walk = Walk {
+        segments,
+        connections,
+        used_segments: vec![false; segments.len()],
+        path: Vec::new(),
+        path_indices: HashMap::new(),
+        rings: Vec::new(),
+    };
+
+    for start_idx in 0..segments.len() {
+        if walk.used_segments[start_idx] {
             continue;
         }
 
-        available_segments[start_idx] = false;
+        walk.path.clear();
+        walk.path_indices.clear();
 
-        {
-            let start_segment = &relation_segments[start_idx];
-            let mut ring = CurrentRing {
-                available_segments,
-                used_segments: vec![start_idx],
-                used_vertices: [start_segment.node1.pos, start_segment.node2.pos]
-                    .iter()
-                    .cloned()
-                    .collect(),
-            };
-            let search_params = SearchParams {
-                first_pos: start_segment.node1.pos,
-                is_inner: start_segment.is_inner,
-            };
+        let start = &segments[start_idx].node1;
+        walk.visit(start);
+
+        let mut current_pos = start.pos();
+        let mut prev_segment = None;
+        // Every vertex has an even number of segments, so the walk can only get stuck at its start.
+        while let Some(seg_idx) = walk.next_segment(current_pos, prev_segment) {
+            walk.used_segments[seg_idx] = true;
+            let next_node = segments[seg_idx].other_side(current_pos).clone();
+            current_pos = next_node.pos();
+            prev_segment = Some(seg_idx);
+            walk.visit(&next_node);
+        }
+    }
+
+    walk.rings
+}
+
+// Returns the number of places where the segments of the rings cross each other, and the location of
+// the first of them. The segments that share a vertex or lie on the same line are not considered to be crossing.
+fn find_intersections(rings: &[Vec<NodeDesc>]) -> (usize, Option<(f64, f64)>) {
+    let mut segments = rings
+        .iter()
+        .flat_map(|ring| ring.windows(2))
+        .map(|pair| (&pair[0], &pair[1]))
+        .collect::<Vec<_>>();
+    let min_lon = |seg: &(&NodeDesc, &NodeDesc)| seg.0.lon.min(seg.1.lon);
+    let max_lon = |seg: &(&NodeDesc, &NodeDesc)| seg.0.lon.max(seg.1.lon);
+    segments.sort_by(|s1, s2| min_lon(s1).partial_cmp(&min_lon(s2)).unwrap_or(Ordering::Equal));
 
-            if find_ring_from(start_segment.node2.pos, &search_params, connections, &mut ring) {
-                return Some(ring.used_segments);
+    // A sweep from the west to the east, which only compares the segments with overlapping longitudes.
+    let mut count = 0;
+    let mut first_intersection = None;
+    let mut active: Vec<(&NodeDesc, &NodeDesc)> = Vec::new();
+    for seg in segments {
+        active.retain(|other| max_lon(other) >= min_lon(&seg));
+        for other in &active {
+            if let Some(point) = segment_intersection(seg, *other) {
+                count += 1;
+                first_intersection = first_intersection.or(Some(point));
             }
         }
+        active.push(seg);
+    }
+    (count, first_intersection)
+}
 
-        available_segments[start_idx] = true;
+fn segment_intersection(s1: (&NodeDesc, &NodeDesc), s2: (&NodeDesc, &NodeDesc)) -> Option<(f64, f64)> {
+    let shared_vertex = [s1.0.pos(), s1.1.pos()]
+        .iter()
+        .any(|pos| *pos == s2.0.pos() || *pos == s2.1.pos());
+    if shared_vertex {
+        return None;
     }
 
-    None
+    let cross = |a: &NodeDesc, b: &NodeDesc, c: &NodeDesc| {
+        (b.lon - a.lon) * (c.lat - a.lat) - (b.lat - a.lat) * (c.lon - a.lon)
+    };
+    let (d1, d2) = (cross(s1.0, s1.1, s2.0), cross(s1.0, s1.1, s2.1));
+    let (d3, d4) = (cross(s2.0, s2.1, s1.0), cross(s2.0, s2.1, s1.1));
+    if d1 * d2 < 0.0 && d3 * d4 < 0.0 {
+        let t = d3 / (d3 - d4);
+        Some((
+            s1.0.lat + t * (s1.1.lat - s1.0.lat),
+            s1.0.lon + t * (s1.1.lon - s1.0.lon),
+        ))
+    } else {
+        None
+    }
 }
 
-enum SearchStackElement<'a> {
-    Root,
-    StartSegment(&'a ConnectedSegment),
-    EndSegment(&'a ConnectedSegment),
+struct RingInfo {
+    ring: Vec<NodeDesc>,
+    min_lat: f64,
+    max_lat: f64,
+    min_lon: f64,
+    max_lon: f64,
 }
 
-fn push_next_segments<'a>(
-    from_pos: NodePos,
-    search_params: &SearchParams,
-    connections: &'a SegmentConnections,
-    ring: &mut CurrentRing<'_>,
-    stack: &mut Vec<SearchStackElement<'a>>,
-) {
-    if let Some(segs) = connections.get(&from_pos) {
-        for seg in segs.iter().rev() {
-            let can_use = seg.is_inner == search_params.is_inner && ring.available_segments[seg.segment_index];
-            let is_duplicate =
-                ring.used_vertices.contains(&seg.other_side) && seg.other_side != search_params.first_pos;
-            if can_use && !is_duplicate {
-                stack.push(SearchStackElement::EndSegment(seg));
-                stack.push(SearchStackElement::StartSegment(seg));
+impl RingInfo {
+    fn new(ring: Vec<NodeDesc>) -> RingInfo {
+        let mut info = RingInfo {
+            min_lat: f64::MAX,
+            max_lat: f64::MIN,
+            min_lon: f64::MAX,
+            max_lon: f64::MIN,
+            ring,
+        };
+        for n in &info.ring {
+            info.min_lat = info.min_lat.min(n.lat);
+            info.max_lat = info.max_lat.max(n.lat);
+            info.min_lon = info.min_lon.min(n.lon);
+            info.max_lon = info.max_lon.max(n.lon);
+        }
+        info
+    }
+
+    fn contains_ring(&self, other: &RingInfo) -> bool {
+        let bbox_contains = self.min_lat <= other.min_lat
+            && self.max_lat >= other.max_lat
+            && self.min_lon <= other.min_lon
+            && self.max_lon >= other.max_lon;
+        if !bbox_contains {
+            return false;
+        }
+
+        // Rings can touch each other, so we have to find a vertex of the other ring that doesn't
+        // lie on this one.
+        let own_vertices = self.ring.iter().map(NodeDesc::pos).collect::<HashSet<_>>();
+        match other.ring.iter().find(|n| !own_vertices.contains(&n.pos())) {
+            Some(n) => self.contains_point(n.lat, n.lon),
+            None => false,
+        }
+    }
+
+    fn contains_point(&self, lat: f64, lon: f64) -> bool {
+        let mut inside = false;
+        for idx in 1..self.ring.len() {
+            let (n1, n2) = (&self.ring[idx - 1], &self.ring[idx]);
+            if (n1.lat > lat) != (n2.lat > lat) {
+                let crossing_lon = n1.lon + (lat - n1.lat) / (n2.lat - n1.lat) * (n2.lon - n1.lon);
+                if lon < crossing_lon {
+                    inside = !inside;
+                }
             }
         }
+        inside
     }
 }
 
-fn find_ring_from(
-    last_pos: NodePos,
-    search_params: &SearchParams,
-    connections: &SegmentConnections,
-    ring: &mut CurrentRing<'_>,
-) -> bool {
-    let mut candidate_stack = vec![SearchStackElement::Root];
+// Outer rings (the ones contained in an even number of other rings) go first, so that
+// the first polygon of a multipolygon is always an outer one.
+fn order_rings(rings: Vec<Vec<NodeDesc>>) -> Vec<Polygon> {
+    let infos = rings.into_iter().map(RingInfo::new).collect::<Vec<_>>();
+    let depths = infos
+        .iter()
+        .enumerate()
+        .map(|(idx, ring)| {
+            infos
+                .iter()
+                .enumerate()
+                .filter(|(other_idx, other)| *other_idx != idx && other.contains_ring(ring))
+                .count()
+        })
+        .collect::<Vec<_>>();
 
-    while let Some(current) = candidate_stack.pop() {
-        match current {
-            SearchStackElement::Root => {
-                push_next_segments(last_pos, search_params, connections, ring, &mut candidate_stack)
-            }
-            SearchStackElement::StartSegment(seg) => {
-                ring.include_segment(seg);
-                if search_params.first_pos == seg.other_side && ring.used_segments.len() >= 3 {
-                    return true;
-                }
-                push_next_segments(seg.other_side, search_params, connections, ring, &mut candidate_stack);
+    let mut indices = (0..infos.len()).collect::<Vec<_>>();
+    indices.sort_by_key(|idx| depths[*idx] % 2 != 0);
+
+    indices
+        .into_iter()
+        .map(|idx| infos[idx].ring.iter().map(|n| n.id).collect())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geodata::validation::MultipolygonIssue;
+
+    // Every way is a list of (node id, lat, lon); node ids are also used as coordinates
+    // in the test shapes below to keep them readable.
+    fn segments_from_ways(ways: &[&[(usize, f64, f64)]]) -> Vec<NodeDescPair> {
+        let mut result = Vec::new();
+        for way in ways {
+            for idx in 1..way.len() {
+                let (id1, lat1, lon1) = way[idx - 1];
+                let (id2, lat2, lon2) = way[idx];
+                result.push(NodeDescPair::new(
+                    NodeDesc::new(id1, lat1, lon1),
+                    NodeDesc::new(id2, lat2, lon2),
+                ));
             }
-            SearchStackElement::EndSegment(seg) => ring.exclude_segment(seg),
         }
+        result
+    }
+
+    fn normalize(ring: &[usize]) -> Vec<usize> {
+        assert_eq!(ring.first(), ring.last(), "ring {:?} is not closed", ring);
+        let mut nodes = ring[1..].to_vec();
+        let min_pos = (0..nodes.len()).min_by_key(|idx| nodes[*idx]).unwrap();
+        nodes.rotate_left(min_pos);
+        if nodes.len() > 2 && nodes[1] > nodes[nodes.len() - 1] {
+            nodes[1..].reverse();
+        }
+        nodes
+    }
+
+    fn find(ways: &[&[(usize, f64, f64)]]) -> Option<Vec<Vec<usize>>> {
+        find_polygons_in_multipolygon(1, &segments_from_ways(ways), None)
+            .map(|polygons| polygons.iter().map(|p| normalize(p)).collect())
+    }
+
+    const OUTER: &[(usize, f64, f64)] = &[
+        (1, 0.0, 0.0),
+        (2, 0.0, 10.0),
+        (3, 10.0, 10.0),
+        (4, 10.0, 0.0),
+        (1, 0.0, 0.0),
+    ];
+
+    #[test]
+    fn test_simple_hole() {
+        let inner: &[_] = &[(5, 2.0, 2.0), (6, 2.0, 4.0), (7, 4.0, 4.0), (5, 2.0, 2.0)];
+        assert_eq!(find(&[OUTER, inner]), Some(vec![vec![1, 2, 3, 4], vec![5, 6, 7]]));
+    }
+
+    #[test]
+    fn test_outer_ring_split_into_several_reversed_ways() {
+        let w1: &[_] = &[(1, 0.0, 0.0), (2, 0.0, 10.0)];
+        let w2: &[_] = &[(4, 10.0, 0.0), (3, 10.0, 10.0), (2, 0.0, 10.0)];
+        let w3: &[_] = &[(4, 10.0, 0.0), (1, 0.0, 0.0)];
+        assert_eq!(find(&[w1, w2, w3]), Some(vec![vec![1, 2, 3, 4]]));
+    }
+
+    #[test]
+    fn test_inner_rings_touching_at_vertex() {
+        let inner1: &[_] = &[(5, 2.0, 2.0), (6, 2.0, 4.0), (7, 4.0, 4.0), (5, 2.0, 2.0)];
+        let inner2: &[_] = &[(7, 4.0, 4.0), (8, 4.0, 6.0), (9, 6.0, 6.0), (7, 4.0, 4.0)];
+        assert_eq!(
+            find(&[OUTER, inner1, inner2]),
+            Some(vec![vec![1, 2, 3, 4], vec![5, 6, 7], vec![7, 8, 9]])
+        );
+    }
+
+    #[test]
+    fn test_inner_ring_touching_outer_ring() {
+        let inner: &[_] = &[(1, 0.0, 0.0), (5, 2.0, 4.0), (6, 4.0, 2.0), (1, 0.0, 0.0)];
+        assert_eq!(find(&[OUTER, inner]), Some(vec![vec![1, 2, 3, 4], vec![1, 5, 6]]));
+    }
+
+    #[test]
+    fn test_inner_rings_sharing_an_edge_are_merged() {
+        let inner1: &[_] = &[
+            (5, 2.0, 2.0),
+            (6, 2.0, 4.0),
+            (7, 4.0, 4.0),
+            (8, 4.0, 2.0),
+            (5, 2.0, 2.0),
+        ];
+        let inner2: &[_] = &[
+            (6, 2.0, 4.0),
+            (9, 2.0, 6.0),
+            (10, 4.0, 6.0),
+            (7, 4.0, 4.0),
+            (6, 2.0, 4.0),
+        ];
+        assert_eq!(
+            find(&[OUTER, inner1, inner2]),
+            Some(vec![vec![1, 2, 3, 4], vec![5, 6, 9, 10, 7, 8]])
+        );
+    }
+
+    #[test]
+    fn test_self_touching_outer_ring_is_split() {
+        let figure_eight: &[_] = &[
+            (1, 0.0, 0.0),
+            (2, 0.0, 5.0),
+            (3, 5.0, 5.0),
+            (4, 5.0, 10.0),
+            (5, 10.0, 10.0),
+            (6, 10.0, 5.0),
+            (3, 5.0, 5.0),
+            (7, 5.0, 0.0),
+            (1, 0.0, 0.0),
+        ];
+        assert_eq!(find(&[figure_eight]), Some(vec![vec![3, 4, 5, 6], vec![1, 2, 3, 7]]));
+    }
+
+    #[test]
+    fn test_members_in_wrong_order_are_classified_by_geometry() {
+        let inner: &[_] = &[(5, 2.0, 2.0), (6, 2.0, 4.0), (7, 4.0, 4.0), (5, 2.0, 2.0)];
+        let island: &[_] = &[(8, 2.5, 3.0), (9, 3.0, 3.5), (10, 3.0, 3.0), (8, 2.5, 3.0)];
+        assert_eq!(
+            find(&[island, inner, OUTER]),
+            Some(vec![vec![8, 9, 10], vec![1, 2, 3, 4], vec![5, 6, 7]])
+        );
+    }
+
+    #[test]
+    fn test_unclosed_ring() {
+        let open: &[_] = &[(1, 0.0, 0.0), (2, 0.0, 10.0), (3, 10.0, 10.0), (4, 10.0, 0.0)];
+        assert_eq!(find(&[open]), None);
+    }
+
+    // Synthetic nodes get ids starting from 100.
+    fn repair(ways: &[&[(usize, f64, f64)]], bounds: Bounds) -> Option<(Vec<Vec<usize>>, bool)> {
+        let mut next_id = 100;
+        let mut add_node = |_, _| {
+            next_id += 1;
+            next_id - 1
+        };
+        let mut repair = MultipolygonRepair {
+            bounds,
+            add_node: &mut add_node,
+        };
+        repair_polygons_in_multipolygon(1, &segments_from_ways(ways), None, &mut repair)
+            .map(|(polygons, repaired)| (polygons.iter().map(|p| normalize(p)).collect(), repaired))
+    }
+
+    const FAR_BOUNDS: Bounds = (-80.0, -170.0, 80.0, 170.0);
+
+    #[test]
+    fn test_valid_multipolygon_is_not_repaired() {
+        assert_eq!(repair(&[OUTER], FAR_BOUNDS), Some((vec![vec![1, 2, 3, 4]], false)));
+    }
+
+    #[test]
+    fn test_small_gap_is_closed() {
+        // The gap between the nodes 5 and 1 is about 11 meters.
+        let open: &[_] = &[
+            (1, 0.0, 0.0),
+            (2, 0.0, 0.01),
+            (3, 0.01, 0.01),
+            (4, 0.01, 0.0),
+            (5, 0.0001, 0.0),
+        ];
+        assert_eq!(repair(&[open], FAR_BOUNDS), Some((vec![vec![1, 2, 3, 4, 5]], true)));
+
+        let wide_open: &[_] = &[(1, 0.0, 0.0), (2, 0.0, 0.01), (3, 0.01, 0.01), (4, 0.01, 0.0)];
+        assert_eq!(repair(&[wide_open], FAR_BOUNDS), None);
+    }
+
+    #[test]
+    fn test_ring_is_closed_along_boundary() {
+        // The way enters the bounds through the west edge and leaves through the north edge, so the ring
+        // is closed around the north-west corner.
+        let cut: &[_] = &[(1, 0.5, -0.1), (2, 0.5, 0.5), (3, 1.1, 0.5)];
+        assert_eq!(
+            repair(&[cut], (0.0, 0.0, 1.0, 1.0)),
+            Some((vec![vec![1, 2, 3, 102, 101, 100]], true))
+        );
+
+        // The ends right on the boundary are not duplicated.
+        let cut: &[_] = &[(1, 0.5, 0.0), (2, 0.5, 0.5), (3, 1.0, 0.5)];
+        assert_eq!(
+            repair(&[cut], (0.0, 0.0, 1.0, 1.0)),
+            Some((vec![vec![1, 2, 3, 100]], true))
+        );
+    }
+
+    #[test]
+    fn test_dangling_ways_are_dropped() {
+        let dangling: &[_] = &[(3, 10.0, 10.0), (5, 5.0, 5.0), (6, 5.0, 7.0)];
+        assert_eq!(
+            repair(&[OUTER, dangling], FAR_BOUNDS),
+            Some((vec![vec![1, 2, 3, 4]], true))
+        );
+    }
+
+    fn validate(ways: &[&[(usize, f64, f64)]]) -> Vec<MultipolygonIssue> {
+        let mut report = ValidationReport::default();
+        find_polygons_in_multipolygon(1, &segments_from_ways(ways), Some(&mut report));
+        report.issues().to_vec()
+    }
+
+    fn issue(problem: MultipolygonProblem, count: usize, location: (f64, f64), dropped: bool) -> MultipolygonIssue {
+        MultipolygonIssue {
+            relation_id: 1,
+            problem,
+            count,
+            location: Some(location),
+            dropped,
+        }
+    }
+
+    #[test]
+    fn test_valid_multipolygon_has_no_issues() {
+        let inner: &[_] = &[(5, 2.0, 2.0), (6, 2.0, 4.0), (7, 4.0, 4.0), (5, 2.0, 2.0)];
+        assert_eq!(validate(&[OUTER, inner]), vec![]);
+    }
+
+    #[test]
+    fn test_unclosed_ring_is_reported() {
+        let open: &[_] = &[(1, 0.0, 0.0), (2, 0.0, 10.0), (3, 10.0, 10.0), (4, 10.0, 0.0)];
+        assert_eq!(
+            validate(&[open]),
+            vec![issue(MultipolygonProblem::UnclosedRing, 2, (0.0, 0.0), true)]
+        );
     }
 
-    false
+    #[test]
+    fn test_self_intersection_is_reported() {
+        let bow_tie: &[_] = &[
+            (1, 0.0, 0.0),
+            (2, 10.0, 10.0),
+            (3, 10.0, 0.0),
+            (4, 0.0, 10.0),
+            (1, 0.0, 0.0),
+        ];
+        assert_eq!(
+            validate(&[bow_tie]),
+            vec![issue(MultipolygonProblem::SelfIntersection, 1, (5.0, 5.0), false)]
+        );
+
+        // Rings that only touch each other are fine.
+        let inner: &[_] = &[(1, 0.0, 0.0), (5, 2.0, 4.0), (6, 4.0, 2.0), (1, 0.0, 0.0)];
+        assert_eq!(validate(&[OUTER, inner]), vec![]);
+    }
+
+    #[test]
+    fn test_duplicate_segments_are_reported() {
+        let inner1: &[_] = &[
+            (5, 2.0, 2.0),
+            (6, 2.0, 4.0),
+            (7, 4.0, 4.0),
+            (8, 4.0, 2.0),
+            (5, 2.0, 2.0),
+        ];
+        let inner2: &[_] = &[
+            (6, 2.0, 4.0),
+            (9, 2.0, 6.0),
+            (10, 4.0, 6.0),
+            (7, 4.0, 4.0),
+            (6, 2.0, 4.0),
+        ];
+        assert_eq!(
+            validate(&[OUTER, inner1, inner2]),
+            vec![issue(MultipolygonProblem::DuplicateSegment, 1, (3.0, 4.0), false)]
+        );
+    }
 }
@@ -0,0 +1,205 @@
+use crate::geodata::importer::{OsmEntityHandler, ParsedMember, ParsedRelation, ParsedWay, RawNode, RawTags};
+use crate::geodata::reader::MemberKind;
+use failure::{bail, format_err, Error, ResultExt};
+use json::JsonValue;
+use std::collections::HashMap;
+use std::io::Read;
+
+// The format is described in RFC 7946. Features don't have OSM ids, so the entities are numbered from 1
+// in the order of the features, separately for nodes, ways and relations.
+
+pub(super) fn parse_geojson<R: Read, H: OsmEntityHandler>(mut input: R, mut handler: H) -> Result<H, Error> {
+    let mut text = String::new();
+    input.read_to_string(&mut text).context("Failed to read the input")?;
+    let data = json::parse(&text).context("Failed to parse the input as JSON")?;
+
+    let mut converter = GeoJsonConverter::default();
+    match data["type"].as_str() {
+        Some("FeatureCollection") => {
+            for (idx, feature) in data["features"].members().enumerate() {
+                converter
+                    .add_feature(feature)
+                    .context(format!("Failed to convert feature #{}", idx))?;
+            }
+        }
+        Some("Feature") => converter.add_feature(&data)?,
+        _ => converter.add_geometry(&data, &RawTags::default())?,
+    }
+
+    for node in converter.nodes {
+        handler.handle_node(node)?;
+    }
+    handler.finish_nodes()?;
+    for way in converter.ways {
+        handler.handle_way(way)?;
+    }
+    handler.finish_ways()?;
+    for relation in converter.relations {
+        handler.handle_relation(relation)?;
+    }
+    println!("Total: {}", handler.dump_state());
+
+    Ok(handler)
+}
+
+// Points become tagged nodes, line strings become tagged ways, and polygons become multipolygon relations
+// whose member ways are the rings. The vertices with the same coordinates are the same node, so that
+// the rings that touch each other are assembled like in OSM data.
+#[derive(Default)]
+struct GeoJsonConverter {
+    nodes: Vec<RawNode>,
+    ways: Vec<ParsedWay>,
+    relations: Vec<ParsedRelation>,
+    vertex_ids: HashMap<(u64, u64), u64>,
+}
+
+impl GeoJsonConverter {
+    fn add_feature(&mut self, feature: &JsonValue) -> Result<(), Error> {
+        if feature["type"].as_str() != Some("Feature") {
+            bail!("Expected a Feature, got {}", feature["type"]);
+        }
+        let tags = properties_to_tags(&feature["properties"]);
+        let geometry = &feature["geometry"];
+        // Features without a location are allowed.
+        if geometry.is_null() {
+            return Ok(());
+        }
+        self.add_geometry(geometry, &tags)
+    }
+
+    fn add_geometry(&mut self, geometry: &JsonValue, tags: &RawTags) -> Result<(), Error> {
+        let coordinates = &geometry["coordinates"];
+        match geometry["type"].as_str() {
+            Some("Point") => self.add_point(coordinates, tags)?,
+            Some("MultiPoint") => {
+                for point in coordinates.members() {
+                    self.add_point(point, tags)?;
+                }
+            }
+            Some("LineString") => {
+                self.add_line(coordinates, tags.clone())?;
+            }
+            Some("MultiLineString") => {
+                for line in coordinates.members() {
+                    self.add_line(line, tags.clone())?;
+                }
+            }
+            Some("Polygon") => self.add_polygons(std::iter::once(coordinates), tags)?,
+            Some("MultiPolygon") => self.add_polygons(coordinates.members(), tags)?,
+            Some("GeometryCollection") => {
+                for geometry in geometry["geometries"].members() {
+                    self.add_geometry(geometry, tags)?;
+                }
+            }
+            _ => bail!("Unknown geometry type: {}", geometry["type"]),
+        }
+        Ok(())
+    }
+
+    fn add_point(&mut self, position: &JsonValue, tags: &RawTags) -> Result<(), Error> {
+        let (lat, lon) = parse_position(position)?;
+        self.nodes.push(RawNode {
+            global_id: self.nodes.len() as u64 + 1,
+            lat,
+            lon,
+            tags: tags.clone(),
+        });
+        Ok(())
+    }
+
+    fn add_line(&mut self, positions: &JsonValue, tags: RawTags) -> Result<u64, Error> {
+        if positions.len() < 2 {
+            bail!("A line has less than two positions: {}", positions);
+        }
+        let node_ids = positions
+            .members()
+            .map(|position| self.add_vertex(position))
+            .collect::<Result<_, _>>()?;
+        let global_id = self.ways.len() as u64 + 1;
+        self.ways.push(ParsedWay {
+            global_id,
+            node_ids,
+            tags,
+        });
+        Ok(global_id)
+    }
+
+    fn add_vertex(&mut self, position: &JsonValue) -> Result<u64, Error> {
+        let (lat, lon) = parse_position(position)?;
+        let nodes = &mut self.nodes;
+        let global_id = *self
+            .vertex_ids
+            .entry((lat.to_bits(), lon.to_bits()))
+            .or_insert_with(|| {
+                let global_id = nodes.len() as u64 + 1;
+                nodes.push(RawNode {
+                    global_id,
+                    lat,
+                    lon,
+                    tags: RawTags::default(),
+                });
+                global_id
+            });
+        Ok(global_id)
+    }
+
+    // All rings of all polygons become the members of a single multipolygon relation.
+    fn add_polygons<'p>(&mut self, polygons: impl Iterator<Item = &'p JsonValue>, tags: &RawTags) -> Result<(), Error> {
+        let mut relation = ParsedRelation {
+            global_id: self.relations.len() as u64 + 1,
+            tags: tags.clone(),
+            ..Default::default()
+        };
+        relation.tags.insert("type".to_string(), "multipolygon".to_string());
+        for rings in polygons {
+            for (idx, ring) in rings.members().enumerate() {
+                relation.members.push(ParsedMember {
+                    kind: MemberKind::Way,
+                    global_id: self.add_line(ring, RawTags::default())?,
+                    role: if idx == 0 { "outer" } else { "inner" }.to_string(),
+                });
+            }
+        }
+        self.relations.push(relation);
+        Ok(())
+    }
+}
+
+fn parse_position(position: &JsonValue) -> Result<(f64, f64), Error> {
+    // GeoJSON puts the longitude first.
+    match (position[0].as_f64(), position[1].as_f64()) {
+        (Some(lon), Some(lat)) => Ok((lat, lon)),
+        _ => Err(format_err!("Invalid position: {}", position)),
+    }
+}
+
+// Strings are kept as they are, and other values are written as JSON.
+fn properties_to_tags(properties: &JsonValue) -> RawTags {
+    properties
+        .entries()
+        .filter(|(_, v)| !v.is_null())
+        .map(|(k, v)| {
+            let value = match v.as_str() {
+                Some(s) => s.to_string(),
+                None => v.dump(),
+            };
+            (k.to_string(), value)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_properties_to_tags() {
+        let properties = json::parse(r#"{"name": "A", "priority": 2, "express": true, "note": null, "days": [1, 2]}"#);
+        let tags = properties_to_tags(&properties.unwrap());
+        let expected = [("days", "[1,2]"), ("express", "true"), ("name", "A"), ("priority", "2")]
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect::<RawTags>();
+        assert_eq!(tags, expected);
+    }
+}
@@ -0,0 +1,198 @@
+use crate::tile;
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+use failure::{bail, Error};
+use std::io::{self, Write};
+use std::mem;
+
+// Every geodata file starts with a fixed-size header that describes where the sections are:
+//
+//   magic: [u8; 8]
+//   format version: u32
+//   flags: u32
+//   checksum: u32 (CRC32 of everything after the header, only meaningful with FLAG_HAS_CHECKSUM)
+//   index zoom: u32 (the highest zoom level of the tiles in the tile index)
+//   section count: u32
+//   reserved: u32
+//   offset and size (both u64, in bytes from the start of the header) for every section
+//
+// All sections start at offsets divisible by 4, so that the ints can be accessed directly as `&[u32]`.
+
+const MAGIC: &[u8; 8] = b"OSMRGEOD";
+pub(super) const FORMAT_VERSION: u32 = 11;
+pub(super) const FLAG_HAS_CHECKSUM: u32 = 1;
+
+#[derive(Clone, Copy, Debug)]
+pub(super) enum Section {
+    Nodes,
+    Ways,
+    Polygons,
+    Multipolygons,
+    Relations,
+    Tiles,
+    NodeIndex,
+    WayIndex,
+    MultipolygonIndex,
+    RelationIndex,
+    WaySimplifications,
+    PolygonSimplifications,
+    NodeMinZooms,
+    WayMinZooms,
+    MultipolygonMinZooms,
+    RelationMinZooms,
+    NodeParents,
+    WayParents,
+    RelationParents,
+    AreaRules,
+    TagFilter,
+    Ints,
+    PackedInts,
+    Strings,
+}
+
+pub(super) const SECTION_COUNT: usize = 24;
+pub(super) const ALL_SECTIONS: [Section; SECTION_COUNT] = [
+    Section::Nodes,
+    Section::Ways,
+    Section::Polygons,
+    Section::Multipolygons,
+    Section::Relations,
+    Section::Tiles,
+    Section::NodeIndex,
+    Section::WayIndex,
+    Section::MultipolygonIndex,
+    Section::RelationIndex,
+    Section::WaySimplifications,
+    Section::PolygonSimplifications,
+    Section::NodeMinZooms,
+    Section::WayMinZooms,
+    Section::MultipolygonMinZooms,
+    Section::RelationMinZooms,
+    Section::NodeParents,
+    Section::WayParents,
+    Section::RelationParents,
+    Section::AreaRules,
+    Section::TagFilter,
+    Section::Ints,
+    Section::PackedInts,
+    Section::Strings,
+];
+
+pub(super) const HEADER_SIZE: usize =
+    MAGIC.len() + 6 * mem::size_of::<u32>() + SECTION_COUNT * 2 * mem::size_of::<u64>();
+
+#[derive(Clone, Copy, Default)]
+pub(super) struct SectionLocation {
+    pub(super) offset: u64,
+    pub(super) size: u64,
+}
+
+#[derive(Default)]
+pub(super) struct Header {
+    pub(super) flags: u32,
+    pub(super) checksum: u32,
+    pub(super) index_zoom: u8,
+    pub(super) sections: [SectionLocation; SECTION_COUNT],
+}
+
+impl Header {
+    pub(super) fn section(&self, section: Section) -> SectionLocation {
+        self.sections[section as usize]
+    }
+
+    pub(super) fn write_to(&self, writer: &mut dyn Write) -> io::Result<()> {
+        writer.write_all(MAGIC)?;
+        writer.write_u32::<LittleEndian>(FORMAT_VERSION)?;
+        writer.write_u32::<LittleEndian>(self.flags)?;
+        writer.write_u32::<LittleEndian>(self.checksum)?;
+        writer.write_u32::<LittleEndian>(u32::from(self.index_zoom))?;
+        writer.write_u32::<LittleEndian>(SECTION_COUNT as u32)?;
+        writer.write_u32::<LittleEndian>(0)?;
+        for section in &self.sections {
+            writer.write_u64::<LittleEndian>(section.offset)?;
+            writer.write_u64::<LittleEndian>(section.size)?;
+        }
+        Ok(())
+    }
+
+    // Checks that `bytes` start with a header of the supported version and that all sections
+    // described by the header lie within `bytes`.
+    pub(super) fn read_from(bytes: &[u8]) -> Result<Header, Error> {
+        if bytes.len() < HEADER_SIZE {
+            bail!(
+                "The file is too small ({} bytes) to be a geodata file, which has a {}-byte header",
+                bytes.len(),
+                HEADER_SIZE
+            );
+        }
+        if &bytes[..MAGIC.len()] != MAGIC {
+            bail!("The file is not a geodata file (it doesn't start with the expected magic bytes)");
+        }
+
+        let read_u32 = |idx: usize| LittleEndian::read_u32(&bytes[MAGIC.len() + idx * mem::size_of::<u32>()..]);
+        let version = read_u32(0);
+        if version != FORMAT_VERSION {
+            bail!(
+                "The geodata file has format version {}, but only version {} is supported. Please import the data again",
+                version,
+                FORMAT_VERSION
+            );
+        }
+        let section_count = read_u32(4) as usize;
+        if section_count != SECTION_COUNT {
+            bail!(
+                "The geodata file has {} sections instead of {}",
+                section_count,
+                SECTION_COUNT
+            );
+        }
+
+        let index_zoom = read_u32(3);
+        if index_zoom > u32::from(tile::MAX_ZOOM) {
+            bail!("The geodata file has an invalid index zoom level {}", index_zoom);
+        }
+
+        let mut header = Header {
+            flags: read_u32(1),
+            checksum: read_u32(2),
+            index_zoom: index_zoom as u8,
+            ..Default::default()
+        };
+        let sections_start = MAGIC.len() + 6 * mem::size_of::<u32>();
+        for (idx, section) in ALL_SECTIONS.iter().enumerate() {
+            let start_pos = sections_start + idx * 2 * mem::size_of::<u64>();
+            let location = SectionLocation {
+                offset: LittleEndian::read_u64(&bytes[start_pos..]),
+                size: LittleEndian::read_u64(&bytes[start_pos + mem::size_of::<u64>()..]),
+            };
+            let fits = match location.offset.checked_add(location.size) {
+                Some(end) => location.offset >= HEADER_SIZE as u64 && end <= bytes.len() as u64,
+                None => false,
+            };
+            if !fits {
+                bail!(
+                    "{:?} section ({} bytes at offset {}) doesn't fit into the file ({} bytes). The file is probably truncated",
+                    section,
+                    location.size,
+                    location.offset,
+                    bytes.len()
+                );
+            }
+            if !location.offset.is_multiple_of(mem::size_of::<u32>() as u64) {
+                bail!(
+                    "{:?} section starts at offset {}, which is not divisible by 4",
+                    section,
+                    location.offset
+                );
+            }
+            header.sections[idx] = location;
+        }
+
+        Ok(header)
+    }
+}
+
+pub(super) fn checksum(bytes: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(bytes);
+    hasher.finalize()
+}
@@ -0,0 +1,238 @@
+use crate::geodata::importer::{
+    get_id, get_required_attr, parse_required_attr, process_node_subelement, process_relation_subelement,
+    process_subelements, process_way_subelement, OsmEntityHandler, ParsedRelation, ParsedWay, RawNode,
+};
+use failure::{bail, format_err, Error, ResultExt};
+use std::io::Read;
+use xml::attribute::OwnedAttribute;
+use xml::reader::{EventReader, XmlEvent};
+
+/// A moment in UTC, stored as the number of seconds since the Unix epoch.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Timestamp(i64);
+
+impl Timestamp {
+    /// Parses either a timestamp in the format used by OSM files (`2019-05-01T12:30:00Z`) or a plain date
+    /// (`2019-05-01`), which stands for the midnight that starts it.
+    pub fn parse(s: &str) -> Result<Timestamp, Error> {
+        let parse_error = || format_err!("Failed to parse the timestamp {} (expected YYYY-MM-DD[THH:MM:SSZ])", s);
+        let (date, time) = match s.find('T') {
+            Some(pos) if s.ends_with('Z') => (&s[..pos], &s[pos + 1..s.len() - 1]),
+            Some(_) => return Err(parse_error()),
+            None => (s, "00:00:00"),
+        };
+        let parse_parts = |part: &str, sep| {
+            part.split(sep)
+                .map(|x| if x.is_empty() { None } else { x.parse::<i64>().ok() })
+                .collect::<Option<Vec<_>>>()
+                .filter(|parts| parts.len() == 3)
+                .ok_or_else(parse_error)
+        };
+        let (year, month, day) = match parse_parts(date, '-')?[..] {
+            [year, month, day] => (year, month, day),
+            _ => unreachable!(),
+        };
+        let (hour, minute, second) = match parse_parts(time, ':')?[..] {
+            [hour, minute, second] => (hour, minute, second),
+            _ => unreachable!(),
+        };
+        if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 || second > 60 {
+            return Err(parse_error());
+        }
+        Ok(Timestamp(
+            days_from_epoch(year, month, day) * 86_400 + hour * 3600 + minute * 60 + second,
+        ))
+    }
+}
+
+// The number of days between 1970-01-01 and the given date of the proleptic Gregorian calendar.
+fn days_from_epoch(year: i64, month: i64, day: i64) -> i64 {
+    // Count the years from March, so that the leap day is the last day of a year.
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+const ENTITY_KINDS: [&str; 3] = ["node", "way", "relation"];
+
+enum Entity {
+    Node(RawNode),
+    Way(ParsedWay),
+    Relation(ParsedRelation),
+}
+
+// The latest version of an entity that is not newer than the requested moment. `entity` is None
+// if there is no such version yet, or if the entity was deleted in it.
+struct LatestVersion {
+    kind_idx: usize,
+    global_id: u64,
+    timestamp: Option<Timestamp>,
+    entity: Option<Entity>,
+}
+
+/// Reads an OSM history file (`.osh`), which lists all versions of every entity, and passes the version
+/// of every entity that was current at `at` (or the latest one if `at` is None) to the handler. The deleted
+/// entities are skipped. Like in the files made by osmium and the planet history dumps, the entities must
+/// be sorted by type and id, so that only one entity is kept in memory at a time.
+pub(super) fn parse_osm_history_xml<R: Read, H: OsmEntityHandler>(
+    mut parser: EventReader<R>,
+    at: Option<Timestamp>,
+    mut handler: H,
+) -> Result<H, Error> {
+    let mut latest: Option<LatestVersion> = None;
+    let mut elem_count = 0;
+
+    loop {
+        let e = parser.next().context("Failed to parse the input file")?;
+        match e {
+            XmlEvent::EndDocument => break,
+            XmlEvent::StartElement { name, attributes, .. } => {
+                let name = name.local_name.as_str();
+                let kind_idx = match ENTITY_KINDS.iter().position(|kind| *kind == name) {
+                    Some(kind_idx) => kind_idx,
+                    None => continue,
+                };
+                let global_id = get_id(name, &attributes)?;
+                let timestamp = Timestamp::parse(get_required_attr(name, &attributes, "timestamp")?)
+                    .context(format!("Failed to parse the timestamp of {} {}", name, global_id))?;
+                let entity = process_version(name, global_id, &attributes, &mut parser)?;
+
+                match latest {
+                    Some(ref version) if (version.kind_idx, version.global_id) == (kind_idx, global_id) => {}
+                    Some(ref version) if (version.kind_idx, version.global_id) > (kind_idx, global_id) => bail!(
+                        "The history file isn't sorted: {} {} comes after {} {}",
+                        name,
+                        global_id,
+                        ENTITY_KINDS[version.kind_idx],
+                        version.global_id
+                    ),
+                    _ => {
+                        if let Some(entity) = latest.take().and_then(|version| version.entity) {
+                            handle_entity(entity, &mut handler)?;
+                        }
+                        latest = Some(LatestVersion {
+                            kind_idx,
+                            global_id,
+                            timestamp: None,
+                            entity: None,
+                        });
+                    }
+                }
+
+                let version = latest.as_mut().unwrap();
+                let is_before_at = at.is_none_or(|at| timestamp <= at);
+                if is_before_at && version.timestamp.is_none_or(|latest| timestamp >= latest) {
+                    version.timestamp = Some(timestamp);
+                    version.entity = entity;
+                }
+
+                elem_count += 1;
+                if elem_count % 100_000 == 0 {
+                    println!("Got {} so far", handler.dump_state());
+                }
+            }
+            _ => {}
+        }
+    }
+
+    if let Some(entity) = latest.and_then(|version| version.entity) {
+        handle_entity(entity, &mut handler)?;
+    }
+    handler.finish_ways()?;
+    println!("Total: {}", handler.dump_state());
+
+    Ok(handler)
+}
+
+// Reads a version of an entity, or skips it and returns None if the entity is deleted in this version.
+fn process_version<R: Read>(
+    name: &str,
+    global_id: u64,
+    attrs: &[OwnedAttribute],
+    parser: &mut EventReader<R>,
+) -> Result<Option<Entity>, Error> {
+    let is_visible = attrs
+        .iter()
+        .find(|a| a.name.local_name == "visible")
+        .is_none_or(|a| a.value != "false");
+
+    let entity = match name {
+        "node" => {
+            let mut node = RawNode {
+                global_id,
+                ..Default::default()
+            };
+            // The deleted versions of nodes don't have coordinates.
+            if is_visible {
+                node.lat = parse_required_attr(name, attrs, "lat")?;
+                node.lon = parse_required_attr(name, attrs, "lon")?;
+            }
+            process_subelements(name, &mut node, &(), process_node_subelement, parser)?;
+            Entity::Node(node)
+        }
+        "way" => {
+            let mut way = ParsedWay {
+                global_id,
+                ..Default::default()
+            };
+            process_subelements(name, &mut way, &(), process_way_subelement, parser)?;
+            Entity::Way(way)
+        }
+        _ => {
+            let mut relation = ParsedRelation {
+                global_id,
+                ..Default::default()
+            };
+            process_subelements(name, &mut relation, &(), process_relation_subelement, parser)?;
+            Entity::Relation(relation)
+        }
+    };
+
+    Ok(if is_visible { Some(entity) } else { None })
+}
+
+fn handle_entity<H: OsmEntityHandler>(entity: Entity, handler: &mut H) -> Result<(), Error> {
+    match entity {
+        Entity::Node(node) => handler.handle_node(node),
+        Entity::Way(way) => {
+            handler.finish_nodes()?;
+            handler.handle_way(way)
+        }
+        Entity::Relation(relation) => {
+            handler.finish_ways()?;
+            handler.handle_relation(relation)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_timestamp() {
+        assert_eq!(Timestamp::parse("1970-01-01").unwrap(), Timestamp(0));
+        assert_eq!(Timestamp::parse("1970-01-02T00:00:01Z").unwrap(), Timestamp(86_401));
+        assert_eq!(Timestamp::parse("2000-03-01").unwrap(), Timestamp(951_868_800));
+        assert_eq!(
+            Timestamp::parse("2019-05-01T12:30:00Z").unwrap(),
+            Timestamp(1_556_713_800)
+        );
+        assert!(Timestamp::parse("2019-05-01").unwrap() < Timestamp::parse("2019-05-01T00:00:01Z").unwrap());
+
+        for bad in &[
+            "",
+            "2019",
+            "2019-05",
+            "2019-13-01",
+            "2019-05-01T12:30:00",
+            "2019-05-01T12:30Z",
+            "x-05-01",
+        ] {
+            assert!(Timestamp::parse(bad).is_err(), "{} shouldn't parse", bad);
+        }
+    }
+}
@@ -1,52 +1,397 @@
 use crate::coords;
-use crate::geodata::find_polygons::{find_polygons_in_multipolygon, NodeDesc, NodeDescPair};
+use crate::geodata::area_rules::AreaRules;
+use crate::geodata::clip::{select_entities, ClipArea, SelectedEntities};
+use crate::geodata::coastline::{close_way_along_boundary, process_coastlines, Boundary, Bounds};
+use crate::geodata::find_polygons::{
+    find_polygons_in_multipolygon, repair_polygons_in_multipolygon, MultipolygonRepair, NodeDesc, NodeDescPair,
+};
+use crate::geodata::geojson::parse_geojson;
+use crate::geodata::history::{parse_osm_history_xml, Timestamp};
+use crate::geodata::overpass::parse_overpass_json;
+use crate::geodata::pbf::parse_osm_pbf;
+use crate::geodata::reader::{MemberKind, WAY_AREA, WAY_CLOSED_ALONG_BOUNDARY, WAY_TRUNCATED};
 use crate::geodata::saver::save_to_internal_format;
-use failure::{format_err, Error, Fail, ResultExt};
+use crate::geodata::saver::{MULTIPOLYGON_REFS_IDX, NODE_REFS_IDX, WAY_REFS_IDX};
+use crate::geodata::temp_storage::{DiskIdTable, DiskNodeList, SpillDir};
+use crate::geodata::validation::{MultipolygonProblem, ValidationReport};
+use crate::mapcss::parser::{parse_file, split_stylesheet_path, ObjectType, Rule};
+use crate::mapcss::styler::{get_min_matching_zoom, get_used_tag_keys};
+use crate::tile;
+use failure::{bail, format_err, Error, Fail, ResultExt};
+use std::borrow::Cow;
 use std::collections::HashSet;
 use std::collections::{BTreeMap, HashMap};
 use std::fs::File;
 use std::io::prelude::*;
 use std::io::{BufReader, BufWriter};
+use std::path::PathBuf;
+use std::rc::Rc;
 use xml::attribute::OwnedAttribute;
 use xml::reader::{EventReader, XmlEvent};
 
+// The tag of the multipolygons that had to be repaired. The `renderer:` prefix keeps it apart from the tags
+// that come from the input, so that a stylesheet can tell the repaired multipolygons by it.
+const REPAIRED_TAG: (&str, &str) = ("renderer:repaired", "yes");
+
+/// Tweaks for the import process. The default values are good for small and medium-sized extracts.
+#[derive(Default)]
+pub struct ImportOptions {
+    /// Keep node coordinates, id translation tables and tile references in temporary files
+    /// instead of RAM. This makes the import slower, but lets it run on machines with little memory.
+    pub low_memory: bool,
+    /// Where to put the temporary files for a low-memory import (the system temporary directory by default).
+    pub temp_dir: Option<PathBuf>,
+    /// Keep only the tags that are used by this MapCSS stylesheet. The nodes that are left without tags
+    /// and are not a part of any way or multipolygon are dropped as well. The tile index also remembers
+    /// the lowest zoom level at which the stylesheet can draw each entity, so that the entities that are
+    /// invisible at a zoom level are not even read when rendering its tiles.
+    pub stylesheet: Option<String>,
+    /// Keep only the nodes inside this area, the ways and multipolygons that have at least one of these nodes,
+    /// and all nodes and ways needed to complete them.
+    pub clip_area: Option<ClipArea>,
+    /// The zoom level of the smallest tiles in the tile index (18 by default). A lower level makes the index
+    /// smaller, but the renderer then reads more entities that are outside of the rendered tile. Regardless
+    /// of this option, the entities that cover many tiles are indexed at lower zoom levels.
+    pub index_zoom: Option<u8>,
+    /// Write the problems found in the multipolygon relations (unclosed rings, self-intersections, duplicate
+    /// segments and missing member ways) to this file. The report is written in CSV if the file name ends
+    /// with `.csv` and in JSON otherwise.
+    pub validation_report: Option<String>,
+    /// Repair the multipolygons with unclosed rings instead of dropping them: the small gaps between the ways
+    /// are closed, the rings cut off by the clip area (or the bounding box of the data) are closed along it,
+    /// and the ways that still lead nowhere are dropped. The repaired multipolygons are tagged with
+    /// `renderer:repaired=yes`, so that stylesheets can draw them differently.
+    pub repair_multipolygons: bool,
+    /// What to do with the ways that have some of their nodes missing from the input, which happens at the edges
+    /// of extracts.
+    pub truncated_ways: TruncatedWays,
+    /// Decides which closed ways are drawn as areas. The decision is stored in the geodata file, so that
+    /// the stylesheet can't drop the tags it is based on.
+    pub area_rules: AreaRules,
+    /// Only for the OSM history files: import every entity as it was at this moment instead of its latest
+    /// version. The entities that didn't exist yet or were already deleted are left out.
+    pub history_at: Option<Timestamp>,
+}
+
+/// What the importer does with the ways that have some of their nodes missing from the input. Such ways
+/// are always flagged (see `Way::is_truncated`).
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum TruncatedWays {
+    /// Keep the nodes that are present. The shape of such ways is wrong, so they are never drawn as areas.
+    #[default]
+    Keep,
+    /// Leave the truncated ways out.
+    Drop,
+    /// Same as `Keep`, but the truncated closed ways are closed again along the clip area (or the bounding box
+    /// of the data), so that they are still drawn as areas.
+    CloseAreas,
+}
+
+/// The formats of the OSM data that can be imported.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InputFormat {
+    Xml,
+    Pbf,
+    /// An OSM XML file with all versions of every entity, as written by osmium or the planet history dumps.
+    History,
+    /// The JSON output of the Overpass API (`out json`).
+    OverpassJson,
+    /// GeoJSON features, with their properties as tags. Polygons and multipolygons become multipolygon
+    /// relations.
+    GeoJson,
+}
+
+impl InputFormat {
+    /// Guesses the format by the file extension: `.pbf` files are PBF, `.osh` files are history XML,
+    /// `.json` files are Overpass API output, `.geojson` files are GeoJSON, and everything else is XML.
+    pub fn from_file_name(file_name: &str) -> InputFormat {
+        let file_name = file_name.to_lowercase();
+        if file_name.ends_with(".pbf") {
+            InputFormat::Pbf
+        } else if file_name.ends_with(".osh") {
+            InputFormat::History
+        } else if file_name.ends_with(".geojson") {
+            InputFormat::GeoJson
+        } else if file_name.ends_with(".json") {
+            InputFormat::OverpassJson
+        } else {
+            InputFormat::Xml
+        }
+    }
+}
+
+// Where the OSM data is read from. Clipping needs to read the input several times, so it can't
+// be a plain reader.
+pub(super) enum OsmInput<'i> {
+    File(&'i str),
+    Bytes(&'i [u8], InputFormat),
+}
+
+impl OsmInput<'_> {
+    fn format(&self) -> InputFormat {
+        match *self {
+            OsmInput::File(file_name) => InputFormat::from_file_name(file_name),
+            OsmInput::Bytes(_, format) => format,
+        }
+    }
+}
+
 pub fn import(input: &str, output: &str) -> Result<(), Error> {
-    let input_file = File::open(input).context(format!("Failed to open {} for reading", input))?;
+    import_with_options(input, output, &ImportOptions::default())
+}
+
+pub fn import_with_options(input: &str, output: &str, options: &ImportOptions) -> Result<(), Error> {
+    check_options(options)?;
+    let input = OsmInput::File(input);
+    check_history_at(input.format(), options)?;
+
     let output_file = File::create(output).context(format!("Failed to open {} for writing", output))?;
 
-    let parser = EventReader::new(BufReader::new(input_file));
     let mut writer = BufWriter::new(output_file);
 
-    println!("Parsing XML");
-    let parsed_xml = parse_osm_xml(parser)?;
+    let selection = match options.clip_area {
+        Some(ref clip_area) => Some(select_entities(&input, options.history_at, clip_area)?),
+        None => None,
+    };
+    import_parsed(&mut writer, options, selection, |storages| {
+        parse_input(&input, options.history_at, storages)
+    })?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Same as `import_with_options`, but reads the OSM data in the given format from `input` and writes
+/// the geodata to `output`, so that neither has to be a file. With a clip area, the whole input is read
+/// into memory first, because the clipping needs to go over it several times.
+pub fn import_from_reader<R: Read, W: Write + Seek>(
+    mut input: R,
+    format: InputFormat,
+    output: &mut W,
+    options: &ImportOptions,
+) -> Result<(), Error> {
+    check_options(options)?;
+    check_history_at(format, options)?;
+
+    match options.clip_area {
+        Some(ref clip_area) => {
+            let mut bytes = Vec::new();
+            input.read_to_end(&mut bytes).context("Failed to read the input")?;
+            let input = OsmInput::Bytes(&bytes, format);
+            let selection = select_entities(&input, options.history_at, clip_area)?;
+            import_parsed(output, options, Some(selection), |storages| {
+                parse_input(&input, options.history_at, storages)
+            })
+        }
+        None => import_parsed(output, options, None, |storages| {
+            parse_osm(input, format, options.history_at, storages)
+        }),
+    }
+}
+
+fn check_options(options: &ImportOptions) -> Result<(), Error> {
+    if options.index_zoom.is_some_and(|zoom| zoom > tile::MAX_ZOOM) {
+        bail!("The index zoom level can't be higher than {}", tile::MAX_ZOOM);
+    }
+    Ok(())
+}
+
+fn check_history_at(format: InputFormat, options: &ImportOptions) -> Result<(), Error> {
+    if options.history_at.is_some() && format != InputFormat::History {
+        bail!("Importing the data as of a moment needs an OSM history file (.osh)");
+    }
+    Ok(())
+}
+
+// `parse` fills the storages with the entities from the input.
+fn import_parsed<W, P>(
+    writer: &mut W,
+    options: &ImportOptions,
+    selection: Option<SelectedEntities>,
+    parse: P,
+) -> Result<(), Error>
+where
+    W: Write + Seek,
+    P: FnOnce(EntityStorages) -> Result<EntityStorages, Error>,
+{
+    let spill_dir = if options.low_memory {
+        let temp_dir = options.temp_dir.clone().unwrap_or_else(std::env::temp_dir);
+        Some(SpillDir::new(&temp_dir)?)
+    } else {
+        None
+    };
+    let mut entity_storages = EntityStorages::new(spill_dir)?;
+    entity_storages.index_zoom = options.index_zoom.unwrap_or(tile::MAX_ZOOM);
+    let rules = match options.stylesheet {
+        Some(ref stylesheet) => {
+            let rules = load_stylesheet(stylesheet)?;
+            entity_storages.tag_filter = Some(get_used_tag_keys(&rules));
+            Some(rules)
+        }
+        None => None,
+    };
+    entity_storages.selection = selection;
+    entity_storages.repair_multipolygons = options.repair_multipolygons;
+    entity_storages.truncated_ways = options.truncated_ways;
+    entity_storages.area_rules = options.area_rules.clone();
+    entity_storages.bounds = options.clip_area.as_ref().map(ClipArea::bounds);
+    if options.validation_report.is_some() {
+        entity_storages.validation = Some(ValidationReport::default());
+    }
+
+    let mut parsed_data = parse(entity_storages)?;
+
+    if let (Some(report_file), Some(report)) = (&options.validation_report, parsed_data.validation.take()) {
+        println!("{}", report.summary());
+        report.save(report_file)?;
+    }
+
+    if parsed_data.tag_filter.is_some() {
+        println!("Removing unused nodes");
+        parsed_data.remove_unused_nodes()?;
+        println!("Left with {}", parsed_data.dump_state());
+    }
+
+    process_coastlines(&mut parsed_data, options.clip_area.as_ref().map(ClipArea::bounds))?;
+
+    if let Some(ref rules) = rules {
+        println!("Computing minimum zoom levels");
+        parsed_data.compute_min_zooms(rules);
+    }
 
     println!("Converting geodata to internal format");
-    save_to_internal_format(&mut writer, &parsed_xml)
-        .context("Failed to write the imported data to the output file")?;
+    save_to_internal_format(writer, &parsed_data).context("Failed to write the imported data to the output")?;
     Ok(())
 }
 
+pub(super) fn load_stylesheet(stylesheet: &str) -> Result<Vec<Rule>, Error> {
+    let (base_path, file_name) = split_stylesheet_path(stylesheet)?;
+    Ok(parse_file(&base_path, &file_name).context("Failed to parse the stylesheet file")?)
+}
+
+// `history_at` is only used for the history files, see `ImportOptions::history_at`.
+pub(super) fn parse_input<H: OsmEntityHandler>(
+    input: &OsmInput<'_>,
+    history_at: Option<Timestamp>,
+    handler: H,
+) -> Result<H, Error> {
+    match *input {
+        OsmInput::File(file_name) => {
+            let input_file = File::open(file_name).context(format!("Failed to open {} for reading", file_name))?;
+            parse_osm(BufReader::new(input_file), input.format(), history_at, handler)
+        }
+        OsmInput::Bytes(bytes, format) => parse_osm(bytes, format, history_at, handler),
+    }
+}
+
+fn parse_osm<R: Read, H: OsmEntityHandler>(
+    input: R,
+    format: InputFormat,
+    history_at: Option<Timestamp>,
+    handler: H,
+) -> Result<H, Error> {
+    match format {
+        InputFormat::Pbf => {
+            println!("Parsing PBF");
+            parse_osm_pbf(input, handler)
+        }
+        InputFormat::Xml => {
+            println!("Parsing XML");
+            parse_osm_xml(EventReader::new(input), handler)
+        }
+        InputFormat::History => {
+            println!("Parsing history XML");
+            parse_osm_history_xml(EventReader::new(input), history_at, handler)
+        }
+        InputFormat::OverpassJson => {
+            println!("Parsing Overpass JSON");
+            parse_overpass_json(input, handler)
+        }
+        InputFormat::GeoJson => {
+            println!("Parsing GeoJSON");
+            parse_geojson(input, handler)
+        }
+    }
+}
+
+// Receives the entities from the parsers. The node and way references are global ids.
+pub(super) trait OsmEntityHandler {
+    fn handle_node(&mut self, node: RawNode) -> Result<(), Error>;
+    fn handle_way(&mut self, way: ParsedWay) -> Result<(), Error>;
+    fn handle_relation(&mut self, relation: ParsedRelation) -> Result<(), Error>;
+
+    // OSM files list nodes first, then ways, then relations. The following two functions
+    // are called at the section boundaries.
+    fn finish_nodes(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn finish_ways(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn dump_state(&self) -> String;
+}
+
+enum IdTranslation {
+    InMemory(HashMap<u64, usize>),
+    OnDisk(DiskIdTable),
+}
+
+impl IdTranslation {
+    fn new(spill_dir: &Option<Rc<SpillDir>>) -> IdTranslation {
+        match spill_dir {
+            Some(dir) => IdTranslation::OnDisk(DiskIdTable::new(dir)),
+            None => IdTranslation::InMemory(HashMap::new()),
+        }
+    }
+
+    fn add(&mut self, global_id: u64, local_id: usize) -> Result<(), Error> {
+        match self {
+            IdTranslation::InMemory(ids) => {
+                ids.insert(global_id, local_id);
+                Ok(())
+            }
+            IdTranslation::OnDisk(table) => table.add(global_id, local_id),
+        }
+    }
+
+    fn finish(&mut self) -> Result<(), Error> {
+        match self {
+            IdTranslation::InMemory(_) => Ok(()),
+            IdTranslation::OnDisk(table) => table.finish(),
+        }
+    }
+
+    fn get(&self, global_id: u64) -> Option<usize> {
+        match self {
+            IdTranslation::InMemory(ids) => ids.get(&global_id).cloned(),
+            IdTranslation::OnDisk(table) => table.get(global_id),
+        }
+    }
+}
+
 pub(super) struct OsmEntityStorage<E: Default> {
-    global_id_to_local_id: HashMap<u64, usize>,
+    global_id_to_local_id: IdTranslation,
     entities: Vec<E>,
 }
 
 impl<E: Default> OsmEntityStorage<E> {
-    fn new() -> OsmEntityStorage<E> {
+    fn new(spill_dir: &Option<Rc<SpillDir>>) -> OsmEntityStorage<E> {
         OsmEntityStorage {
-            global_id_to_local_id: HashMap::new(),
+            global_id_to_local_id: IdTranslation::new(spill_dir),
             entities: Vec::new(),
         }
     }
 
-    fn add(&mut self, global_id: u64, entity: E) {
+    fn add(&mut self, global_id: u64, entity: E) -> Result<(), Error> {
         let old_size = self.entities.len();
-        self.global_id_to_local_id.insert(global_id, old_size);
+        self.global_id_to_local_id.add(global_id, old_size)?;
         self.entities.push(entity);
+        Ok(())
     }
 
-    fn translate_id(&self, global_id: u64) -> Option<usize> {
-        self.global_id_to_local_id.get(&global_id).cloned()
+    pub(super) fn translate_id(&self, global_id: u64) -> Option<usize> {
+        self.global_id_to_local_id.get(global_id)
     }
 
     pub(super) fn get_entities(&self) -> &Vec<E> {
@@ -54,56 +399,570 @@ impl<E: Default> OsmEntityStorage<E> {
     }
 }
 
+enum NodeList {
+    InMemory(Vec<RawNode>),
+    OnDisk(DiskNodeList),
+}
+
+pub(super) struct NodeStorage {
+    global_id_to_local_id: IdTranslation,
+    nodes: NodeList,
+    // The nodes created by the importer itself, which follow the nodes from the input. They are never
+    // looked up by id, and there are few of them, so they are always kept in memory.
+    synthetic_nodes: Vec<RawNode>,
+}
+
+impl NodeStorage {
+    pub(super) fn new(spill_dir: &Option<Rc<SpillDir>>) -> Result<NodeStorage, Error> {
+        let nodes = match spill_dir {
+            Some(dir) => NodeList::OnDisk(DiskNodeList::new(dir)?),
+            None => NodeList::InMemory(Vec::new()),
+        };
+        Ok(NodeStorage {
+            global_id_to_local_id: IdTranslation::new(spill_dir),
+            nodes,
+            synthetic_nodes: Vec::new(),
+        })
+    }
+
+    pub(super) fn add(&mut self, node: RawNode) -> Result<(), Error> {
+        self.global_id_to_local_id.add(node.global_id, self.len())?;
+        match self.nodes {
+            NodeList::InMemory(ref mut nodes) => nodes.push(node),
+            NodeList::OnDisk(ref mut nodes) => nodes
+                .add(&node)
+                .context("Nodes must precede ways and relations in the input file for a low-memory import")?,
+        }
+        Ok(())
+    }
+
+    // Unlike `add`, works after `finish`. Returns the local id of the node.
+    pub(super) fn add_synthetic(&mut self, lat: f64, lon: f64) -> usize {
+        self.synthetic_nodes.push(RawNode {
+            global_id: 0,
+            lat,
+            lon,
+            tags: RawTags::default(),
+        });
+        self.len() - 1
+    }
+
+    // With on-disk storage, neither id translation nor node lookups work until this is called.
+    fn finish(&mut self) -> Result<(), Error> {
+        self.global_id_to_local_id.finish()?;
+        if let NodeList::OnDisk(ref mut nodes) = self.nodes {
+            nodes.finish()?;
+        }
+        Ok(())
+    }
+
+    pub(super) fn translate_id(&self, global_id: u64) -> Option<usize> {
+        self.global_id_to_local_id.get(global_id)
+    }
+
+    pub(super) fn len(&self) -> usize {
+        self.input_node_count() + self.synthetic_nodes.len()
+    }
+
+    fn input_node_count(&self) -> usize {
+        match self.nodes {
+            NodeList::InMemory(ref nodes) => nodes.len(),
+            NodeList::OnDisk(ref nodes) => nodes.len(),
+        }
+    }
+
+    pub(super) fn get(&self, idx: usize) -> Cow<'_, RawNode> {
+        if let Some(node) = self.get_synthetic(idx) {
+            return Cow::Borrowed(node);
+        }
+        match self.nodes {
+            NodeList::InMemory(ref nodes) => Cow::Borrowed(&nodes[idx]),
+            NodeList::OnDisk(ref nodes) => Cow::Owned(nodes.get(idx)),
+        }
+    }
+
+    // The bounding box of all nodes.
+    pub(super) fn bounds(&self) -> Bounds {
+        (0..self.len()).fold(
+            (f64::INFINITY, f64::INFINITY, f64::NEG_INFINITY, f64::NEG_INFINITY),
+            |(min_lat, min_lon, max_lat, max_lon), idx| {
+                let (lat, lon) = self.get_coords(idx);
+                (min_lat.min(lat), min_lon.min(lon), max_lat.max(lat), max_lon.max(lon))
+            },
+        )
+    }
+
+    pub(super) fn get_coords(&self, idx: usize) -> (f64, f64) {
+        if let Some(node) = self.get_synthetic(idx) {
+            return (node.lat, node.lon);
+        }
+        match self.nodes {
+            NodeList::InMemory(ref nodes) => (nodes[idx].lat, nodes[idx].lon),
+            NodeList::OnDisk(ref nodes) => nodes.coords(idx),
+        }
+    }
+
+    fn get_synthetic(&self, idx: usize) -> Option<&RawNode> {
+        idx.checked_sub(self.input_node_count())
+            .map(|synthetic_idx| &self.synthetic_nodes[synthetic_idx])
+    }
+}
+
+// A compact set of local node ids, which also tells how many members of the set precede a given id.
+struct NodeBitSet {
+    bits: Vec<u64>,
+    ranks: Vec<usize>,
+}
+
+impl NodeBitSet {
+    fn new(len: usize) -> NodeBitSet {
+        NodeBitSet {
+            bits: vec![0; len.div_ceil(64)],
+            ranks: Vec::new(),
+        }
+    }
+
+    fn insert(&mut self, idx: usize) {
+        self.bits[idx / 64] |= 1 << (idx % 64);
+    }
+
+    fn contains(&self, idx: usize) -> bool {
+        self.bits[idx / 64] & (1 << (idx % 64)) != 0
+    }
+
+    // Must be called after all insertions and before `rank`.
+    fn compute_ranks(&mut self) {
+        let mut total = 0;
+        self.ranks = Vec::with_capacity(self.bits.len());
+        for block in &self.bits {
+            self.ranks.push(total);
+            total += block.count_ones() as usize;
+        }
+    }
+
+    fn rank(&self, idx: usize) -> usize {
+        let preceding_bits = self.bits[idx / 64] & ((1 << (idx % 64)) - 1);
+        self.ranks[idx / 64] + preceding_bits.count_ones() as usize
+    }
+}
+
 pub(super) struct EntityStorages {
-    pub(super) node_storage: OsmEntityStorage<RawNode>,
+    pub(super) node_storage: NodeStorage,
     pub(super) way_storage: OsmEntityStorage<RawWay>,
     pub(super) polygon_storage: Vec<Polygon>,
     pub(super) multipolygon_storage: OsmEntityStorage<Multipolygon>,
+    // Members of these relations are referenced by global ids, which are translated when saving.
+    pub(super) relation_storage: OsmEntityStorage<ParsedRelation>,
+    pub(super) spill_dir: Option<Rc<SpillDir>>,
+    // The lowest zoom levels at which the nodes, the ways, the multipolygons and the relations can be drawn,
+    // indexed by NODE_REFS_IDX, WAY_REFS_IDX, MULTIPOLYGON_REFS_IDX and RELATION_REFS_IDX. An empty list means
+    // that all entities of this type can be drawn at any zoom level.
+    pub(super) min_zooms: [Vec<u8>; 4],
+    // The highest zoom level of the tile index. Entities that cover too many tiles at this level are indexed
+    // at lower levels.
+    pub(super) index_zoom: u8,
+    // If present, only the tags with these keys are kept.
+    pub(super) tag_filter: Option<HashSet<String>>,
+    // If present, only these entities are kept.
+    selection: Option<SelectedEntities>,
+    // If present, the problems with multipolygons are collected here.
+    validation: Option<ValidationReport>,
+    // Whether the broken multipolygons are repaired instead of being dropped.
+    repair_multipolygons: bool,
+    // The rings that are cut off by these bounds are closed along them when repairing multipolygons
+    // and truncated ways. Computed from the nodes when they are first needed, unless a clip area is given.
+    bounds: Option<Bounds>,
+    // What to do with the ways that have some of their nodes missing.
+    truncated_ways: TruncatedWays,
+    pub(super) area_rules: AreaRules,
 }
 
-fn parse_osm_xml<R: Read>(mut parser: EventReader<R>) -> Result<EntityStorages, Error> {
-    let mut entity_storages = EntityStorages {
-        node_storage: OsmEntityStorage::new(),
-        way_storage: OsmEntityStorage::new(),
-        polygon_storage: Vec::new(),
-        multipolygon_storage: OsmEntityStorage::new(),
-    };
+impl EntityStorages {
+    pub(super) fn new(spill_dir: Option<Rc<SpillDir>>) -> Result<EntityStorages, Error> {
+        Ok(EntityStorages {
+            node_storage: NodeStorage::new(&spill_dir)?,
+            way_storage: OsmEntityStorage::new(&spill_dir),
+            polygon_storage: Vec::new(),
+            // Multipolygons are never looked up by id, so there's no point in keeping them on disk.
+            multipolygon_storage: OsmEntityStorage::new(&None),
+            relation_storage: OsmEntityStorage::new(&None),
+            spill_dir,
+            min_zooms: Default::default(),
+            index_zoom: tile::MAX_ZOOM,
+            tag_filter: None,
+            selection: None,
+            validation: None,
+            repair_multipolygons: false,
+            bounds: None,
+            truncated_ways: TruncatedWays::Keep,
+            area_rules: AreaRules::default(),
+        })
+    }
 
-    let mut elem_count = 0;
+    pub(super) fn add_node(&mut self, mut node: RawNode) -> Result<(), Error> {
+        self.filter_tags(&mut node.tags);
+        self.node_storage.add(node)
+    }
+
+    pub(super) fn add_way(&mut self, mut way: RawWay) -> Result<(), Error> {
+        self.filter_tags(&mut way.tags);
+        postprocess_node_refs(&mut way.node_ids);
+        self.way_storage.add(way.global_id, way)
+    }
+
+    pub(super) fn add_relation(&mut self, mut relation: RawRelation) -> Result<(), Error> {
+        if !is_multipolygon(&relation.tags) {
+            return Ok(());
+        }
+        self.filter_tags(&mut relation.tags);
+        let segments = relation.to_segments(self);
+        if !self.repair_multipolygons {
+            if let Some(polygons) =
+                find_polygons_in_multipolygon(relation.global_id, &segments, self.validation.as_mut())
+            {
+                self.add_multipolygon(relation.global_id, polygons, relation.tags)?;
+            }
+            return Ok(());
+        }
+
+        let bounds = self.data_bounds();
+        let node_storage = &mut self.node_storage;
+        let mut repair = MultipolygonRepair {
+            bounds,
+            add_node: &mut |lat, lon| node_storage.add_synthetic(lat, lon),
+        };
+        let result =
+            repair_polygons_in_multipolygon(relation.global_id, &segments, self.validation.as_mut(), &mut repair);
+        if let Some((polygons, repaired)) = result {
+            if repaired {
+                relation
+                    .tags
+                    .insert(REPAIRED_TAG.0.to_string(), REPAIRED_TAG.1.to_string());
+                self.filter_tags(&mut relation.tags);
+            }
+            self.add_multipolygon(relation.global_id, polygons, relation.tags)?;
+        }
+        Ok(())
+    }
+
+    // The bounds along which the rings that are cut off by the boundary of the data are closed.
+    fn data_bounds(&mut self) -> Bounds {
+        let node_storage = &self.node_storage;
+        *self.bounds.get_or_insert_with(|| node_storage.bounds())
+    }
+
+    pub(super) fn add_multipolygon(
+        &mut self,
+        global_id: u64,
+        polygons: Vec<Polygon>,
+        tags: RawTags,
+    ) -> Result<(), Error> {
+        let mut multipolygon = Multipolygon {
+            global_id,
+            polygon_ids: Vec::new(),
+            tags,
+        };
+        for poly in polygons {
+            multipolygon.polygon_ids.push(self.polygon_storage.len());
+            self.polygon_storage.push(poly);
+        }
+        self.multipolygon_storage.add(global_id, multipolygon)
+    }
+
+    pub(super) fn add_stored_relation(&mut self, mut relation: ParsedRelation) -> Result<(), Error> {
+        self.filter_tags(&mut relation.tags);
+        self.relation_storage.add(relation.global_id, relation)
+    }
+
+    // Returns the kinds, the local ids and the roles of the members that are present in the storages.
+    pub(super) fn relation_members<'r>(
+        &'r self,
+        relation: &'r ParsedRelation,
+    ) -> impl Iterator<Item = (MemberKind, usize, &'r str)> + 'r {
+        relation.members.iter().filter_map(move |m| {
+            let local_id = match m.kind {
+                MemberKind::Node => self.node_storage.translate_id(m.global_id),
+                MemberKind::Way => self.way_storage.translate_id(m.global_id),
+                MemberKind::Relation => self.relation_storage.translate_id(m.global_id),
+            };
+            local_id.map(|id| (m.kind, id, m.role.as_str()))
+        })
+    }
+
+    // Called at the section boundaries so that on-disk storages can prepare for lookups.
+    pub(super) fn finish_nodes(&mut self) -> Result<(), Error> {
+        self.node_storage.finish()
+    }
+
+    pub(super) fn finish_ways(&mut self) -> Result<(), Error> {
+        self.finish_nodes()?;
+        self.way_storage.global_id_to_local_id.finish()
+    }
+
+    fn filter_tags(&self, tags: &mut RawTags) {
+        if let Some(ref keys) = self.tag_filter {
+            tags.retain(|k, _| keys.contains(k));
+        }
+    }
+
+    // Drops the nodes without tags that are not used by any way, polygon or relation, and updates
+    // the references to the remaining nodes.
+    fn remove_unused_nodes(&mut self) -> Result<(), Error> {
+        let mut used_nodes = NodeBitSet::new(self.node_storage.len());
+        for relation in &self.relation_storage.entities {
+            for (kind, local_id, _) in self.relation_members(relation) {
+                if kind == MemberKind::Node {
+                    used_nodes.insert(local_id);
+                }
+            }
+        }
+        let polygons = self.polygon_storage.iter();
+        for node_id in self
+            .way_storage
+            .entities
+            .iter()
+            .flat_map(|w| w.node_ids.iter())
+            .chain(polygons.flatten())
+        {
+            used_nodes.insert(*node_id);
+        }
+
+        let mut new_node_storage = NodeStorage::new(&self.spill_dir)?;
+        for idx in 0..self.node_storage.len() {
+            let node = self.node_storage.get(idx);
+            if !node.tags.is_empty() {
+                used_nodes.insert(idx);
+            }
+            if used_nodes.contains(idx) {
+                // The synthetic nodes (e.g. the corners added when closing the truncated ways) follow the input
+                // ones, so they stay synthetic and keep their order after the renumbering.
+                match self.node_storage.get_synthetic(idx) {
+                    Some(node) => {
+                        new_node_storage.add_synthetic(node.lat, node.lon);
+                    }
+                    None => new_node_storage.add(node.into_owned())?,
+                }
+            }
+        }
+        new_node_storage.finish()?;
+        self.node_storage = new_node_storage;
+
+        used_nodes.compute_ranks();
+        let node_ids = self.way_storage.entities.iter_mut().map(|w| &mut w.node_ids);
+        for ids in node_ids.chain(self.polygon_storage.iter_mut()) {
+            for id in ids.iter_mut() {
+                *id = used_nodes.rank(*id);
+            }
+        }
+        Ok(())
+    }
+
+    pub(super) fn min_zoom(&self, refs_idx: u8, local_id: usize) -> u8 {
+        self.min_zooms[refs_idx as usize].get(local_id).cloned().unwrap_or(0)
+    }
+
+    // The entities that can't be drawn at any zoom level get u8::MAX.
+    pub(super) fn compute_min_zooms(&mut self, rules: &[Rule]) {
+        let min_zoom = |object_types: &[ObjectType], tags: &RawTags| {
+            get_min_matching_zoom(rules, |t| object_types.contains(t), |k| tags.get(k).map(String::as_str))
+                .unwrap_or(u8::MAX)
+        };
+
+        let nodes = &self.node_storage;
+        self.min_zooms[NODE_REFS_IDX as usize] = (0..nodes.len())
+            .map(|idx| min_zoom(&[ObjectType::Node], &nodes.get(idx).tags))
+            .collect();
+
+        self.min_zooms[WAY_REFS_IDX as usize] = self
+            .way_storage
+            .entities
+            .iter()
+            .map(|way| {
+                if way.is_area(nodes) {
+                    min_zoom(&[ObjectType::Way, ObjectType::Area], &way.tags)
+                } else {
+                    min_zoom(&[ObjectType::Way], &way.tags)
+                }
+            })
+            .collect();
+
+        self.min_zooms[MULTIPOLYGON_REFS_IDX as usize] = self
+            .multipolygon_storage
+            .entities
+            .iter()
+            .map(|mp| min_zoom(&[ObjectType::Way, ObjectType::Area], &mp.tags))
+            .collect();
+    }
+
+    // The location of the missing ways is unknown, so the first node of the other members is reported instead.
+    fn report_missing_member_ways(&mut self, relation_id: u64, present_way_ids: &[usize], missing_way_count: usize) {
+        if self.validation.is_none() {
+            return;
+        }
+        let location = present_way_ids
+            .iter()
+            .filter_map(|id| self.way_storage.entities[*id].node_ids.first())
+            .map(|node_id| self.node_storage.get_coords(*node_id))
+            .next();
+        if let Some(ref mut report) = self.validation {
+            report.add(
+                relation_id,
+                MultipolygonProblem::MissingMemberWay,
+                missing_way_count,
+                location,
+                false,
+            );
+        }
+    }
 
-    fn dump_state(entity_storages: &EntityStorages) -> String {
+    pub(super) fn dump_state(&self) -> String {
         format!(
-            "{} nodes, {} ways and {} multipolygon relations",
-            entity_storages.node_storage.entities.len(),
-            entity_storages.way_storage.entities.len(),
-            entity_storages.multipolygon_storage.entities.len()
+            "{} nodes, {} ways, {} multipolygon relations and {} other relations",
+            self.node_storage.len(),
+            self.way_storage.entities.len(),
+            self.multipolygon_storage.entities.len(),
+            self.relation_storage.entities.len()
         )
     }
+}
+
+impl OsmEntityHandler for EntityStorages {
+    fn handle_node(&mut self, node: RawNode) -> Result<(), Error> {
+        if self
+            .selection
+            .as_ref()
+            .is_none_or(|s| s.nodes.contains(&node.global_id))
+        {
+            self.add_node(node)?;
+        }
+        Ok(())
+    }
+
+    fn handle_way(&mut self, way: ParsedWay) -> Result<(), Error> {
+        if self
+            .selection
+            .as_ref()
+            .is_some_and(|s| !s.ways.contains(&way.global_id))
+        {
+            return Ok(());
+        }
+        let present_node_ids = way
+            .node_ids
+            .iter()
+            .map(|id| self.node_storage.translate_id(*id))
+            .collect::<Vec<_>>();
+        let mut node_ids = present_node_ids.iter().flatten().cloned().collect::<RawRefs>();
+        // The tags are checked before the stylesheet gets to drop them.
+        let mut flags = if self.area_rules.is_area(&way.tags) {
+            WAY_AREA
+        } else {
+            0
+        };
+        if node_ids.len() < present_node_ids.len() {
+            flags |= WAY_TRUNCATED;
+            match self.truncated_ways {
+                TruncatedWays::Keep => {}
+                TruncatedWays::Drop => return Ok(()),
+                TruncatedWays::CloseAreas => {
+                    let is_closed = way.node_ids.len() > 3 && way.node_ids.first() == way.node_ids.last();
+                    if is_closed && flags & WAY_AREA != 0 && node_ids.len() >= 2 {
+                        let boundary = Boundary::new(self.data_bounds());
+                        let pieces = split_truncated_ring(&present_node_ids[1..]);
+                        node_ids = close_way_along_boundary(&pieces, &boundary, &mut self.node_storage);
+                        flags |= WAY_CLOSED_ALONG_BOUNDARY;
+                    }
+                }
+            }
+        }
+        let raw_way = RawWay {
+            global_id: way.global_id,
+            node_ids,
+            tags: way.tags,
+            flags,
+        };
+        self.add_way(raw_way)
+    }
+
+    fn handle_relation(&mut self, relation: ParsedRelation) -> Result<(), Error> {
+        if self
+            .selection
+            .as_ref()
+            .is_some_and(|s| !s.relations.contains(&relation.global_id))
+        {
+            return Ok(());
+        }
+        if is_stored_relation(&relation.tags) {
+            return self.add_stored_relation(relation);
+        }
+        let way_ids = relation
+            .way_ids()
+            .filter_map(|id| self.way_storage.translate_id(id))
+            .collect::<Vec<_>>();
+        let missing_way_count = relation.way_ids().count() - way_ids.len();
+        if missing_way_count > 0 && is_multipolygon(&relation.tags) {
+            self.report_missing_member_ways(relation.global_id, &way_ids, missing_way_count);
+        }
+        let raw_relation = RawRelation {
+            global_id: relation.global_id,
+            way_ids,
+            tags: relation.tags,
+        };
+        self.add_relation(raw_relation)
+    }
+
+    fn finish_nodes(&mut self) -> Result<(), Error> {
+        EntityStorages::finish_nodes(self)
+    }
+
+    fn finish_ways(&mut self) -> Result<(), Error> {
+        EntityStorages::finish_ways(self)
+    }
+
+    fn dump_state(&self) -> String {
+        EntityStorages::dump_state(self)
+    }
+}
+
+pub(super) fn is_multipolygon(tags: &RawTags) -> bool {
+    tags.get("type").map(String::as_str) == Some("multipolygon")
+}
+
+// Multipolygons are converted to polygons, while these relations are stored with their members as is.
+const STORED_RELATION_TYPES: [&str; 3] = ["route", "boundary", "associatedStreet"];
+
+pub(super) fn is_stored_relation(tags: &RawTags) -> bool {
+    tags.get("type")
+        .is_some_and(|t| STORED_RELATION_TYPES.contains(&t.as_str()))
+}
+
+fn parse_osm_xml<R: Read, H: OsmEntityHandler>(mut parser: EventReader<R>, mut handler: H) -> Result<H, Error> {
+    let mut elem_count = 0;
 
     loop {
         let e = parser.next().context("Failed to parse the input file")?;
         match e {
             XmlEvent::EndDocument => break,
             XmlEvent::StartElement { name, attributes, .. } => {
-                process_element(&name.local_name, &attributes, &mut entity_storages, &mut parser)?;
+                process_element(&name.local_name, &attributes, &mut handler, &mut parser)?;
                 elem_count += 1;
                 if elem_count % 100_000 == 0 {
-                    println!("Got {} so far", dump_state(&entity_storages));
+                    println!("Got {} so far", handler.dump_state());
                 }
             }
             _ => {}
         }
     }
 
-    println!("Total: {}", dump_state(&entity_storages));
+    handler.finish_ways()?;
+    println!("Total: {}", handler.dump_state());
 
-    Ok(entity_storages)
+    Ok(handler)
 }
 
-fn process_element<R: Read>(
+fn process_element<R: Read, H: OsmEntityHandler>(
     name: &str,
     attrs: &[OwnedAttribute],
-    entity_storages: &mut EntityStorages,
+    handler: &mut H,
     parser: &mut EventReader<R>,
 ) -> Result<(), Error> {
     match name {
@@ -114,64 +973,41 @@ fn process_element<R: Read>(
                 lon: parse_required_attr(name, attrs, "lon")?,
                 tags: RawTags::default(),
             };
-            process_subelements(name, &mut node, entity_storages, process_node_subelement, parser)?;
-            entity_storages.node_storage.add(node.global_id, node);
+            process_subelements(name, &mut node, &(), process_node_subelement, parser)?;
+            handler.handle_node(node)?;
         }
         "way" => {
-            let mut way = RawWay {
+            handler.finish_nodes()?;
+            let mut way = ParsedWay {
                 global_id: get_id(name, attrs)?,
-                node_ids: RawRefs::default(),
-                tags: RawTags::default(),
+                ..Default::default()
             };
-            process_subelements(name, &mut way, entity_storages, process_way_subelement, parser)?;
-            postprocess_node_refs(&mut way.node_ids);
-            entity_storages.way_storage.add(way.global_id, way);
+            process_subelements(name, &mut way, &(), process_way_subelement, parser)?;
+            handler.handle_way(way)?;
         }
         "relation" => {
-            let mut relation = RawRelation {
+            handler.finish_ways()?;
+            let mut relation = ParsedRelation {
                 global_id: get_id(name, attrs)?,
-                way_refs: Vec::<RelationWayRef>::default(),
-                tags: RawTags::default(),
+                ..Default::default()
             };
-            process_subelements(
-                name,
-                &mut relation,
-                entity_storages,
-                process_relation_subelement,
-                parser,
-            )?;
-            if relation.tags.iter().any(|(k, v)| k == "type" && v == "multipolygon") {
-                let segments = relation.to_segments(&entity_storages);
-                if let Some(polygons) = find_polygons_in_multipolygon(relation.global_id, &segments) {
-                    let mut multipolygon = Multipolygon {
-                        global_id: relation.global_id,
-                        polygon_ids: Vec::new(),
-                        tags: relation.tags,
-                    };
-                    for poly in polygons {
-                        multipolygon.polygon_ids.push(entity_storages.polygon_storage.len());
-                        entity_storages.polygon_storage.push(poly);
-                    }
-                    entity_storages
-                        .multipolygon_storage
-                        .add(relation.global_id, multipolygon);
-                }
-            }
+            process_subelements(name, &mut relation, &(), process_relation_subelement, parser)?;
+            handler.handle_relation(relation)?;
         }
         _ => {}
     }
     Ok(())
 }
 
-fn process_subelements<E: Default, R: Read, F>(
+pub(super) fn process_subelements<E, C, R: Read, F>(
     entity_name: &str,
     entity: &mut E,
-    entity_storages: &EntityStorages,
+    context: &C,
     subelement_processor: F,
     parser: &mut EventReader<R>,
 ) -> Result<(), Error>
 where
-    F: Fn(&mut E, &EntityStorages, &str, &[OwnedAttribute]) -> Result<(), Error>,
+    F: Fn(&mut E, &C, &str, &[OwnedAttribute]) -> Result<(), Error>,
 {
     loop {
         let e = parser.next().context(format!(
@@ -182,7 +1018,7 @@ where
             XmlEvent::EndDocument => break,
             XmlEvent::EndElement { ref name } if name.local_name == *entity_name => break,
             XmlEvent::StartElement { name, attributes, .. } => {
-                subelement_processor(entity, entity_storages, &name.local_name, &attributes)?
+                subelement_processor(entity, context, &name.local_name, &attributes)?
             }
             _ => {}
         }
@@ -190,7 +1026,23 @@ where
     Ok(())
 }
 
-fn postprocess_node_refs(refs: &mut RawRefs) {
+// Splits a closed way without the repeated first node into the pieces of the nodes that are present,
+// starting after a missing node, so that the pieces follow each other in the order of the way.
+fn split_truncated_ring(node_ids: &[Option<usize>]) -> Vec<Vec<usize>> {
+    let first_missing = node_ids.iter().position(Option::is_none).unwrap_or(0);
+    let mut pieces = Vec::new();
+    let mut piece = Vec::new();
+    for idx in 1..=node_ids.len() {
+        match node_ids[(first_missing + idx) % node_ids.len()] {
+            Some(id) => piece.push(id),
+            None if !piece.is_empty() => pieces.push(std::mem::take(&mut piece)),
+            None => {}
+        }
+    }
+    pieces
+}
+
+pub(super) fn postprocess_node_refs(refs: &mut RawRefs) {
     if refs.is_empty() {
         return;
     }
@@ -211,18 +1063,18 @@ fn postprocess_node_refs(refs: &mut RawRefs) {
     *refs = refs_without_duplicates;
 }
 
-fn process_node_subelement(
+pub(super) fn process_node_subelement(
     node: &mut RawNode,
-    _: &EntityStorages,
+    _: &(),
     sub_name: &str,
     sub_attrs: &[OwnedAttribute],
 ) -> Result<(), Error> {
     try_add_tag(sub_name, sub_attrs, &mut node.tags).map(|_| ())
 }
 
-fn process_way_subelement(
-    way: &mut RawWay,
-    entity_storages: &EntityStorages,
+pub(super) fn process_way_subelement(
+    way: &mut ParsedWay,
+    _: &(),
     sub_name: &str,
     sub_attrs: &[OwnedAttribute],
 ) -> Result<(), Error> {
@@ -230,32 +1082,42 @@ fn process_way_subelement(
         return Ok(());
     }
     if sub_name == "nd" {
-        if let Some(r) = get_ref(sub_name, sub_attrs, &entity_storages.node_storage)? {
-            way.node_ids.push(r);
-        }
+        way.node_ids.push(get_ref(sub_name, sub_attrs)?);
     }
     Ok(())
 }
 
-fn process_relation_subelement(
-    relation: &mut RawRelation,
-    entity_storages: &EntityStorages,
+pub(super) fn process_relation_subelement(
+    relation: &mut ParsedRelation,
+    _: &(),
     sub_name: &str,
     sub_attrs: &[OwnedAttribute],
 ) -> Result<(), Error> {
     if try_add_tag(sub_name, sub_attrs, &mut relation.tags)? {
         return Ok(());
     }
-    if sub_name == "member" && get_required_attr(sub_name, sub_attrs, "type")? == "way" {
-        if let Some(r) = get_ref(sub_name, sub_attrs, &entity_storages.way_storage)? {
-            let is_inner = get_required_attr(sub_name, sub_attrs, "role")? == "inner";
-            relation.way_refs.push(RelationWayRef { way_id: r, is_inner });
-        }
+    if sub_name == "member" {
+        let kind = match get_required_attr(sub_name, sub_attrs, "type")?.as_str() {
+            "node" => MemberKind::Node,
+            "way" => MemberKind::Way,
+            "relation" => MemberKind::Relation,
+            _ => return Ok(()),
+        };
+        let role = sub_attrs.iter().find(|a| a.name.local_name == "role");
+        relation.members.push(ParsedMember {
+            kind,
+            global_id: get_ref(sub_name, sub_attrs)?,
+            role: role.map(|a| a.value.clone()).unwrap_or_default(),
+        });
     }
     Ok(())
 }
 
-fn get_required_attr<'a>(elem_name: &str, attrs: &'a [OwnedAttribute], attr_name: &str) -> Result<&'a String, Error> {
+pub(super) fn get_required_attr<'a>(
+    elem_name: &str,
+    attrs: &'a [OwnedAttribute],
+    attr_name: &str,
+) -> Result<&'a String, Error> {
     attrs
         .iter()
         .filter(|x| x.name.local_name == attr_name)
@@ -264,7 +1126,7 @@ fn get_required_attr<'a>(elem_name: &str, attrs: &'a [OwnedAttribute], attr_name
         .ok_or_else(|| format_err!("Element {} doesn't have required attribute: {}", elem_name, attr_name))
 }
 
-fn parse_required_attr<T>(elem_name: &str, attrs: &[OwnedAttribute], attr_name: &str) -> Result<T, Error>
+pub(super) fn parse_required_attr<T>(elem_name: &str, attrs: &[OwnedAttribute], attr_name: &str) -> Result<T, Error>
 where
     T: std::str::FromStr,
     T::Err: Fail,
@@ -279,16 +1141,11 @@ where
     Ok(parsed_value)
 }
 
-fn get_ref<E: Default>(
-    elem_name: &str,
-    attrs: &[OwnedAttribute],
-    storage: &OsmEntityStorage<E>,
-) -> Result<Option<usize>, Error> {
-    let reference = parse_required_attr(elem_name, attrs, "ref")?;
-    Ok(storage.translate_id(reference))
+pub(super) fn get_ref(elem_name: &str, attrs: &[OwnedAttribute]) -> Result<u64, Error> {
+    parse_required_attr(elem_name, attrs, "ref")
 }
 
-fn try_add_tag<'a>(elem_name: &str, attrs: &'a [OwnedAttribute], tags: &mut RawTags) -> Result<bool, Error> {
+pub(super) fn try_add_tag<'a>(elem_name: &str, attrs: &'a [OwnedAttribute], tags: &mut RawTags) -> Result<bool, Error> {
     if elem_name != "tag" {
         return Ok(false);
     }
@@ -298,14 +1155,14 @@ fn try_add_tag<'a>(elem_name: &str, attrs: &'a [OwnedAttribute], tags: &mut RawT
     Ok(true)
 }
 
-fn get_id(elem_name: &str, attrs: &[OwnedAttribute]) -> Result<u64, Error> {
+pub(super) fn get_id(elem_name: &str, attrs: &[OwnedAttribute]) -> Result<u64, Error> {
     parse_required_attr(elem_name, attrs, "id")
 }
 
 pub(super) type RawRefs = Vec<usize>;
 pub(super) type RawTags = BTreeMap<String, String>;
 
-#[derive(Default)]
+#[derive(Clone, Default)]
 pub(super) struct RawNode {
     pub(super) global_id: u64,
     pub(super) lat: f64,
@@ -323,43 +1180,78 @@ impl coords::Coords for RawNode {
     }
 }
 
+// A way or a relation as it's stored in the input file, with global ids in references.
+#[derive(Default)]
+pub(super) struct ParsedWay {
+    pub(super) global_id: u64,
+    pub(super) node_ids: Vec<u64>,
+    pub(super) tags: RawTags,
+}
+
+#[derive(Clone, Default)]
+pub(super) struct ParsedRelation {
+    pub(super) global_id: u64,
+    pub(super) members: Vec<ParsedMember>,
+    pub(super) tags: RawTags,
+}
+
+impl ParsedRelation {
+    pub(super) fn way_ids(&self) -> impl Iterator<Item = u64> + '_ {
+        self.members
+            .iter()
+            .filter(|m| m.kind == MemberKind::Way)
+            .map(|m| m.global_id)
+    }
+}
+
+#[derive(Clone)]
+pub(super) struct ParsedMember {
+    pub(super) kind: MemberKind,
+    pub(super) global_id: u64,
+    pub(super) role: String,
+}
+
 #[derive(Default)]
 pub(super) struct RawWay {
     pub(super) global_id: u64,
     pub(super) node_ids: RawRefs,
     pub(super) tags: RawTags,
+    // WAY_TRUNCATED and WAY_CLOSED_ALONG_BOUNDARY.
+    pub(super) flags: u32,
 }
 
-pub struct RelationWayRef {
-    way_id: usize,
-    is_inner: bool,
+impl RawWay {
+    // Same as `OsmArea::is_area` for the ways in the geodata file.
+    fn is_area(&self, nodes: &NodeStorage) -> bool {
+        let node_ids = &self.node_ids;
+        let is_cut_open = self.flags & WAY_TRUNCATED != 0 && self.flags & WAY_CLOSED_ALONG_BOUNDARY == 0;
+        self.flags & WAY_AREA != 0
+            && !is_cut_open
+            && node_ids.len() > 2
+            && nodes.get_coords(node_ids[0]) == nodes.get_coords(node_ids[node_ids.len() - 1])
+    }
 }
 
 #[derive(Default)]
-struct RawRelation {
-    global_id: u64,
-    way_refs: Vec<RelationWayRef>,
-    tags: RawTags,
+pub(super) struct RawRelation {
+    pub(super) global_id: u64,
+    pub(super) way_ids: RawRefs,
+    pub(super) tags: RawTags,
 }
 
 impl RawRelation {
     fn to_segments(&self, entity_storages: &EntityStorages) -> Vec<NodeDescPair> {
         let create_node_desc = |way: &RawWay, node_idx_in_way| {
             let node_id = way.node_ids[node_idx_in_way];
-            let node = &entity_storages.node_storage.entities[node_id];
-            NodeDesc::new(node_id, node.lat, node.lon)
+            let (lat, lon) = entity_storages.node_storage.get_coords(node_id);
+            NodeDesc::new(node_id, lat, lon)
         };
-        self.way_refs
+        self.way_ids
             .iter()
-            .flat_map(|way_ref| {
-                let way = &entity_storages.way_storage.entities[way_ref.way_id];
-                (1..way.node_ids.len()).map(move |idx| {
-                    NodeDescPair::new(
-                        create_node_desc(way, idx - 1),
-                        create_node_desc(way, idx),
-                        way_ref.is_inner,
-                    )
-                })
+            .flat_map(|way_id| {
+                let way = &entity_storages.way_storage.entities[*way_id];
+                (1..way.node_ids.len())
+                    .map(move |idx| NodeDescPair::new(create_node_desc(way, idx - 1), create_node_desc(way, idx)))
             })
             .collect()
     }
@@ -373,3 +1265,47 @@ pub(super) struct Multipolygon {
     pub(super) polygon_ids: RawRefs,
     pub(super) tags: RawTags,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check_remove_unused_nodes(mut storages: EntityStorages) {
+        for global_id in 1..=3 {
+            let node = RawNode {
+                global_id,
+                lat: global_id as f64,
+                lon: global_id as f64,
+                tags: RawTags::default(),
+            };
+            storages.handle_node(node).unwrap();
+        }
+        storages.finish_nodes().unwrap();
+        let way = ParsedWay {
+            global_id: 10,
+            node_ids: vec![1, 3],
+            tags: RawTags::default(),
+        };
+        storages.handle_way(way).unwrap();
+        storages.finish_ways().unwrap();
+        let synthetic_id = storages.node_storage.add_synthetic(4.0, 4.0);
+        storages.way_storage.entities[0].node_ids.push(synthetic_id);
+
+        storages.remove_unused_nodes().unwrap();
+        let nodes = &storages.node_storage;
+        assert_eq!(nodes.input_node_count(), 2);
+        assert_eq!(nodes.len(), 3);
+        assert_eq!(storages.way_storage.entities[0].node_ids, vec![0, 1, 2]);
+        assert_eq!(nodes.get_coords(2), (4.0, 4.0));
+        assert!(nodes.get_synthetic(2).is_some());
+        assert_eq!(nodes.translate_id(0), None);
+        assert_eq!(nodes.translate_id(3), Some(1));
+    }
+
+    #[test]
+    fn test_remove_unused_nodes_keeps_synthetic_nodes() {
+        check_remove_unused_nodes(EntityStorages::new(None).unwrap());
+        let spill_dir = SpillDir::new(&std::env::temp_dir()).unwrap();
+        check_remove_unused_nodes(EntityStorages::new(Some(spill_dir)).unwrap());
+    }
+}
@@ -0,0 +1,499 @@
+use crate::coords::Coords;
+use crate::geodata::clip::{select_geodata_entities, ClipArea, SelectedEntities};
+use crate::geodata::reader::{GeodataReader, Member, Multipolygon, Node, OsmEntity, Polygon, Relation, Tags, Way};
+use crate::tile;
+use failure::{format_err, Error};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::io::Write;
+use std::str::FromStr;
+use xml::writer::{EmitterConfig, EventWriter, XmlEvent};
+
+const HEAVIEST_TILE_COUNT: usize = 10;
+const TOP_TAG_KEY_COUNT: usize = 20;
+
+/// A summary of the contents of a geodata file.
+pub struct GeodataStats {
+    pub node_count: usize,
+    pub way_count: usize,
+    pub multipolygon_count: usize,
+    pub relation_count: usize,
+    /// The number of tiles in the tile index for every zoom level of the index.
+    pub tile_counts: BTreeMap<u8, usize>,
+    pub string_table_size: usize,
+    /// The tiles with the most entity references, along with the reference counts.
+    pub heaviest_tiles: Vec<(tile::Tile, usize)>,
+    /// The most common tag keys, along with the number of entities that have them.
+    pub top_tag_keys: Vec<(String, usize)>,
+}
+
+pub fn get_stats<'a>(reader: &'a GeodataReader<'a>) -> GeodataStats {
+    let mut tile_counts = BTreeMap::new();
+    let mut tile_sizes = Vec::new();
+    for tile_idx in 0..reader.tile_count() {
+        let tile = reader.tile(tile_idx);
+        *tile_counts.entry(tile.zoom).or_default() += 1;
+        let ref_count = (0..4)
+            .map(|refs_idx| reader.tile_local_ids(tile_idx, refs_idx).len())
+            .sum::<usize>();
+        tile_sizes.push((Reverse(ref_count), tile_idx));
+    }
+    tile_sizes.sort();
+    let heaviest_tiles = tile_sizes
+        .into_iter()
+        .take(HEAVIEST_TILE_COUNT)
+        .map(|(Reverse(ref_count), tile_idx)| (reader.tile(tile_idx), ref_count))
+        .collect();
+
+    // Equal strings are stored only once, so the keys can be counted by their offsets.
+    let mut tag_key_counts = HashMap::new();
+    let mut count_keys = |tags: Tags<'_>| {
+        for (k, _) in tags.iter() {
+            tag_key_counts
+                .entry(k.offset)
+                .or_insert_with(|| (k.str.to_string(), 0))
+                .1 += 1;
+        }
+    };
+    (0..reader.node_count()).for_each(|idx| count_keys(reader.get_node(idx).tags()));
+    (0..reader.way_count()).for_each(|idx| count_keys(reader.get_way(idx).tags()));
+    (0..reader.multipolygon_count()).for_each(|idx| count_keys(reader.get_multipolygon(idx).tags()));
+    (0..reader.relation_count()).for_each(|idx| count_keys(reader.get_relation(idx).tags()));
+    let mut top_tag_keys = tag_key_counts.into_values().collect::<Vec<_>>();
+    top_tag_keys.sort_by(|(k1, c1), (k2, c2)| c2.cmp(c1).then(k1.cmp(k2)));
+    top_tag_keys.truncate(TOP_TAG_KEY_COUNT);
+
+    GeodataStats {
+        node_count: reader.node_count(),
+        way_count: reader.way_count(),
+        multipolygon_count: reader.multipolygon_count(),
+        relation_count: reader.relation_count(),
+        tile_counts,
+        string_table_size: reader.string_table_size(),
+        heaviest_tiles,
+        top_tag_keys,
+    }
+}
+
+impl fmt::Display for GeodataStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Nodes: {}", self.node_count)?;
+        writeln!(f, "Ways: {}", self.way_count)?;
+        writeln!(f, "Multipolygons: {}", self.multipolygon_count)?;
+        writeln!(f, "Relations: {}", self.relation_count)?;
+        writeln!(f, "String table: {} bytes", self.string_table_size)?;
+        writeln!(f, "Tiles:")?;
+        for (zoom, count) in &self.tile_counts {
+            writeln!(f, "  zoom {}: {}", zoom, count)?;
+        }
+        writeln!(f, "Heaviest tiles:")?;
+        for (tile, ref_count) in &self.heaviest_tiles {
+            writeln!(f, "  {}/{}/{}: {} references", tile.zoom, tile.x, tile.y, ref_count)?;
+        }
+        writeln!(f, "Top tag keys:")?;
+        for (key, count) in &self.top_tag_keys {
+            writeln!(f, "  {}: {}", key, count)?;
+        }
+        Ok(())
+    }
+}
+
+/// An OSM entity id with its type, written as `n123`, `w123` or `r123`. Relation ids refer both to
+/// multipolygons and to other relations.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OsmId {
+    Node(u64),
+    Way(u64),
+    Relation(u64),
+}
+
+impl FromStr for OsmId {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<OsmId, Error> {
+        let invalid = || format_err!("Invalid entity id {} (expected n123, w123 or r123)", s);
+        let id = s.get(1..).and_then(|id| id.parse().ok()).ok_or_else(invalid)?;
+        match s.get(..1) {
+            Some("n") => Ok(OsmId::Node(id)),
+            Some("w") => Ok(OsmId::Way(id)),
+            Some("r") => Ok(OsmId::Relation(id)),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// The entities to dump.
+pub enum DumpSelection {
+    /// The entities that would be kept when clipping the data to this area during the import.
+    Area(ClipArea),
+    Ids(Vec<OsmId>),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DumpFormat {
+    OsmXml,
+    GeoJson,
+}
+
+// The entities to dump, sorted by their ids.
+struct DumpedEntities<'a> {
+    nodes: Vec<Node<'a>>,
+    ways: Vec<Way<'a>>,
+    multipolygons: Vec<Multipolygon<'a>>,
+    relations: Vec<Relation<'a>>,
+}
+
+/// Writes the selected entities to `writer`.
+///
+/// In the OSM XML format, the nodes of the selected ways and multipolygons are written as well. The member ways
+/// of multipolygons are not stored in geodata files, so a multipolygon is written as a relation whose members
+/// are its rings, which get new way ids that are larger than the ids of all ways in the file.
+///
+/// In the GeoJSON format, the untagged nodes are skipped, ways are written as line strings, multipolygons as
+/// multipolygons, and relations as collections of the geometries of their node and way members. The OSM ids
+/// are stored in the `@id` property, along with the tags.
+pub fn dump<'a>(
+    reader: &'a GeodataReader<'a>,
+    selection: &DumpSelection,
+    format: DumpFormat,
+    writer: &mut dyn Write,
+) -> Result<(), Error> {
+    let selected = match selection {
+        DumpSelection::Area(area) => select_geodata_entities(reader, area),
+        DumpSelection::Ids(ids) => {
+            let mut selected = SelectedEntities::default();
+            for id in ids {
+                match id {
+                    OsmId::Node(id) => selected.nodes.insert(*id),
+                    OsmId::Way(id) => selected.ways.insert(*id),
+                    OsmId::Relation(id) => selected.relations.insert(*id),
+                };
+            }
+            selected
+        }
+    };
+
+    let sorted = |ids: &HashSet<u64>| ids.iter().cloned().collect::<BTreeSet<_>>();
+    let relation_ids = sorted(&selected.relations);
+    let entities = DumpedEntities {
+        nodes: sorted(&selected.nodes)
+            .into_iter()
+            .filter_map(|id| reader.get_node_by_id(id))
+            .collect(),
+        ways: sorted(&selected.ways)
+            .into_iter()
+            .filter_map(|id| reader.get_way_by_id(id))
+            .collect(),
+        multipolygons: relation_ids
+            .iter()
+            .filter_map(|id| reader.get_multipolygon_by_id(*id))
+            .collect(),
+        relations: relation_ids
+            .iter()
+            .filter_map(|id| reader.get_relation_by_id(*id))
+            .collect(),
+    };
+
+    match format {
+        DumpFormat::OsmXml => dump_osm_xml(reader, &entities, writer),
+        DumpFormat::GeoJson => dump_geojson(&entities, writer),
+    }
+}
+
+fn dump_osm_xml<'a>(
+    reader: &'a GeodataReader<'a>,
+    entities: &DumpedEntities<'a>,
+    writer: &mut dyn Write,
+) -> Result<(), Error> {
+    let mut xml = EmitterConfig::new().perform_indent(true).create_writer(writer);
+    xml.write(
+        XmlEvent::start_element("osm")
+            .attr("version", "0.6")
+            .attr("generator", "osm-renderer"),
+    )?;
+
+    let mut nodes = BTreeMap::new();
+    let mut add_node = |node: Node<'a>| {
+        nodes.insert(node.global_id(), (node.lat(), node.lon(), node.tags()));
+    };
+    entities.nodes.iter().cloned().for_each(&mut add_node);
+    for way in &entities.ways {
+        (0..way.node_count()).for_each(|idx| add_node(way.get_node(idx)));
+    }
+    for multipolygon in &entities.multipolygons {
+        for poly_idx in 0..multipolygon.polygon_count() {
+            let polygon = multipolygon.get_polygon(poly_idx);
+            (0..polygon.node_count()).for_each(|idx| add_node(polygon.get_node(idx)));
+        }
+    }
+    for (id, (lat, lon, tags)) in &nodes {
+        xml.write(
+            XmlEvent::start_element("node")
+                .attr("id", &id.to_string())
+                .attr("lat", &lat.to_string())
+                .attr("lon", &lon.to_string()),
+        )?;
+        write_xml_tags(&mut xml, tags.iter().map(|(k, v)| (k.str, v.str)))?;
+        xml.write(XmlEvent::end_element())?;
+    }
+
+    let write_way = |xml: &mut EventWriter<&mut dyn Write>, id: u64, node_ids: &[u64], tags: Option<Tags<'_>>| {
+        xml.write(XmlEvent::start_element("way").attr("id", &id.to_string()))?;
+        for node_id in node_ids {
+            xml.write(XmlEvent::start_element("nd").attr("ref", &node_id.to_string()))?;
+            xml.write(XmlEvent::end_element())?;
+        }
+        if let Some(tags) = tags {
+            write_xml_tags(xml, tags.iter().map(|(k, v)| (k.str, v.str)))?;
+        }
+        xml.write(XmlEvent::end_element())
+    };
+    for way in &entities.ways {
+        let node_ids = (0..way.node_count())
+            .map(|idx| way.get_node(idx).global_id())
+            .collect::<Vec<_>>();
+        write_way(&mut xml, way.global_id(), &node_ids, Some(way.tags()))?;
+    }
+    let mut next_ring_id = (0..reader.way_count())
+        .map(|idx| reader.get_way(idx).global_id() + 1)
+        .max()
+        .unwrap_or(1);
+    let mut rings = Vec::new();
+    for multipolygon in &entities.multipolygons {
+        let mut ring_ids = Vec::new();
+        for poly_idx in 0..multipolygon.polygon_count() {
+            let polygon = multipolygon.get_polygon(poly_idx);
+            let node_ids = (0..polygon.node_count())
+                .map(|idx| polygon.get_node(idx).global_id())
+                .collect::<Vec<_>>();
+            write_way(&mut xml, next_ring_id, &node_ids, None)?;
+            ring_ids.push(next_ring_id);
+            next_ring_id += 1;
+        }
+        rings.push(ring_ids);
+    }
+
+    for (multipolygon, ring_ids) in entities.multipolygons.iter().zip(rings.iter()) {
+        xml.write(XmlEvent::start_element("relation").attr("id", &multipolygon.global_id().to_string()))?;
+        for ring_id in ring_ids {
+            write_xml_member(&mut xml, "way", *ring_id, "")?;
+        }
+        let tags = multipolygon.tags();
+        let type_tag = match tags.get_by_key("type") {
+            Some(_) => None,
+            None => Some(("type", "multipolygon")),
+        };
+        write_xml_tags(
+            &mut xml,
+            type_tag.into_iter().chain(tags.iter().map(|(k, v)| (k.str, v.str))),
+        )?;
+        xml.write(XmlEvent::end_element())?;
+    }
+    for relation in &entities.relations {
+        xml.write(XmlEvent::start_element("relation").attr("id", &relation.global_id().to_string()))?;
+        for member in relation.members() {
+            let (kind, id) = match member.member {
+                Member::Node(node) => ("node", node.global_id()),
+                Member::Way(way) => ("way", way.global_id()),
+                Member::Relation(relation) => ("relation", relation.global_id()),
+            };
+            write_xml_member(&mut xml, kind, id, member.role)?;
+        }
+        write_xml_tags(&mut xml, relation.tags().iter().map(|(k, v)| (k.str, v.str)))?;
+        xml.write(XmlEvent::end_element())?;
+    }
+
+    xml.write(XmlEvent::end_element())?;
+    Ok(())
+}
+
+fn write_xml_tags<'t>(
+    xml: &mut EventWriter<&mut dyn Write>,
+    tags: impl Iterator<Item = (&'t str, &'t str)>,
+) -> Result<(), xml::writer::Error> {
+    for (k, v) in tags {
+        xml.write(XmlEvent::start_element("tag").attr("k", k).attr("v", v))?;
+        xml.write(XmlEvent::end_element())?;
+    }
+    Ok(())
+}
+
+fn write_xml_member(
+    xml: &mut EventWriter<&mut dyn Write>,
+    kind: &str,
+    id: u64,
+    role: &str,
+) -> Result<(), xml::writer::Error> {
+    xml.write(
+        XmlEvent::start_element("member")
+            .attr("type", kind)
+            .attr("ref", &id.to_string())
+            .attr("role", role),
+    )?;
+    xml.write(XmlEvent::end_element())
+}
+
+fn dump_geojson(entities: &DumpedEntities<'_>, writer: &mut dyn Write) -> Result<(), Error> {
+    let mut features = Vec::new();
+    for node in entities.nodes.iter().filter(|n| n.tags().iter().next().is_some()) {
+        features.push(geojson_feature(
+            &format!("n{}", node.global_id()),
+            &node.tags(),
+            &point(node),
+        ));
+    }
+    for way in &entities.ways {
+        features.push(geojson_feature(
+            &format!("w{}", way.global_id()),
+            &way.tags(),
+            &line_string(way),
+        ));
+    }
+    for multipolygon in &entities.multipolygons {
+        let geometry = multipolygon_geometry(multipolygon);
+        features.push(geojson_feature(
+            &format!("r{}", multipolygon.global_id()),
+            &multipolygon.tags(),
+            &geometry,
+        ));
+    }
+    for relation in &entities.relations {
+        let geometries = relation
+            .members()
+            .filter_map(|m| match m.member {
+                Member::Node(node) => Some(point(&node)),
+                Member::Way(way) => Some(line_string(&way)),
+                Member::Relation(_) => None,
+            })
+            .collect::<Vec<_>>();
+        let geometry = format!(
+            "{{\"type\":\"GeometryCollection\",\"geometries\":[{}]}}",
+            geometries.join(",")
+        );
+        features.push(geojson_feature(
+            &format!("r{}", relation.global_id()),
+            &relation.tags(),
+            &geometry,
+        ));
+    }
+
+    writeln!(writer, "{{\"type\":\"FeatureCollection\",\"features\":[")?;
+    writeln!(writer, "{}", features.join(",\n"))?;
+    writeln!(writer, "]}}")?;
+    Ok(())
+}
+
+fn geojson_feature(id: &str, tags: &Tags<'_>, geometry: &str) -> String {
+    let properties = std::iter::once(("@id", id))
+        .chain(tags.iter().map(|(k, v)| (k.str, v.str)))
+        .map(|(k, v)| format!("{}:{}", json_string(k), json_string(v)))
+        .collect::<Vec<_>>();
+    format!(
+        "{{\"type\":\"Feature\",\"properties\":{{{}}},\"geometry\":{}}}",
+        properties.join(","),
+        geometry
+    )
+}
+
+pub(super) fn json_string(s: &str) -> String {
+    let mut result = String::with_capacity(s.len() + 2);
+    result.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            '\n' => result.push_str("\\n"),
+            '\r' => result.push_str("\\r"),
+            '\t' => result.push_str("\\t"),
+            c if (c as u32) < 0x20 => result.push_str(&format!("\\u{:04x}", c as u32)),
+            c => result.push(c),
+        }
+    }
+    result.push('"');
+    result
+}
+
+fn position(node: &Node<'_>) -> String {
+    format!("[{},{}]", node.lon(), node.lat())
+}
+
+fn positions(node_count: usize, get_node: impl Fn(usize) -> String) -> String {
+    format!("[{}]", (0..node_count).map(get_node).collect::<Vec<_>>().join(","))
+}
+
+fn point(node: &Node<'_>) -> String {
+    format!("{{\"type\":\"Point\",\"coordinates\":{}}}", position(node))
+}
+
+fn line_string(way: &Way<'_>) -> String {
+    let coordinates = positions(way.node_count(), |idx| position(&way.get_node(idx)));
+    format!("{{\"type\":\"LineString\",\"coordinates\":{}}}", coordinates)
+}
+
+// Geodata files don't store the roles of the multipolygon rings, so a ring is considered to be a hole
+// if it's inside an odd number of other rings. A hole belongs to the innermost ring that contains it.
+fn multipolygon_geometry(multipolygon: &Multipolygon<'_>) -> String {
+    let rings = (0..multipolygon.polygon_count())
+        .map(|idx| multipolygon.get_polygon(idx))
+        .collect::<Vec<_>>();
+    let containers = (0..rings.len())
+        .map(|i| {
+            (0..rings.len())
+                .filter(|j| *j != i && ring_contains(&rings[*j], &rings[i]))
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+
+    let mut polygons = BTreeMap::<usize, Vec<usize>>::new();
+    for (idx, ring_containers) in containers.iter().enumerate() {
+        if ring_containers.len() % 2 == 0 {
+            polygons.entry(idx).or_default().insert(0, idx);
+        } else {
+            let outer = ring_containers
+                .iter()
+                .max_by_key(|c| containers[**c].len())
+                .cloned()
+                .unwrap();
+            polygons.entry(outer).or_default().push(idx);
+        }
+    }
+
+    let ring_positions = |ring: &Polygon<'_>| positions(ring.node_count(), |idx| position(&ring.get_node(idx)));
+    let polygons = polygons
+        .values()
+        .map(|ring_ids| {
+            let ring_ids = ring_ids.iter().map(|idx| ring_positions(&rings[*idx]));
+            format!("[{}]", ring_ids.collect::<Vec<_>>().join(","))
+        })
+        .collect::<Vec<_>>();
+    format!("{{\"type\":\"MultiPolygon\",\"coordinates\":[{}]}}", polygons.join(","))
+}
+
+// Checks if the first node of `inner` that isn't shared with `outer` is inside `outer`.
+fn ring_contains(outer: &Polygon<'_>, inner: &Polygon<'_>) -> bool {
+    let outer_coords = (0..outer.node_count())
+        .map(|idx| {
+            let node = outer.get_node(idx);
+            (node.lon(), node.lat())
+        })
+        .collect::<Vec<_>>();
+    let point = (0..inner.node_count())
+        .map(|idx| {
+            let node = inner.get_node(idx);
+            (node.lon(), node.lat())
+        })
+        .find(|p| !outer_coords.contains(p));
+    let (x, y) = match point {
+        Some(p) => p,
+        None => return false,
+    };
+
+    let mut inside = false;
+    for (p1, p2) in outer_coords.iter().zip(outer_coords.iter().skip(1)) {
+        if (p1.1 > y) != (p2.1 > y) && x < p1.0 + (y - p1.1) * (p2.0 - p1.0) / (p2.1 - p1.1) {
+            inside = !inside;
+        }
+    }
+    inside
+}
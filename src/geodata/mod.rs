@@ -1,4 +1,20 @@
+pub mod area_rules;
+pub mod clip;
+mod coastline;
+pub mod diff;
+mod encoding;
+pub mod extract;
 mod find_polygons;
+mod geojson;
+mod header;
+pub mod history;
 pub mod importer;
+pub mod inspect;
+pub mod multi_reader;
+mod overpass;
+mod pbf;
 pub mod reader;
 mod saver;
+mod simplify;
+mod temp_storage;
+mod validation;
@@ -0,0 +1,100 @@
+use crate::geodata::reader::{GeodataReader, Multipolygon, Node, OsmEntities, OsmEntity, Relation, Way};
+use crate::tile;
+use failure::{bail, Error};
+use std::collections::HashSet;
+
+/// Queries several geodata files (e.g. the neighboring regional extracts) as if they were one file.
+///
+/// The entities near the borders of the extracts are usually stored in several files. Such entities are
+/// returned only once, and the copy from the file that comes first in the list is used. The links between
+/// the entities (the way nodes, the relation members and the parents) never cross the files, so a route
+/// that is split between two extracts is returned as two relations with the same id, of which only the first
+/// one is kept. The water multipolygons that the importer makes from the coastlines are returned from every file.
+pub struct MultiGeodataReader<'a> {
+    readers: Vec<GeodataReader<'a>>,
+}
+
+impl<'a> MultiGeodataReader<'a> {
+    pub fn load(file_names: &[String]) -> Result<MultiGeodataReader<'a>, Error> {
+        if file_names.is_empty() {
+            bail!("No geodata files to load");
+        }
+        let readers = file_names
+            .iter()
+            .map(|file_name| GeodataReader::load(file_name))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(MultiGeodataReader { readers })
+    }
+
+    pub fn get_entities_in_tile_with_neighbors(
+        &'a self,
+        t: &tile::Tile,
+        osm_ids: &Option<HashSet<u64>>,
+    ) -> OsmEntities<'a> {
+        merge_entities(
+            self.readers
+                .iter()
+                .map(|reader| reader.get_entities_in_tile_with_neighbors(t, osm_ids)),
+        )
+    }
+
+    pub fn get_entities_in_bbox(&'a self, min_lat: f64, min_lon: f64, max_lat: f64, max_lon: f64) -> OsmEntities<'a> {
+        merge_entities(
+            self.readers
+                .iter()
+                .map(|reader| reader.get_entities_in_bbox(min_lat, min_lon, max_lat, max_lon)),
+        )
+    }
+
+    pub fn get_node_by_id(&'a self, global_id: u64) -> Option<Node<'a>> {
+        self.readers.iter().find_map(|reader| reader.get_node_by_id(global_id))
+    }
+
+    pub fn get_way_by_id(&'a self, global_id: u64) -> Option<Way<'a>> {
+        self.readers.iter().find_map(|reader| reader.get_way_by_id(global_id))
+    }
+
+    pub fn get_multipolygon_by_id(&'a self, global_id: u64) -> Option<Multipolygon<'a>> {
+        self.readers
+            .iter()
+            .find_map(|reader| reader.get_multipolygon_by_id(global_id))
+    }
+
+    pub fn get_relation_by_id(&'a self, global_id: u64) -> Option<Relation<'a>> {
+        self.readers
+            .iter()
+            .find_map(|reader| reader.get_relation_by_id(global_id))
+    }
+}
+
+const SYNTHETIC_ID: u64 = 0;
+
+fn merge_entities<'a>(mut results: impl Iterator<Item = OsmEntities<'a>>) -> OsmEntities<'a> {
+    let mut merged = results.next().unwrap_or_default();
+
+    let mut node_ids = global_ids(&merged.nodes);
+    let mut way_ids = global_ids(&merged.ways);
+    let mut multipolygon_ids = global_ids(&merged.multipolygons);
+    let mut relation_ids = global_ids(&merged.relations);
+    for entities in results {
+        append_new_entities(&mut merged.nodes, entities.nodes, &mut node_ids);
+        append_new_entities(&mut merged.ways, entities.ways, &mut way_ids);
+        append_new_entities(&mut merged.multipolygons, entities.multipolygons, &mut multipolygon_ids);
+        append_new_entities(&mut merged.relations, entities.relations, &mut relation_ids);
+    }
+    merged
+}
+
+fn global_ids<'a, E: OsmEntity<'a>>(entities: &[E]) -> HashSet<u64> {
+    entities.iter().map(|e| e.global_id()).collect()
+}
+
+// The entities added by the importer (the water multipolygon made from the coastlines and the nodes that close
+// it) have the id 0 in every file, so they are never copies of each other and are always kept.
+fn append_new_entities<'a, E: OsmEntity<'a>>(merged: &mut Vec<E>, entities: Vec<E>, ids: &mut HashSet<u64>) {
+    merged.extend(
+        entities
+            .into_iter()
+            .filter(|e| e.global_id() == SYNTHETIC_ID || ids.insert(e.global_id())),
+    );
+}
@@ -0,0 +1,134 @@
+use crate::geodata::importer::{OsmEntityHandler, ParsedMember, ParsedRelation, ParsedWay, RawNode, RawTags};
+use crate::geodata::reader::MemberKind;
+use failure::{bail, format_err, Error, ResultExt};
+use json::JsonValue;
+use std::collections::HashSet;
+use std::io::Read;
+
+// The format is described at https://wiki.openstreetmap.org/wiki/OSM_JSON. The whole file is parsed
+// in memory, which is fine for the sizes that the Overpass API returns.
+
+pub(super) fn parse_overpass_json<R: Read, H: OsmEntityHandler>(mut input: R, mut handler: H) -> Result<H, Error> {
+    let mut text = String::new();
+    input.read_to_string(&mut text).context("Failed to read the input")?;
+    let data = json::parse(&text).context("Failed to parse the input as JSON")?;
+    if !data["elements"].is_array() {
+        bail!("The input doesn't have the elements array of the Overpass API output");
+    }
+
+    // The elements are listed in the order of the output statements, but the handler needs nodes first,
+    // then ways, then relations.
+    let kind_order = |element: &JsonValue| match element["type"].as_str() {
+        Some("node") => Some(0),
+        Some("way") => Some(1),
+        Some("relation") => Some(2),
+        _ => None,
+    };
+    let mut elements = data["elements"]
+        .members()
+        .filter_map(|element| kind_order(element).map(|order| (order, element)))
+        .collect::<Vec<_>>();
+    elements.sort_by_key(|(order, _)| *order);
+
+    let mut node_ids = HashSet::new();
+    for (_, element) in &elements {
+        match element["type"].as_str() {
+            Some("node") => {
+                let node = RawNode {
+                    global_id: get_u64(element, "id")?,
+                    lat: get_f64(element, "lat")?,
+                    lon: get_f64(element, "lon")?,
+                    tags: get_tags(element)?,
+                };
+                node_ids.insert(node.global_id);
+                handler.handle_node(node)?;
+            }
+            Some("way") => {
+                // With `out geom`, the ways come with the coordinates of their nodes, and the nodes themselves
+                // are usually not in the output.
+                for (node_id, position) in element["nodes"].members().zip(element["geometry"].members()) {
+                    let node_id = node_id
+                        .as_u64()
+                        .ok_or_else(|| format_err!("Way {} has an invalid node id", element["id"]))?;
+                    if !position.is_null() && node_ids.insert(node_id) {
+                        handler.handle_node(RawNode {
+                            global_id: node_id,
+                            lat: get_f64(position, "lat")?,
+                            lon: get_f64(position, "lon")?,
+                            tags: RawTags::default(),
+                        })?;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    for (_, element) in &elements {
+        match element["type"].as_str() {
+            Some("way") => {
+                handler.finish_nodes()?;
+                let way = ParsedWay {
+                    global_id: get_u64(element, "id")?,
+                    node_ids: element["nodes"]
+                        .members()
+                        .map(|id| id.as_u64())
+                        .collect::<Option<_>>()
+                        .ok_or_else(|| format_err!("Way {} has an invalid node id", element["id"]))?,
+                    tags: get_tags(element)?,
+                };
+                handler.handle_way(way)?;
+            }
+            Some("relation") => {
+                handler.finish_ways()?;
+                let mut relation = ParsedRelation {
+                    global_id: get_u64(element, "id")?,
+                    tags: get_tags(element)?,
+                    ..Default::default()
+                };
+                for member in element["members"].members() {
+                    let kind = match member["type"].as_str() {
+                        Some("node") => MemberKind::Node,
+                        Some("way") => MemberKind::Way,
+                        Some("relation") => MemberKind::Relation,
+                        _ => continue,
+                    };
+                    relation.members.push(ParsedMember {
+                        kind,
+                        global_id: get_u64(member, "ref")?,
+                        role: member["role"].as_str().unwrap_or_default().to_string(),
+                    });
+                }
+                handler.handle_relation(relation)?;
+            }
+            _ => {}
+        }
+    }
+
+    handler.finish_ways()?;
+    println!("Total: {}", handler.dump_state());
+
+    Ok(handler)
+}
+
+fn get_u64(value: &JsonValue, key: &str) -> Result<u64, Error> {
+    value[key]
+        .as_u64()
+        .ok_or_else(|| format_err!("{} doesn't have a valid {}", value, key))
+}
+
+fn get_f64(value: &JsonValue, key: &str) -> Result<f64, Error> {
+    value[key]
+        .as_f64()
+        .ok_or_else(|| format_err!("{} doesn't have a valid {}", value, key))
+}
+
+fn get_tags(element: &JsonValue) -> Result<RawTags, Error> {
+    element["tags"]
+        .entries()
+        .map(|(k, v)| match v.as_str() {
+            Some(v) => Ok((k.to_string(), v.to_string())),
+            None => Err(format_err!("The value of tag {} isn't a string: {}", k, v)),
+        })
+        .collect()
+}
@@ -0,0 +1,734 @@
+use crate::geodata::importer::{OsmEntityHandler, ParsedMember, ParsedRelation, ParsedWay, RawNode, RawTags};
+use crate::geodata::reader::MemberKind;
+use byteorder::{BigEndian, ReadBytesExt};
+use failure::{bail, format_err, Error, Fail, ResultExt};
+use std::io::{ErrorKind, Read};
+use std::str;
+
+// The format is described at https://wiki.openstreetmap.org/wiki/PBF_Format.
+// We only need a tiny subset of protobuf to read it, so the decoding is done by hand.
+
+const MAX_BLOB_HEADER_SIZE: u32 = 64 * 1024;
+const MAX_UNCOMPRESSED_BLOB_SIZE: usize = 32 * 1024 * 1024;
+const SUPPORTED_FEATURES: [&str; 2] = ["OsmSchema-V0.6", "DenseNodes"];
+
+pub(super) fn parse_osm_pbf<R: Read, H: OsmEntityHandler>(mut reader: R, mut handler: H) -> Result<H, Error> {
+    let mut seen_header = false;
+    let mut block_count = 0;
+
+    while let Some((blob_type, blob)) = read_blob(&mut reader)? {
+        let data = decode_blob(&blob).context(format!("Failed to decode a {} blob", blob_type))?;
+        match blob_type.as_str() {
+            "OSMHeader" => {
+                check_header_block(&data)?;
+                seen_header = true;
+            }
+            "OSMData" => {
+                if !seen_header {
+                    bail!("The PBF file doesn't start with an OSMHeader blob");
+                }
+                process_primitive_block(&data, &mut handler)
+                    .context(format!("Failed to process data block #{}", block_count))?;
+                block_count += 1;
+                if block_count % 100 == 0 {
+                    println!("Got {} so far", handler.dump_state());
+                }
+            }
+            // Unknown blob types should be skipped according to the spec.
+            _ => {}
+        }
+    }
+
+    handler.finish_ways()?;
+    println!("Total: {}", handler.dump_state());
+
+    Ok(handler)
+}
+
+fn read_blob<R: Read>(reader: &mut R) -> Result<Option<(String, Vec<u8>)>, Error> {
+    let header_size = match reader.read_u32::<BigEndian>() {
+        Ok(size) => size,
+        Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.context("Failed to read the blob header size").into()),
+    };
+    if header_size > MAX_BLOB_HEADER_SIZE {
+        bail!("Blob header is too large ({} bytes)", header_size);
+    }
+
+    let header = read_exact_vec(reader, header_size as usize).context("Failed to read a blob header")?;
+    let mut blob_type = None;
+    let mut data_size = None;
+    for field in ProtobufMessage::new(&header) {
+        match field? {
+            (1, FieldValue::Bytes(b)) => blob_type = Some(to_str(b)?.to_string()),
+            (3, FieldValue::Varint(v)) => data_size = Some(v as usize),
+            _ => {}
+        }
+    }
+
+    let blob_type = blob_type.ok_or_else(|| format_err!("Blob header doesn't have a type"))?;
+    let data_size = data_size.ok_or_else(|| format_err!("Blob header doesn't have a data size"))?;
+    // The limit on the uncompressed size covers the compressed blobs too.
+    if data_size > MAX_UNCOMPRESSED_BLOB_SIZE {
+        bail!("{} blob is too large ({} bytes)", blob_type, data_size);
+    }
+    let blob = read_exact_vec(reader, data_size).context(format!("Failed to read a {} blob", blob_type))?;
+
+    Ok(Some((blob_type, blob)))
+}
+
+fn read_exact_vec<R: Read>(reader: &mut R, size: usize) -> Result<Vec<u8>, Error> {
+    let mut result = vec![0; size];
+    reader.read_exact(&mut result)?;
+    Ok(result)
+}
+
+fn decode_blob(blob: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut raw_size = None;
+    let mut raw_data = None;
+    let mut zlib_data = None;
+    for field in ProtobufMessage::new(blob) {
+        match field? {
+            (1, FieldValue::Bytes(raw)) => raw_data = Some(raw),
+            (2, FieldValue::Varint(size)) => raw_size = Some(size as usize),
+            (3, FieldValue::Bytes(zlib)) => zlib_data = Some(zlib),
+            (4, _) | (5, _) | (6, _) | (7, _) => bail!("Only raw and zlib-compressed blobs are supported"),
+            _ => {}
+        }
+    }
+
+    // Check the declared size before inflating, and don't trust it either: a small blob can inflate
+    // to gigabytes, so the decoder never produces more than the limit allows.
+    if let Some(size) = raw_size {
+        if size > MAX_UNCOMPRESSED_BLOB_SIZE {
+            bail!("Blob is too large ({} bytes)", size);
+        }
+    }
+    let data = match (raw_data, zlib_data) {
+        (_, Some(zlib_data)) => {
+            let mut inflated = Vec::with_capacity(raw_size.unwrap_or_default());
+            inflate::DeflateDecoderBuf::from_zlib(zlib_data)
+                .take(MAX_UNCOMPRESSED_BLOB_SIZE as u64 + 1)
+                .read_to_end(&mut inflated)
+                .context("Failed to decompress zlib data")?;
+            inflated
+        }
+        (Some(raw_data), None) => raw_data.to_vec(),
+        (None, None) => bail!("Blob doesn't contain any data"),
+    };
+
+    if data.len() > MAX_UNCOMPRESSED_BLOB_SIZE {
+        bail!("Blob is too large (more than {} bytes)", MAX_UNCOMPRESSED_BLOB_SIZE);
+    }
+    if let Some(expected_size) = raw_size {
+        if expected_size != data.len() {
+            bail!(
+                "Blob has wrong uncompressed size (expected {}, got {})",
+                expected_size,
+                data.len()
+            );
+        }
+    }
+    Ok(data)
+}
+
+fn check_header_block(data: &[u8]) -> Result<(), Error> {
+    for field in ProtobufMessage::new(data) {
+        if let (4, FieldValue::Bytes(feature)) = field? {
+            let feature = to_str(feature)?;
+            if !SUPPORTED_FEATURES.contains(&feature) {
+                bail!("The PBF file requires an unsupported feature: {}", feature);
+            }
+        }
+    }
+    Ok(())
+}
+
+struct BlockContext<'a> {
+    strings: Vec<&'a str>,
+    granularity: i64,
+    lat_offset: i64,
+    lon_offset: i64,
+}
+
+impl<'a> BlockContext<'a> {
+    fn get_string(&self, idx: u64) -> Result<&'a str, Error> {
+        self.strings
+            .get(idx as usize)
+            .cloned()
+            .ok_or_else(|| format_err!("String table index {} is out of bounds", idx))
+    }
+
+    fn get_tags(&self, keys: &[u64], values: &[u64]) -> Result<RawTags, Error> {
+        if keys.len() != values.len() {
+            bail!("Got {} tag keys, but {} tag values", keys.len(), values.len());
+        }
+        let mut tags = RawTags::default();
+        for (k, v) in keys.iter().zip(values.iter()) {
+            tags.insert(self.get_string(*k)?.to_string(), self.get_string(*v)?.to_string());
+        }
+        Ok(tags)
+    }
+
+    fn to_lat(&self, lat: i64) -> f64 {
+        1e-9 * (self.lat_offset + self.granularity * lat) as f64
+    }
+
+    fn to_lon(&self, lon: i64) -> f64 {
+        1e-9 * (self.lon_offset + self.granularity * lon) as f64
+    }
+}
+
+fn process_primitive_block<H: OsmEntityHandler>(data: &[u8], handler: &mut H) -> Result<(), Error> {
+    let mut ctx = BlockContext {
+        strings: Vec::new(),
+        granularity: 100,
+        lat_offset: 0,
+        lon_offset: 0,
+    };
+    let mut groups = Vec::new();
+
+    for field in ProtobufMessage::new(data) {
+        match field? {
+            (1, FieldValue::Bytes(string_table)) => {
+                for string_field in ProtobufMessage::new(string_table) {
+                    if let (1, FieldValue::Bytes(s)) = string_field? {
+                        ctx.strings.push(to_str(s)?);
+                    }
+                }
+            }
+            (2, FieldValue::Bytes(group)) => groups.push(group),
+            (17, FieldValue::Varint(v)) => ctx.granularity = v as i64,
+            (19, FieldValue::Varint(v)) => ctx.lat_offset = v as i64,
+            (20, FieldValue::Varint(v)) => ctx.lon_offset = v as i64,
+            _ => {}
+        }
+    }
+
+    // The string table and offsets may come after the groups, so we process the groups only
+    // after the whole block is read.
+    for group in groups {
+        for field in ProtobufMessage::new(group) {
+            match field? {
+                (1, FieldValue::Bytes(node)) => process_node(node, &ctx, handler)?,
+                (2, FieldValue::Bytes(dense)) => process_dense_nodes(dense, &ctx, handler)?,
+                (3, FieldValue::Bytes(way)) => process_way(way, &ctx, handler)?,
+                (4, FieldValue::Bytes(relation)) => process_relation(relation, &ctx, handler)?,
+                _ => {}
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn process_node<H: OsmEntityHandler>(data: &[u8], ctx: &BlockContext<'_>, handler: &mut H) -> Result<(), Error> {
+    let (mut id, mut lat, mut lon) = (0, 0, 0);
+    let (mut keys, mut values) = (Vec::new(), Vec::new());
+    for field in ProtobufMessage::new(data) {
+        match field? {
+            (1, FieldValue::Varint(v)) => id = zigzag_decode(v),
+            (2, value) => value.extend_packed(&mut keys)?,
+            (3, value) => value.extend_packed(&mut values)?,
+            (8, FieldValue::Varint(v)) => lat = zigzag_decode(v),
+            (9, FieldValue::Varint(v)) => lon = zigzag_decode(v),
+            _ => {}
+        }
+    }
+    handler.handle_node(RawNode {
+        global_id: id as u64,
+        lat: ctx.to_lat(lat),
+        lon: ctx.to_lon(lon),
+        tags: ctx.get_tags(&keys, &values)?,
+    })
+}
+
+fn process_dense_nodes<H: OsmEntityHandler>(data: &[u8], ctx: &BlockContext<'_>, handler: &mut H) -> Result<(), Error> {
+    let (mut ids, mut lats, mut lons, mut keys_vals) = (Vec::new(), Vec::new(), Vec::new(), Vec::new());
+    for field in ProtobufMessage::new(data) {
+        match field? {
+            (1, value) => value.extend_packed(&mut ids)?,
+            (8, value) => value.extend_packed(&mut lats)?,
+            (9, value) => value.extend_packed(&mut lons)?,
+            (10, value) => value.extend_packed(&mut keys_vals)?,
+            _ => {}
+        }
+    }
+
+    if ids.len() != lats.len() || ids.len() != lons.len() {
+        bail!(
+            "Dense nodes have {} ids, {} latitudes and {} longitudes",
+            ids.len(),
+            lats.len(),
+            lons.len()
+        );
+    }
+
+    // keys_vals is either empty (no node has tags) or has a 0-terminated list of key/value pairs
+    // for every node.
+    let mut kv_iter = keys_vals.into_iter();
+    let (mut id, mut lat, mut lon) = (0, 0, 0);
+    for idx in 0..ids.len() {
+        id += zigzag_decode(ids[idx]);
+        lat += zigzag_decode(lats[idx]);
+        lon += zigzag_decode(lons[idx]);
+
+        let mut tags = RawTags::default();
+        while let Some(k) = kv_iter.next() {
+            if k == 0 {
+                break;
+            }
+            let v = kv_iter
+                .next()
+                .ok_or_else(|| format_err!("Dense node #{} has a tag key without a value", id))?;
+            tags.insert(ctx.get_string(k)?.to_string(), ctx.get_string(v)?.to_string());
+        }
+
+        handler.handle_node(RawNode {
+            global_id: id as u64,
+            lat: ctx.to_lat(lat),
+            lon: ctx.to_lon(lon),
+            tags,
+        })?;
+    }
+    Ok(())
+}
+
+fn process_way<H: OsmEntityHandler>(data: &[u8], ctx: &BlockContext<'_>, handler: &mut H) -> Result<(), Error> {
+    handler.finish_nodes()?;
+    let mut id = 0;
+    let (mut keys, mut values, mut refs) = (Vec::new(), Vec::new(), Vec::new());
+    for field in ProtobufMessage::new(data) {
+        match field? {
+            (1, FieldValue::Varint(v)) => id = v,
+            (2, value) => value.extend_packed(&mut keys)?,
+            (3, value) => value.extend_packed(&mut values)?,
+            (8, value) => value.extend_packed(&mut refs)?,
+            _ => {}
+        }
+    }
+
+    let mut way = ParsedWay {
+        global_id: id,
+        node_ids: Vec::with_capacity(refs.len()),
+        tags: ctx.get_tags(&keys, &values)?,
+    };
+    let mut node_id = 0;
+    for r in refs {
+        node_id += zigzag_decode(r);
+        way.node_ids.push(node_id as u64);
+    }
+    handler.handle_way(way)
+}
+
+fn process_relation<H: OsmEntityHandler>(data: &[u8], ctx: &BlockContext<'_>, handler: &mut H) -> Result<(), Error> {
+    handler.finish_ways()?;
+    let mut id = 0;
+    let (mut keys, mut values) = (Vec::new(), Vec::new());
+    let (mut roles, mut member_ids, mut member_types) = (Vec::new(), Vec::new(), Vec::new());
+    for field in ProtobufMessage::new(data) {
+        match field? {
+            (1, FieldValue::Varint(v)) => id = v,
+            (2, value) => value.extend_packed(&mut keys)?,
+            (3, value) => value.extend_packed(&mut values)?,
+            (8, value) => value.extend_packed(&mut roles)?,
+            (9, value) => value.extend_packed(&mut member_ids)?,
+            (10, value) => value.extend_packed(&mut member_types)?,
+            _ => {}
+        }
+    }
+
+    if roles.len() != member_ids.len() || roles.len() != member_types.len() {
+        bail!(
+            "Relation #{} has {} roles, {} member ids and {} member types",
+            id,
+            roles.len(),
+            member_ids.len(),
+            member_types.len()
+        );
+    }
+
+    let mut relation = ParsedRelation {
+        global_id: id,
+        members: Vec::new(),
+        tags: ctx.get_tags(&keys, &values)?,
+    };
+    let mut member_id = 0;
+    for idx in 0..member_ids.len() {
+        member_id += zigzag_decode(member_ids[idx]);
+        let kind = match member_types[idx] {
+            0 => MemberKind::Node,
+            1 => MemberKind::Way,
+            2 => MemberKind::Relation,
+            _ => continue,
+        };
+        relation.members.push(ParsedMember {
+            kind,
+            global_id: member_id as u64,
+            role: ctx.get_string(roles[idx])?.to_string(),
+        });
+    }
+    handler.handle_relation(relation)
+}
+
+fn to_str(bytes: &[u8]) -> Result<&str, Error> {
+    Ok(str::from_utf8(bytes).context("Invalid UTF-8 string in the PBF file")?)
+}
+
+fn zigzag_decode(v: u64) -> i64 {
+    ((v >> 1) as i64) ^ -((v & 1) as i64)
+}
+
+enum FieldValue<'a> {
+    Varint(u64),
+    Fixed64,
+    Bytes(&'a [u8]),
+    Fixed32,
+}
+
+impl<'a> FieldValue<'a> {
+    // Repeated numeric fields are usually packed, but the spec allows the unpacked form too.
+    fn extend_packed(self, result: &mut Vec<u64>) -> Result<(), Error> {
+        match self {
+            FieldValue::Varint(v) => result.push(v),
+            FieldValue::Bytes(mut bytes) => {
+                while !bytes.is_empty() {
+                    result.push(read_varint(&mut bytes)?);
+                }
+            }
+            _ => bail!("Expected a varint or a packed list of varints"),
+        }
+        Ok(())
+    }
+}
+
+struct ProtobufMessage<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> ProtobufMessage<'a> {
+    fn new(bytes: &'a [u8]) -> ProtobufMessage<'a> {
+        ProtobufMessage { bytes }
+    }
+
+    fn read_field(&mut self) -> Result<(u64, FieldValue<'a>), Error> {
+        let key = read_varint(&mut self.bytes)?;
+        let value = match key & 0x7 {
+            0 => FieldValue::Varint(read_varint(&mut self.bytes)?),
+            1 => {
+                self.skip(8)?;
+                FieldValue::Fixed64
+            }
+            2 => {
+                let length = read_varint(&mut self.bytes)? as usize;
+                FieldValue::Bytes(self.skip(length)?)
+            }
+            5 => {
+                self.skip(4)?;
+                FieldValue::Fixed32
+            }
+            wire_type => bail!("Unsupported protobuf wire type: {}", wire_type),
+        };
+        Ok((key >> 3, value))
+    }
+
+    fn skip(&mut self, length: usize) -> Result<&'a [u8], Error> {
+        if length > self.bytes.len() {
+            bail!("Unexpected end of a protobuf message");
+        }
+        let (skipped, rest) = self.bytes.split_at(length);
+        self.bytes = rest;
+        Ok(skipped)
+    }
+}
+
+impl<'a> Iterator for ProtobufMessage<'a> {
+    type Item = Result<(u64, FieldValue<'a>), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.bytes.is_empty() {
+            return None;
+        }
+        let result = self.read_field();
+        if result.is_err() {
+            // Don't try to parse anything after a malformed field.
+            self.bytes = &[];
+        }
+        Some(result)
+    }
+}
+
+fn read_varint(bytes: &mut &[u8]) -> Result<u64, Error> {
+    let mut result = 0;
+    for (idx, b) in bytes.iter().enumerate().take(10) {
+        result |= u64::from(b & 0x7f) << (7 * idx);
+        if b & 0x80 == 0 {
+            *bytes = &bytes[idx + 1..];
+            return Ok(result);
+        }
+    }
+    bail!("Malformed protobuf varint")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geodata::importer::EntityStorages;
+    use crate::geodata::temp_storage::SpillDir;
+
+    fn varint(mut v: u64, out: &mut Vec<u8>) {
+        while v >= 0x80 {
+            out.push((v as u8) | 0x80);
+            v >>= 7;
+        }
+        out.push(v as u8);
+    }
+
+    fn zigzag(v: i64) -> u64 {
+        ((v << 1) ^ (v >> 63)) as u64
+    }
+
+    fn varint_field(field: u64, v: u64, out: &mut Vec<u8>) {
+        varint(field << 3, out);
+        varint(v, out);
+    }
+
+    fn bytes_field(field: u64, bytes: &[u8], out: &mut Vec<u8>) {
+        varint((field << 3) | 2, out);
+        varint(bytes.len() as u64, out);
+        out.extend_from_slice(bytes);
+    }
+
+    fn packed_field(field: u64, values: &[u64], out: &mut Vec<u8>) {
+        let mut packed = Vec::new();
+        for v in values {
+            varint(*v, &mut packed);
+        }
+        bytes_field(field, &packed, out);
+    }
+
+    fn deltas(values: &[i64]) -> Vec<u64> {
+        let mut prev = 0;
+        values
+            .iter()
+            .map(|v| {
+                let delta = zigzag(v - prev);
+                prev = *v;
+                delta
+            })
+            .collect()
+    }
+
+    // A zlib stream with a single stored (uncompressed) deflate block.
+    fn zlib_stored(data: &[u8]) -> Vec<u8> {
+        let mut result = vec![0x78, 0x01, 0x01];
+        let len = data.len() as u16;
+        result.extend_from_slice(&len.to_le_bytes());
+        result.extend_from_slice(&(!len).to_le_bytes());
+        result.extend_from_slice(data);
+        let (mut a, mut b) = (1u32, 0u32);
+        for byte in data {
+            a = (a + u32::from(*byte)) % 65521;
+            b = (b + a) % 65521;
+        }
+        result.extend_from_slice(&((b << 16) | a).to_be_bytes());
+        result
+    }
+
+    // A zlib stream with a single fixed Huffman block that inflates to `runs * 258 + 1` zero bytes:
+    // a zero literal followed by the longest matches at distance 1.
+    fn zlib_zeros(runs: usize) -> Vec<u8> {
+        let mut bits = Vec::new();
+        // Huffman codes are packed starting from the most significant bit, everything else from the least.
+        let mut push = |value: u32, len: u32, is_code: bool| {
+            for i in 0..len {
+                let shift = if is_code { len - 1 - i } else { i };
+                bits.push((value >> shift) as u8 & 1);
+            }
+        };
+        push(1, 1, false);
+        push(1, 2, false);
+        push(0x30, 8, true);
+        for _ in 0..runs {
+            push(0xc5, 8, true);
+            push(0, 5, true);
+        }
+        push(0, 7, true);
+
+        let mut result = vec![0x78, 0x01];
+        result.extend(
+            bits.chunks(8)
+                .map(|byte| byte.iter().rev().fold(0, |acc, bit| (acc << 1) | bit)),
+        );
+        let (mut a, mut b) = (1u32, 0u32);
+        for _ in 0..runs * 258 + 1 {
+            b = (b + a) % 65521;
+        }
+        a %= 65521;
+        result.extend_from_slice(&((b << 16) | a).to_be_bytes());
+        result
+    }
+
+    fn blob(blob_type: &str, data: &[u8], compress: bool, out: &mut Vec<u8>) {
+        let mut blob = Vec::new();
+        varint_field(2, data.len() as u64, &mut blob);
+        if compress {
+            bytes_field(3, &zlib_stored(data), &mut blob);
+        } else {
+            bytes_field(1, data, &mut blob);
+        }
+        let mut header = Vec::new();
+        bytes_field(1, blob_type.as_bytes(), &mut header);
+        varint_field(3, blob.len() as u64, &mut header);
+        out.extend_from_slice(&(header.len() as u32).to_be_bytes());
+        out.extend_from_slice(&header);
+        out.extend_from_slice(&blob);
+    }
+
+    fn test_pbf() -> Vec<u8> {
+        let mut header_block = Vec::new();
+        bytes_field(4, b"OsmSchema-V0.6", &mut header_block);
+        bytes_field(4, b"DenseNodes", &mut header_block);
+
+        let mut strings = Vec::new();
+        for s in &[
+            "",
+            "highway",
+            "crossing",
+            "natural",
+            "water",
+            "type",
+            "multipolygon",
+            "outer",
+            "inner",
+        ] {
+            bytes_field(1, s.as_bytes(), &mut strings);
+        }
+
+        let mut dense = Vec::new();
+        packed_field(1, &deltas(&[1, 2, 3, 4]), &mut dense);
+        packed_field(
+            8,
+            &deltas(&[557_500_000, 557_500_000, 557_510_000, 557_510_000]),
+            &mut dense,
+        );
+        packed_field(
+            9,
+            &deltas(&[376_100_000, 376_110_000, 376_110_000, 376_100_000]),
+            &mut dense,
+        );
+        packed_field(10, &[1, 2, 0, 0, 0, 0], &mut dense);
+
+        let mut way = Vec::new();
+        varint_field(1, 10, &mut way);
+        packed_field(2, &[3], &mut way);
+        packed_field(3, &[4], &mut way);
+        packed_field(8, &deltas(&[1, 2, 3, 4, 1, 42]), &mut way);
+
+        let mut relation = Vec::new();
+        varint_field(1, 20, &mut relation);
+        packed_field(2, &[5], &mut relation);
+        packed_field(3, &[6], &mut relation);
+        packed_field(8, &[7, 7], &mut relation);
+        packed_field(9, &deltas(&[10, 1]), &mut relation);
+        packed_field(10, &[1, 0], &mut relation);
+
+        let mut node_group = Vec::new();
+        bytes_field(2, &dense, &mut node_group);
+        let mut other_group = Vec::new();
+        bytes_field(3, &way, &mut other_group);
+        bytes_field(4, &relation, &mut other_group);
+
+        let mut data_block = Vec::new();
+        bytes_field(1, &strings, &mut data_block);
+        bytes_field(2, &node_group, &mut data_block);
+        bytes_field(2, &other_group, &mut data_block);
+        varint_field(17, 100, &mut data_block);
+
+        let mut result = Vec::new();
+        blob("OSMHeader", &header_block, false, &mut result);
+        blob("OSMData", &data_block, true, &mut result);
+        result
+    }
+
+    fn check_parsed_pbf(storages: EntityStorages) {
+        let nodes = &storages.node_storage;
+        assert_eq!(nodes.len(), 4);
+        assert_eq!(nodes.get(1).global_id, 2);
+        assert!((nodes.get(2).lat - 55.751).abs() < 1e-9);
+        assert!((nodes.get(2).lon - 37.611).abs() < 1e-9);
+        assert_eq!(nodes.get(0).tags.get("highway").map(String::as_str), Some("crossing"));
+        assert!(nodes.get(1).tags.is_empty());
+
+        let ways = storages.way_storage.get_entities();
+        assert_eq!(ways.len(), 1);
+        assert_eq!(ways[0].global_id, 10);
+        // The reference to the missing node #42 is dropped.
+        assert_eq!(ways[0].node_ids, vec![0, 1, 2, 3, 0]);
+        assert_eq!(ways[0].tags.get("natural").map(String::as_str), Some("water"));
+
+        let multipolygons = storages.multipolygon_storage.get_entities();
+        assert_eq!(multipolygons.len(), 1);
+        assert_eq!(multipolygons[0].global_id, 20);
+        assert_eq!(storages.polygon_storage.len(), 1);
+    }
+
+    #[test]
+    fn test_parse_pbf() {
+        check_parsed_pbf(parse_osm_pbf(&test_pbf()[..], EntityStorages::new(None).unwrap()).unwrap());
+    }
+
+    #[test]
+    fn test_parse_pbf_with_low_memory() {
+        let spill_dir = SpillDir::new(&std::env::temp_dir()).unwrap();
+        let storages = EntityStorages::new(Some(spill_dir)).unwrap();
+        check_parsed_pbf(parse_osm_pbf(&test_pbf()[..], storages).unwrap());
+    }
+
+    #[test]
+    fn test_truncated_pbf() {
+        let pbf = test_pbf();
+        assert!(parse_osm_pbf(&pbf[..pbf.len() - 10], EntityStorages::new(None).unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_blob_size_limits() {
+        let zlib_blob = |raw_size: Option<u64>, runs| {
+            let mut blob = Vec::new();
+            if let Some(raw_size) = raw_size {
+                varint_field(2, raw_size, &mut blob);
+            }
+            bytes_field(3, &zlib_zeros(runs), &mut blob);
+            blob
+        };
+
+        assert_eq!(decode_blob(&zlib_blob(Some(2581), 10)).unwrap(), vec![0; 2581]);
+        assert!(decode_blob(&zlib_blob(Some(2580), 10)).is_err());
+
+        // The declared size is checked before inflating.
+        let too_large = MAX_UNCOMPRESSED_BLOB_SIZE as u64 + 1;
+        let error = decode_blob(&zlib_blob(Some(too_large), 10)).unwrap_err();
+        assert_eq!(error.to_string(), format!("Blob is too large ({} bytes)", too_large));
+
+        // A blob that inflates past the limit is rejected without inflating it completely.
+        let runs = MAX_UNCOMPRESSED_BLOB_SIZE / 258 + 1;
+        let error = decode_blob(&zlib_blob(None, runs)).unwrap_err();
+        assert!(error.to_string().starts_with("Blob is too large"));
+    }
+
+    #[test]
+    fn test_blob_data_size_limit() {
+        let mut header = Vec::new();
+        bytes_field(1, b"OSMData", &mut header);
+        varint_field(3, 1 << 40, &mut header);
+        let mut pbf = (header.len() as u32).to_be_bytes().to_vec();
+        pbf.extend_from_slice(&header);
+
+        // The size is rejected before the blob is read, so there's no need to have the data.
+        let error = read_blob(&mut &pbf[..]).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!("OSMData blob is too large ({} bytes)", 1u64 << 40)
+        );
+    }
+}
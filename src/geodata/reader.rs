@@ -1,15 +1,21 @@
 use crate::coords::Coords;
+use crate::geodata::encoding::{fixed_to_coord, unpack_refs};
+use crate::geodata::header::{checksum, Header, Section, FLAG_HAS_CHECKSUM, HEADER_SIZE};
+use crate::geodata::simplify::{get_simplification_idx, SIMPLIFICATION_ZOOMS};
 use crate::tile;
 use byteorder::{ByteOrder, LittleEndian, ReadBytesExt};
-use failure::{Error, ResultExt};
+use failure::{bail, Error, ResultExt};
 use memmap::{Mmap, MmapOptions};
 use owning_ref::OwningHandle;
+use std::borrow::Cow;
+use std::cell::OnceCell;
 use std::cmp::Ordering;
 use std::collections::HashSet;
 use std::fs::File;
 use std::hash::{Hash, Hasher};
 use std::io::Cursor;
 use std::mem;
+use std::ops::{Deref, Range};
 use std::slice;
 use std::str;
 
@@ -18,10 +24,12 @@ pub trait OsmEntity<'a> {
     fn tags(&self) -> Tags<'a>;
 }
 
+#[derive(Default)]
 pub struct OsmEntities<'a> {
     pub nodes: Vec<Node<'a>>,
     pub ways: Vec<Way<'a>>,
     pub multipolygons: Vec<Multipolygon<'a>>,
+    pub relations: Vec<Relation<'a>>,
 }
 
 #[derive(Default)]
@@ -29,10 +37,13 @@ pub(super) struct OsmEntityIds {
     pub(super) nodes: Vec<u32>,
     pub(super) ways: Vec<u32>,
     pub(super) multipolygons: Vec<u32>,
+    pub(super) relations: Vec<u32>,
 }
 
 pub trait OsmArea {
     fn is_closed(&self) -> bool;
+    /// Whether the entity is drawn as an area rather than as a line.
+    fn is_area(&self) -> bool;
 }
 
 pub struct GeodataReader<'a> {
@@ -48,8 +59,40 @@ impl<'a> GeodataReader<'a> {
                 .context(format!("Failed to map {} to memory", file_name))?
         };
 
-        let handle = OwningHandle::try_new(Box::new(mmap), |mm| {
-            ObjectStorages::from_bytes(unsafe { &*mm }).map(Box::new)
+        let reader = GeodataReader::from_geodata_bytes(GeodataBytes::Mapped(mmap))
+            .context(format!("Failed to load geodata from {}", file_name))?;
+        Ok(reader)
+    }
+
+    /// Reads the geodata from a buffer instead of a file, e.g. `from_bytes(vec)` or `from_bytes(&vec[..])`.
+    /// The buffer is copied if it is not aligned to 4 bytes.
+    pub fn from_bytes<B: Into<Cow<'a, [u8]>>>(bytes: B) -> Result<GeodataReader<'a>, Error> {
+        let bytes = bytes.into();
+        let bytes = if (bytes.as_ptr() as usize).is_multiple_of(mem::align_of::<u32>()) {
+            GeodataBytes::Buffer(bytes)
+        } else {
+            let mut words = vec![0u32; bytes.len().div_ceil(mem::size_of::<u32>())];
+            unsafe { slice::from_raw_parts_mut(words.as_mut_ptr() as *mut u8, bytes.len()) }.copy_from_slice(&bytes);
+            GeodataBytes::Words(words, bytes.len())
+        };
+        GeodataReader::from_geodata_bytes(bytes)
+    }
+
+    /// Checks that the data isn't corrupted. Unlike the structure of the file, which is checked by `load`,
+    /// this requires reading the whole file, so it's not done when the file is loaded.
+    pub fn verify(&self) -> Result<(), Error> {
+        let storages = self.storages();
+        if let Some(expected) = storages.checksum {
+            if checksum(storages.checksummed_bytes) != expected {
+                bail!("The geodata file is corrupted (checksum mismatch)");
+            }
+        }
+        Ok(())
+    }
+
+    fn from_geodata_bytes(bytes: GeodataBytes<'a>) -> Result<GeodataReader<'a>, Error> {
+        let handle = OwningHandle::try_new(Box::new(bytes), |bytes| {
+            ObjectStorages::from_bytes(unsafe { &*bytes }).map(Box::new)
         })?;
         Ok(GeodataReader { handle })
     }
@@ -73,6 +116,50 @@ impl<'a> GeodataReader<'a> {
             }
         }
 
+        self.get_entities_by_local_ids(entity_ids, osm_ids)
+    }
+
+    /// Returns the entities that can intersect the given box. All nodes are inside the box, but the other
+    /// entities are selected by the tiles of the tile index they are referenced from, so some of them can
+    /// be outside of it.
+    pub fn get_entities_in_bbox(&'a self, min_lat: f64, min_lon: f64, max_lat: f64, max_lon: f64) -> OsmEntities<'a> {
+        let mut entity_ids = OsmEntityIds::default();
+        if min_lat > max_lat || min_lon > max_lon {
+            return self.get_entities_by_local_ids(entity_ids, &None);
+        }
+
+        let max_tile = (1 << tile::MAX_ZOOM) - 1;
+        let tile_xy = |lat: f64, lon: f64| {
+            let (x, y) = tile::coords_to_xy(&(lat, lon), tile::MAX_ZOOM);
+            let to_tile_index = |t: f64| ((t / f64::from(tile::TILE_SIZE)).max(0.0) as u32).min(max_tile);
+            (to_tile_index(x), to_tile_index(y))
+        };
+        // Latitude grows to the north, while tile y grows to the south.
+        let (min_x, min_y) = tile_xy(max_lat, min_lon);
+        let (max_x, max_y) = tile_xy(min_lat, max_lon);
+        self.get_entities_in_tile_range(
+            tile::TileRange {
+                min_x,
+                max_x,
+                min_y,
+                max_y,
+            },
+            None,
+            &mut entity_ids,
+        );
+
+        let mut entities = self.get_entities_by_local_ids(entity_ids, &None);
+        entities
+            .nodes
+            .retain(|n| min_lat <= n.lat() && n.lat() <= max_lat && min_lon <= n.lon() && n.lon() <= max_lon);
+        entities
+    }
+
+    fn get_entities_by_local_ids(
+        &'a self,
+        mut entity_ids: OsmEntityIds,
+        osm_ids: &Option<HashSet<u64>>,
+    ) -> OsmEntities<'a> {
         let uniq = |ids: &mut Vec<u32>| {
             ids.sort();
             ids.dedup();
@@ -81,6 +168,7 @@ impl<'a> GeodataReader<'a> {
         uniq(&mut entity_ids.nodes);
         uniq(&mut entity_ids.ways);
         uniq(&mut entity_ids.multipolygons);
+        uniq(&mut entity_ids.relations);
 
         let nodes = entity_ids.nodes.iter().map(|id| self.get_node(*id as usize));
         let ways = entity_ids.ways.iter().map(|id| self.get_way(*id as usize));
@@ -92,147 +180,283 @@ impl<'a> GeodataReader<'a> {
                 None
             }
         });
+        let relations = entity_ids.relations.iter().map(|id| self.get_relation(*id as usize));
 
         OsmEntities {
             nodes: filter_entities_by_ids(nodes, osm_ids),
             ways: filter_entities_by_ids(ways, osm_ids),
             multipolygons: filter_entities_by_ids(multipolygons, osm_ids),
+            relations: filter_entities_by_ids(relations, osm_ids),
         }
     }
 
-    pub(super) fn get_entities_in_tile(&'a self, t: &tile::Tile, entity_ids: &mut OsmEntityIds) {
-        let mut bounds = tile::tile_to_max_zoom_tile_range(t);
-        let mut start_from_index = 0;
-
-        let tile_count = self.tile_count();
-        while start_from_index < tile_count {
-            match self.next_good_tile(&mut bounds, start_from_index) {
-                None => break,
-                Some(mut current_index) => {
-                    let (mut tile_x, mut tile_y) = self.tile_xy(current_index);
-                    let current_x = tile_x;
-
-                    while (tile_x == current_x) && (tile_y <= bounds.max_y) {
-                        entity_ids.nodes.extend(self.tile_local_ids(current_index, 0));
-                        entity_ids.ways.extend(self.tile_local_ids(current_index, 1));
-                        entity_ids.multipolygons.extend(self.tile_local_ids(current_index, 2));
-
-                        current_index += 1;
-                        if current_index >= tile_count {
-                            break;
-                        }
-                        let (next_tile_x, next_tile_y) = self.tile_xy(current_index);
-                        tile_x = next_tile_x;
-                        tile_y = next_tile_y;
-                    }
-
-                    start_from_index = current_index;
-                    bounds.min_x = current_x + 1;
-                }
-            }
-        }
+    pub fn get_node_by_id(&'a self, global_id: u64) -> Option<Node<'a>> {
+        let storages = self.storages();
+        find_by_global_id(&storages.node_index, &storages.node_storage, global_id).map(|idx| self.get_node(idx))
     }
 
-    fn next_good_tile(&self, bounds: &mut tile::TileRange, start_index: usize) -> Option<usize> {
-        let tile_count = self.tile_count();
-        if start_index >= tile_count {
-            return None;
-        }
-
-        let find_smallest_feasible_index = |from, min_x, min_y| {
-            let large_enough = |idx| self.tile_xy(idx) >= (min_x, min_y);
+    pub fn get_way_by_id(&'a self, global_id: u64) -> Option<Way<'a>> {
+        let storages = self.storages();
+        find_by_global_id(&storages.way_index, &storages.way_storage, global_id).map(|idx| self.get_way(idx))
+    }
 
-            let mut lo = from;
-            let mut hi = tile_count - 1;
+    pub fn get_multipolygon_by_id(&'a self, global_id: u64) -> Option<Multipolygon<'a>> {
+        let storages = self.storages();
+        find_by_global_id(&storages.multipolygon_index, &storages.multipolygon_storage, global_id)
+            .map(|idx| self.get_multipolygon(idx))
+            .filter(|mp| mp.polygon_count() > 0)
+    }
 
-            while lo < hi {
-                let mid = (lo + hi) / 2;
+    pub fn get_relation_by_id(&'a self, global_id: u64) -> Option<Relation<'a>> {
+        let storages = self.storages();
+        find_by_global_id(&storages.relation_index, &storages.relation_storage, global_id)
+            .map(|idx| self.get_relation(idx))
+    }
 
-                if large_enough(mid) {
-                    hi = mid;
-                } else {
-                    lo = mid + 1;
-                }
-            }
+    // Skips the entities that can't be drawn at the zoom level of the tile.
+    pub(super) fn get_entities_in_tile(&'a self, t: &tile::Tile, entity_ids: &mut OsmEntityIds) {
+        self.get_entities_in_tile_range(tile::tile_to_max_zoom_tile_range(t), Some(t.zoom), entity_ids);
+    }
 
-            if large_enough(lo) {
-                Some(lo)
-            } else {
-                None
-            }
+    // If `zoom` is present, only the entities that can be drawn at this zoom level are returned.
+    fn get_entities_in_tile_range(&'a self, bounds: tile::TileRange, zoom: Option<u8>, entity_ids: &mut OsmEntityIds) {
+        let visible_ids = |tile_idx, refs_idx| {
+            let ids = self.tile_local_ids(tile_idx, refs_idx);
+            let visible_count = match zoom {
+                // The ids in a tile are sorted by the minimum zoom level.
+                Some(zoom) => ids.partition_point(|id| self.min_zoom(refs_idx, *id as usize) <= zoom),
+                None => ids.len(),
+            };
+            &ids[..visible_count]
+        };
+        let mut add_tile = |tile_idx| {
+            entity_ids.nodes.extend(visible_ids(tile_idx, 0));
+            entity_ids.ways.extend(visible_ids(tile_idx, 1));
+            entity_ids.multipolygons.extend(visible_ids(tile_idx, 2));
+            entity_ids.relations.extend(visible_ids(tile_idx, 3));
         };
 
-        let mut idx = start_index;
-        while let Some(next_idx) = find_smallest_feasible_index(idx, bounds.min_x, bounds.min_y) {
-            let (tile_x, tile_y) = self.tile_xy(next_idx);
-            if (tile_x, tile_y) > (bounds.max_x, bounds.max_y) {
-                return None;
-            }
+        // The tiles are sorted by the zoom level first, so every level of the index is searched separately.
+        let tile_count = self.tile_count();
+        let mut level_start = 0;
+        while level_start < tile_count {
+            let level = self.tile(level_start).zoom;
+            let level_end = self.partition_tiles(level_start..tile_count, |t| t.zoom <= level);
+            let shift = tile::MAX_ZOOM - level;
+            let level_bounds = tile::TileRange {
+                min_x: bounds.min_x >> shift,
+                max_x: bounds.max_x >> shift,
+                min_y: bounds.min_y >> shift,
+                max_y: bounds.max_y >> shift,
+            };
+            let root = tile::Tile { zoom: 0, x: 0, y: 0 };
+            self.visit_tiles_in_range(level_start..level_end, &level_bounds, &root, &mut add_tile);
+            level_start = level_end;
+        }
+    }
 
-            if tile_x == bounds.min_x {
-                return Some(next_idx);
-            }
+    // Calls `visit` for every tile among `tiles` that is inside `bounds`. All `tiles` must have the same zoom level
+    // and lie within `cell`, which is a tile of the same or a lower zoom level. The tiles of a level are sorted
+    // along the Hilbert curve, so the tiles within every quarter of `cell` are stored together, and the quarters
+    // that are completely inside or completely outside of `bounds` are processed without looking at
+    // individual tiles.
+    fn visit_tiles_in_range(
+        &self,
+        tiles: Range<usize>,
+        bounds: &tile::TileRange,
+        cell: &tile::Tile,
+        visit: &mut dyn FnMut(usize),
+    ) {
+        if tiles.is_empty() {
+            return;
+        }
+        let level = self.tile(tiles.start).zoom;
+        let shift = level - cell.zoom;
+        let (min_x, min_y) = (cell.x << shift, cell.y << shift);
+        let (max_x, max_y) = (min_x + (1 << shift) - 1, min_y + (1 << shift) - 1);
+        if max_x < bounds.min_x || min_x > bounds.max_x || max_y < bounds.min_y || min_y > bounds.max_y {
+            return;
+        }
+        if bounds.min_x <= min_x && max_x <= bounds.max_x && bounds.min_y <= min_y && max_y <= bounds.max_y {
+            tiles.for_each(visit);
+            return;
+        }
 
-            idx = next_idx;
-            bounds.min_x = tile_x;
+        // A single tile is either inside or outside of `bounds`, so `cell` has a lower zoom level here.
+        let hilbert_index = |t: &tile::Tile| tile::hilbert_index(t.zoom, t.x, t.y);
+        for (dx, dy) in &[(0, 0), (0, 1), (1, 0), (1, 1)] {
+            let child = tile::Tile {
+                zoom: cell.zoom + 1,
+                x: 2 * cell.x + dx,
+                y: 2 * cell.y + dy,
+            };
+            let child_shift = 2 * u32::from(level - child.zoom);
+            let first_index = hilbert_index(&child) << child_shift;
+            let last_index = first_index + (1 << child_shift) - 1;
+            let start = self.partition_tiles(tiles.clone(), |t| hilbert_index(t) < first_index);
+            let end = self.partition_tiles(start..tiles.end, |t| hilbert_index(t) <= last_index);
+            self.visit_tiles_in_range(start..end, bounds, &child, visit);
         }
+    }
 
-        None
+    // Returns the index of the first tile in `tiles` for which `pred` is false. `pred` must be true for all tiles
+    // before this one and false for all tiles after it.
+    fn partition_tiles(&self, tiles: Range<usize>, pred: impl Fn(&tile::Tile) -> bool) -> usize {
+        let (mut lo, mut hi) = (tiles.start, tiles.end);
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            if pred(&self.tile(mid)) {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+        lo
     }
 
-    fn get_node(&'a self, idx: usize) -> Node<'a> {
+    pub(super) fn get_node(&'a self, idx: usize) -> Node<'a> {
         Node {
             entity: BaseOsmEntity {
                 bytes: self.storages().node_storage.get_object(idx),
                 reader: self,
             },
+            idx,
         }
     }
 
-    fn get_way(&'a self, idx: usize) -> Way<'a> {
+    pub(super) fn get_way(&'a self, idx: usize) -> Way<'a> {
         let bytes = self.storages().way_storage.get_object(idx);
         let node_ids_start_pos = mem::size_of::<u64>();
-        let node_ids = self.get_ints_by_ref(&bytes[node_ids_start_pos..]);
+        let node_ids = self.get_packed_ints_by_ref(&bytes[node_ids_start_pos..], 1);
         Way {
             entity: BaseOsmEntity { bytes, reader: self },
+            idx,
             node_ids,
         }
     }
 
     fn get_polygon(&'a self, idx: usize) -> Polygon<'a> {
         let bytes = self.storages().polygon_storage.get_object(idx);
-        let node_ids = self.get_ints_by_ref(&bytes);
-        Polygon { reader: self, node_ids }
+        let node_ids = self.get_packed_ints_by_ref(bytes, 1);
+        Polygon {
+            reader: self,
+            idx,
+            node_ids,
+        }
     }
 
-    fn get_multipolygon(&'a self, idx: usize) -> Multipolygon<'a> {
+    fn get_simplified_node_ids(&self, storage: &ObjectStorage<'a>, idx: usize, zoom: u8) -> Option<PackedInts<'a>> {
+        get_simplification_idx(zoom).map(|simplification_idx| {
+            let offset = simplification_idx * INT_REF_SIZE;
+            self.get_packed_ints_by_ref(&storage.get_object(idx)[offset..], 1)
+        })
+    }
+
+    pub(super) fn get_multipolygon(&'a self, idx: usize) -> Multipolygon<'a> {
         let bytes = self.storages().multipolygon_storage.get_object(idx);
         let way_ids_start_pos = mem::size_of::<u64>();
-        let way_ids = self.get_ints_by_ref(&bytes[way_ids_start_pos..]);
+        let way_ids = self.get_packed_ints_by_ref(&bytes[way_ids_start_pos..], 1);
         Multipolygon {
             entity: BaseOsmEntity { bytes, reader: self },
             polygon_ids: way_ids,
         }
     }
 
-    fn tile_xy(&self, idx: usize) -> (u32, u32) {
+    pub(super) fn get_relation(&'a self, idx: usize) -> Relation<'a> {
+        let bytes = self.storages().relation_storage.get_object(idx);
+        let members_start_pos = mem::size_of::<u64>();
+        let members = self.get_ints_by_ref(&bytes[members_start_pos..]);
+        Relation {
+            entity: BaseOsmEntity { bytes, reader: self },
+            idx,
+            members,
+        }
+    }
+
+    fn get_parents(&'a self, storage: &ObjectStorage<'a>, idx: usize) -> impl Iterator<Item = Parent<'a>> + 'a {
+        let parents = self
+            .get_packed_ints_by_ref(storage.get_object(idx), PARENT_SIZE)
+            .unpack();
+        (0..parents.len() / PARENT_SIZE).map(move |parent_idx| {
+            let parent = &parents[parent_idx * PARENT_SIZE..(parent_idx + 1) * PARENT_SIZE];
+            let (local_id, index) = (parent[1] as usize, parent[2] as usize);
+            match MemberKind::from_u32(parent[0]) {
+                Some(MemberKind::Way) => Parent {
+                    role: "",
+                    parent: Member::Way(self.get_way(local_id)),
+                },
+                Some(MemberKind::Relation) => {
+                    let relation = self.get_relation(local_id);
+                    Parent {
+                        role: relation.member_role(index),
+                        parent: Member::Relation(relation),
+                    }
+                }
+                _ => panic!("Unexpected parent kind {}", parent[0]),
+            }
+        })
+    }
+
+    pub(super) fn tile(&self, idx: usize) -> tile::Tile {
         let tile = self.storages().tile_storage.get_object(idx);
         let mut cursor = Cursor::new(tile);
+        let zoom = cursor.read_u32::<LittleEndian>().unwrap() as u8;
         let x = cursor.read_u32::<LittleEndian>().unwrap();
         let y = cursor.read_u32::<LittleEndian>().unwrap();
-        (x, y)
+        tile::Tile { zoom, x, y }
     }
 
-    fn tile_local_ids(&self, idx: usize, local_ids_idx: usize) -> &'a [u32] {
+    pub(super) fn tile_local_ids(&self, idx: usize, local_ids_idx: usize) -> &'a [u32] {
         let tile = self.storages().tile_storage.get_object(idx);
-        let offset = 2 * mem::size_of::<u32>() * (local_ids_idx + 1);
+        let offset = 3 * mem::size_of::<u32>() + local_ids_idx * INT_REF_SIZE;
         self.get_ints_by_ref(&tile[offset..])
     }
 
-    fn tile_count(&self) -> usize {
+    /// The lowest zoom level at which the entity can be drawn by the stylesheet that was given to the importer.
+    /// Without a stylesheet, it's 0 for all entities.
+    pub(super) fn min_zoom(&self, local_ids_idx: usize, local_id: usize) -> u8 {
+        self.storages().min_zooms[local_ids_idx][local_id]
+    }
+
+    /// The highest zoom level of the tiles in the tile index.
+    pub(super) fn index_zoom(&self) -> u8 {
+        self.storages().index_zoom
+    }
+
+    /// The area rules that the importer used, in the format of `AreaRules::from_file`.
+    pub(super) fn area_rules(&self) -> &str {
+        self.storages().area_rules
+    }
+
+    /// The keys of the tags that the importer kept for the stylesheet, one per line, or None if all tags were kept.
+    pub(super) fn tag_filter(&self) -> Option<&str> {
+        self.storages().tag_filter
+    }
+
+    pub(super) fn string_table_size(&self) -> usize {
+        self.storages().strings.len()
+    }
+
+    pub(super) fn tile_count(&self) -> usize {
         self.storages().tile_storage.object_count
     }
 
+    pub(super) fn node_count(&self) -> usize {
+        self.storages().node_storage.object_count
+    }
+
+    pub(super) fn way_count(&self) -> usize {
+        self.storages().way_storage.object_count
+    }
+
+    pub(super) fn multipolygon_count(&self) -> usize {
+        self.storages().multipolygon_storage.object_count
+    }
+
+    pub(super) fn relation_count(&self) -> usize {
+        self.storages().relation_storage.object_count
+    }
+
     fn tags(&self, ref_bytes: &'a [u8]) -> Tags<'a> {
         Tags {
             kv_refs: self.get_ints_by_ref(ref_bytes),
@@ -247,6 +471,22 @@ impl<'a> GeodataReader<'a> {
         &self.storages().ints[offset..offset + length]
     }
 
+    fn get_packed_ints_by_ref(&self, ref_bytes: &'a [u8], stride: usize) -> PackedInts<'a> {
+        let mut cursor = Cursor::new(ref_bytes);
+        let offset = cursor.read_u32::<LittleEndian>().unwrap() as usize;
+        let count = cursor.read_u32::<LittleEndian>().unwrap() as usize;
+        PackedInts {
+            bytes: &self.storages().packed_ints[offset..],
+            count,
+            stride,
+            unpacked: OnceCell::new(),
+        }
+    }
+
+    fn get_str(&self, offset: usize, length: usize) -> &'a str {
+        unsafe { str::from_utf8_unchecked(&self.storages().strings[offset..offset + length]) }
+    }
+
     fn storages(&self) -> &ObjectStorages<'a> {
         &self.handle
     }
@@ -262,6 +502,24 @@ where
     }
 }
 
+// `index` contains the local ids of the entities from `storage`, sorted by their global ids.
+// Every entity starts with its global id.
+fn find_by_global_id(index: &ObjectStorage<'_>, storage: &ObjectStorage<'_>, global_id: u64) -> Option<usize> {
+    let local_id_at = |idx| LittleEndian::read_u32(index.get_object(idx)) as usize;
+    let global_id_at = |idx| LittleEndian::read_u64(storage.get_object(local_id_at(idx)));
+
+    let (mut lo, mut hi) = (0, index.object_count);
+    while lo < hi {
+        let mid = (lo + hi) / 2;
+        match global_id_at(mid).cmp(&global_id) {
+            Ordering::Less => lo = mid + 1,
+            Ordering::Greater => hi = mid,
+            Ordering::Equal => return Some(local_id_at(mid)),
+        }
+    }
+    None
+}
+
 struct ObjectStorage<'a> {
     object_count: usize,
     object_size: usize,
@@ -269,17 +527,26 @@ struct ObjectStorage<'a> {
 }
 
 impl<'a> ObjectStorage<'a> {
-    fn from_bytes(bytes: &[u8], object_size: usize) -> Result<(ObjectStorage<'_>, &[u8]), Error> {
+    fn from_bytes(bytes: &'a [u8], object_size: usize, section: Section) -> Result<ObjectStorage<'a>, Error> {
+        if bytes.len() < mem::size_of::<u32>() {
+            bail!("{:?} section is too small to hold the object count", section);
+        }
         let object_count = LittleEndian::read_u32(bytes) as usize;
-        let object_start_pos = mem::size_of::<u32>();
-        let object_end_pos = object_start_pos + object_size * object_count;
-        let storage = ObjectStorage {
+        let objects = &bytes[mem::size_of::<u32>()..];
+        if objects.len() != object_size * object_count {
+            bail!(
+                "{:?} section should contain {} objects of size {}, but its size is {} bytes",
+                section,
+                object_count,
+                object_size,
+                bytes.len()
+            );
+        }
+        Ok(ObjectStorage {
             object_count,
             object_size,
-            objects: &bytes[object_start_pos..object_end_pos],
-        };
-        let rest = &bytes[object_end_pos..];
-        Ok((storage, rest))
+            objects,
+        })
     }
 
     fn get_object(&self, idx: usize) -> &'a [u8] {
@@ -294,51 +561,190 @@ struct ObjectStorages<'a> {
     way_storage: ObjectStorage<'a>,
     polygon_storage: ObjectStorage<'a>,
     multipolygon_storage: ObjectStorage<'a>,
+    relation_storage: ObjectStorage<'a>,
     tile_storage: ObjectStorage<'a>,
+    way_simplifications: ObjectStorage<'a>,
+    polygon_simplifications: ObjectStorage<'a>,
+    node_index: ObjectStorage<'a>,
+    way_index: ObjectStorage<'a>,
+    multipolygon_index: ObjectStorage<'a>,
+    relation_index: ObjectStorage<'a>,
+    min_zooms: [&'a [u8]; 4],
+    node_parents: ObjectStorage<'a>,
+    way_parents: ObjectStorage<'a>,
+    relation_parents: ObjectStorage<'a>,
     ints: &'a [u32],
+    packed_ints: &'a [u8],
     strings: &'a [u8],
+    area_rules: &'a str,
+    tag_filter: Option<&'a str>,
+    index_zoom: u8,
+    // Not checked when loading, see `GeodataReader::verify`.
+    checksum: Option<u32>,
+    checksummed_bytes: &'a [u8],
 }
 
 const INT_REF_SIZE: usize = 2 * mem::size_of::<u32>();
-const NODE_SIZE: usize = mem::size_of::<u64>() + 2 * mem::size_of::<f64>() + INT_REF_SIZE;
+const NODE_SIZE: usize = mem::size_of::<u64>() + 2 * mem::size_of::<i32>() + INT_REF_SIZE;
 const POLYGON_SIZE: usize = INT_REF_SIZE;
-const WAY_OR_MULTIPOLYGON_SIZE: usize = mem::size_of::<u64>() + 2 * INT_REF_SIZE;
-const TILE_SIZE: usize = 2 * mem::size_of::<u32>() + 3 * INT_REF_SIZE;
+// Global id, node ids, flags and tags.
+const WAY_SIZE: usize = mem::size_of::<u64>() + 2 * INT_REF_SIZE + mem::size_of::<u32>();
+const MULTIPOLYGON_SIZE: usize = mem::size_of::<u64>() + 2 * INT_REF_SIZE;
+const RELATION_SIZE: usize = mem::size_of::<u64>() + 2 * INT_REF_SIZE;
+const TILE_SIZE: usize = 3 * mem::size_of::<u32>() + 4 * INT_REF_SIZE;
+// Member kind, local id, role offset and role length.
+const MEMBER_SIZE: usize = 4;
+// Parent kind, local id and the index of the child among the nodes or the members of the parent.
+const PARENT_SIZE: usize = 3;
+const SIMPLIFICATIONS_SIZE: usize = SIMPLIFICATION_ZOOMS.len() * INT_REF_SIZE;
 
 impl<'a> ObjectStorages<'a> {
-    // All geodata members have sizes divisible by 4, so the u8* -> u32* cast should be safe,
-    // provided that `bytes` is aligned to 4 bytes (if it's not, we're in trouble anyway).
+    // All sections start at offsets divisible by 4, so the u8* -> u32* cast is safe, provided that `bytes`
+    // is aligned to 4 bytes (this is checked below, and memory-mapped files are always aligned).
     #[cfg_attr(feature = "cargo-clippy", allow(clippy::cast_ptr_alignment))]
     fn from_bytes(bytes: &[u8]) -> Result<ObjectStorages<'_>, Error> {
-        let (node_storage, rest) = ObjectStorage::from_bytes(bytes, NODE_SIZE)?;
-        let (way_storage, rest) = ObjectStorage::from_bytes(rest, WAY_OR_MULTIPOLYGON_SIZE)?;
-        let (polygon_storage, rest) = ObjectStorage::from_bytes(rest, POLYGON_SIZE)?;
-        let (multipolygon_storage, rest) = ObjectStorage::from_bytes(rest, WAY_OR_MULTIPOLYGON_SIZE)?;
-        let (tile_storage, rest) = ObjectStorage::from_bytes(rest, TILE_SIZE)?;
-
-        let int_count = LittleEndian::read_u32(rest) as usize;
-        let start_pos = mem::size_of::<u32>();
-        let end_pos = start_pos + mem::size_of::<u32>() * int_count;
+        let header = Header::read_from(bytes)?;
+
+        let section_bytes = |section| {
+            let location = header.section(section);
+            &bytes[location.offset as usize..(location.offset + location.size) as usize]
+        };
+        let object_storage =
+            |section, object_size| ObjectStorage::from_bytes(section_bytes(section), object_size, section);
+
+        let ints_bytes = section_bytes(Section::Ints);
+        if !(ints_bytes.as_ptr() as usize).is_multiple_of(mem::align_of::<u32>()) {
+            bail!("The geodata is not aligned to 4 bytes in memory");
+        }
+        let int_storage = ObjectStorage::from_bytes(ints_bytes, mem::size_of::<u32>(), Section::Ints)?;
         let ints = unsafe {
-            let byte_seq = &rest[start_pos..end_pos];
-            let int_ptr = byte_seq.as_ptr() as *const u32;
-            slice::from_raw_parts(int_ptr, int_count)
+            let int_ptr = int_storage.objects.as_ptr() as *const u32;
+            slice::from_raw_parts(int_ptr, int_storage.object_count)
+        };
+
+        let node_storage = object_storage(Section::Nodes, NODE_SIZE)?;
+        let way_storage = object_storage(Section::Ways, WAY_SIZE)?;
+        let polygon_storage = object_storage(Section::Polygons, POLYGON_SIZE)?;
+        let multipolygon_storage = object_storage(Section::Multipolygons, MULTIPOLYGON_SIZE)?;
+        let relation_storage = object_storage(Section::Relations, RELATION_SIZE)?;
+        // Id indexes, simplifications and parents have an object for every entity of the corresponding type.
+        let per_entity_storage = |section, object_size, storage: &ObjectStorage<'_>| {
+            let index = object_storage(section, object_size)?;
+            if index.object_count != storage.object_count {
+                bail!(
+                    "{:?} section has {} entries for {} entities",
+                    section,
+                    index.object_count,
+                    storage.object_count
+                );
+            }
+            Ok(index)
+        };
+
+        let id_index = |section, storage| per_entity_storage(section, mem::size_of::<u32>(), storage);
+
+        // Min zooms are bytes padded to a multiple of 4, so they don't fit into an ObjectStorage.
+        let min_zooms = |section, storage: &ObjectStorage<'_>| {
+            let bytes = section_bytes(section);
+            let count = storage.object_count;
+            if bytes.len() != mem::size_of::<u32>() + count.next_multiple_of(4)
+                || LittleEndian::read_u32(bytes) as usize != count
+            {
+                bail!(
+                    "{:?} section ({} bytes) doesn't match the number of entities ({})",
+                    section,
+                    bytes.len(),
+                    count
+                );
+            }
+            Ok(&bytes[mem::size_of::<u32>()..mem::size_of::<u32>() + count])
+        };
+
+        // The text sections start with the length of the text and are padded to a multiple of 4 bytes.
+        let text_section = |section| {
+            let bytes = section_bytes(section);
+            let len = match bytes.len() {
+                len if len >= mem::size_of::<u32>() => LittleEndian::read_u32(bytes) as usize,
+                _ => bail!("{:?} section is too small", section),
+            };
+            if bytes.len() != mem::size_of::<u32>() + len.next_multiple_of(4) {
+                bail!(
+                    "{:?} section ({} bytes) doesn't match the length of the text ({})",
+                    section,
+                    bytes.len(),
+                    len
+                );
+            }
+            let text = str::from_utf8(&bytes[mem::size_of::<u32>()..][..len])
+                .context(format!("{:?} section is not valid UTF-8", section))?;
+            Ok(text)
+        };
+        let area_rules = text_section(Section::AreaRules)?;
+        let tag_filter = if section_bytes(Section::TagFilter).is_empty() {
+            None
+        } else {
+            Some(text_section(Section::TagFilter)?)
         };
-        let strings = &rest[end_pos..];
 
         Ok(ObjectStorages {
+            node_index: id_index(Section::NodeIndex, &node_storage)?,
+            way_index: id_index(Section::WayIndex, &way_storage)?,
+            multipolygon_index: id_index(Section::MultipolygonIndex, &multipolygon_storage)?,
+            relation_index: id_index(Section::RelationIndex, &relation_storage)?,
+            way_simplifications: per_entity_storage(Section::WaySimplifications, SIMPLIFICATIONS_SIZE, &way_storage)?,
+            polygon_simplifications: per_entity_storage(
+                Section::PolygonSimplifications,
+                SIMPLIFICATIONS_SIZE,
+                &polygon_storage,
+            )?,
+            min_zooms: [
+                min_zooms(Section::NodeMinZooms, &node_storage)?,
+                min_zooms(Section::WayMinZooms, &way_storage)?,
+                min_zooms(Section::MultipolygonMinZooms, &multipolygon_storage)?,
+                min_zooms(Section::RelationMinZooms, &relation_storage)?,
+            ],
+            node_parents: per_entity_storage(Section::NodeParents, INT_REF_SIZE, &node_storage)?,
+            way_parents: per_entity_storage(Section::WayParents, INT_REF_SIZE, &way_storage)?,
+            relation_parents: per_entity_storage(Section::RelationParents, INT_REF_SIZE, &relation_storage)?,
             node_storage,
             way_storage,
             polygon_storage,
             multipolygon_storage,
-            tile_storage,
+            relation_storage,
+            tile_storage: object_storage(Section::Tiles, TILE_SIZE)?,
             ints,
-            strings,
+            packed_ints: section_bytes(Section::PackedInts),
+            strings: section_bytes(Section::Strings),
+            area_rules,
+            tag_filter,
+            index_zoom: header.index_zoom,
+            checksum: Some(header.checksum).filter(|_| header.flags & FLAG_HAS_CHECKSUM != 0),
+            checksummed_bytes: &bytes[HEADER_SIZE..],
         })
     }
 }
 
-type GeodataHandle<'a> = OwningHandle<Box<Mmap>, Box<ObjectStorages<'a>>>;
+// The memory that the geodata is read from.
+enum GeodataBytes<'a> {
+    Mapped(Mmap),
+    Buffer(Cow<'a, [u8]>),
+    // A copy of a misaligned buffer and its length in bytes.
+    Words(Vec<u32>, usize),
+}
+
+impl<'a> Deref for GeodataBytes<'a> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            GeodataBytes::Mapped(mmap) => mmap,
+            GeodataBytes::Buffer(bytes) => bytes,
+            GeodataBytes::Words(words, len) => unsafe { slice::from_raw_parts(words.as_ptr() as *const u8, *len) },
+        }
+    }
+}
+
+type GeodataHandle<'a> = OwningHandle<Box<GeodataBytes<'a>>, Box<ObjectStorages<'a>>>;
 
 pub struct Tags<'a> {
     kv_refs: &'a [u32],
@@ -442,25 +848,66 @@ macro_rules! implement_osm_entity {
 #[derive(Clone)]
 pub struct Node<'a> {
     entity: BaseOsmEntity<'a>,
+    idx: usize,
 }
 
 implement_osm_entity!(Node<'a>);
 
+impl<'a> Node<'a> {
+    /// Returns the ways that contain the node and the relations that have it as a member.
+    pub fn parents(&self) -> impl Iterator<Item = Parent<'a>> {
+        let reader = self.entity.reader;
+        reader.get_parents(&reader.storages().node_parents, self.idx)
+    }
+}
+
 impl<'a> Coords for Node<'a> {
     fn lat(&self) -> f64 {
         let start_pos = mem::size_of::<u64>();
-        LittleEndian::read_f64(&self.entity.bytes[start_pos..])
+        fixed_to_coord(LittleEndian::read_i32(&self.entity.bytes[start_pos..]))
     }
 
     fn lon(&self) -> f64 {
-        let start_pos = mem::size_of::<u64>() + mem::size_of::<f64>();
-        LittleEndian::read_f64(&self.entity.bytes[start_pos..])
+        let start_pos = mem::size_of::<u64>() + mem::size_of::<i32>();
+        fixed_to_coord(LittleEndian::read_i32(&self.entity.bytes[start_pos..]))
+    }
+}
+
+// A packed list of ints (see `encoding`), unpacked on the first access.
+#[derive(Clone)]
+struct PackedInts<'a> {
+    bytes: &'a [u8],
+    count: usize,
+    stride: usize,
+    unpacked: OnceCell<Vec<u32>>,
+}
+
+impl<'a> PackedInts<'a> {
+    fn len(&self) -> usize {
+        self.count
+    }
+
+    fn get(&self, idx: usize) -> u32 {
+        self.as_slice()[idx]
+    }
+
+    fn as_slice(&self) -> &[u32] {
+        self.unpacked
+            .get_or_init(|| unpack_refs(self.bytes, self.count, self.stride))
+    }
+
+    fn unpack(self) -> Vec<u32> {
+        let (bytes, count, stride) = (self.bytes, self.count, self.stride);
+        self.unpacked
+            .into_inner()
+            .unwrap_or_else(|| unpack_refs(bytes, count, stride))
     }
 }
 
 pub struct Way<'a> {
     entity: BaseOsmEntity<'a>,
-    node_ids: &'a [u32],
+    idx: usize,
+    node_ids: PackedInts<'a>,
 }
 
 implement_osm_entity!(Way<'a>);
@@ -471,13 +918,52 @@ impl<'a> Way<'a> {
     }
 
     pub fn get_node(&self, idx: usize) -> Node<'a> {
-        let node_id = self.node_ids[idx];
+        let node_id = self.node_ids.get(idx);
         self.entity.reader.get_node(node_id as usize)
     }
+
+    /// Whether some nodes of the way were missing from the imported data. Such ways are shorter than in OSM,
+    /// unless they were closed along the boundary of the data by the importer.
+    pub fn is_truncated(&self) -> bool {
+        self.flags() & WAY_TRUNCATED != 0
+    }
+
+    pub(super) fn flags(&self) -> u32 {
+        let flags_pos = mem::size_of::<u64>() + INT_REF_SIZE;
+        LittleEndian::read_u32(&self.entity.bytes[flags_pos..])
+    }
+
+    /// Returns the same way with the nodes that are not needed for drawing it at the given zoom level removed.
+    pub fn simplified_for_zoom(&self, zoom: u8) -> Way<'a> {
+        let reader = self.entity.reader;
+        let storage = &reader.storages().way_simplifications;
+        Way {
+            entity: self.entity.clone(),
+            idx: self.idx,
+            node_ids: reader
+                .get_simplified_node_ids(storage, self.idx, zoom)
+                .unwrap_or_else(|| self.node_ids.clone()),
+        }
+    }
+
+    pub(super) fn local_node_ids(&self) -> &[u32] {
+        self.node_ids.as_slice()
+    }
+
+    /// Returns the relations that have the way as a member.
+    pub fn parents(&self) -> impl Iterator<Item = Parent<'a>> {
+        let reader = self.entity.reader;
+        reader.get_parents(&reader.storages().way_parents, self.idx)
+    }
 }
 
 impl<'a> OsmArea for Way<'a> {
+    // The shape of a truncated way is wrong, so it's only an area if the importer has closed it.
     fn is_closed(&self) -> bool {
+        let flags = self.flags();
+        if flags & WAY_TRUNCATED != 0 && flags & WAY_CLOSED_ALONG_BOUNDARY == 0 {
+            return false;
+        }
         if self.node_count() <= 2 {
             return false;
         }
@@ -485,11 +971,17 @@ impl<'a> OsmArea for Way<'a> {
         let last_node = self.get_node(self.node_count() - 1);
         (first_node.lat(), first_node.lon()) == (last_node.lat(), last_node.lon())
     }
+
+    // The importer decides by the tags which closed ways are areas (see `AreaRules`).
+    fn is_area(&self) -> bool {
+        self.flags() & WAY_AREA != 0 && self.is_closed()
+    }
 }
 
 pub struct Polygon<'a> {
     reader: &'a GeodataReader<'a>,
-    node_ids: &'a [u32],
+    idx: usize,
+    node_ids: PackedInts<'a>,
 }
 
 impl<'a> Polygon<'a> {
@@ -498,14 +990,31 @@ impl<'a> Polygon<'a> {
     }
 
     pub fn get_node(&self, idx: usize) -> Node<'a> {
-        let node_id = self.node_ids[idx];
+        let node_id = self.node_ids.get(idx);
         self.reader.get_node(node_id as usize)
     }
+
+    /// Returns the same polygon with the nodes that are not needed for drawing it at the given zoom level removed.
+    pub fn simplified_for_zoom(&self, zoom: u8) -> Polygon<'a> {
+        let storage = &self.reader.storages().polygon_simplifications;
+        Polygon {
+            reader: self.reader,
+            idx: self.idx,
+            node_ids: self
+                .reader
+                .get_simplified_node_ids(storage, self.idx, zoom)
+                .unwrap_or_else(|| self.node_ids.clone()),
+        }
+    }
+
+    pub(super) fn local_node_ids(&self) -> &[u32] {
+        self.node_ids.as_slice()
+    }
 }
 
 pub struct Multipolygon<'a> {
     entity: BaseOsmEntity<'a>,
-    polygon_ids: &'a [u32],
+    polygon_ids: PackedInts<'a>,
 }
 
 implement_osm_entity!(Multipolygon<'a>);
@@ -516,7 +1025,7 @@ impl<'a> Multipolygon<'a> {
     }
 
     pub fn get_polygon(&self, idx: usize) -> Polygon<'a> {
-        let polygon_id = self.polygon_ids[idx];
+        let polygon_id = self.polygon_ids.get(idx);
         self.entity.reader.get_polygon(polygon_id as usize)
     }
 }
@@ -525,4 +1034,101 @@ impl<'a> OsmArea for Multipolygon<'a> {
     fn is_closed(&self) -> bool {
         true
     }
+
+    fn is_area(&self) -> bool {
+        true
+    }
+}
+
+// The flags of the ways that are stored in the geodata file.
+pub(super) const WAY_TRUNCATED: u32 = 1;
+pub(super) const WAY_CLOSED_ALONG_BOUNDARY: u32 = 2;
+// The tags say that the way is an area if it's closed.
+pub(super) const WAY_AREA: u32 = 4;
+
+/// The type of a relation member. The values are stored in the geodata file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MemberKind {
+    Node = 0,
+    Way = 1,
+    Relation = 2,
+}
+
+impl MemberKind {
+    pub(super) fn from_u32(value: u32) -> Option<MemberKind> {
+        match value {
+            0 => Some(MemberKind::Node),
+            1 => Some(MemberKind::Way),
+            2 => Some(MemberKind::Relation),
+            _ => None,
+        }
+    }
+}
+
+/// A `type=route`, `type=boundary` or `type=associatedStreet` relation. The members are listed in the original order,
+/// except for those that are missing from the imported data.
+pub struct Relation<'a> {
+    entity: BaseOsmEntity<'a>,
+    idx: usize,
+    members: &'a [u32],
+}
+
+implement_osm_entity!(Relation<'a>);
+
+pub enum Member<'a> {
+    Node(Node<'a>),
+    Way(Way<'a>),
+    Relation(Relation<'a>),
+}
+
+pub struct RelationMember<'a> {
+    pub role: &'a str,
+    pub member: Member<'a>,
+}
+
+/// A way that contains a node, or a relation that has an entity as a member. The role is empty for ways.
+pub struct Parent<'a> {
+    pub role: &'a str,
+    pub parent: Member<'a>,
+}
+
+impl<'a> Relation<'a> {
+    pub fn member_count(&self) -> usize {
+        self.members.len() / MEMBER_SIZE
+    }
+
+    pub fn get_member(&self, idx: usize) -> RelationMember<'a> {
+        let reader = self.entity.reader;
+        let member = &self.members[idx * MEMBER_SIZE..(idx + 1) * MEMBER_SIZE];
+        let local_id = member[1] as usize;
+        let member_entity = match MemberKind::from_u32(member[0]) {
+            Some(MemberKind::Node) => Member::Node(reader.get_node(local_id)),
+            Some(MemberKind::Way) => Member::Way(reader.get_way(local_id)),
+            Some(MemberKind::Relation) => Member::Relation(reader.get_relation(local_id)),
+            None => panic!(
+                "Relation #{} has a member of unknown kind {}",
+                self.global_id(),
+                member[0]
+            ),
+        };
+        RelationMember {
+            role: self.member_role(idx),
+            member: member_entity,
+        }
+    }
+
+    pub fn members(&self) -> impl Iterator<Item = RelationMember<'a>> + '_ {
+        (0..self.member_count()).map(move |idx| self.get_member(idx))
+    }
+
+    /// Returns the relations that have this relation as a member.
+    pub fn parents(&self) -> impl Iterator<Item = Parent<'a>> {
+        let reader = self.entity.reader;
+        reader.get_parents(&reader.storages().relation_parents, self.idx)
+    }
+
+    fn member_role(&self, idx: usize) -> &'a str {
+        let member = &self.members[idx * MEMBER_SIZE..(idx + 1) * MEMBER_SIZE];
+        self.entity.reader.get_str(member[2] as usize, member[3] as usize)
+    }
 }
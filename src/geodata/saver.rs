@@ -1,81 +1,387 @@
-use crate::geodata::importer::{EntityStorages, Multipolygon, Polygon, RawNode, RawRefs, RawWay};
+use crate::geodata::encoding::{coord_to_fixed, pack_refs};
+use crate::geodata::header::{Header, Section, SectionLocation, FLAG_HAS_CHECKSUM, HEADER_SIZE};
+use crate::geodata::importer::{EntityStorages, Multipolygon, NodeStorage, Polygon, RawRefs, RawWay};
+use crate::geodata::reader::MemberKind;
+use crate::geodata::simplify::{simplify, SIMPLIFICATION_ZOOMS};
+use crate::geodata::temp_storage::{
+    copy_temp_file, ExternalSorter, FixedSizeRecord, IdPair, SortedRecords, SpillDir, TempFile,
+};
 use crate::tile;
-use byteorder::{LittleEndian, WriteBytesExt};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use failure::{bail, Error};
 use std::cmp::{max, min};
 use std::collections::{BTreeMap, BTreeSet, HashMap};
-use std::io::Write;
+use std::io::{self, Seek, SeekFrom, Write};
+use std::iter;
+use std::rc::Rc;
 
-#[derive(Default)]
-struct TileReferences {
-    local_node_ids: BTreeSet<usize>,
-    local_way_ids: BTreeSet<usize>,
-    local_multipolygon_ids: BTreeSet<usize>,
+pub(super) const NODE_REFS_IDX: u8 = 0;
+pub(super) const WAY_REFS_IDX: u8 = 1;
+pub(super) const MULTIPOLYGON_REFS_IDX: u8 = 2;
+pub(super) const RELATION_REFS_IDX: u8 = 3;
+
+// An entity is indexed at the highest zoom level (up to the index zoom) at which it covers at most
+// this many tiles, so that long rivers and boundaries don't get references from thousands of tiles.
+const MAX_TILES_PER_ENTITY: u64 = 16;
+
+// A single entity referenced from a single tile. Sorting these gives exactly the order in which
+// the tiles and their references are stored: the tiles are sorted by the zoom level and then along
+// the Hilbert curve, and within a tile, the references are sorted by the minimum zoom level of the entity,
+// so that the reader can stop at the first entity that is not visible.
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd)]
+pub(super) struct TileReference {
+    tile_zoom: u8,
+    hilbert_index: u64,
+    tile_x: u32,
+    tile_y: u32,
+    refs_idx: u8,
+    min_zoom: u8,
+    local_id: u32,
 }
 
-#[derive(Default)]
-struct TileIdToReferences {
-    refs: BTreeMap<(u32, u32), TileReferences>,
+impl TileReference {
+    pub(super) fn new(tile: &tile::Tile, refs_idx: u8, min_zoom: u8, local_id: u32) -> TileReference {
+        TileReference {
+            tile_zoom: tile.zoom,
+            hilbert_index: tile::hilbert_index(tile.zoom, tile.x, tile.y),
+            tile_x: tile.x,
+            tile_y: tile.y,
+            refs_idx,
+            min_zoom,
+            local_id,
+        }
+    }
+}
+
+impl FixedSizeRecord for TileReference {
+    const SIZE: usize = 3 * 4 + 3;
+
+    fn write_to(&self, writer: &mut dyn Write) -> io::Result<()> {
+        writer.write_u8(self.tile_zoom)?;
+        writer.write_u32::<LittleEndian>(self.tile_x)?;
+        writer.write_u32::<LittleEndian>(self.tile_y)?;
+        writer.write_u8(self.refs_idx)?;
+        writer.write_u8(self.min_zoom)?;
+        writer.write_u32::<LittleEndian>(self.local_id)
+    }
+
+    fn read_from(mut bytes: &[u8]) -> TileReference {
+        let tile = tile::Tile {
+            zoom: bytes.read_u8().unwrap(),
+            x: bytes.read_u32::<LittleEndian>().unwrap(),
+            y: bytes.read_u32::<LittleEndian>().unwrap(),
+        };
+        let refs_idx = bytes.read_u8().unwrap();
+        let min_zoom = bytes.read_u8().unwrap();
+        let local_id = bytes.read_u32::<LittleEndian>().unwrap();
+        TileReference::new(&tile, refs_idx, min_zoom, local_id)
+    }
+}
+
+type TileReferences = SortedRecords<TileReference>;
+
+// A way that contains a node, or a relation that has a node, a way or another relation as a member.
+// `index` is the position of the child among the nodes or the members of the parent.
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd)]
+struct ParentReference {
+    child_id: u32,
+    parent_kind: u8,
+    parent_id: u32,
+    index: u32,
+}
+
+impl FixedSizeRecord for ParentReference {
+    const SIZE: usize = 3 * 4 + 1;
+
+    fn write_to(&self, writer: &mut dyn Write) -> io::Result<()> {
+        writer.write_u32::<LittleEndian>(self.child_id)?;
+        writer.write_u8(self.parent_kind)?;
+        writer.write_u32::<LittleEndian>(self.parent_id)?;
+        writer.write_u32::<LittleEndian>(self.index)
+    }
+
+    fn read_from(mut bytes: &[u8]) -> ParentReference {
+        ParentReference {
+            child_id: bytes.read_u32::<LittleEndian>().unwrap(),
+            parent_kind: bytes.read_u8().unwrap(),
+            parent_id: bytes.read_u32::<LittleEndian>().unwrap(),
+            index: bytes.read_u32::<LittleEndian>().unwrap(),
+        }
+    }
+}
+
+pub(super) fn save_to_internal_format<W: Write + Seek>(
+    writer: &mut W,
+    entity_storages: &EntityStorages,
+) -> Result<(), Error> {
+    save_to_internal_format_with_tiles(writer, entity_storages, Vec::new(), &|_, _| false)?;
+    Ok(())
+}
+
+// Same as `save_to_internal_format`, but doesn't compute the tile references for the entities
+// for which `is_known(refs_idx, local_id)` returns true: these must be among `known_tile_references`.
+// Returns the tiles (as zoom, x and y) that got references to the rest of the entities.
+pub(super) fn save_to_internal_format_with_tiles<W: Write + Seek>(
+    writer: &mut W,
+    entity_storages: &EntityStorages,
+    known_tile_references: Vec<TileReference>,
+    is_known: &dyn Fn(u8, usize) -> bool,
+) -> Result<BTreeSet<(u8, u32, u32)>, Error> {
+    let mut computed_tiles = BTreeSet::new();
+    let mut tile_references =
+        get_tile_references(entity_storages, known_tile_references, is_known, &mut computed_tiles)?;
+    save_with_tile_references(writer, entity_storages, &mut tile_references)?;
+    Ok(computed_tiles)
 }
 
-pub(super) fn save_to_internal_format(writer: &mut dyn Write, entity_storages: &EntityStorages) -> Result<(), Error> {
-    let mut buffered_data = BufferedData::default();
-    let nodes = &entity_storages.node_storage.get_entities();
-    save_nodes(writer, nodes, &mut buffered_data)?;
+fn save_with_tile_references<W: Write + Seek>(
+    writer: &mut W,
+    entity_storages: &EntityStorages,
+    tile_references: &mut TileReferences,
+) -> Result<(), Error> {
+    let header_pos = writer.stream_position()?;
+    // The real header is written when all section locations are known.
+    Header::default().write_to(writer)?;
+    let mut section_writer = SectionWriter::new(writer);
+    section_writer.header.index_zoom = entity_storages.index_zoom;
+
+    let mut buffered_data = BufferedData::new(&entity_storages.spill_dir)?;
+    let nodes = &entity_storages.node_storage;
+    save_nodes(&mut section_writer, nodes, &mut buffered_data)?;
+    section_writer.finish_section(Section::Nodes);
 
     let ways = &entity_storages.way_storage.get_entities();
-    save_ways(writer, &ways, &mut buffered_data)?;
+    let way_simplifications = save_ways(&mut section_writer, ways, nodes, &mut buffered_data)?;
+    section_writer.finish_section(Section::Ways);
 
     let polygons = &entity_storages.polygon_storage;
-    save_polygons(writer, &polygons, &mut buffered_data)?;
+    let polygon_simplifications = save_polygons(&mut section_writer, polygons, nodes, &mut buffered_data)?;
+    section_writer.finish_section(Section::Polygons);
 
     let multipolygons = &entity_storages.multipolygon_storage.get_entities();
-    save_multipolygons(writer, &multipolygons, &mut buffered_data)?;
+    save_multipolygons(&mut section_writer, multipolygons, &mut buffered_data)?;
+    section_writer.finish_section(Section::Multipolygons);
+
+    let relations = &entity_storages.relation_storage.get_entities();
+    save_relations(&mut section_writer, entity_storages, &mut buffered_data)?;
+    section_writer.finish_section(Section::Relations);
+
+    save_tile_references(&mut section_writer, tile_references, &mut buffered_data)?;
+    section_writer.finish_section(Section::Tiles);
 
-    let tile_references = get_tile_references(&entity_storages);
-    save_tile_references(writer, &tile_references, &mut buffered_data)?;
+    let spill_dir = &entity_storages.spill_dir;
+    let node_ids = (0..nodes.len()).map(|idx| nodes.get(idx).global_id);
+    save_id_index(&mut section_writer, node_ids, spill_dir)?;
+    section_writer.finish_section(Section::NodeIndex);
+    save_id_index(&mut section_writer, ways.iter().map(|w| w.global_id), spill_dir)?;
+    section_writer.finish_section(Section::WayIndex);
+    save_id_index(
+        &mut section_writer,
+        multipolygons.iter().map(|m| m.global_id),
+        spill_dir,
+    )?;
+    section_writer.finish_section(Section::MultipolygonIndex);
+    save_id_index(&mut section_writer, relations.iter().map(|r| r.global_id), spill_dir)?;
+    section_writer.finish_section(Section::RelationIndex);
 
-    buffered_data.save(writer)?;
+    save_simplifications(&mut section_writer, &way_simplifications)?;
+    section_writer.finish_section(Section::WaySimplifications);
+    save_simplifications(&mut section_writer, &polygon_simplifications)?;
+    section_writer.finish_section(Section::PolygonSimplifications);
+
+    let min_zoom_sections = [
+        (NODE_REFS_IDX, nodes.len(), Section::NodeMinZooms),
+        (WAY_REFS_IDX, ways.len(), Section::WayMinZooms),
+        (
+            MULTIPOLYGON_REFS_IDX,
+            multipolygons.len(),
+            Section::MultipolygonMinZooms,
+        ),
+        (RELATION_REFS_IDX, relations.len(), Section::RelationMinZooms),
+    ];
+    for (refs_idx, count, section) in &min_zoom_sections {
+        save_min_zooms(&mut section_writer, entity_storages, *refs_idx, *count)?;
+        section_writer.finish_section(*section);
+    }
+
+    save_parents(&mut section_writer, entity_storages, &mut buffered_data)?;
+
+    save_text(&mut section_writer, &entity_storages.area_rules.to_text())?;
+    section_writer.finish_section(Section::AreaRules);
+
+    // The section is empty if the tags were not filtered.
+    if let Some(ref tag_filter) = entity_storages.tag_filter {
+        let mut keys = tag_filter.iter().map(String::as_str).collect::<Vec<_>>();
+        keys.sort_unstable();
+        save_text(&mut section_writer, &keys.join("\n"))?;
+    }
+    section_writer.finish_section(Section::TagFilter);
+
+    buffered_data.save(&mut section_writer)?;
+
+    let header = section_writer.finish();
+    writer.seek(SeekFrom::Start(header_pos))?;
+    header.write_to(writer)?;
+    writer.seek(SeekFrom::End(0))?;
+    writer.flush()?;
 
     Ok(())
 }
 
-impl TileIdToReferences {
-    fn tile_ref_by_node(&mut self, node: &RawNode) -> &mut TileReferences {
-        let node_tile = tile::coords_to_max_zoom_tile(node);
-        self.tile_ref_by_xy(node_tile.x, node_tile.y)
+// Writes the sections that follow the header, keeping track of their locations and the checksum.
+struct SectionWriter<'a> {
+    writer: &'a mut dyn Write,
+    position: u64,
+    section_start: u64,
+    hasher: crc32fast::Hasher,
+    header: Header,
+}
+
+impl<'a> SectionWriter<'a> {
+    fn new(writer: &'a mut dyn Write) -> SectionWriter<'a> {
+        SectionWriter {
+            writer,
+            position: HEADER_SIZE as u64,
+            section_start: HEADER_SIZE as u64,
+            hasher: crc32fast::Hasher::new(),
+            header: Header {
+                flags: FLAG_HAS_CHECKSUM,
+                ..Default::default()
+            },
+        }
+    }
+
+    fn finish_section(&mut self, section: Section) {
+        self.header.sections[section as usize] = SectionLocation {
+            offset: self.section_start,
+            size: self.position - self.section_start,
+        };
+        self.section_start = self.position;
+    }
+
+    fn finish(mut self) -> Header {
+        self.header.checksum = self.hasher.finalize();
+        self.header
+    }
+}
+
+impl<'a> Write for SectionWriter<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.writer.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        self.position += written as u64;
+        Ok(written)
     }
 
-    fn tile_ref_by_xy(&mut self, tile_x: u32, tile_y: u32) -> &mut TileReferences {
-        self.refs.entry((tile_x, tile_y)).or_insert_with(Default::default)
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
     }
 }
 
-fn save_nodes(writer: &mut dyn Write, nodes: &[RawNode], data: &mut BufferedData) -> Result<(), Error> {
+fn save_nodes(writer: &mut dyn Write, nodes: &NodeStorage, data: &mut BufferedData) -> Result<(), Error> {
     writer.write_u32::<LittleEndian>(to_u32_safe(nodes.len())?)?;
-    for node in nodes {
+    for idx in 0..nodes.len() {
+        let node = nodes.get(idx);
         writer.write_u64::<LittleEndian>(node.global_id)?;
-        writer.write_f64::<LittleEndian>(node.lat)?;
-        writer.write_f64::<LittleEndian>(node.lon)?;
+        writer.write_i32::<LittleEndian>(coord_to_fixed(node.lat))?;
+        writer.write_i32::<LittleEndian>(coord_to_fixed(node.lon))?;
         save_tags(writer, &node.tags, data)?;
     }
     Ok(())
 }
 
-fn save_ways(writer: &mut dyn Write, ways: &[RawWay], data: &mut BufferedData) -> Result<(), Error> {
+// An offset and a length. For packed refs, the offset is in bytes and the length is the number of values.
+type IntRef = (u32, u32);
+type Simplifications = [IntRef; SIMPLIFICATION_ZOOMS.len()];
+
+fn save_ways(
+    writer: &mut dyn Write,
+    ways: &[RawWay],
+    nodes: &NodeStorage,
+    data: &mut BufferedData,
+) -> Result<Vec<Simplifications>, Error> {
+    let mut simplifications = Vec::with_capacity(ways.len());
     writer.write_u32::<LittleEndian>(to_u32_safe(ways.len())?)?;
     for way in ways {
         writer.write_u64::<LittleEndian>(way.global_id)?;
-        save_refs(writer, way.node_ids.iter(), data)?;
+        let node_ids_ref = save_packed_refs(writer, way.node_ids.iter(), 1, data)?;
+        writer.write_u32::<LittleEndian>(way.flags)?;
         save_tags(writer, &way.tags, data)?;
+        simplifications.push(add_simplifications(&way.node_ids, node_ids_ref, nodes, data)?);
     }
-    Ok(())
+    Ok(simplifications)
 }
 
-fn save_polygons(writer: &mut dyn Write, polygons: &[Polygon], data: &mut BufferedData) -> Result<(), Error> {
+fn save_polygons(
+    writer: &mut dyn Write,
+    polygons: &[Polygon],
+    nodes: &NodeStorage,
+    data: &mut BufferedData,
+) -> Result<Vec<Simplifications>, Error> {
+    let mut simplifications = Vec::with_capacity(polygons.len());
     writer.write_u32::<LittleEndian>(to_u32_safe(polygons.len())?)?;
     for polygon in polygons {
-        save_refs(writer, polygon.iter(), data)?;
+        let node_ids_ref = save_packed_refs(writer, polygon.iter(), 1, data)?;
+        simplifications.push(add_simplifications(polygon, node_ids_ref, nodes, data)?);
+    }
+    Ok(simplifications)
+}
+
+// Adds the simplified node lists to the ints. A simplification that doesn't remove any nodes reuses
+// the ints of the more detailed list.
+fn add_simplifications(
+    node_ids: &[usize],
+    node_ids_ref: IntRef,
+    nodes: &NodeStorage,
+    data: &mut BufferedData,
+) -> Result<Simplifications, Error> {
+    let mut result = [node_ids_ref; SIMPLIFICATION_ZOOMS.len()];
+    let coords = node_ids.iter().map(|id| nodes.get_coords(*id)).collect::<Vec<_>>();
+    let mut more_detailed = (node_ids_ref, node_ids.len());
+    for (idx, zoom) in SIMPLIFICATION_ZOOMS.iter().enumerate().rev() {
+        let kept_nodes = simplify(&coords, *zoom);
+        if kept_nodes.len() != more_detailed.1 {
+            let simplified_ids = kept_nodes.iter().map(|idx| &node_ids[*idx]);
+            more_detailed = (add_packed_refs(simplified_ids, 1, data)?, kept_nodes.len());
+        }
+        result[idx] = more_detailed.0;
+    }
+    Ok(result)
+}
+
+fn save_simplifications(writer: &mut dyn Write, simplifications: &[Simplifications]) -> Result<(), Error> {
+    writer.write_u32::<LittleEndian>(to_u32_safe(simplifications.len())?)?;
+    for int_ref in simplifications.iter().flat_map(|s| s.iter()) {
+        write_int_ref(writer, *int_ref)?;
+    }
+    Ok(())
+}
+
+// Writes the minimum zoom level of every entity as a byte. The bytes are padded with zeros so that
+// the next section starts at an offset divisible by 4.
+fn save_min_zooms(
+    writer: &mut dyn Write,
+    entity_storages: &EntityStorages,
+    refs_idx: u8,
+    count: usize,
+) -> Result<(), Error> {
+    writer.write_u32::<LittleEndian>(to_u32_safe(count)?)?;
+    for local_id in 0..count {
+        writer.write_u8(entity_storages.min_zoom(refs_idx, local_id))?;
+    }
+    for _ in count..count.next_multiple_of(4) {
+        writer.write_u8(0)?;
+    }
+    Ok(())
+}
+
+// The rules are stored as text, so that applying a diff classifies the changed ways the same way as the import.
+// The length of the text, the text and the padding to a multiple of 4 bytes.
+fn save_text(writer: &mut dyn Write, text: &str) -> Result<(), Error> {
+    writer.write_u32::<LittleEndian>(to_u32_safe(text.len())?)?;
+    writer.write_all(text.as_bytes())?;
+    for _ in text.len()..text.len().next_multiple_of(4) {
+        writer.write_u8(0)?;
     }
     Ok(())
 }
@@ -88,40 +394,250 @@ fn save_multipolygons(
     writer.write_u32::<LittleEndian>(to_u32_safe(multipolygons.len())?)?;
     for multipolygon in multipolygons {
         writer.write_u64::<LittleEndian>(multipolygon.global_id)?;
-        save_refs(writer, multipolygon.polygon_ids.iter(), data)?;
+        save_packed_refs(writer, multipolygon.polygon_ids.iter(), 1, data)?;
         save_tags(writer, &multipolygon.tags, data)?;
     }
     Ok(())
 }
 
+// Every member is stored as its kind, its local id and its role.
+fn save_relations(
+    writer: &mut dyn Write,
+    entity_storages: &EntityStorages,
+    data: &mut BufferedData,
+) -> Result<(), Error> {
+    let relations = entity_storages.relation_storage.get_entities();
+    writer.write_u32::<LittleEndian>(to_u32_safe(relations.len())?)?;
+    for relation in relations {
+        writer.write_u64::<LittleEndian>(relation.global_id)?;
+        let mut member_refs = RawRefs::new();
+        for (kind, local_id, role) in entity_storages.relation_members(relation) {
+            let (role_offset, role_length) = data.add_string(role);
+            member_refs.extend([kind as usize, local_id, role_offset, role_length].iter());
+        }
+        save_refs(writer, member_refs.iter(), data)?;
+        save_tags(writer, &relation.tags, data)?;
+    }
+    Ok(())
+}
+
+// Writes the NodeParents, WayParents and RelationParents sections. Every entity gets a reference to
+// the list of its parents, and every parent is stored as its kind, its local id and the index
+// of the child in it.
+fn save_parents(
+    writer: &mut SectionWriter,
+    entity_storages: &EntityStorages,
+    data: &mut BufferedData,
+) -> Result<(), Error> {
+    let spill_dir = &entity_storages.spill_dir;
+    let mut node_parents = ExternalSorter::new(spill_dir.clone());
+    let mut way_parents = ExternalSorter::new(spill_dir.clone());
+    let mut relation_parents = ExternalSorter::new(spill_dir.clone());
+
+    for (way_id, way) in entity_storages.way_storage.get_entities().iter().enumerate() {
+        for (index, node_id) in way.node_ids.iter().enumerate() {
+            // The last node of a closed way is the first one again.
+            if index > 0 && index + 1 == way.node_ids.len() && *node_id == way.node_ids[0] {
+                continue;
+            }
+            node_parents.push(ParentReference {
+                child_id: to_u32_safe(*node_id)?,
+                parent_kind: MemberKind::Way as u8,
+                parent_id: to_u32_safe(way_id)?,
+                index: to_u32_safe(index)?,
+            })?;
+        }
+    }
+
+    for (relation_id, relation) in entity_storages.relation_storage.get_entities().iter().enumerate() {
+        for (index, (kind, local_id, _)) in entity_storages.relation_members(relation).enumerate() {
+            let sorter = match kind {
+                MemberKind::Node => &mut node_parents,
+                MemberKind::Way => &mut way_parents,
+                MemberKind::Relation => &mut relation_parents,
+            };
+            sorter.push(ParentReference {
+                child_id: to_u32_safe(local_id)?,
+                parent_kind: MemberKind::Relation as u8,
+                parent_id: to_u32_safe(relation_id)?,
+                index: to_u32_safe(index)?,
+            })?;
+        }
+    }
+
+    let sections = [
+        (node_parents, entity_storages.node_storage.len(), Section::NodeParents),
+        (
+            way_parents,
+            entity_storages.way_storage.get_entities().len(),
+            Section::WayParents,
+        ),
+        (
+            relation_parents,
+            entity_storages.relation_storage.get_entities().len(),
+            Section::RelationParents,
+        ),
+    ];
+    for (sorter, count, section) in sections {
+        writer.write_u32::<LittleEndian>(to_u32_safe(count)?)?;
+        let mut parent_refs = sorter.finish()?;
+        let mut parent_refs = parent_refs.iter()?.peekable();
+        let mut parents = RawRefs::new();
+        for child_id in 0..count {
+            parents.clear();
+            while let Some(parent_ref) =
+                parent_refs.next_if(|r| r.as_ref().map_or(true, |r| r.child_id as usize == child_id))
+            {
+                let parent_ref = parent_ref?;
+                parents.extend(
+                    [
+                        parent_ref.parent_kind as usize,
+                        parent_ref.parent_id as usize,
+                        parent_ref.index as usize,
+                    ]
+                    .iter(),
+                );
+            }
+            save_packed_refs(writer, parents.iter(), 3, data)?;
+        }
+        writer.finish_section(section);
+    }
+    Ok(())
+}
+
 fn save_tile_references(
     writer: &mut dyn Write,
-    tile_references: &TileIdToReferences,
+    tile_references: &mut TileReferences,
     data: &mut BufferedData,
 ) -> Result<(), Error> {
-    writer.write_u32::<LittleEndian>(to_u32_safe(tile_references.refs.len())?)?;
-    for (k, v) in &tile_references.refs {
-        writer.write_u32::<LittleEndian>(k.0)?;
-        writer.write_u32::<LittleEndian>(k.1)?;
+    let mut tile_count = 0;
+    let mut last_tile = None;
+    for tile_ref in tile_references.iter()? {
+        let tile_ref = tile_ref?;
+        let tile = Some((tile_ref.tile_zoom, tile_ref.tile_x, tile_ref.tile_y));
+        if tile != last_tile {
+            tile_count += 1;
+            last_tile = tile;
+        }
+    }
+    writer.write_u32::<LittleEndian>(to_u32_safe(tile_count)?)?;
 
-        save_refs(writer, v.local_node_ids.iter(), data)?;
-        save_refs(writer, v.local_way_ids.iter(), data)?;
-        save_refs(writer, v.local_multipolygon_ids.iter(), data)?;
+    let mut current_tile = None;
+    let mut current_refs = [RawRefs::new(), RawRefs::new(), RawRefs::new(), RawRefs::new()];
+    let mut prev_ref = None;
+    for tile_ref in tile_references.iter()? {
+        let tile_ref = tile_ref?;
+        // A relation gets a reference to the same tile from each member that crosses it.
+        if prev_ref.as_ref() == Some(&tile_ref) {
+            continue;
+        }
+        let tile = (tile_ref.tile_zoom, tile_ref.tile_x, tile_ref.tile_y);
+        if current_tile != Some(tile) {
+            if let Some(prev_tile) = current_tile {
+                save_tile(writer, prev_tile, &mut current_refs, data)?;
+            }
+            current_tile = Some(tile);
+        }
+        current_refs[tile_ref.refs_idx as usize].push(tile_ref.local_id as usize);
+        prev_ref = Some(tile_ref);
+    }
+    if let Some(last_tile) = current_tile {
+        save_tile(writer, last_tile, &mut current_refs, data)?;
     }
 
     Ok(())
 }
 
-fn save_refs<'a, I>(writer: &mut dyn Write, refs: I, data: &mut BufferedData) -> Result<(), Error>
+fn save_tile(
+    writer: &mut dyn Write,
+    (tile_zoom, tile_x, tile_y): (u8, u32, u32),
+    refs: &mut [RawRefs; 4],
+    data: &mut BufferedData,
+) -> Result<(), Error> {
+    writer.write_u32::<LittleEndian>(u32::from(tile_zoom))?;
+    writer.write_u32::<LittleEndian>(tile_x)?;
+    writer.write_u32::<LittleEndian>(tile_y)?;
+    for r in refs.iter_mut() {
+        save_refs(writer, r.iter(), data)?;
+        r.clear();
+    }
+    Ok(())
+}
+
+// Writes the local ids of the entities in the order of their global ids, so that the reader
+// can find an entity by its global id with a binary search.
+fn save_id_index<I>(writer: &mut dyn Write, global_ids: I, spill_dir: &Option<Rc<SpillDir>>) -> Result<(), Error>
+where
+    I: Iterator<Item = u64>,
+{
+    let mut sorter = ExternalSorter::new(spill_dir.clone());
+    let mut count = 0;
+    for (local_id, global_id) in global_ids.enumerate() {
+        sorter.push(IdPair {
+            global_id,
+            local_id: local_id as u64,
+        })?;
+        count += 1;
+    }
+    writer.write_u32::<LittleEndian>(to_u32_safe(count)?)?;
+    for id_pair in sorter.finish()?.iter()? {
+        writer.write_u32::<LittleEndian>(id_pair?.local_id as u32)?;
+    }
+    Ok(())
+}
+
+fn save_refs<'a, I>(writer: &mut dyn Write, refs: I, data: &mut BufferedData) -> Result<IntRef, Error>
+where
+    I: Iterator<Item = &'a usize>,
+{
+    let int_ref = add_refs(refs, data)?;
+    write_int_ref(writer, int_ref)?;
+    Ok(int_ref)
+}
+
+fn add_refs<'a, I>(refs: I, data: &mut BufferedData) -> Result<IntRef, Error>
 where
     I: Iterator<Item = &'a usize>,
 {
     let offset = data.all_ints.len();
     for r in refs {
-        data.all_ints.push(to_u32_safe(*r)?);
+        data.all_ints.push(to_u32_safe(*r)?)?;
+    }
+    Ok((to_u32_safe(offset)?, to_u32_safe(data.all_ints.len() - offset)?))
+}
+
+fn save_packed_refs<'a, I>(
+    writer: &mut dyn Write,
+    refs: I,
+    stride: usize,
+    data: &mut BufferedData,
+) -> Result<IntRef, Error>
+where
+    I: Iterator<Item = &'a usize>,
+{
+    let int_ref = add_packed_refs(refs, stride, data)?;
+    write_int_ref(writer, int_ref)?;
+    Ok(int_ref)
+}
+
+fn add_packed_refs<'a, I>(refs: I, stride: usize, data: &mut BufferedData) -> Result<IntRef, Error>
+where
+    I: Iterator<Item = &'a usize>,
+{
+    let mut refs_u32 = Vec::new();
+    for r in refs {
+        refs_u32.push(to_u32_safe(*r)?);
     }
-    writer.write_u32::<LittleEndian>(to_u32_safe(offset)?)?;
-    writer.write_u32::<LittleEndian>(to_u32_safe(data.all_ints.len() - offset)?)?;
+    let mut packed = Vec::new();
+    pack_refs(&refs_u32, stride, &mut packed);
+    let offset = data.packed_ints.len();
+    data.packed_ints.push(&packed)?;
+    Ok((to_u32_safe(offset)?, to_u32_safe(refs_u32.len())?))
+}
+
+fn write_int_ref(writer: &mut dyn Write, (offset, length): IntRef) -> Result<(), Error> {
+    writer.write_u32::<LittleEndian>(offset)?;
+    writer.write_u32::<LittleEndian>(length)?;
     Ok(())
 }
 
@@ -139,14 +655,124 @@ fn save_tags(writer: &mut dyn Write, tags: &BTreeMap<String, String>, data: &mut
     Ok(())
 }
 
+enum IntBuffer {
+    InMemory(Vec<u32>),
+    OnDisk { file: TempFile, len: usize },
+}
+
+impl Default for IntBuffer {
+    fn default() -> IntBuffer {
+        IntBuffer::InMemory(Vec::new())
+    }
+}
+
+impl IntBuffer {
+    fn len(&self) -> usize {
+        match self {
+            IntBuffer::InMemory(ints) => ints.len(),
+            IntBuffer::OnDisk { len, .. } => *len,
+        }
+    }
+
+    fn push(&mut self, i: u32) -> Result<(), Error> {
+        match self {
+            IntBuffer::InMemory(ints) => ints.push(i),
+            IntBuffer::OnDisk { file, len } => {
+                file.write_u32::<LittleEndian>(i)?;
+                *len += 1;
+            }
+        }
+        Ok(())
+    }
+
+    fn save(&mut self, writer: &mut dyn Write) -> Result<(), Error> {
+        writer.write_u32::<LittleEndian>(to_u32_safe(self.len())?)?;
+        match self {
+            IntBuffer::InMemory(ints) => {
+                for i in ints.iter() {
+                    writer.write_u32::<LittleEndian>(*i)?;
+                }
+            }
+            IntBuffer::OnDisk { file, .. } => copy_temp_file(file, writer)?,
+        }
+        Ok(())
+    }
+}
+
+// The packed refs are written as is, followed by zeros up to a multiple of 4 bytes.
+enum ByteBuffer {
+    InMemory(Vec<u8>),
+    OnDisk { file: TempFile, len: usize },
+}
+
+impl Default for ByteBuffer {
+    fn default() -> ByteBuffer {
+        ByteBuffer::InMemory(Vec::new())
+    }
+}
+
+impl ByteBuffer {
+    fn len(&self) -> usize {
+        match self {
+            ByteBuffer::InMemory(bytes) => bytes.len(),
+            ByteBuffer::OnDisk { len, .. } => *len,
+        }
+    }
+
+    fn push(&mut self, data: &[u8]) -> Result<(), Error> {
+        match self {
+            ByteBuffer::InMemory(bytes) => bytes.extend_from_slice(data),
+            ByteBuffer::OnDisk { file, len } => {
+                file.write_all(data)?;
+                *len += data.len();
+            }
+        }
+        Ok(())
+    }
+
+    fn save(&mut self, writer: &mut dyn Write) -> Result<(), Error> {
+        let len = self.len();
+        match self {
+            ByteBuffer::InMemory(bytes) => writer.write_all(bytes)?,
+            ByteBuffer::OnDisk { file, .. } => copy_temp_file(file, writer)?,
+        }
+        for _ in len..len.next_multiple_of(4) {
+            writer.write_u8(0)?;
+        }
+        Ok(())
+    }
+}
+
 #[derive(Default)]
 struct BufferedData {
-    all_ints: Vec<u32>,
+    all_ints: IntBuffer,
+    packed_ints: ByteBuffer,
     string_to_offset: HashMap<String, usize>,
     all_strings: Vec<u8>,
 }
 
 impl BufferedData {
+    fn new(spill_dir: &Option<Rc<SpillDir>>) -> Result<BufferedData, Error> {
+        let (all_ints, packed_ints) = match spill_dir {
+            Some(dir) => (
+                IntBuffer::OnDisk {
+                    file: dir.create_file("ints")?,
+                    len: 0,
+                },
+                ByteBuffer::OnDisk {
+                    file: dir.create_file("packed_ints")?,
+                    len: 0,
+                },
+            ),
+            None => (IntBuffer::default(), ByteBuffer::default()),
+        };
+        Ok(BufferedData {
+            all_ints,
+            packed_ints,
+            ..Default::default()
+        })
+    }
+
     fn add_string(&mut self, s: &str) -> (usize, usize) {
         let bytes = s.as_bytes();
         let all_strings = &mut self.all_strings;
@@ -158,74 +784,156 @@ impl BufferedData {
         (*offset, bytes.len())
     }
 
-    fn save(&self, writer: &mut dyn Write) -> Result<(), Error> {
-        writer.write_u32::<LittleEndian>(to_u32_safe(self.all_ints.len())?)?;
-        for i in &self.all_ints {
-            writer.write_u32::<LittleEndian>(*i)?;
-        }
+    fn save(&mut self, writer: &mut SectionWriter) -> Result<(), Error> {
+        self.all_ints.save(writer)?;
+        writer.finish_section(Section::Ints);
+        self.packed_ints.save(writer)?;
+        writer.finish_section(Section::PackedInts);
         writer.write_all(&self.all_strings)?;
+        writer.finish_section(Section::Strings);
         Ok(())
     }
 }
 
-fn get_tile_references(entity_storages: &EntityStorages) -> TileIdToReferences {
-    let mut result = TileIdToReferences::default();
+fn get_tile_references(
+    entity_storages: &EntityStorages,
+    known_tile_references: Vec<TileReference>,
+    is_known: &dyn Fn(u8, usize) -> bool,
+    computed_tiles: &mut BTreeSet<(u8, u32, u32)>,
+) -> Result<TileReferences, Error> {
+    let mut result = TileReferenceSorter {
+        sorter: ExternalSorter::new(entity_storages.spill_dir.clone()),
+        computed_tiles,
+        index_zoom: entity_storages.index_zoom,
+    };
+    for tile_ref in known_tile_references {
+        result.sorter.push(tile_ref)?;
+    }
 
-    let nodes = &entity_storages.node_storage.get_entities();
-    for (i, node) in nodes.iter().enumerate() {
-        result.tile_ref_by_node(node).local_node_ids.insert(i);
+    let nodes = &entity_storages.node_storage;
+    for i in 0..nodes.len() {
+        if !is_known(NODE_REFS_IDX, i) {
+            let node_coords = iter::once(nodes.get_coords(i));
+            let min_zoom = entity_storages.min_zoom(NODE_REFS_IDX, i);
+            insert_entity_id_to_tiles(&mut result, node_coords, NODE_REFS_IDX, min_zoom, i)?;
+        }
     }
 
     for (i, way) in entity_storages.way_storage.get_entities().iter().enumerate() {
-        let node_ids = way.node_ids.iter().map(|idx| &nodes[*idx]);
-
-        insert_entity_id_to_tiles(&mut result, node_ids, |x| &mut x.local_way_ids, i);
+        if !is_known(WAY_REFS_IDX, i) {
+            let node_coords = way.node_ids.iter().map(|idx| nodes.get_coords(*idx));
+            let min_zoom = entity_storages.min_zoom(WAY_REFS_IDX, i);
+            insert_entity_id_to_tiles(&mut result, node_coords, WAY_REFS_IDX, min_zoom, i)?;
+        }
     }
 
     let polygons = &entity_storages.polygon_storage;
     for (i, multipolygon) in entity_storages.multipolygon_storage.get_entities().iter().enumerate() {
-        let node_ids = multipolygon
-            .polygon_ids
-            .iter()
-            .flat_map(move |poly_id| polygons[*poly_id].iter())
-            .map(|idx| &nodes[*idx]);
-        insert_entity_id_to_tiles(&mut result, node_ids, |x| &mut x.local_multipolygon_ids, i);
+        if !is_known(MULTIPOLYGON_REFS_IDX, i) {
+            let node_coords = multipolygon
+                .polygon_ids
+                .iter()
+                .flat_map(move |poly_id| polygons[*poly_id].iter())
+                .map(|idx| nodes.get_coords(*idx));
+            let min_zoom = entity_storages.min_zoom(MULTIPOLYGON_REFS_IDX, i);
+            insert_entity_id_to_tiles(&mut result, node_coords, MULTIPOLYGON_REFS_IDX, min_zoom, i)?;
+        }
     }
 
-    result
+    // Relations are referenced from the tiles of their node and way members. The members that are
+    // relations themselves are skipped, as they are usually too large to be drawn as a whole.
+    let ways = entity_storages.way_storage.get_entities();
+    for (i, relation) in entity_storages.relation_storage.get_entities().iter().enumerate() {
+        if is_known(RELATION_REFS_IDX, i) {
+            continue;
+        }
+        let min_zoom = entity_storages.min_zoom(RELATION_REFS_IDX, i);
+        for (kind, local_id, _) in entity_storages.relation_members(relation) {
+            match kind {
+                MemberKind::Node => {
+                    let node_coords = iter::once(nodes.get_coords(local_id));
+                    insert_entity_id_to_tiles(&mut result, node_coords, RELATION_REFS_IDX, min_zoom, i)?;
+                }
+                MemberKind::Way => {
+                    let node_coords = ways[local_id].node_ids.iter().map(|idx| nodes.get_coords(*idx));
+                    insert_entity_id_to_tiles(&mut result, node_coords, RELATION_REFS_IDX, min_zoom, i)?;
+                }
+                MemberKind::Relation => {}
+            }
+        }
+    }
+
+    result.sorter.finish()
+}
+
+struct TileReferenceSorter<'a> {
+    sorter: ExternalSorter<TileReference>,
+    computed_tiles: &'a mut BTreeSet<(u8, u32, u32)>,
+    index_zoom: u8,
+}
+
+impl<'a> TileReferenceSorter<'a> {
+    fn push(&mut self, tile: &tile::Tile, refs_idx: u8, min_zoom: u8, local_id: u32) -> Result<(), Error> {
+        self.computed_tiles.insert((tile.zoom, tile.x, tile.y));
+        self.sorter.push(TileReference::new(tile, refs_idx, min_zoom, local_id))
+    }
 }
 
-fn insert_entity_id_to_tiles<'a, I>(
-    result: &mut TileIdToReferences,
-    mut nodes: I,
-    get_refs: impl Fn(&mut TileReferences) -> &mut BTreeSet<usize>,
+fn insert_entity_id_to_tiles<I>(
+    result: &mut TileReferenceSorter,
+    mut node_coords: I,
+    refs_idx: u8,
+    min_zoom: u8,
     entity_id: usize,
-) where
-    I: Iterator<Item = &'a RawNode>,
+) -> Result<(), Error>
+where
+    I: Iterator<Item = (f64, f64)>,
 {
-    let first_node = match nodes.next() {
+    let first_node = match node_coords.next() {
         Some(n) => n,
-        _ => return,
+        _ => return Ok(()),
     };
 
-    let first_tile = tile::coords_to_max_zoom_tile(first_node);
+    let first_tile = tile::coords_to_max_zoom_tile(&first_node);
     let mut tile_range = tile::TileRange {
         min_x: first_tile.x,
         max_x: first_tile.x,
         min_y: first_tile.y,
         max_y: first_tile.y,
     };
-    for node in nodes {
-        let next_tile = tile::coords_to_max_zoom_tile(node);
+    for node in node_coords {
+        let next_tile = tile::coords_to_max_zoom_tile(&node);
         tile_range.min_x = min(tile_range.min_x, next_tile.x);
         tile_range.max_x = max(tile_range.max_x, next_tile.x);
         tile_range.min_y = min(tile_range.min_y, next_tile.y);
         tile_range.max_y = max(tile_range.max_y, next_tile.y);
     }
+    let local_id = to_u32_safe(entity_id)?;
+    let (zoom, tile_range) = get_index_tile_range(&tile_range, result.index_zoom);
     for x in tile_range.min_x..=tile_range.max_x {
         for y in tile_range.min_y..=tile_range.max_y {
-            get_refs(result.tile_ref_by_xy(x, y)).insert(entity_id);
+            result.push(&tile::Tile { zoom, x, y }, refs_idx, min_zoom, local_id)?;
+        }
+    }
+    Ok(())
+}
+
+// Converts the max zoom tiles covered by an entity to the tiles of the zoom level it should be indexed at.
+fn get_index_tile_range(max_zoom_range: &tile::TileRange, index_zoom: u8) -> (u8, tile::TileRange) {
+    let mut zoom = index_zoom;
+    loop {
+        let shift = tile::MAX_ZOOM - zoom;
+        let range = tile::TileRange {
+            min_x: max_zoom_range.min_x >> shift,
+            max_x: max_zoom_range.max_x >> shift,
+            min_y: max_zoom_range.min_y >> shift,
+            max_y: max_zoom_range.max_y >> shift,
+        };
+        let tile_count = u64::from(range.max_x - range.min_x + 1) * u64::from(range.max_y - range.min_y + 1);
+        if tile_count <= MAX_TILES_PER_ENTITY || zoom == 0 {
+            return (zoom, range);
         }
+        zoom -= 1;
     }
 }
 
@@ -239,6 +947,7 @@ fn to_u32_safe(num: usize) -> Result<u32, Error> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::geodata::importer::RawNode;
     use std::env;
     use std::fs::File;
     use std::io::BufWriter;
@@ -249,58 +958,63 @@ mod tests {
         let mut tile_ids = Vec::new();
 
         {
-            let mut add_tile = |x, y, good| {
+            let mut add_tile = |zoom, x, y, good| {
                 let node_idx = tile_ids.len();
-                tile_ids.push((x, y));
+                tile_ids.push(crate::tile::Tile { zoom, x, y });
                 if good {
                     good_node_ids.push(node_idx as u32);
                 }
             };
 
             // y = {8, 9, 13} are in the range for x = 1
-            add_tile(1, 7, false);
-            add_tile(1, 8, true);
-            add_tile(1, 9, true);
-            add_tile(1, 13, true);
+            add_tile(18, 1, 7, false);
+            add_tile(18, 1, 8, true);
+            add_tile(18, 1, 9, true);
+            add_tile(18, 1, 13, true);
             // y = {10, 11, 15} is in the range for x = 2
-            add_tile(2, 10, true);
-            add_tile(2, 11, true);
-            add_tile(2, 15, true);
-            add_tile(2, 16, false);
-            add_tile(2, 17, false);
+            add_tile(18, 2, 10, true);
+            add_tile(18, 2, 11, true);
+            add_tile(18, 2, 15, true);
+            add_tile(18, 2, 16, false);
+            add_tile(18, 2, 17, false);
             // nothing is in the range for x = 4
-            add_tile(4, 1, false);
-            add_tile(4, 4, false);
+            add_tile(18, 4, 1, false);
+            add_tile(18, 4, 4, false);
             // nothing is in the range for x = 5
-            add_tile(5, 20, false);
-            add_tile(5, 23, false);
-            add_tile(5, 200, false);
+            add_tile(18, 5, 20, false);
+            add_tile(18, 5, 23, false);
+            add_tile(18, 5, 200, false);
             // y = {11, 12, 14} are in the range for x = 7
-            add_tile(7, 6, false);
-            add_tile(7, 11, true);
-            add_tile(7, 12, true);
-            add_tile(7, 14, true);
-            add_tile(7, 16, false);
-            add_tile(7, 17, false);
+            add_tile(18, 7, 6, false);
+            add_tile(18, 7, 11, true);
+            add_tile(18, 7, 12, true);
+            add_tile(18, 7, 14, true);
+            add_tile(18, 7, 16, false);
+            add_tile(18, 7, 17, false);
+            // Lower zoom levels: tiles inside the range, outside of it and covering it
+            add_tile(16, 1, 3, true);
+            add_tile(16, 2, 3, false);
+            add_tile(10, 0, 0, true);
+            add_tile(10, 1, 0, false);
         }
 
-        let mut nodes = Vec::new();
+        let mut entity_storages = EntityStorages::new(None).unwrap();
         for idx in 0..tile_ids.len() {
-            nodes.push(RawNode {
-                global_id: idx as u64,
-                lat: 1.0,
-                lon: 1.0,
-                tags: crate::geodata::importer::RawTags::default(),
-            });
+            entity_storages
+                .add_node(RawNode {
+                    global_id: idx as u64,
+                    lat: 1.0,
+                    lon: 1.0,
+                    tags: crate::geodata::importer::RawTags::default(),
+                })
+                .unwrap();
         }
 
-        let mut tile_refs = TileIdToReferences::default();
-        for (idx, &(x, y)) in tile_ids.iter().enumerate() {
-            tile_refs.refs.entry((x, y)).or_insert(TileReferences {
-                local_node_ids: [idx].iter().cloned().collect(),
-                local_way_ids: BTreeSet::default(),
-                local_multipolygon_ids: BTreeSet::default(),
-            });
+        let mut tile_refs = ExternalSorter::new(None);
+        for (idx, tile) in tile_ids.iter().enumerate() {
+            tile_refs
+                .push(TileReference::new(tile, NODE_REFS_IDX, 0, idx as u32))
+                .unwrap();
         }
 
         let mut tmp_path = env::temp_dir();
@@ -310,19 +1024,14 @@ mod tests {
             let tmp_file = File::create(&tmp_path).unwrap();
             let mut writer = BufWriter::new(tmp_file);
 
-            let mut data = BufferedData::default();
-            save_nodes(&mut writer, &nodes, &mut data).unwrap();
-            save_ways(&mut writer, &[], &mut data).unwrap();
-            save_polygons(&mut writer, &[], &mut data).unwrap();
-            save_multipolygons(&mut writer, &[], &mut data).unwrap();
-            save_tile_references(&mut writer, &tile_refs, &mut data).unwrap();
-            data.save(&mut writer).unwrap();
+            save_with_tile_references(&mut writer, &entity_storages, &mut tile_refs.finish().unwrap()).unwrap();
         }
 
         let reader = crate::geodata::reader::GeodataReader::load(tmp_path.to_str().unwrap()).unwrap();
         let tile = crate::tile::Tile { zoom: 15, x: 0, y: 1 };
         let mut local_ids = crate::geodata::reader::OsmEntityIds::default();
         reader.get_entities_in_tile(&tile, &mut local_ids);
+        local_ids.nodes.sort();
         assert_eq!(good_node_ids, local_ids.nodes);
     }
 }
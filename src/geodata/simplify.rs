@@ -0,0 +1,89 @@
+use crate::tile;
+
+// Ways and polygons are stored along with their simplified copies: the copy for SIMPLIFICATION_ZOOMS[i]
+// is used for all zoom levels from SIMPLIFICATION_ZOOMS[i - 1] + 1 to SIMPLIFICATION_ZOOMS[i] inclusive.
+// The original node lists are used for zoom levels above the last one.
+pub(super) const SIMPLIFICATION_ZOOMS: [u8; 3] = [11, 13, 15];
+
+// The maximum distance between the original and the simplified line, in pixels at the zoom level
+// of the copy. It's less than a pixel so that the simplification is invisible even on high-resolution tiles.
+const TOLERANCE_IN_PIXELS: f64 = 0.25;
+
+pub(super) fn get_simplification_idx(zoom: u8) -> Option<usize> {
+    SIMPLIFICATION_ZOOMS.iter().position(|z| zoom <= *z)
+}
+
+// Returns the indices of the nodes that are left after simplifying the line for a given zoom level
+// with the Douglas-Peucker algorithm. The first and the last nodes are always kept.
+pub(super) fn simplify(coords: &[(f64, f64)], zoom: u8) -> Vec<usize> {
+    if coords.len() <= 2 {
+        return (0..coords.len()).collect();
+    }
+
+    let points = coords.iter().map(|c| tile::coords_to_xy(c, zoom)).collect::<Vec<_>>();
+    let mut keep = vec![false; points.len()];
+    keep[0] = true;
+    keep[points.len() - 1] = true;
+
+    let mut ranges = vec![(0, points.len() - 1)];
+    while let Some((first, last)) = ranges.pop() {
+        let farthest = (first + 1..last)
+            .map(|idx| (idx, distance_to_segment(points[idx], points[first], points[last])))
+            .max_by(|(_, d1), (_, d2)| d1.partial_cmp(d2).unwrap());
+        if let Some((idx, distance)) = farthest {
+            if distance > TOLERANCE_IN_PIXELS {
+                keep[idx] = true;
+                ranges.push((first, idx));
+                ranges.push((idx, last));
+            }
+        }
+    }
+
+    (0..points.len()).filter(|idx| keep[*idx]).collect()
+}
+
+fn distance_to_segment(p: (f64, f64), start: (f64, f64), end: (f64, f64)) -> f64 {
+    let (dx, dy) = (end.0 - start.0, end.1 - start.1);
+    let length_squared = dx * dx + dy * dy;
+    let t = if length_squared > 0.0 {
+        (((p.0 - start.0) * dx + (p.1 - start.1) * dy) / length_squared).clamp(0.0, 1.0)
+    } else {
+        0.0
+    };
+    let (closest_x, closest_y) = (start.0 + t * dx, start.1 + t * dy);
+    ((p.0 - closest_x).powi(2) + (p.1 - closest_y).powi(2)).sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_simplification_idx() {
+        assert_eq!(get_simplification_idx(5), Some(0));
+        assert_eq!(get_simplification_idx(11), Some(0));
+        assert_eq!(get_simplification_idx(12), Some(1));
+        assert_eq!(get_simplification_idx(15), Some(2));
+        assert_eq!(get_simplification_idx(16), None);
+    }
+
+    #[test]
+    fn test_simplify() {
+        // A zigzag that is much smaller than a pixel at zoom 11, but not at zoom 18.
+        let coords = (0..10)
+            .map(|idx| (55.75 + 0.000_01 * f64::from(idx % 2), 37.6 + 0.0001 * f64::from(idx)))
+            .collect::<Vec<_>>();
+        assert_eq!(simplify(&coords, 11), vec![0, 9]);
+        assert_eq!(simplify(&coords, 18).len(), 10);
+
+        // A closed ring keeps its shape.
+        let ring = vec![
+            (55.75, 37.6),
+            (55.75, 37.7),
+            (55.76, 37.7),
+            (55.76, 37.6),
+            (55.75, 37.6),
+        ];
+        assert_eq!(simplify(&ring, 11), vec![0, 1, 2, 3, 4]);
+    }
+}
@@ -0,0 +1,556 @@
+use crate::geodata::importer::{RawNode, RawTags};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use failure::{bail, Error, ResultExt};
+use memmap::{Mmap, MmapOptions};
+use std::cell::Cell;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// How much memory a single in-memory chunk of an external sort can take before it's spilled to disk.
+const SORT_CHUNK_SIZE_IN_BYTES: usize = 128 * 1024 * 1024;
+
+/// A uniquely named directory for the temporary files of a single import run.
+/// It's removed with everything inside when the last reference to it goes away.
+pub(super) struct SpillDir {
+    path: PathBuf,
+    file_counter: Cell<usize>,
+}
+
+impl SpillDir {
+    pub(super) fn new(parent: &Path) -> Result<Rc<SpillDir>, Error> {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.subsec_nanos())
+            .unwrap_or_default();
+        let path = parent.join(format!("osm-renderer-import-{}-{}", std::process::id(), nanos));
+        fs::create_dir_all(&path).context(format!("Failed to create temporary directory {}", path.display()))?;
+        Ok(Rc::new(SpillDir {
+            path,
+            file_counter: Cell::new(0),
+        }))
+    }
+
+    pub(super) fn create_file(self: &Rc<Self>, name: &str) -> Result<TempFile, Error> {
+        let idx = self.file_counter.get();
+        self.file_counter.set(idx + 1);
+        let path = self.path.join(format!("{}-{}.tmp", name, idx));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .context(format!("Failed to create temporary file {}", path.display()))?;
+        Ok(TempFile {
+            path,
+            writer: Some(BufWriter::new(file)),
+            size: 0,
+            _dir: Rc::clone(self),
+        })
+    }
+}
+
+impl Drop for SpillDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+/// An append-only temporary file that can be memory-mapped or re-read once writing is done.
+pub(super) struct TempFile {
+    path: PathBuf,
+    writer: Option<BufWriter<File>>,
+    size: u64,
+    _dir: Rc<SpillDir>,
+}
+
+impl TempFile {
+    pub(super) fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub(super) fn finish_writing(&mut self) -> Result<(), Error> {
+        if let Some(mut writer) = self.writer.take() {
+            writer
+                .flush()
+                .context(format!("Failed to write temporary file {}", self.path.display()))?;
+        }
+        Ok(())
+    }
+
+    /// Maps the file to memory. Empty files can't be mapped, so `None` is returned for them.
+    pub(super) fn map_to_memory(&mut self) -> Result<Option<Mmap>, Error> {
+        self.finish_writing()?;
+        if self.size == 0 {
+            return Ok(None);
+        }
+        let file = File::open(&self.path)?;
+        let mmap = unsafe { MmapOptions::new().map(&file) }.context(format!(
+            "Failed to map temporary file {} to memory",
+            self.path.display()
+        ))?;
+        Ok(Some(mmap))
+    }
+
+    pub(super) fn open_reader(&mut self) -> Result<BufReader<File>, Error> {
+        self.finish_writing()?;
+        let file = File::open(&self.path).context(format!("Failed to open temporary file {}", self.path.display()))?;
+        Ok(BufReader::new(file))
+    }
+}
+
+impl Write for TempFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let writer = match self.writer.as_mut() {
+            Some(writer) => writer,
+            None => return Err(io::Error::other("the temporary file is already closed for writing")),
+        };
+        let written = writer.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.writer.as_mut() {
+            Some(writer) => writer.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        self.writer = None;
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// A record that can be stored in a temporary file as a fixed number of bytes.
+pub(super) trait FixedSizeRecord: Ord + Sized {
+    const SIZE: usize;
+    fn write_to(&self, writer: &mut dyn Write) -> io::Result<()>;
+    fn read_from(bytes: &[u8]) -> Self;
+}
+
+/// Sorts a sequence of records that doesn't necessarily fit into memory.
+/// Without a spill directory, everything is kept in memory and the sorter is just a vector.
+pub(super) struct ExternalSorter<T: FixedSizeRecord> {
+    spill_dir: Option<Rc<SpillDir>>,
+    chunk: Vec<T>,
+    runs: Vec<TempFile>,
+}
+
+impl<T: FixedSizeRecord> ExternalSorter<T> {
+    pub(super) fn new(spill_dir: Option<Rc<SpillDir>>) -> ExternalSorter<T> {
+        ExternalSorter {
+            spill_dir,
+            chunk: Vec::new(),
+            runs: Vec::new(),
+        }
+    }
+
+    pub(super) fn push(&mut self, record: T) -> Result<(), Error> {
+        self.chunk.push(record);
+        if self.spill_dir.is_some() && self.chunk.len() * T::SIZE >= SORT_CHUNK_SIZE_IN_BYTES {
+            self.spill_chunk()?;
+        }
+        Ok(())
+    }
+
+    pub(super) fn finish(mut self) -> Result<SortedRecords<T>, Error> {
+        self.chunk.sort_unstable();
+        self.chunk.dedup();
+        for run in &mut self.runs {
+            run.finish_writing()?;
+        }
+        Ok(SortedRecords {
+            last_chunk: self.chunk,
+            runs: self.runs,
+        })
+    }
+
+    fn spill_chunk(&mut self) -> Result<(), Error> {
+        let spill_dir = match self.spill_dir {
+            Some(ref dir) => dir,
+            None => return Ok(()),
+        };
+        self.chunk.sort_unstable();
+        self.chunk.dedup();
+        let mut run = spill_dir.create_file("sort-run")?;
+        for record in self.chunk.drain(..) {
+            record.write_to(&mut run)?;
+        }
+        run.finish_writing()?;
+        self.runs.push(run);
+        Ok(())
+    }
+}
+
+/// The result of an external sort. It can be iterated over several times; every iteration
+/// yields the records in ascending order without duplicates.
+pub(super) struct SortedRecords<T: FixedSizeRecord> {
+    last_chunk: Vec<T>,
+    runs: Vec<TempFile>,
+}
+
+impl<T: FixedSizeRecord + Clone> SortedRecords<T> {
+    pub(super) fn iter(&mut self) -> Result<MergedRecords<'_, T>, Error> {
+        let mut sources = Vec::new();
+        for run in &mut self.runs {
+            sources.push(RecordSource::Run(run.open_reader()?));
+        }
+        sources.push(RecordSource::Chunk(self.last_chunk.iter()));
+
+        let mut heap = BinaryHeap::new();
+        for (idx, source) in sources.iter_mut().enumerate() {
+            if let Some(record) = source.next_record()? {
+                heap.push(Reverse((record, idx)));
+            }
+        }
+
+        Ok(MergedRecords {
+            sources,
+            heap,
+            last: None,
+        })
+    }
+}
+
+enum RecordSource<'a, T> {
+    Run(BufReader<File>),
+    Chunk(std::slice::Iter<'a, T>),
+}
+
+impl<'a, T: FixedSizeRecord + Clone> RecordSource<'a, T> {
+    fn next_record(&mut self) -> Result<Option<T>, Error> {
+        match self {
+            RecordSource::Chunk(iter) => Ok(iter.next().cloned()),
+            RecordSource::Run(reader) => {
+                let mut buf = vec![0; T::SIZE];
+                match reader.read_exact(&mut buf) {
+                    Ok(()) => Ok(Some(T::read_from(&buf))),
+                    Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+                    Err(e) => Err(Error::from(e)),
+                }
+            }
+        }
+    }
+}
+
+pub(super) struct MergedRecords<'a, T: FixedSizeRecord> {
+    sources: Vec<RecordSource<'a, T>>,
+    heap: BinaryHeap<Reverse<(T, usize)>>,
+    last: Option<T>,
+}
+
+impl<'a, T: FixedSizeRecord + Clone> MergedRecords<'a, T> {
+    fn next_record(&mut self) -> Result<Option<T>, Error> {
+        while let Some(Reverse((record, idx))) = self.heap.pop() {
+            if let Some(next) = self.sources[idx].next_record()? {
+                self.heap.push(Reverse((next, idx)));
+            }
+            // Different runs can contain the same record.
+            if self.last.as_ref() != Some(&record) {
+                self.last = Some(record.clone());
+                return Ok(Some(record));
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl<'a, T: FixedSizeRecord + Clone> Iterator for MergedRecords<'a, T> {
+    type Item = Result<T, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_record().transpose()
+    }
+}
+
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd)]
+pub(super) struct IdPair {
+    pub(super) global_id: u64,
+    pub(super) local_id: u64,
+}
+
+impl FixedSizeRecord for IdPair {
+    const SIZE: usize = 16;
+
+    fn write_to(&self, writer: &mut dyn Write) -> io::Result<()> {
+        writer.write_u64::<LittleEndian>(self.global_id)?;
+        writer.write_u64::<LittleEndian>(self.local_id)
+    }
+
+    fn read_from(mut bytes: &[u8]) -> IdPair {
+        IdPair {
+            global_id: bytes.read_u64::<LittleEndian>().unwrap(),
+            local_id: bytes.read_u64::<LittleEndian>().unwrap(),
+        }
+    }
+}
+
+enum IdTableState {
+    Collecting(ExternalSorter<IdPair>),
+    Finished { _file: TempFile, mmap: Option<Mmap> },
+}
+
+/// Translates global OSM ids to local ones with a binary search over a sorted temporary file.
+/// All ids have to be added before the first lookup.
+pub(super) struct DiskIdTable {
+    spill_dir: Rc<SpillDir>,
+    state: IdTableState,
+}
+
+impl DiskIdTable {
+    pub(super) fn new(spill_dir: &Rc<SpillDir>) -> DiskIdTable {
+        DiskIdTable {
+            spill_dir: Rc::clone(spill_dir),
+            state: IdTableState::Collecting(ExternalSorter::new(Some(Rc::clone(spill_dir)))),
+        }
+    }
+
+    pub(super) fn add(&mut self, global_id: u64, local_id: usize) -> Result<(), Error> {
+        match self.state {
+            IdTableState::Collecting(ref mut sorter) => sorter.push(IdPair {
+                global_id,
+                local_id: local_id as u64,
+            }),
+            IdTableState::Finished { .. } => bail!("Can't add object #{} after the id table was finished", global_id),
+        }
+    }
+
+    pub(super) fn finish(&mut self) -> Result<(), Error> {
+        let sorter = match self.state {
+            IdTableState::Collecting(ref mut sorter) => std::mem::replace(sorter, ExternalSorter::new(None)),
+            IdTableState::Finished { .. } => return Ok(()),
+        };
+        let mut sorted = sorter.finish()?;
+        let mut file = self.spill_dir.create_file("ids")?;
+        for pair in sorted.iter()? {
+            pair?.write_to(&mut file)?;
+        }
+        let mmap = file.map_to_memory()?;
+        self.state = IdTableState::Finished { _file: file, mmap };
+        Ok(())
+    }
+
+    pub(super) fn get(&self, global_id: u64) -> Option<usize> {
+        let bytes = match self.state {
+            IdTableState::Finished {
+                mmap: Some(ref mmap), ..
+            } => &mmap[..],
+            _ => return None,
+        };
+        let count = bytes.len() / IdPair::SIZE;
+        let get_pair = |idx: usize| IdPair::read_from(&bytes[idx * IdPair::SIZE..(idx + 1) * IdPair::SIZE]);
+        // The pairs with the same global id are sorted by the local id, so the last one is the object
+        // that was added last, like with the in-memory table. Find the first pair after it.
+        let (mut lo, mut hi) = (0, count);
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            if get_pair(mid).global_id <= global_id {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+        lo.checked_sub(1)
+            .map(get_pair)
+            .filter(|pair| pair.global_id == global_id)
+            .map(|pair| pair.local_id as usize)
+    }
+}
+
+const NODE_RECORD_SIZE: usize = 5 * 8;
+
+/// Node records with a fixed size (the id, the coordinates and a reference to the tags)
+/// plus a separate file for the variable-length tags.
+pub(super) struct DiskNodeList {
+    records: TempFile,
+    tags: TempFile,
+    count: usize,
+    mapped: Option<(Option<Mmap>, Option<Mmap>)>,
+}
+
+impl DiskNodeList {
+    pub(super) fn new(spill_dir: &Rc<SpillDir>) -> Result<DiskNodeList, Error> {
+        Ok(DiskNodeList {
+            records: spill_dir.create_file("nodes")?,
+            tags: spill_dir.create_file("node-tags")?,
+            count: 0,
+            mapped: None,
+        })
+    }
+
+    pub(super) fn add(&mut self, node: &RawNode) -> Result<(), Error> {
+        if self.mapped.is_some() {
+            bail!("Can't add node #{} after the node list was finished", node.global_id);
+        }
+        let tags_offset = self.tags.size();
+        for (k, v) in &node.tags {
+            for s in &[k, v] {
+                self.tags.write_u32::<LittleEndian>(s.len() as u32)?;
+                self.tags.write_all(s.as_bytes())?;
+            }
+        }
+        self.records.write_u64::<LittleEndian>(node.global_id)?;
+        self.records.write_f64::<LittleEndian>(node.lat)?;
+        self.records.write_f64::<LittleEndian>(node.lon)?;
+        self.records.write_u64::<LittleEndian>(tags_offset)?;
+        self.records.write_u64::<LittleEndian>(self.tags.size() - tags_offset)?;
+        self.count += 1;
+        Ok(())
+    }
+
+    pub(super) fn finish(&mut self) -> Result<(), Error> {
+        if self.mapped.is_none() {
+            self.mapped = Some((self.records.map_to_memory()?, self.tags.map_to_memory()?));
+        }
+        Ok(())
+    }
+
+    pub(super) fn len(&self) -> usize {
+        self.count
+    }
+
+    pub(super) fn coords(&self, idx: usize) -> (f64, f64) {
+        let mut record = &self.record(idx)[8..];
+        let lat = record.read_f64::<LittleEndian>().unwrap();
+        let lon = record.read_f64::<LittleEndian>().unwrap();
+        (lat, lon)
+    }
+
+    pub(super) fn get(&self, idx: usize) -> RawNode {
+        let mut record = self.record(idx);
+        let global_id = record.read_u64::<LittleEndian>().unwrap();
+        let lat = record.read_f64::<LittleEndian>().unwrap();
+        let lon = record.read_f64::<LittleEndian>().unwrap();
+        let tags_offset = record.read_u64::<LittleEndian>().unwrap() as usize;
+        let tags_len = record.read_u64::<LittleEndian>().unwrap() as usize;
+
+        let mut tags = RawTags::default();
+        if tags_len > 0 {
+            let all_tags = match self.mapped {
+                Some((_, Some(ref mmap))) => &mmap[..],
+                _ => panic!("node tags are not mapped to memory"),
+            };
+            let mut tag_bytes = &all_tags[tags_offset..tags_offset + tags_len];
+            while !tag_bytes.is_empty() {
+                let k = read_tag_string(&mut tag_bytes);
+                let v = read_tag_string(&mut tag_bytes);
+                tags.insert(k, v);
+            }
+        }
+
+        RawNode {
+            global_id,
+            lat,
+            lon,
+            tags,
+        }
+    }
+
+    fn record(&self, idx: usize) -> &[u8] {
+        match self.mapped {
+            Some((Some(ref mmap), _)) => &mmap[idx * NODE_RECORD_SIZE..(idx + 1) * NODE_RECORD_SIZE],
+            _ => panic!("node #{} is requested before the node list is mapped to memory", idx),
+        }
+    }
+}
+
+fn read_tag_string(bytes: &mut &[u8]) -> String {
+    let len = bytes.read_u32::<LittleEndian>().unwrap() as usize;
+    let (s, rest) = bytes.split_at(len);
+    *bytes = rest;
+    String::from_utf8_lossy(s).into_owned()
+}
+
+/// Copies the whole contents of a temporary file to `writer`.
+pub(super) fn copy_temp_file(file: &mut TempFile, writer: &mut dyn Write) -> Result<(), Error> {
+    let mut reader = file.open_reader()?;
+    io::copy(&mut reader, writer)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
+    struct TestRecord(u32);
+
+    impl FixedSizeRecord for TestRecord {
+        const SIZE: usize = 4;
+
+        fn write_to(&self, writer: &mut dyn Write) -> io::Result<()> {
+            writer.write_u32::<LittleEndian>(self.0)
+        }
+
+        fn read_from(mut bytes: &[u8]) -> TestRecord {
+            TestRecord(bytes.read_u32::<LittleEndian>().unwrap())
+        }
+    }
+
+    #[test]
+    fn test_external_sort_with_spilled_runs() {
+        let spill_dir = SpillDir::new(&std::env::temp_dir()).unwrap();
+        let mut sorter = ExternalSorter::new(Some(Rc::clone(&spill_dir)));
+        let values = [5, 3, 9, 3, 1, 7, 5, 2, 8, 8];
+        for chunk in values.chunks(3) {
+            for v in chunk {
+                sorter.push(TestRecord(*v)).unwrap();
+            }
+            sorter.spill_chunk().unwrap();
+        }
+        sorter.push(TestRecord(4)).unwrap();
+        sorter.push(TestRecord(1)).unwrap();
+
+        let mut sorted = sorter.finish().unwrap();
+        let expected = vec![1, 2, 3, 4, 5, 7, 8, 9];
+        for _ in 0..2 {
+            let actual = sorted.iter().unwrap().map(|r| r.unwrap().0).collect::<Vec<_>>();
+            assert_eq!(actual, expected);
+        }
+    }
+
+    #[test]
+    fn test_disk_id_table() {
+        let spill_dir = SpillDir::new(&std::env::temp_dir()).unwrap();
+        let dir_path = spill_dir.path.clone();
+        {
+            let mut table = DiskIdTable::new(&spill_dir);
+            for (local_id, global_id) in [100, 5, 42, 7].iter().enumerate() {
+                table.add(*global_id, local_id).unwrap();
+            }
+            assert_eq!(table.get(5), None);
+            table.finish().unwrap();
+            assert_eq!(table.get(100), Some(0));
+            assert_eq!(table.get(42), Some(2));
+            assert_eq!(table.get(7), Some(3));
+            assert_eq!(table.get(8), None);
+            assert!(table.add(1, 4).is_err());
+        }
+        drop(spill_dir);
+        assert!(!dir_path.exists());
+    }
+
+    #[test]
+    fn test_disk_id_table_with_duplicates() {
+        let spill_dir = SpillDir::new(&std::env::temp_dir()).unwrap();
+        let mut table = DiskIdTable::new(&spill_dir);
+        for (local_id, global_id) in [5, 42, 5, 7, 42, 5].iter().enumerate() {
+            table.add(*global_id, local_id).unwrap();
+        }
+        table.finish().unwrap();
+        // Like with the in-memory table, the object that was added last wins.
+        assert_eq!(table.get(5), Some(5));
+        assert_eq!(table.get(42), Some(4));
+        assert_eq!(table.get(7), Some(3));
+        assert_eq!(table.get(4), None);
+        assert_eq!(table.get(43), None);
+    }
+}
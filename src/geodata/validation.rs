@@ -0,0 +1,168 @@
+use crate::geodata::inspect::json_string;
+use failure::{Error, ResultExt};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub(super) enum MultipolygonProblem {
+    /// Some ring ends are not connected to anything. Such relations are dropped.
+    UnclosedRing,
+    /// The rings cross each other or themselves.
+    SelfIntersection,
+    /// The same segment is used more than once. Such segments cancel each other out.
+    DuplicateSegment,
+    /// A member way is not present in the input.
+    MissingMemberWay,
+}
+
+impl MultipolygonProblem {
+    fn name(self) -> &'static str {
+        match self {
+            MultipolygonProblem::UnclosedRing => "unclosed_ring",
+            MultipolygonProblem::SelfIntersection => "self_intersection",
+            MultipolygonProblem::DuplicateSegment => "duplicate_segment",
+            MultipolygonProblem::MissingMemberWay => "missing_member_way",
+        }
+    }
+}
+
+impl fmt::Display for MultipolygonProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// A problem with a multipolygon relation. `count` is the number of places where the problem occurs,
+/// and `location` is the approximate (lat, lon) of the first of them, if it is known.
+#[derive(Clone, Debug, PartialEq)]
+pub(super) struct MultipolygonIssue {
+    pub(super) relation_id: u64,
+    pub(super) problem: MultipolygonProblem,
+    pub(super) count: usize,
+    pub(super) location: Option<(f64, f64)>,
+    /// Whether the relation is missing from the imported data because of this problem.
+    pub(super) dropped: bool,
+}
+
+#[derive(Default)]
+pub(super) struct ValidationReport {
+    issues: Vec<MultipolygonIssue>,
+}
+
+impl ValidationReport {
+    pub(super) fn add(
+        &mut self,
+        relation_id: u64,
+        problem: MultipolygonProblem,
+        count: usize,
+        location: Option<(f64, f64)>,
+        dropped: bool,
+    ) {
+        self.issues.push(MultipolygonIssue {
+            relation_id,
+            problem,
+            count,
+            location,
+            dropped,
+        });
+    }
+
+    #[cfg(test)]
+    pub(super) fn issues(&self) -> &[MultipolygonIssue] {
+        &self.issues
+    }
+
+    // The number of relations with every kind of problem.
+    fn problem_counts(&self) -> BTreeMap<MultipolygonProblem, usize> {
+        let mut counts = BTreeMap::new();
+        for issue in &self.issues {
+            *counts.entry(issue.problem).or_default() += 1;
+        }
+        counts
+    }
+
+    fn dropped_count(&self) -> usize {
+        self.issues.iter().filter(|issue| issue.dropped).count()
+    }
+
+    pub(super) fn summary(&self) -> String {
+        let counts = self
+            .problem_counts()
+            .into_iter()
+            .map(|(problem, count)| format!("{} {}", count, problem))
+            .collect::<Vec<_>>();
+        if counts.is_empty() {
+            "No multipolygon problems found".to_string()
+        } else {
+            format!(
+                "Multipolygon problems: {} ({} relations dropped)",
+                counts.join(", "),
+                self.dropped_count()
+            )
+        }
+    }
+
+    pub(super) fn save(&self, file_name: &str) -> Result<(), Error> {
+        let file = File::create(file_name).context(format!("Failed to open {} for writing", file_name))?;
+        let mut writer = BufWriter::new(file);
+        // The format is chosen by the file extension.
+        if file_name.to_lowercase().ends_with(".csv") {
+            self.write_csv(&mut writer)
+        } else {
+            self.write_json(&mut writer)
+        }
+        .context(format!("Failed to write the validation report to {}", file_name))?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    fn write_json(&self, writer: &mut dyn Write) -> Result<(), Error> {
+        writeln!(writer, "{{\"issues\":[")?;
+        for (idx, issue) in self.issues.iter().enumerate() {
+            let location = match issue.location {
+                Some((lat, lon)) => format!("{{\"lat\":{:.7},\"lon\":{:.7}}}", lat, lon),
+                None => "null".to_string(),
+            };
+            writeln!(
+                writer,
+                "{{\"relation_id\":{},\"problem\":{},\"count\":{},\"location\":{},\"dropped\":{}}}{}",
+                issue.relation_id,
+                json_string(issue.problem.name()),
+                issue.count,
+                location,
+                issue.dropped,
+                if idx + 1 < self.issues.len() { "," } else { "" }
+            )?;
+        }
+        let counts = self
+            .problem_counts()
+            .into_iter()
+            .map(|(problem, count)| format!("{}:{}", json_string(problem.name()), count))
+            .collect::<Vec<_>>();
+        writeln!(
+            writer,
+            "],\"summary\":{{\"problems\":{{{}}},\"dropped_relations\":{}}}}}",
+            counts.join(","),
+            self.dropped_count()
+        )?;
+        Ok(())
+    }
+
+    fn write_csv(&self, writer: &mut dyn Write) -> Result<(), Error> {
+        writeln!(writer, "relation_id,problem,count,lat,lon,dropped")?;
+        for issue in &self.issues {
+            let (lat, lon) = match issue.location {
+                Some((lat, lon)) => (format!("{:.7}", lat), format!("{:.7}", lon)),
+                None => (String::new(), String::new()),
+            };
+            writeln!(
+                writer,
+                "{},{},{},{},{},{}",
+                issue.relation_id, issue.problem, issue.count, lat, lon, issue.dropped
+            )?;
+        }
+        Ok(())
+    }
+}
@@ -1,17 +1,16 @@
 use crate::draw::drawer::Drawer;
 use crate::draw::tile_pixels::TilePixels;
-use crate::geodata::reader::GeodataReader;
-use crate::mapcss::parser::parse_file;
+use crate::geodata::multi_reader::MultiGeodataReader;
+use crate::mapcss::parser::{parse_file, split_stylesheet_path};
 use crate::mapcss::styler::{StyleType, Styler};
 use crate::perf_stats::PerfStats;
 use crate::tile::{Tile, MAX_ZOOM};
-use failure::{bail, format_err, Error, ResultExt};
+use failure::{bail, Error, ResultExt};
 use num_cpus;
 use std::collections::HashSet;
 use std::io::prelude::*;
 use std::io::BufReader;
 use std::net::{TcpListener, TcpStream};
-use std::path::PathBuf;
 use std::sync::mpsc;
 use std::sync::mpsc::{Receiver, Sender};
 use std::sync::Arc;
@@ -31,7 +30,7 @@ struct HandlerState {
 #[cfg_attr(feature = "cargo-clippy", allow(clippy::implicit_hasher))]
 pub fn run_server(
     address: &str,
-    geodata_file: &str,
+    geodata_files: &[String],
     stylesheet_file: &str,
     stylesheet_type: &StyleType,
     font_size_multiplier: Option<f64>,
@@ -42,7 +41,7 @@ pub fn run_server(
 
     let server = Arc::new(HttpServer {
         styler: Styler::new(rules, stylesheet_type, font_size_multiplier),
-        reader: GeodataReader::load(geodata_file).context("Failed to load the geodata file")?,
+        reader: MultiGeodataReader::load(geodata_files).context("Failed to load the geodata files")?,
         drawer: Drawer::new(&base_path),
         osm_ids,
         perf_stats: Mutex::new(PerfStats::default()),
@@ -119,7 +118,7 @@ pub fn run_server(
 
 struct HttpServer<'a> {
     styler: Styler,
-    reader: GeodataReader<'a>,
+    reader: MultiGeodataReader<'a>,
     drawer: Drawer,
     osm_ids: Option<HashSet<u64>>,
     perf_stats: Mutex<PerfStats>,
@@ -267,16 +266,6 @@ fn extract_tile_from_path(path: &str) -> Option<RequestTile> {
     }
 }
 
-fn split_stylesheet_path(file_path: &str) -> Result<(PathBuf, String), Error> {
-    let mut result = PathBuf::from(file_path);
-    let file_name = result
-        .file_name()
-        .and_then(|x| x.to_str().map(ToString::to_string))
-        .ok_or_else(|| format_err!("Failed to extract the file name for {}", file_path))?;
-    result.pop();
-    Ok((result, file_name))
-}
-
 fn peer_addr(stream: &TcpStream) -> String {
     stream
         .peer_addr()
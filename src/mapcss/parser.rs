@@ -2,14 +2,14 @@ use crate::mapcss::color::Color;
 use crate::mapcss::token::{InputPosition, Token, TokenWithPosition, Tokenizer};
 use crate::mapcss::MapcssError;
 
-use failure::{Error, ResultExt};
+use failure::{format_err, Error, ResultExt};
 use std::collections::HashMap;
 use std::fmt;
 use std::fs::File;
 use std::io::prelude::*;
 use std::path::{Path, PathBuf};
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum ObjectType {
     All,
     Canvas,
@@ -17,6 +17,7 @@ pub enum ObjectType {
     Node,
     Way,
     Area,
+    Relation,
 }
 
 impl fmt::Display for ObjectType {
@@ -28,6 +29,7 @@ impl fmt::Display for ObjectType {
             ObjectType::Node => "node",
             ObjectType::Way => "way",
             ObjectType::Area => "area",
+            ObjectType::Relation => "relation",
         };
         write!(f, "{}", object_type)
     }
@@ -156,6 +158,43 @@ impl fmt::Display for Property {
     }
 }
 
+#[derive(Debug, PartialEq)]
+pub enum LinkType {
+    // `parent > entity`
+    Parent,
+    // `child < entity`
+    Child,
+}
+
+/// The part of a JOSM link selector that describes the parent or the child of the selected entity,
+/// e.g. `relation[type=route] >[role=stop]` in `relation[type=route] >[role=stop] node`. The role tests
+/// only look at the `role` key, which holds the role of the child in the parent (empty for the nodes of
+/// a way).
+#[derive(Debug)]
+pub struct Link {
+    pub link_type: LinkType,
+    pub object_type: ObjectType,
+    pub tests: Vec<Test>,
+    pub role_tests: Vec<Test>,
+}
+
+impl fmt::Display for Link {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}{} {}{}",
+            self.object_type,
+            self.tests.iter().map(fmt_item::<Test>).collect::<Vec<_>>().join(""),
+            if self.link_type == LinkType::Parent { ">" } else { "<" },
+            self.role_tests
+                .iter()
+                .map(fmt_item::<Test>)
+                .collect::<Vec<_>>()
+                .join(""),
+        )
+    }
+}
+
 #[derive(Debug)]
 pub struct Selector {
     pub object_type: ObjectType,
@@ -163,6 +202,7 @@ pub struct Selector {
     pub max_zoom: Option<u8>,
     pub tests: Vec<Test>,
     pub layer_id: Option<String>,
+    pub link: Option<Link>,
 }
 
 impl fmt::Display for Selector {
@@ -183,6 +223,9 @@ impl fmt::Display for Selector {
             Some(ref id) => format!("::{}", id),
             None => String::new(),
         };
+        if let Some(ref link) = self.link {
+            write!(f, "{} ", link)?;
+        }
         write!(
             f,
             "{}{}{}{}{}",
@@ -231,6 +274,18 @@ pub fn parse_file(base_path: &Path, file_name: &str) -> Result<Vec<Rule>, Error>
     parser.parse()
 }
 
+/// Splits the path to a stylesheet into the base directory (against which imports and icons
+/// are resolved) and the file name.
+pub fn split_stylesheet_path(file_path: &str) -> Result<(PathBuf, String), Error> {
+    let mut result = PathBuf::from(file_path);
+    let file_name = result
+        .file_name()
+        .and_then(|x| x.to_str().map(ToString::to_string))
+        .ok_or_else(|| format_err!("Failed to extract the file name for {}", file_path))?;
+    result.pop();
+    Ok((result, file_name))
+}
+
 type ColorDefs = HashMap<String, Color>;
 
 struct Parser<'a> {
@@ -329,21 +384,7 @@ impl<'a> Parser<'a> {
     }
 
     fn read_selector(&mut self, selector_first_token: &TokenWithPosition<'a>) -> Result<ConsumedSelector, Error> {
-        let mut selector = match selector_first_token.token {
-            Token::Identifier(id) => {
-                let object_type = id_to_object_type(id).ok_or_else(|| {
-                    self.parse_error(format!("Unknown object type: {}", id), selector_first_token.position)
-                })?;
-                Selector {
-                    object_type,
-                    min_zoom: None,
-                    max_zoom: None,
-                    tests: Vec::new(),
-                    layer_id: None,
-                }
-            }
-            _ => return self.unexpected_token(selector_first_token),
-        };
+        let mut selector = self.start_selector(selector_first_token)?;
 
         loop {
             let current_token = self.read_mandatory_token()?;
@@ -371,6 +412,14 @@ impl<'a> Parser<'a> {
                 Token::DoubleColon => {
                     selector.layer_id = Some(self.read_identifier()?);
                 }
+                Token::Greater | Token::Less => {
+                    let link_type = if current_token.token == Token::Greater {
+                        LinkType::Parent
+                    } else {
+                        LinkType::Child
+                    };
+                    selector = self.read_link(selector, link_type, current_token.position)?;
+                }
                 _ => return self.unexpected_token(&current_token),
             }
 
@@ -383,6 +432,66 @@ impl<'a> Parser<'a> {
         }
     }
 
+    fn start_selector(&mut self, first_token: &TokenWithPosition<'a>) -> Result<Selector, Error> {
+        match first_token.token {
+            Token::Identifier(id) => {
+                let object_type = id_to_object_type(id)
+                    .ok_or_else(|| self.parse_error(format!("Unknown object type: {}", id), first_token.position))?;
+                Ok(Selector {
+                    object_type,
+                    min_zoom: None,
+                    max_zoom: None,
+                    tests: Vec::new(),
+                    layer_id: None,
+                    link: None,
+                })
+            }
+            _ => self.unexpected_token(first_token),
+        }
+    }
+
+    // Reads the role tests that follow `>` or `<` and starts the selector of the entity that is actually
+    // styled, turning the selector read so far into its link.
+    fn read_link(&mut self, linked: Selector, link_type: LinkType, position: InputPosition) -> Result<Selector, Error> {
+        if linked.link.is_some() {
+            return Err(self.parse_error("Chains of more than two selectors are not supported", position));
+        }
+        if linked.min_zoom.is_some() || linked.max_zoom.is_some() || linked.layer_id.is_some() {
+            return Err(self.parse_error(
+                "Zoom ranges and layers are only allowed in the last selector of a chain",
+                position,
+            ));
+        }
+
+        let mut role_tests = Vec::new();
+        let mut current_token = self.read_mandatory_token()?;
+        while let Token::LeftBracket = current_token.token {
+            let test = self.read_test()?;
+            let tag_name = match test {
+                Test::Unary { ref tag_name, .. }
+                | Test::BinaryStringCompare { ref tag_name, .. }
+                | Test::BinaryNumericCompare { ref tag_name, .. } => tag_name,
+            };
+            if tag_name != "role" {
+                return Err(self.parse_error(
+                    format!("Only role conditions are supported in links, found {}", test),
+                    current_token.position,
+                ));
+            }
+            role_tests.push(test);
+            current_token = self.read_mandatory_token()?;
+        }
+
+        let mut selector = self.start_selector(&current_token)?;
+        selector.link = Some(Link {
+            link_type,
+            object_type: linked.object_type,
+            tests: linked.tests,
+            role_tests,
+        });
+        Ok(selector)
+    }
+
     fn read_test(&mut self) -> Result<Test, Error> {
         let mut starts_with_bang = false;
 
@@ -671,6 +780,7 @@ fn id_to_object_type(id: &str) -> Option<ObjectType> {
         "node" => Some(ObjectType::Node),
         "way" | "line" => Some(ObjectType::Way),
         "area" => Some(ObjectType::Area),
+        "relation" => Some(ObjectType::Relation),
         _ => None,
     }
 }
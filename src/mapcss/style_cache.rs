@@ -12,6 +12,7 @@ struct StyleCacheKey {
     cache_slot: usize,
     tags: Vec<usize>,
     zoom: u8,
+    matched_links: Vec<bool>,
 }
 
 pub struct StyleCache {
@@ -21,54 +22,30 @@ pub struct StyleCache {
 
 impl StyleCache {
     pub fn new(rules: &[Rule]) -> StyleCache {
-        let mut tag_value_matters = HashMap::new();
-
-        tag_value_matters.insert("layer".to_string(), true);
-
-        for r in rules.iter() {
-            for sel in r.selectors.iter() {
-                for test in sel.tests.iter() {
-                    let (tag_name, value_matters) = match test {
-                        Test::Unary {
-                            ref tag_name,
-                            ref test_type,
-                        } => {
-                            let value_matters = match test_type {
-                                UnaryTestType::Exists | UnaryTestType::NotExists => false,
-                                _ => true,
-                            };
-                            (tag_name, value_matters)
-                        }
-                        Test::BinaryStringCompare { ref tag_name, .. } => (tag_name, true),
-                        Test::BinaryNumericCompare { ref tag_name, .. } => (tag_name, true),
-                    };
-
-                    *tag_value_matters.entry(tag_name.clone()).or_default() |= value_matters;
-                }
-            }
-        }
-
         StyleCache {
             cache: HashMap::default(),
-            tag_value_matters,
+            tag_value_matters: get_tag_value_matters(rules),
         }
     }
 
-    pub fn get<'e, E>(&self, entity: &E, zoom: u8) -> Option<Vec<Arc<Style>>>
+    pub fn get<'e, E>(&self, entity: &E, zoom: u8, matched_links: &[bool]) -> Option<Vec<Arc<Style>>>
     where
         E: CacheableEntity + OsmEntity<'e>,
     {
-        self.cache.get(&self.to_cache_key(entity, zoom)).cloned()
+        self.cache
+            .get(&self.to_cache_key(entity, zoom, matched_links.to_vec()))
+            .cloned()
     }
 
-    pub fn insert<'e, E>(&mut self, entity: &E, zoom: u8, styles: Vec<Arc<Style>>)
+    pub fn insert<'e, E>(&mut self, entity: &E, zoom: u8, matched_links: Vec<bool>, styles: Vec<Arc<Style>>)
     where
         E: CacheableEntity + OsmEntity<'e>,
     {
-        self.cache.insert(self.to_cache_key(entity, zoom), styles);
+        self.cache
+            .insert(self.to_cache_key(entity, zoom, matched_links), styles);
     }
 
-    fn to_cache_key<'e, E>(&self, entity: &E, zoom: u8) -> StyleCacheKey
+    fn to_cache_key<'e, E>(&self, entity: &E, zoom: u8, matched_links: Vec<bool>) -> StyleCacheKey
     where
         E: CacheableEntity + OsmEntity<'e>,
     {
@@ -86,6 +63,41 @@ impl StyleCache {
             cache_slot: entity.cache_slot(),
             tags,
             zoom,
+            matched_links,
+        }
+    }
+}
+
+// For every tag key that is tested in at least one selector (or in the parent or the child part of a link
+// selector), tells if the rules can look at the value of the tag or only check if the tag is present.
+pub(super) fn get_tag_value_matters(rules: &[Rule]) -> HashMap<String, bool> {
+    let mut tag_value_matters = HashMap::new();
+
+    tag_value_matters.insert("layer".to_string(), true);
+
+    for r in rules.iter() {
+        for sel in r.selectors.iter() {
+            let link_tests = sel.link.iter().flat_map(|link| link.tests.iter());
+            for test in sel.tests.iter().chain(link_tests) {
+                let (tag_name, value_matters) = match test {
+                    Test::Unary {
+                        ref tag_name,
+                        ref test_type,
+                    } => {
+                        let value_matters = match test_type {
+                            UnaryTestType::Exists | UnaryTestType::NotExists => false,
+                            _ => true,
+                        };
+                        (tag_name, value_matters)
+                    }
+                    Test::BinaryStringCompare { ref tag_name, .. } => (tag_name, true),
+                    Test::BinaryNumericCompare { ref tag_name, .. } => (tag_name, true),
+                };
+
+                *tag_value_matters.entry(tag_name.clone()).or_default() |= value_matters;
+            }
         }
     }
+
+    tag_value_matters
 }
@@ -1,10 +1,11 @@
 use crate::mapcss::color::{from_color_name, Color};
 use crate::mapcss::parser::*;
-use crate::mapcss::style_cache::StyleCache;
+use crate::mapcss::style_cache::{get_tag_value_matters, StyleCache};
 
-use crate::geodata::reader::{Multipolygon, Node, OsmArea, OsmEntity, Way};
+use crate::geodata::reader::{Member, Multipolygon, Node, OsmArea, OsmEntity, Way};
 use indexmap::IndexMap;
 use std::cmp::Ordering;
+use std::collections::HashSet;
 use std::sync::Arc;
 use std::sync::RwLock;
 
@@ -36,6 +37,9 @@ pub enum StyleType {
 pub trait StyleableEntity {
     fn default_z_index(&self) -> f64;
     fn matches_object_type(&self, object_type: &ObjectType) -> bool;
+    /// Returns the parents (for `LinkType::Parent`) or the children (for `LinkType::Child`) of the entity
+    /// along with the roles of the children in the parents.
+    fn linked_entities(&self, link_type: &LinkType) -> Vec<(&str, Member<'_>)>;
 }
 
 pub trait CacheableEntity {
@@ -93,6 +97,23 @@ where
     Multipolygon(&'wr Multipolygon<'a>),
 }
 
+/// Returns the keys of all tags that can affect how entities are rendered with the given rules:
+/// the keys tested in selectors, the keys whose values are used as label text, and `layer`.
+pub fn get_used_tag_keys(rules: &[Rule]) -> HashSet<String> {
+    let mut keys = get_tag_value_matters(rules).into_keys().collect::<HashSet<_>>();
+    for rule in rules {
+        for prop in rule.properties.iter().filter(|p| p.name == "text") {
+            match prop.value {
+                PropertyValue::Identifier(ref key) | PropertyValue::String(ref key) => {
+                    keys.insert(key.clone());
+                }
+                _ => {}
+            }
+        }
+    }
+    keys
+}
+
 impl Styler {
     pub fn new(rules: Vec<Rule>, style_type: &StyleType, font_size_multiplier: Option<f64>) -> Styler {
         let use_caps_for_dashes = match *style_type {
@@ -131,9 +152,22 @@ impl Styler {
                 }
             };
 
+            // The entities with the same tags can still be styled differently if their parents or children
+            // are different, so the cache also needs to know which links match. Collecting the linked entities
+            // is expensive (e.g. all nodes of a way), so the links are only checked for the selectors that
+            // match otherwise. The rest are decided by the object type, the tags and the zoom, which are
+            // already a part of the cache key.
+            let matched_links = self
+                .rules
+                .iter()
+                .flat_map(|r| r.selectors.iter())
+                .filter_map(|sel| sel.link.as_ref().map(|link| (sel, link)))
+                .map(|(sel, link)| area_matches_without_link(area, sel, zoom) && link_matches(area, link))
+                .collect::<Vec<_>>();
+
             {
                 let read_cache = self.style_cache.read().unwrap();
-                if let Some(styles) = read_cache.get(area, zoom) {
+                if let Some(styles) = read_cache.get(area, zoom, &matched_links) {
                     add_styles(&styles);
                     continue;
                 }
@@ -163,7 +197,10 @@ impl Styler {
             }
 
             add_styles(&styles);
-            self.style_cache.write().unwrap().insert(area, zoom, styles)
+            self.style_cache
+                .write()
+                .unwrap()
+                .insert(area, zoom, matched_links, styles)
         }
 
         styled_areas.sort_by(|a, b| compare_styled_entities(a, b, for_labels));
@@ -455,12 +492,7 @@ fn extract_canvas_fill_color(rules: &[Rule], style_type: &StyleType) -> Option<C
     None
 }
 
-fn matches_by_tags<'e, E>(entity: &E, test: &Test) -> bool
-where
-    E: OsmEntity<'e>,
-{
-    let tags = entity.tags();
-
+fn matches_by_tags<'t>(get_tag: impl Fn(&str) -> Option<&'t str>, test: &Test) -> bool {
     let is_true_value = |x| x == "yes" || x == "true" || x == "1";
 
     match *test {
@@ -468,7 +500,7 @@ where
             ref tag_name,
             ref test_type,
         } => {
-            let tag_val = tags.get_by_key(tag_name);
+            let tag_val = get_tag(tag_name);
             match *test_type {
                 UnaryTestType::Exists => tag_val.is_some(),
                 UnaryTestType::NotExists => tag_val.is_none(),
@@ -487,7 +519,7 @@ where
             ref value,
             ref test_type,
         } => {
-            let tag_val = tags.get_by_key(tag_name);
+            let tag_val = get_tag(tag_name);
             match *test_type {
                 BinaryStringTestType::Equal => tag_val == Some(value),
                 BinaryStringTestType::NotEqual => tag_val != Some(value),
@@ -498,7 +530,7 @@ where
             ref value,
             ref test_type,
         } => {
-            let tag_val = match tags.get_by_key(tag_name).map(str::parse::<f64>) {
+            let tag_val = match get_tag(tag_name).map(str::parse::<f64>) {
                 Some(Ok(x)) => x,
                 _ => return false,
             };
@@ -513,6 +545,14 @@ where
 }
 
 fn area_matches<'e, A>(area: &A, selector: &Selector, zoom: u8) -> bool
+where
+    A: StyleableEntity + OsmEntity<'e>,
+{
+    area_matches_without_link(area, selector, zoom)
+        && selector.link.as_ref().is_none_or(|link| link_matches(area, link))
+}
+
+fn area_matches_without_link<'e, A>(area: &A, selector: &Selector, zoom: u8) -> bool
 where
     A: StyleableEntity + OsmEntity<'e>,
 {
@@ -529,8 +569,77 @@ where
     }
 
     let good_object_type = area.matches_object_type(&selector.object_type);
+    let tags = area.tags();
 
-    good_object_type && selector.tests.iter().all(|x| matches_by_tags(area, x))
+    good_object_type
+        && selector
+            .tests
+            .iter()
+            .all(|x| matches_by_tags(|k| tags.get_by_key(k), x))
+}
+
+fn link_matches<A: StyleableEntity>(area: &A, link: &Link) -> bool {
+    area.linked_entities(&link.link_type).iter().any(|(role, linked)| {
+        let role = Some(*role).filter(|r| !r.is_empty());
+        let good_object_type = match linked {
+            Member::Node(node) => node.matches_object_type(&link.object_type),
+            Member::Way(way) => way.matches_object_type(&link.object_type),
+            Member::Relation(_) => link.object_type == ObjectType::Relation,
+        };
+        let tags = match linked {
+            Member::Node(node) => node.tags(),
+            Member::Way(way) => way.tags(),
+            Member::Relation(relation) => relation.tags(),
+        };
+
+        good_object_type
+            && link.tests.iter().all(|x| matches_by_tags(|k| tags.get_by_key(k), x))
+            && link
+                .role_tests
+                .iter()
+                .all(|x| matches_by_tags(|k| if k == "role" { role } else { None }, x))
+    })
+}
+
+// An entity is drawn only if at least one of these properties is set for it. The rest of the properties
+// (line joins, opacities, z-indices etc.) only change how the entity looks.
+const DRAWING_PROPERTIES: [&str; 8] = [
+    "color",
+    "fill-color",
+    "width",
+    "casing-color",
+    "casing-width",
+    "icon-image",
+    "fill-image",
+    "text",
+];
+
+/// Returns the lowest zoom level at which an entity with the given tags can be drawn, that is, at which
+/// at least one of the selectors of a rule that sets a drawing property (like `color` or `text`) matches
+/// the entity. Returns `None` if there's no such zoom level. `matches_object_type` tells if the entity
+/// has the object type of a selector. The parents and the children of the entity are not known here,
+/// so the links of the selectors are assumed to match.
+pub fn get_min_matching_zoom<'t>(
+    rules: &[Rule],
+    matches_object_type: impl Fn(&ObjectType) -> bool,
+    get_tag: impl Fn(&str) -> Option<&'t str>,
+) -> Option<u8> {
+    rules
+        .iter()
+        .filter(|r| {
+            r.properties
+                .iter()
+                .any(|p| DRAWING_PROPERTIES.contains(&p.name.as_str()))
+        })
+        .flat_map(|r| r.selectors.iter())
+        .filter(|sel| matches_object_type(&sel.object_type))
+        .filter(|sel| match (sel.min_zoom, sel.max_zoom) {
+            (Some(min_zoom), Some(max_zoom)) => min_zoom <= max_zoom,
+            _ => true,
+        })
+        .filter(|sel| sel.tests.iter().all(|x| matches_by_tags(&get_tag, x)))
+        .map(|sel| sel.min_zoom.unwrap_or(0))
+        .min()
 }
 
 fn get_layer_id(selector: &Selector) -> &str {
@@ -553,26 +662,66 @@ impl<'a> StyleableEntity for Node<'a> {
             _ => false,
         }
     }
+
+    fn linked_entities(&self, link_type: &LinkType) -> Vec<(&str, Member<'_>)> {
+        match *link_type {
+            LinkType::Parent => self.parents().map(|p| (p.role, p.parent)).collect(),
+            LinkType::Child => Vec::new(),
+        }
+    }
 }
 
-impl<A: OsmArea> StyleableEntity for A {
+impl<'a> StyleableEntity for Way<'a> {
     fn default_z_index(&self) -> f64 {
-        if self.is_closed() {
-            1.0
-        } else {
-            3.0
-        }
+        area_default_z_index(self)
     }
 
     fn matches_object_type(&self, object_type: &ObjectType) -> bool {
-        match *object_type {
-            ObjectType::Way => true,
-            ObjectType::Area => self.is_closed(),
-            _ => false,
+        area_matches_object_type(self, object_type)
+    }
+
+    fn linked_entities(&self, link_type: &LinkType) -> Vec<(&str, Member<'_>)> {
+        match *link_type {
+            LinkType::Parent => self.parents().map(|p| (p.role, p.parent)).collect(),
+            LinkType::Child => (0..self.node_count())
+                .map(|idx| ("", Member::Node(self.get_node(idx))))
+                .collect(),
         }
     }
 }
 
+// The ways that a multipolygon was built from are not stored, so it has no children, and it can't be
+// a member of the relations either.
+impl<'a> StyleableEntity for Multipolygon<'a> {
+    fn default_z_index(&self) -> f64 {
+        area_default_z_index(self)
+    }
+
+    fn matches_object_type(&self, object_type: &ObjectType) -> bool {
+        area_matches_object_type(self, object_type)
+    }
+
+    fn linked_entities(&self, _: &LinkType) -> Vec<(&str, Member<'_>)> {
+        Vec::new()
+    }
+}
+
+fn area_default_z_index(area: &impl OsmArea) -> f64 {
+    if area.is_area() {
+        1.0
+    } else {
+        3.0
+    }
+}
+
+fn area_matches_object_type(area: &impl OsmArea, object_type: &ObjectType) -> bool {
+    match *object_type {
+        ObjectType::Way => true,
+        ObjectType::Area => area.is_area(),
+        _ => false,
+    }
+}
+
 impl<'a> CacheableEntity for Node<'a> {
     fn cache_slot(&self) -> usize {
         0
@@ -581,7 +730,7 @@ impl<'a> CacheableEntity for Node<'a> {
 
 impl<'a> CacheableEntity for Way<'a> {
     fn cache_slot(&self) -> usize {
-        if self.is_closed() {
+        if self.is_area() {
             1
         } else {
             2
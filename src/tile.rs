@@ -72,6 +72,36 @@ pub fn tile_to_max_zoom_tile_range(tile: &Tile) -> TileRange {
     }
 }
 
+/// Returns the position of a tile along the Hilbert curve that passes through all tiles of its zoom level.
+/// Tiles that are close on the map are usually close on the curve. The curve of a zoom level refines the
+/// curve of the previous one: the positions of the four children of a tile start at 4 times its position.
+/// # Examples
+/// ```
+/// use renderer::tile::hilbert_index;
+/// assert_eq!(hilbert_index(0, 0, 0), 0);
+/// assert_eq!((0..4).map(|i| hilbert_index(1, i % 2, i / 2)).collect::<Vec<_>>(), vec![0, 3, 1, 2]);
+/// assert_eq!(hilbert_index(18, 158333, 81957) >> 2, hilbert_index(17, 158333 >> 1, 81957 >> 1));
+/// ```
+pub fn hilbert_index(zoom: u8, x: u32, y: u32) -> u64 {
+    let max_coord = (1u32 << zoom) - 1;
+    let (mut x, mut y) = (x, y);
+    let mut index = 0;
+    for bit in (0..zoom).rev() {
+        let rx = (x >> bit) & 1;
+        let ry = (y >> bit) & 1;
+        index += u64::from((3 * rx) ^ ry) << (2 * bit);
+        // Rotate the quadrant, so that the curve inside it starts and ends at the right corners.
+        if ry == 0 {
+            if rx == 1 {
+                x = max_coord - x;
+                y = max_coord - y;
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+    }
+    index
+}
+
 /// Projects a given geopoint to Web Mercator coordinates for a given zoom level.
 /// # Examples
 /// ```
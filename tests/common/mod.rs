@@ -1,3 +1,4 @@
+use renderer::geodata::reader::OsmEntity;
 use std::path::PathBuf;
 
 pub fn get_test_path(relative_path: &[&str]) -> String {
@@ -9,3 +10,11 @@ pub fn get_test_path(relative_path: &[&str]) -> String {
 
     test_path.to_str().unwrap().to_string()
 }
+
+// Not every test uses it, and each test file compiles its own copy of this module.
+#[allow(dead_code)]
+pub fn sorted_ids<'a, E: OsmEntity<'a>>(entities: &[E]) -> Vec<u64> {
+    let mut ids = entities.iter().map(|e| e.global_id()).collect::<Vec<_>>();
+    ids.sort();
+    ids
+}
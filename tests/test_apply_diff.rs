@@ -0,0 +1,183 @@
+use renderer;
+
+mod common;
+
+use renderer::geodata::area_rules::AreaRules;
+use renderer::geodata::diff::{apply_diff, apply_diff_with_options, DiffOptions};
+use renderer::geodata::importer::{import, import_with_options, ImportOptions};
+use renderer::geodata::reader::{GeodataReader, OsmArea, OsmEntities, OsmEntity};
+use renderer::tile::{coords_to_max_zoom_tile, Tile};
+
+fn entities_at<'a>(reader: &'a GeodataReader<'a>, lat: f64, lon: f64) -> OsmEntities<'a> {
+    reader.get_entities_in_tile_with_neighbors(&coords_to_max_zoom_tile(&(lat, lon)), &None)
+}
+
+#[test]
+fn test_apply_diff() {
+    let base_osm = common::get_test_path(&["osm", "diff_base.osm"]);
+    let base = common::get_test_path(&["osm", "diff_base.bin"]);
+    let changes = common::get_test_path(&["osm", "diff_changes.osc"]);
+    let result = common::get_test_path(&["osm", "diff_result.bin"]);
+
+    import(&base_osm, &base).unwrap();
+    let changed_tiles = apply_diff(&base, &changes, &result).unwrap();
+
+    let is_changed = |lat, lon| changed_tiles.contains(&coords_to_max_zoom_tile(&(lat, lon)));
+    // The moved node.
+    assert!(is_changed(55.75, 37.60));
+    assert!(is_changed(55.76, 37.62));
+    // The multipolygon with a modified member way, the deleted way and relation, the new way.
+    assert!(is_changed(55.7405, 37.6090));
+    assert!(is_changed(55.7605, 37.6305));
+    assert!(is_changed(55.7705, 37.6405));
+    assert!(is_changed(55.7805, 37.6505));
+    // The node that is left intact.
+    assert!(!is_changed(55.70, 37.50));
+    assert!(changed_tiles.iter().all(|t| t.zoom == 18));
+
+    let reader = GeodataReader::load(&result).unwrap();
+
+    assert_eq!(common::sorted_ids(&entities_at(&reader, 55.70, 37.50).nodes), vec![2]);
+    assert!(entities_at(&reader, 55.75, 37.60).nodes.is_empty());
+    assert_eq!(common::sorted_ids(&entities_at(&reader, 55.76, 37.62).nodes), vec![1]);
+
+    assert!(entities_at(&reader, 55.7605, 37.6305).ways.is_empty());
+    assert_eq!(
+        common::sorted_ids(&entities_at(&reader, 55.7805, 37.6505).ways),
+        vec![201]
+    );
+
+    let water = entities_at(&reader, 55.7705, 37.6405);
+    assert_eq!(common::sorted_ids(&water.ways), vec![102]);
+    assert!(water.multipolygons.is_empty());
+
+    let grass = entities_at(&reader, 55.7405, 37.6105);
+    assert_eq!(common::sorted_ids(&grass.multipolygons), vec![1000]);
+    let polygon = grass.multipolygons[0].get_polygon(0);
+    let node_ids = (0..polygon.node_count())
+        .map(|idx| polygon.get_node(idx).global_id())
+        .collect::<Vec<_>>();
+    assert_eq!(node_ids, vec![10, 11, 12, 13, 14, 10]);
+
+    // Applying an empty diff changes nothing.
+    let empty_changes = common::get_test_path(&["osm", "empty_changes.osc"]);
+    std::fs::write(&empty_changes, "<osmChange version=\"0.6\"></osmChange>").unwrap();
+    let unchanged = common::get_test_path(&["osm", "diff_unchanged.bin"]);
+    assert_eq!(
+        apply_diff(&base, &empty_changes, &unchanged).unwrap(),
+        Vec::<Tile>::new()
+    );
+    assert!(std::fs::read(&base).unwrap() == std::fs::read(&unchanged).unwrap());
+}
+
+#[test]
+fn test_apply_diff_to_relations() {
+    let base_osm = common::get_test_path(&["osm", "relations.osm"]);
+    let base = common::get_test_path(&["osm", "relations_base.bin"]);
+    let changes = common::get_test_path(&["osm", "relations_changes.osc"]);
+    let result = common::get_test_path(&["osm", "relations_result.bin"]);
+
+    import(&base_osm, &base).unwrap();
+    let changed_tiles = apply_diff(&base, &changes, &result).unwrap();
+    // The new position of the node that belongs to the associatedStreet relation.
+    assert!(changed_tiles.contains(&coords_to_max_zoom_tile(&(55.7535, 37.607))));
+
+    let reader = GeodataReader::load(&result).unwrap();
+    assert!(reader.get_relation_by_id(101).is_none());
+
+    let route = reader.get_relation_by_id(100).unwrap();
+    assert_eq!(route.tags().get_by_key("ref"), Some("43"));
+    assert_eq!(route.member_count(), 2);
+    assert_eq!(
+        common::sorted_ids(&entities_at(&reader, 55.7505, 37.601).relations),
+        vec![100]
+    );
+    // The route no longer goes along way 11, which still belongs to the associatedStreet relation.
+    assert_eq!(
+        common::sorted_ids(&entities_at(&reader, 55.756, 37.612).relations),
+        vec![102]
+    );
+    assert_eq!(
+        common::sorted_ids(&entities_at(&reader, 55.7535, 37.607).relations),
+        vec![102]
+    );
+}
+
+#[test]
+fn test_apply_diff_uses_the_area_rules_of_the_import() {
+    let base_osm = common::get_test_path(&["osm", "diff_base.osm"]);
+    let changes = common::get_test_path(&["osm", "diff_area_changes.osc"]);
+    let is_area_after_diff = |options: &ImportOptions, name| {
+        let base = common::get_test_path(&["osm", &format!("{}_base.bin", name)]);
+        let result = common::get_test_path(&["osm", &format!("{}_result.bin", name)]);
+        import_with_options(&base_osm, &base, options).unwrap();
+        apply_diff(&base, &changes, &result).unwrap();
+        let reader = GeodataReader::load(&result).unwrap();
+        let is_area = reader.get_way_by_id(202).unwrap().is_area();
+        is_area
+    };
+
+    assert!(!is_area_after_diff(&ImportOptions::default(), "diff_default_rules"));
+    let options = ImportOptions {
+        area_rules: AreaRules::from_file(&common::get_test_path(&["osm", "area_rules.txt"])).unwrap(),
+        ..Default::default()
+    };
+    assert!(is_area_after_diff(&options, "diff_custom_rules"));
+}
+
+#[test]
+fn test_apply_diff_with_the_stylesheet_of_the_import() {
+    let base_osm = common::get_test_path(&["osm", "diff_base.osm"]);
+    let base = common::get_test_path(&["osm", "diff_stylesheet_base.bin"]);
+    let unfiltered_base = common::get_test_path(&["osm", "diff_unfiltered_base.bin"]);
+    let changes = common::get_test_path(&["osm", "diff_stylesheet.osc"]);
+    let result = common::get_test_path(&["osm", "diff_stylesheet_result.bin"]);
+    let stylesheet = common::get_test_path(&["mapcss", "diff.mapcss"]);
+    let other_stylesheet = common::get_test_path(&["mapcss", "areas.mapcss"]);
+    let with_stylesheet = |stylesheet: &str| DiffOptions {
+        stylesheet: Some(stylesheet.to_string()),
+    };
+
+    let options = ImportOptions {
+        stylesheet: Some(stylesheet.clone()),
+        ..Default::default()
+    };
+    import_with_options(&base_osm, &base, &options).unwrap();
+    import(&base_osm, &unfiltered_base).unwrap();
+
+    // The stylesheet has to be the same as the one that the base file was imported with.
+    assert!(apply_diff(&base, &changes, &result).is_err());
+    assert!(apply_diff_with_options(&base, &changes, &result, &with_stylesheet(&other_stylesheet)).is_err());
+    assert!(apply_diff_with_options(&unfiltered_base, &changes, &result, &with_stylesheet(&stylesheet)).is_err());
+    apply_diff_with_options(&base, &changes, &result, &with_stylesheet(&stylesheet)).unwrap();
+
+    let reader = GeodataReader::load(&result).unwrap();
+    let cafe = reader.get_node_by_id(1).unwrap();
+    assert_eq!(cafe.tags().get_by_key("name"), Some("Кофейня"));
+    assert_eq!(cafe.tags().get_by_key("cuisine"), None);
+    let road = reader.get_way_by_id(201).unwrap();
+    assert_eq!(road.tags().get_by_key("highway"), Some("service"));
+    assert_eq!(road.tags().get_by_key("surface"), None);
+
+    // The changed entities are skipped at the zoom levels at which the stylesheet doesn't draw them.
+    let entities_at_zoom = |lat, lon, zoom| {
+        let tile = coords_to_max_zoom_tile(&(lat, lon));
+        let tile = Tile {
+            x: tile.x >> (18 - zoom),
+            y: tile.y >> (18 - zoom),
+            zoom,
+        };
+        reader.get_entities_in_tile_with_neighbors(&tile, &None)
+    };
+    assert!(entities_at_zoom(55.76, 37.62, 16).nodes.is_empty());
+    assert_eq!(common::sorted_ids(&entities_at_zoom(55.76, 37.62, 17).nodes), vec![1]);
+    assert!(entities_at_zoom(55.7805, 37.6505, 14).ways.is_empty());
+    assert_eq!(
+        common::sorted_ids(&entities_at_zoom(55.7805, 37.6505, 15).ways),
+        vec![201]
+    );
+
+    // The result can be updated again.
+    let second_result = common::get_test_path(&["osm", "diff_stylesheet_second_result.bin"]);
+    apply_diff_with_options(&result, &changes, &second_result, &with_stylesheet(&stylesheet)).unwrap();
+}
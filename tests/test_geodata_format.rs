@@ -0,0 +1,51 @@
+use renderer;
+
+mod common;
+
+use renderer::geodata::importer::import;
+use renderer::geodata::reader::GeodataReader;
+use std::fs;
+
+fn load_error(name: &str, bytes: &[u8]) -> String {
+    let path = common::get_test_path(&["osm", name]);
+    fs::write(&path, bytes).unwrap();
+    match GeodataReader::load(&path).and_then(|reader| reader.verify()) {
+        Ok(_) => panic!("{} was loaded successfully", name),
+        Err(err) => err.iter_chain().map(|c| c.to_string()).collect::<Vec<_>>().join(": "),
+    }
+}
+
+#[test]
+fn test_broken_geodata_files_are_rejected() {
+    let input = common::get_test_path(&["osm", "touching_inner_rings.osm"]);
+    let output = common::get_test_path(&["osm", "format_good.bin"]);
+    import(&input, &output).unwrap();
+    assert!(GeodataReader::load(&output).unwrap().verify().is_ok());
+
+    let good = fs::read(&output).unwrap();
+
+    let error = load_error("format_tiny.bin", &good[..10]);
+    assert!(error.contains("too small"), "{}", error);
+
+    let mut wrong_magic = good.clone();
+    wrong_magic[0] = b'X';
+    let error = load_error("format_wrong_magic.bin", &wrong_magic);
+    assert!(error.contains("not a geodata file"), "{}", error);
+
+    let mut wrong_version = good.clone();
+    wrong_version[8] += 1;
+    let error = load_error("format_wrong_version.bin", &wrong_version);
+    let expected = format!("format version {}", wrong_version[8]);
+    assert!(error.contains(&expected), "{}", error);
+
+    let error = load_error("format_truncated.bin", &good[..good.len() - 1]);
+    assert!(error.contains("truncated"), "{}", error);
+
+    let mut corrupted = good.clone();
+    let last = corrupted.len() - 1;
+    corrupted[last] ^= 0xff;
+    let error = load_error("format_corrupted.bin", &corrupted);
+    assert!(error.contains("checksum mismatch"), "{}", error);
+    // The checksum is only checked on request, so the corrupted file still loads.
+    assert!(GeodataReader::load(&common::get_test_path(&["osm", "format_corrupted.bin"])).is_ok());
+}
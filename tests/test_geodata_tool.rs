@@ -0,0 +1,145 @@
+use renderer;
+
+mod common;
+
+use renderer::geodata::clip::ClipArea;
+use renderer::geodata::extract::extract;
+use renderer::geodata::importer::{import, import_with_options, ImportOptions};
+use renderer::geodata::inspect::{dump, get_stats, DumpFormat, DumpSelection, OsmId};
+use renderer::geodata::reader::{GeodataReader, OsmEntities, OsmEntity};
+use std::fs;
+
+fn all_entities<'a>(reader: &'a GeodataReader<'a>) -> OsmEntities<'a> {
+    reader.get_entities_in_bbox(-85.0, -180.0, 85.0, 180.0)
+}
+
+fn import_clip_test_data(output_name: &str) -> String {
+    let input = common::get_test_path(&["osm", "clip.osm"]);
+    let output = common::get_test_path(&["osm", output_name]);
+    import(&input, &output).unwrap();
+    output
+}
+
+fn clip_area() -> ClipArea {
+    ClipArea::from_bbox_str("37.60,55.75,37.62,55.76").unwrap()
+}
+
+#[test]
+fn test_parse_osm_ids() {
+    assert_eq!("n1".parse::<OsmId>().unwrap(), OsmId::Node(1));
+    assert_eq!("w22".parse::<OsmId>().unwrap(), OsmId::Way(22));
+    assert_eq!("r333".parse::<OsmId>().unwrap(), OsmId::Relation(333));
+    assert!("x1".parse::<OsmId>().is_err());
+    assert!("n".parse::<OsmId>().is_err());
+    assert!("w-1".parse::<OsmId>().is_err());
+    assert!("".parse::<OsmId>().is_err());
+}
+
+#[test]
+fn test_stats() {
+    let geodata = import_clip_test_data("geodata_tool_stats.bin");
+    let reader = GeodataReader::load(&geodata).unwrap();
+    let entities = all_entities(&reader);
+
+    let stats = get_stats(&reader);
+    assert_eq!(stats.node_count, entities.nodes.len());
+    assert_eq!(stats.way_count, entities.ways.len());
+    assert_eq!(stats.multipolygon_count, entities.multipolygons.len());
+    assert!(stats.string_table_size > 0);
+    assert!(!stats.tile_counts.is_empty());
+    assert!(!stats.heaviest_tiles.is_empty());
+    assert!(stats.top_tag_keys.iter().any(|(key, _)| key == "highway"));
+
+    let printed = stats.to_string();
+    assert!(printed.contains(&format!("Nodes: {}", entities.nodes.len())));
+}
+
+#[test]
+fn test_extract_matches_clipped_import() {
+    let full = import_clip_test_data("geodata_tool_full.bin");
+    let extracted = common::get_test_path(&["osm", "geodata_tool_extracted.bin"]);
+    extract(&full, &clip_area(), &extracted).unwrap();
+
+    let input = common::get_test_path(&["osm", "clip.osm"]);
+    let clipped = common::get_test_path(&["osm", "geodata_tool_clipped.bin"]);
+    let options = ImportOptions {
+        clip_area: Some(clip_area()),
+        ..Default::default()
+    };
+    import_with_options(&input, &clipped, &options).unwrap();
+
+    assert!(fs::metadata(&extracted).unwrap().len() < fs::metadata(&full).unwrap().len());
+
+    let extracted_reader = GeodataReader::load(&extracted).unwrap();
+    let clipped_reader = GeodataReader::load(&clipped).unwrap();
+    let extracted_entities = all_entities(&extracted_reader);
+    let clipped_entities = all_entities(&clipped_reader);
+    assert_eq!(
+        common::sorted_ids(&extracted_entities.nodes),
+        common::sorted_ids(&clipped_entities.nodes)
+    );
+    // The multipolygon members that are outside of the area are only kept by the import.
+    assert_eq!(common::sorted_ids(&extracted_entities.ways), vec![100, 102]);
+    assert_eq!(common::sorted_ids(&clipped_entities.ways), vec![100, 102, 103, 104]);
+    assert_eq!(
+        common::sorted_ids(&extracted_entities.multipolygons),
+        common::sorted_ids(&clipped_entities.multipolygons)
+    );
+}
+
+#[test]
+fn test_dump_osm_xml_can_be_imported() {
+    let full = import_clip_test_data("geodata_tool_dump_source.bin");
+    let reader = GeodataReader::load(&full).unwrap();
+
+    let dumped = common::get_test_path(&["osm", "geodata_tool_dump.osm"]);
+    let mut output = Vec::new();
+    dump(
+        &reader,
+        &DumpSelection::Area(clip_area()),
+        DumpFormat::OsmXml,
+        &mut output,
+    )
+    .unwrap();
+    fs::write(&dumped, &output).unwrap();
+
+    let reimported = common::get_test_path(&["osm", "geodata_tool_dump.bin"]);
+    import(&dumped, &reimported).unwrap();
+    let reimported_reader = GeodataReader::load(&reimported).unwrap();
+    let entities = all_entities(&reimported_reader);
+
+    // The ring of the multipolygon is written as a new way with an id after the largest way id (105).
+    assert_eq!(common::sorted_ids(&entities.ways), vec![100, 102, 106]);
+    assert_eq!(common::sorted_ids(&entities.multipolygons), vec![1000]);
+
+    for way in entities.ways.iter().filter(|w| w.global_id() != 106) {
+        let original = reader.get_way_by_id(way.global_id()).unwrap();
+        assert_eq!(way.node_count(), original.node_count());
+        assert_eq!(way.tags().get_by_key("highway"), original.tags().get_by_key("highway"));
+    }
+    let multipolygon = &entities.multipolygons[0];
+    let original = reader.get_multipolygon_by_id(1000).unwrap();
+    assert_eq!(multipolygon.polygon_count(), original.polygon_count());
+    assert_eq!(
+        multipolygon.get_polygon(0).node_count(),
+        original.get_polygon(0).node_count()
+    );
+}
+
+#[test]
+fn test_dump_geojson() {
+    let full = import_clip_test_data("geodata_tool_geojson.bin");
+    let reader = GeodataReader::load(&full).unwrap();
+
+    let selection = DumpSelection::Ids(vec![OsmId::Way(100), OsmId::Relation(1000), OsmId::Node(424242)]);
+    let mut output = Vec::new();
+    dump(&reader, &selection, DumpFormat::GeoJson, &mut output).unwrap();
+    let output = String::from_utf8(output).unwrap();
+
+    assert!(output.starts_with("{\"type\":\"FeatureCollection\""));
+    assert!(output.contains("\"@id\":\"w100\""));
+    assert!(output.contains("\"type\":\"LineString\""));
+    assert!(output.contains("\"@id\":\"r1000\""));
+    assert!(output.contains("\"type\":\"MultiPolygon\""));
+    assert!(!output.contains("424242"));
+}
@@ -0,0 +1,463 @@
+use renderer;
+
+mod common;
+
+use renderer::coords::Coords;
+use renderer::geodata::clip::ClipArea;
+use renderer::geodata::history::Timestamp;
+use renderer::geodata::importer::{
+    import, import_from_reader, import_with_options, ImportOptions, InputFormat, TruncatedWays,
+};
+use renderer::geodata::reader::{GeodataReader, OsmArea, OsmEntity};
+use renderer::tile;
+use std::collections::HashSet;
+use std::fs;
+use std::io::Cursor;
+
+#[test]
+fn test_low_memory_import_gives_the_same_result() {
+    let input = common::get_test_path(&["osm", "nano_moscow.osm"]);
+    let regular_output = common::get_test_path(&["osm", "nano_moscow_regular.bin"]);
+    let low_memory_output = common::get_test_path(&["osm", "nano_moscow_low_memory.bin"]);
+
+    import(&input, &regular_output).unwrap();
+    let options = ImportOptions {
+        low_memory: true,
+        ..Default::default()
+    };
+    import_with_options(&input, &low_memory_output, &options).unwrap();
+
+    assert!(fs::read(&regular_output).unwrap() == fs::read(&low_memory_output).unwrap());
+}
+
+#[test]
+fn test_multipolygon_with_touching_inner_rings_and_missing_roles() {
+    let input = common::get_test_path(&["osm", "touching_inner_rings.osm"]);
+    let output = common::get_test_path(&["osm", "touching_inner_rings.bin"]);
+    import(&input, &output).unwrap();
+
+    let reader = GeodataReader::load(&output).unwrap();
+    let tile = tile::coords_to_max_zoom_tile(&(55.7505, 37.6005));
+    let entities = reader.get_entities_in_tile_with_neighbors(&tile, &None);
+
+    assert_eq!(entities.multipolygons.len(), 1);
+    let multipolygon = &entities.multipolygons[0];
+    assert_eq!(multipolygon.global_id(), 1000);
+    assert_eq!(multipolygon.polygon_count(), 3);
+
+    let outer = multipolygon.get_polygon(0);
+    let outer_ids = (0..outer.node_count())
+        .map(|idx| outer.get_node(idx).global_id())
+        .collect::<Vec<_>>();
+    assert_eq!(outer_ids, vec![1, 2, 3, 4, 1]);
+
+    for idx in 1..3 {
+        assert_eq!(multipolygon.get_polygon(idx).node_count(), 5);
+    }
+}
+
+#[test]
+fn test_multipolygon_validation_report() {
+    let input = common::get_test_path(&["osm", "broken_multipolygons.osm"]);
+    let output = common::get_test_path(&["osm", "broken_multipolygons.bin"]);
+    let csv_report = common::get_test_path(&["osm", "broken_multipolygons.csv"]);
+    let json_report = common::get_test_path(&["osm", "broken_multipolygons.json"]);
+
+    let options = ImportOptions {
+        validation_report: Some(csv_report.clone()),
+        ..Default::default()
+    };
+    import_with_options(&input, &output, &options).unwrap();
+    assert_eq!(
+        fs::read_to_string(&csv_report).unwrap(),
+        "relation_id,problem,count,lat,lon,dropped\n\
+         1001,missing_member_way,1,55.7600000,37.6000000,false\n\
+         1001,unclosed_ring,2,55.7600000,37.6000000,true\n\
+         1002,self_intersection,1,55.7705000,37.6005000,false\n"
+    );
+
+    let options = ImportOptions {
+        validation_report: Some(json_report.clone()),
+        ..Default::default()
+    };
+    import_with_options(&input, &output, &options).unwrap();
+    let json = fs::read_to_string(&json_report).unwrap();
+    assert!(json.starts_with("{\"issues\":[\n{\"relation_id\":1001,\"problem\":\"missing_member_way\",\"count\":1,"));
+    assert!(json.contains("\"location\":{\"lat\":55.7705000,\"lon\":37.6005000}"));
+    assert!(json.ends_with(
+        "\"summary\":{\"problems\":{\"unclosed_ring\":1,\"self_intersection\":1,\"missing_member_way\":1},\
+         \"dropped_relations\":1}}\n"
+    ));
+
+    // The unclosed multipolygon is dropped, the others are kept.
+    let reader = GeodataReader::load(&output).unwrap();
+    let entities = reader.get_entities_in_bbox(55.0, 37.0, 56.0, 38.0);
+    let mut multipolygon_ids = entities
+        .multipolygons
+        .iter()
+        .map(|mp| mp.global_id())
+        .collect::<Vec<_>>();
+    multipolygon_ids.sort();
+    assert_eq!(multipolygon_ids, vec![1000, 1002]);
+}
+
+#[test]
+fn test_import_with_stylesheet_drops_unused_tags_and_nodes() {
+    let input = common::get_test_path(&["osm", "nano_moscow.osm"]);
+    let stylesheet = common::get_test_path(&["mapcss", "mapnik.mapcss"]);
+    let full_output = common::get_test_path(&["osm", "nano_moscow_full.bin"]);
+    let filtered_output = common::get_test_path(&["osm", "nano_moscow_filtered.bin"]);
+    let filtered_low_memory_output = common::get_test_path(&["osm", "nano_moscow_filtered_low_memory.bin"]);
+
+    import(&input, &full_output).unwrap();
+    let options = ImportOptions {
+        stylesheet: Some(stylesheet.clone()),
+        ..Default::default()
+    };
+    import_with_options(&input, &filtered_output, &options).unwrap();
+    let low_memory_options = ImportOptions {
+        low_memory: true,
+        stylesheet: Some(stylesheet),
+        ..Default::default()
+    };
+    import_with_options(&input, &filtered_low_memory_output, &low_memory_options).unwrap();
+
+    let filtered = fs::read(&filtered_output).unwrap();
+    assert!(filtered.len() < fs::read(&full_output).unwrap().len());
+    assert!(filtered == fs::read(&filtered_low_memory_output).unwrap());
+
+    let full_reader = GeodataReader::load(&full_output).unwrap();
+    let filtered_reader = GeodataReader::load(&filtered_output).unwrap();
+    // Tile queries skip the entities that the stylesheet doesn't draw, while bbox queries don't.
+    let full = full_reader.get_entities_in_bbox(55.750, 37.607, 55.757, 37.621);
+    let filtered = filtered_reader.get_entities_in_bbox(55.750, 37.607, 55.757, 37.621);
+
+    assert_eq!(full.ways.len(), filtered.ways.len());
+    assert_eq!(full.multipolygons.len(), filtered.multipolygons.len());
+    assert!(filtered.nodes.len() < full.nodes.len());
+    let full_node_ids = full.nodes.iter().map(|n| n.global_id()).collect::<HashSet<_>>();
+    assert!(filtered.nodes.iter().all(|n| full_node_ids.contains(&n.global_id())));
+
+    let has_tag = |tags: renderer::geodata::reader::Tags, key| tags.get_by_key(key).is_some();
+    assert!(full.ways.iter().any(|w| has_tag(w.tags(), "indoor")));
+    assert!(!filtered.ways.iter().any(|w| has_tag(w.tags(), "indoor")));
+    assert!(filtered.ways.iter().any(|w| has_tag(w.tags(), "highway")));
+    assert!(filtered.ways.iter().any(|w| has_tag(w.tags(), "name")));
+
+    let mut full_ways = full.ways.iter().collect::<Vec<_>>();
+    let mut filtered_ways = filtered.ways.iter().collect::<Vec<_>>();
+    full_ways.sort_by_key(|w| w.global_id());
+    filtered_ways.sort_by_key(|w| w.global_id());
+    for (full_way, filtered_way) in full_ways.iter().zip(filtered_ways.iter()) {
+        assert_eq!(full_way.global_id(), filtered_way.global_id());
+        assert_eq!(full_way.node_count(), filtered_way.node_count());
+        for idx in 0..full_way.node_count() {
+            let (full_node, filtered_node) = (full_way.get_node(idx), filtered_way.get_node(idx));
+            assert_eq!(full_node.global_id(), filtered_node.global_id());
+            assert_eq!(full_node.lat(), filtered_node.lat());
+        }
+    }
+}
+
+#[test]
+fn test_import_clipped_to_area() {
+    let input = common::get_test_path(&["osm", "clip.osm"]);
+    let bbox_output = common::get_test_path(&["osm", "clip_bbox.bin"]);
+    let poly_output = common::get_test_path(&["osm", "clip_poly.bin"]);
+    let poly_file = common::get_test_path(&["osm", "clip.poly"]);
+
+    let options = ImportOptions {
+        clip_area: Some(ClipArea::from_bbox(37.60, 55.75, 37.62, 55.76).unwrap()),
+        ..Default::default()
+    };
+    import_with_options(&input, &bbox_output, &options).unwrap();
+
+    fs::write(
+        &poly_file,
+        "area\n1\n  37.60 55.75\n  37.62 55.75\n  37.62 55.76\n  37.60 55.76\nEND\nEND\n",
+    )
+    .unwrap();
+    let options = ImportOptions {
+        clip_area: Some(ClipArea::from_poly_file(&poly_file).unwrap()),
+        ..Default::default()
+    };
+    import_with_options(&input, &poly_output, &options).unwrap();
+    assert!(fs::read(&bbox_output).unwrap() == fs::read(&poly_output).unwrap());
+
+    let reader = GeodataReader::load(&bbox_output).unwrap();
+    let tile = tile::coords_to_max_zoom_tile(&(55.755, 37.61));
+    let tile = tile::Tile {
+        x: tile.x >> 8,
+        y: tile.y >> 8,
+        zoom: 10,
+    };
+    let entities = reader.get_entities_in_tile_with_neighbors(&tile, &None);
+
+    let sorted_ids = |mut ids: Vec<u64>| {
+        ids.sort();
+        ids
+    };
+    let node_ids = sorted_ids(entities.nodes.iter().map(|n| n.global_id()).collect());
+    assert_eq!(node_ids, vec![1, 2, 10, 11, 12, 13]);
+    let way_ids = sorted_ids(entities.ways.iter().map(|w| w.global_id()).collect());
+    assert_eq!(way_ids, vec![100, 102, 103, 104]);
+    assert_eq!(entities.multipolygons.len(), 1);
+    assert_eq!(entities.multipolygons[0].global_id(), 1000);
+    assert_eq!(entities.multipolygons[0].get_polygon(0).node_count(), 5);
+}
+
+#[test]
+fn test_coastlines_become_water_polygons() {
+    let input = common::get_test_path(&["osm", "coastline.osm"]);
+    let output = common::get_test_path(&["osm", "coastline.bin"]);
+    let clipped_output = common::get_test_path(&["osm", "coastline_clipped.bin"]);
+
+    let water_polygon_sizes = |file_name: &str| {
+        let reader = GeodataReader::load(file_name).unwrap();
+        let water = reader.get_multipolygon_by_id(0).unwrap();
+        assert_eq!(water.tags().get_by_key("natural"), Some("ocean"));
+        (0..water.polygon_count())
+            .map(|idx| water.get_polygon(idx).node_count())
+            .collect::<Vec<_>>()
+    };
+
+    // The open coastline ends on the boundary of the data, so only the two southern corners are added
+    // to close the water polygon. The island is a hole in it.
+    import(&input, &output).unwrap();
+    assert_eq!(water_polygon_sizes(&output), vec![6, 5]);
+
+    // With clipping, the coastline is closed along the clip area, which adds a point on the western
+    // and the eastern edges too.
+    let options = ImportOptions {
+        clip_area: Some(ClipArea::from_bbox(20.0, 10.0, 21.0, 11.0).unwrap()),
+        ..Default::default()
+    };
+    import_with_options(&input, &clipped_output, &options).unwrap();
+    assert_eq!(water_polygon_sizes(&clipped_output), vec![8, 5]);
+
+    let reader = GeodataReader::load(&clipped_output).unwrap();
+    let water = reader.get_multipolygon_by_id(0).unwrap();
+    let outer = water.get_polygon(0);
+    let outer_nodes = (0..outer.node_count())
+        .map(|idx| outer.get_node(idx))
+        .collect::<Vec<_>>();
+    assert_eq!((outer_nodes[3].lat(), outer_nodes[3].lon()), (10.5, 21.0));
+    assert_eq!((outer_nodes[4].lat(), outer_nodes[4].lon()), (10.0, 21.0));
+    assert_eq!((outer_nodes[5].lat(), outer_nodes[5].lon()), (10.0, 20.0));
+    assert_eq!((outer_nodes[6].lat(), outer_nodes[6].lon()), (10.5, 20.0));
+}
+
+#[test]
+fn test_broken_multipolygons_are_repaired() {
+    let input = common::get_test_path(&["osm", "broken_multipolygons.osm"]);
+    let output = common::get_test_path(&["osm", "broken_multipolygons_repaired.bin"]);
+    let csv_report = common::get_test_path(&["osm", "broken_multipolygons_repaired.csv"]);
+
+    let options = ImportOptions {
+        validation_report: Some(csv_report.clone()),
+        repair_multipolygons: true,
+        ..Default::default()
+    };
+    import_with_options(&input, &output, &options).unwrap();
+    // The ends of the unclosed ring are on the west edge of the data, so the ring is closed along it.
+    assert_eq!(
+        fs::read_to_string(&csv_report).unwrap(),
+        "relation_id,problem,count,lat,lon,dropped\n\
+         1001,missing_member_way,1,55.7600000,37.6000000,false\n\
+         1001,unclosed_ring,2,55.7600000,37.6000000,false\n\
+         1002,self_intersection,1,55.7705000,37.6005000,false\n"
+    );
+
+    let reader = GeodataReader::load(&output).unwrap();
+    let entities = reader.get_entities_in_bbox(55.0, 37.0, 56.0, 38.0);
+    let mut repaired = entities
+        .multipolygons
+        .iter()
+        .map(|mp| (mp.global_id(), mp.tags().get_by_key("renderer:repaired").is_some()))
+        .collect::<Vec<_>>();
+    repaired.sort();
+    assert_eq!(repaired, vec![(1000, false), (1001, true), (1002, false)]);
+
+    let repaired = reader.get_multipolygon_by_id(1001).unwrap();
+    assert_eq!(repaired.polygon_count(), 1);
+    assert_eq!(repaired.get_polygon(0).node_count(), 5);
+}
+
+#[test]
+fn test_import_from_reader_gives_the_same_result() {
+    let input = common::get_test_path(&["osm", "clip.osm"]);
+    let file_output = common::get_test_path(&["osm", "clip_from_file.bin"]);
+    let input_bytes = fs::read(&input).unwrap();
+
+    let import_in_memory = |options: &ImportOptions| {
+        let mut output = Cursor::new(Vec::new());
+        import_from_reader(&input_bytes[..], InputFormat::Xml, &mut output, options).unwrap();
+        output.into_inner()
+    };
+
+    import(&input, &file_output).unwrap();
+    assert!(import_in_memory(&ImportOptions::default()) == fs::read(&file_output).unwrap());
+
+    // Clipping reads the input several times.
+    let options = ImportOptions {
+        clip_area: Some(ClipArea::from_bbox(37.60, 55.75, 37.62, 55.76).unwrap()),
+        ..Default::default()
+    };
+    import_with_options(&input, &file_output, &options).unwrap();
+    assert!(import_in_memory(&options) == fs::read(&file_output).unwrap());
+}
+
+#[test]
+fn test_truncated_ways() {
+    // Two nodes of the park are beyond the east edge of the data.
+    let input = common::get_test_path(&["osm", "truncated_ways.osm"]);
+    let import_truncated = |truncated_ways, output_name| {
+        let output = common::get_test_path(&["osm", output_name]);
+        let options = ImportOptions {
+            truncated_ways,
+            ..Default::default()
+        };
+        import_with_options(&input, &output, &options).unwrap();
+        output
+    };
+
+    let kept = import_truncated(TruncatedWays::Keep, "truncated_ways_kept.bin");
+    let reader = GeodataReader::load(&kept).unwrap();
+    let park = reader.get_way_by_id(100).unwrap();
+    assert!(park.is_truncated());
+    assert_eq!(park.node_count(), 2);
+    assert!(!park.is_closed());
+    let building = reader.get_way_by_id(101).unwrap();
+    assert!(!building.is_truncated());
+    assert!(building.is_closed());
+    // The remaining nodes of this way still form a ring, but it's not the right one.
+    let grass = reader.get_way_by_id(102).unwrap();
+    assert_eq!(grass.node_count(), 4);
+    assert!(!grass.is_closed());
+
+    let dropped = import_truncated(TruncatedWays::Drop, "truncated_ways_dropped.bin");
+    let reader = GeodataReader::load(&dropped).unwrap();
+    assert!(reader.get_way_by_id(100).is_none());
+    assert!(reader.get_way_by_id(101).is_some());
+
+    let closed = import_truncated(TruncatedWays::CloseAreas, "truncated_ways_closed.bin");
+    let reader = GeodataReader::load(&closed).unwrap();
+    let park = reader.get_way_by_id(100).unwrap();
+    assert!(park.is_truncated());
+    assert!(park.is_closed());
+    let coords = (0..park.node_count())
+        .map(|idx| {
+            let node = park.get_node(idx);
+            (node.lat(), node.lon())
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        coords,
+        vec![
+            (55.758, 37.61),
+            (55.752, 37.61),
+            (55.752, 37.62),
+            (55.758, 37.62),
+            (55.758, 37.61)
+        ]
+    );
+}
+
+#[test]
+fn test_import_history_as_of_a_moment() {
+    let input = common::get_test_path(&["osm", "history.osh"]);
+    let import_at = |at: Option<&str>, output_name| {
+        let output = common::get_test_path(&["osm", output_name]);
+        let options = ImportOptions {
+            history_at: at.map(|at| Timestamp::parse(at).unwrap()),
+            ..Default::default()
+        };
+        import_with_options(&input, &output, &options).unwrap();
+        output
+    };
+
+    let early = import_at(Some("2019-03-01"), "history_2019_03.bin");
+    let reader = GeodataReader::load(&early).unwrap();
+    assert_eq!(reader.get_node_by_id(1).unwrap().lat(), 55.75);
+    assert!(reader.get_node_by_id(4).is_none());
+    assert!(reader.get_node_by_id(5).is_some());
+    assert_eq!(reader.get_way_by_id(10).unwrap().node_count(), 4);
+    assert!(reader.get_way_by_id(11).is_none());
+    // The relation is created later on the same day.
+    assert!(reader.get_relation_by_id(20).is_none());
+
+    let later = import_at(Some("2019-12-31T23:59:59Z"), "history_2019_12.bin");
+    let reader = GeodataReader::load(&later).unwrap();
+    assert!(reader.get_node_by_id(5).is_none());
+    assert!(reader.get_relation_by_id(20).is_some());
+
+    let latest = import_at(None, "history_latest.bin");
+    let reader = GeodataReader::load(&latest).unwrap();
+    assert_eq!(reader.get_node_by_id(1).unwrap().lat(), 55.751);
+    let building = reader.get_way_by_id(10).unwrap();
+    assert_eq!(building.node_count(), 5);
+    assert_eq!(building.tags().get_by_key("name"), Some("Новый корпус"));
+    assert!(reader.get_way_by_id(11).is_some());
+    assert!(reader.get_relation_by_id(20).is_none());
+
+    // Plain OSM files don't have the old versions.
+    let options = ImportOptions {
+        history_at: Some(Timestamp::parse("2019-03-01").unwrap()),
+        ..Default::default()
+    };
+    let plain_input = common::get_test_path(&["osm", "clip.osm"]);
+    let output = common::get_test_path(&["osm", "history_plain.bin"]);
+    assert!(import_with_options(&plain_input, &output, &options).is_err());
+}
+
+#[test]
+fn test_import_overpass_json() {
+    // The same data as in the XML file, but with relations first, as Overpass may list them.
+    let json_output = common::get_test_path(&["osm", "diff_base_json.bin"]);
+    import(&common::get_test_path(&["osm", "diff_base.json"]), &json_output).unwrap();
+    let xml_output = common::get_test_path(&["osm", "diff_base_xml.bin"]);
+    import(&common::get_test_path(&["osm", "diff_base.osm"]), &xml_output).unwrap();
+    assert!(fs::read(&json_output).unwrap() == fs::read(&xml_output).unwrap());
+
+    // With `out geom`, the nodes are only listed in the ways.
+    let geom_output = common::get_test_path(&["osm", "overpass_geom.bin"]);
+    import(&common::get_test_path(&["osm", "overpass_geom.json"]), &geom_output).unwrap();
+    let reader = GeodataReader::load(&geom_output).unwrap();
+    let building = reader.get_way_by_id(300).unwrap();
+    assert!(building.is_closed());
+    assert_eq!(building.get_node(2).lat(), 55.751);
+    assert_eq!(reader.get_way_by_id(301).unwrap().get_node(1).lon(), 37.612);
+    assert_eq!(reader.get_node_by_id(31).unwrap().lon(), 37.611);
+}
+
+#[test]
+fn test_import_geojson() {
+    let output = common::get_test_path(&["osm", "zones.bin"]);
+    import(&common::get_test_path(&["osm", "zones.geojson"]), &output).unwrap();
+    let reader = GeodataReader::load(&output).unwrap();
+
+    // The entities are numbered in the order of the features.
+    let center = reader.get_multipolygon_by_id(1).unwrap();
+    assert_eq!(center.polygon_count(), 2);
+    let tags = center.tags();
+    assert_eq!(tags.get_by_key("type"), Some("multipolygon"));
+    assert_eq!(tags.get_by_key("zone"), Some("delivery"));
+    assert_eq!(tags.get_by_key("name"), Some("Центр"));
+    assert_eq!(tags.get_by_key("priority"), Some("2"));
+    assert_eq!(tags.get_by_key("express"), Some("true"));
+    assert_eq!(tags.get_by_key("note"), None);
+
+    let south = reader.get_multipolygon_by_id(2).unwrap();
+    assert_eq!(south.polygon_count(), 2);
+    assert_eq!(south.tags().get_by_key("name"), Some("Юг"));
+
+    // The courier route shares its nodes with the rings.
+    let route = reader.get_way_by_id(5).unwrap();
+    assert_eq!(route.tags().get_by_key("route"), Some("courier"));
+    assert_eq!(route.get_node(0).global_id(), 1);
+    assert_eq!(route.get_node(1).global_id(), 9);
+
+    let locker = reader.get_node_by_id(17).unwrap();
+    assert_eq!(locker.tags().get_by_key("amenity"), Some("parcel_locker"));
+    assert_eq!((locker.lat(), locker.lon()), (55.755, 37.61));
+}
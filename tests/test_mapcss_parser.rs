@@ -47,3 +47,23 @@ fn test_parsing_is_idempotent() {
     let rules_str = rules.iter().map(|x| format!("{}", x)).collect::<Vec<_>>().join("\n\n");
     assert_eq!(rules_str, canonize_newlines(&canonical));
 }
+
+#[test]
+fn test_link_selectors() {
+    let rules = parse_file(Path::new(&get_test_path(&["mapcss"])), "links.mapcss").unwrap();
+    let selectors = rules
+        .iter()
+        .flat_map(|r| r.selectors.iter())
+        .map(|s| format!("{}", s))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        selectors,
+        vec![
+            "relation[type=route][route=bus] > way[highway]",
+            "relation[type=route] >[role=stop] node",
+            "way[highway=primary] > node[highway=bus_stop]",
+            "node[highway=bus_stop] < way",
+            "relation[type=associatedStreet] >[role=street] way|z16-",
+        ]
+    );
+}
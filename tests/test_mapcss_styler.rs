@@ -3,6 +3,8 @@ use renderer;
 mod common;
 
 use crate::common::get_test_path;
+use renderer::geodata::area_rules::AreaRules;
+use renderer::geodata::importer::{import_with_options, ImportOptions};
 use renderer::geodata::reader::OsmEntity;
 use renderer::mapcss::color::{from_color_name, Color};
 use renderer::mapcss::parser::parse_file;
@@ -182,3 +184,87 @@ fn from_josm_style(way_is_closed: bool, style: &str) -> Style {
         text_style: None,
     }
 }
+
+#[test]
+fn test_link_selectors() {
+    let bin_file = get_test_path(&["osm", "relations_styled.bin"]);
+    renderer::geodata::importer::import(&get_test_path(&["osm", "relations.osm"]), &bin_file).unwrap();
+    let reader = renderer::geodata::reader::GeodataReader::load(&bin_file).unwrap();
+    let styler = Styler::new(
+        parse_file(Path::new(&get_test_path(&["mapcss"])), "links.mapcss").unwrap(),
+        &StyleType::Josm,
+        None,
+    );
+
+    let ways = [10, 11]
+        .iter()
+        .map(|id| reader.get_way_by_id(*id).unwrap())
+        .collect::<Vec<_>>();
+    for zoom in &[15, 16] {
+        let styles = styler.style_entities(ways.iter(), *zoom, false);
+        let get_style = |id| &styles.iter().find(|(w, _)| w.global_id() == id).unwrap().1;
+
+        let primary = get_style(10);
+        assert_eq!(primary.color, from_color_name("red"));
+        assert_eq!(primary.width, Some(3.0));
+        assert!(primary.casing_width.is_some());
+        assert!(primary.text_style.is_none());
+
+        let residential = get_style(11);
+        assert_eq!(residential.color, from_color_name("red"));
+        assert!(residential.casing_width.is_none());
+        let text = residential.text_style.as_ref().map(|t| t.text.as_str());
+        assert_eq!(text, if *zoom >= 16 { Some("name") } else { None });
+    }
+
+    // Both bus stops have the same tags, but only the first one is a part of the route and of a way.
+    let nodes = [5, 7]
+        .iter()
+        .map(|id| reader.get_node_by_id(*id).unwrap())
+        .collect::<Vec<_>>();
+    let styles = styler.style_entities(nodes.iter(), 18, false);
+    let get_style = |id| styles.iter().find(|(n, _)| n.global_id() == id).map(|(_, s)| s);
+
+    let stop = get_style(5).unwrap();
+    assert_eq!(stop.icon_image, Some("bus.png".to_string()));
+    assert_eq!(stop.text_style.as_ref().map(|t| t.text.as_str()), Some("name"));
+    assert!(get_style(7).is_none());
+}
+
+#[test]
+fn test_areas_are_decided_by_tags() {
+    let styler = Styler::new(
+        parse_file(Path::new(&get_test_path(&["mapcss"])), "areas.mapcss").unwrap(),
+        &StyleType::Josm,
+        None,
+    );
+    let filled_way_ids = |options: &ImportOptions, bin_name| {
+        let bin_file = get_test_path(&["osm", bin_name]);
+        import_with_options(&get_test_path(&["osm", "areas.osm"]), &bin_file, options).unwrap();
+        let reader = renderer::geodata::reader::GeodataReader::load(&bin_file).unwrap();
+        let ways = (100..=104)
+            .map(|id| reader.get_way_by_id(id).unwrap())
+            .collect::<Vec<_>>();
+        let mut ids = styler
+            .style_entities(ways.iter(), 18, false)
+            .iter()
+            .filter(|(_, style)| style.fill_color.is_some())
+            .map(|(way, _)| way.global_id())
+            .collect::<Vec<_>>();
+        ids.sort();
+        ids.dedup();
+        ids
+    };
+
+    // The roundabout and the pedestrian loop are closed lines, and `area=yes` and `area=no` override the rules.
+    assert_eq!(
+        filled_way_ids(&ImportOptions::default(), "areas_default.bin"),
+        vec![102, 104]
+    );
+
+    let options = ImportOptions {
+        area_rules: AreaRules::from_file(&get_test_path(&["osm", "area_rules.txt"])).unwrap(),
+        ..Default::default()
+    };
+    assert_eq!(filled_way_ids(&options, "areas_custom.bin"), vec![101, 102]);
+}
@@ -0,0 +1,108 @@
+use renderer;
+
+mod common;
+
+use renderer::geodata::clip::ClipArea;
+use renderer::geodata::extract::extract;
+use renderer::geodata::importer::{import, import_with_options, ImportOptions};
+use renderer::geodata::multi_reader::MultiGeodataReader;
+use renderer::geodata::reader::{GeodataReader, OsmEntities, OsmEntity};
+use renderer::tile;
+
+// Unlike `common::sorted_ids`, keeps the order in which the entities are returned.
+fn ids<'a, E: OsmEntity<'a>>(entities: &[E]) -> Vec<u64> {
+    entities.iter().map(|e| e.global_id()).collect()
+}
+
+fn assert_same_entities(merged: &OsmEntities<'_>, full: &OsmEntities<'_>) {
+    // The entities near the border are stored in both files, but must be returned only once.
+    assert_eq!(common::sorted_ids(&merged.nodes), common::sorted_ids(&full.nodes));
+    assert_eq!(common::sorted_ids(&merged.ways), common::sorted_ids(&full.ways));
+    assert_eq!(
+        common::sorted_ids(&merged.multipolygons),
+        common::sorted_ids(&full.multipolygons)
+    );
+}
+
+#[test]
+fn test_regional_extracts_are_merged() {
+    let input = common::get_test_path(&["osm", "nano_moscow.osm"]);
+    let full_geodata = common::get_test_path(&["osm", "nano_moscow_multi_full.bin"]);
+    import(&input, &full_geodata).unwrap();
+
+    // The data covers the longitudes from 37.506 to 37.645, so the halves overlap in the middle.
+    let west = common::get_test_path(&["osm", "nano_moscow_multi_west.bin"]);
+    let east = common::get_test_path(&["osm", "nano_moscow_multi_east.bin"]);
+    extract(
+        &full_geodata,
+        &ClipArea::from_bbox(37.5, 55.69, 37.58, 55.8).unwrap(),
+        &west,
+    )
+    .unwrap();
+    extract(
+        &full_geodata,
+        &ClipArea::from_bbox(37.57, 55.69, 37.65, 55.8).unwrap(),
+        &east,
+    )
+    .unwrap();
+
+    let full_reader = GeodataReader::load(&full_geodata).unwrap();
+    let west_reader = GeodataReader::load(&west).unwrap();
+    let reader = MultiGeodataReader::load(&[west.clone(), east.clone()]).unwrap();
+
+    let (min_lat, min_lon, max_lat, max_lon) = (55.69, 37.5, 55.8, 37.65);
+    let merged = reader.get_entities_in_bbox(min_lat, min_lon, max_lat, max_lon);
+    let full = full_reader.get_entities_in_bbox(min_lat, min_lon, max_lat, max_lon);
+    assert_same_entities(&merged, &full);
+
+    let tile = tile::coords_to_max_zoom_tile(&(55.75, 37.575));
+    let tile = tile::Tile {
+        x: tile.x >> 4,
+        y: tile.y >> 4,
+        zoom: 14,
+    };
+    let merged = reader.get_entities_in_tile_with_neighbors(&tile, &None);
+    let full = full_reader.get_entities_in_tile_with_neighbors(&tile, &None);
+    assert!(!merged.ways.is_empty());
+    assert_same_entities(&merged, &full);
+
+    // The copies from the first file are used.
+    let west_entities = west_reader.get_entities_in_tile_with_neighbors(&tile, &None);
+    assert_eq!(
+        ids(&merged.ways)[..west_entities.ways.len()],
+        ids(&west_entities.ways)[..]
+    );
+
+    for way in &full.ways {
+        let found = reader.get_way_by_id(way.global_id()).unwrap();
+        assert_eq!(found.node_count(), way.node_count());
+    }
+    assert!(reader.get_node_by_id(0).is_none());
+
+    assert!(MultiGeodataReader::load(&[]).is_err());
+    assert!(MultiGeodataReader::load(&[west, common::get_test_path(&["osm", "missing.bin"])]).is_err());
+}
+
+#[test]
+fn test_water_of_every_extract_is_kept() {
+    let input = common::get_test_path(&["osm", "coastline.osm"]);
+    let west = common::get_test_path(&["osm", "coastline_multi_west.bin"]);
+    let east = common::get_test_path(&["osm", "coastline_multi_east.bin"]);
+    let import_clipped = |min_lon, max_lon, output: &str| {
+        let options = ImportOptions {
+            clip_area: Some(ClipArea::from_bbox(min_lon, 10.0, max_lon, 11.0).unwrap()),
+            ..Default::default()
+        };
+        import_with_options(&input, output, &options).unwrap();
+    };
+    import_clipped(20.0, 20.5, &west);
+    import_clipped(20.5, 21.0, &east);
+
+    // Both files have a water multipolygon with the id 0, and each one covers its own half of the sea.
+    let reader = MultiGeodataReader::load(&[west, east]).unwrap();
+    let entities = reader.get_entities_in_bbox(10.0, 20.0, 11.0, 21.0);
+    assert_eq!(ids(&entities.multipolygons), vec![0, 0]);
+    for water in &entities.multipolygons {
+        assert_eq!(water.tags().get_by_key("natural"), Some("ocean"));
+    }
+}
@@ -0,0 +1,372 @@
+use renderer;
+
+mod common;
+
+use renderer::coords::Coords;
+use renderer::geodata::importer::{import, import_from_reader, import_with_options, ImportOptions, InputFormat};
+use renderer::geodata::reader::{GeodataReader, Member, OsmEntity, Parent, Relation, Way};
+use renderer::mapcss::parser::parse_file;
+use renderer::mapcss::styler::{StyleType, Styler};
+use renderer::tile;
+use std::fs::File;
+use std::io::{BufReader, Cursor};
+use std::path::Path;
+
+fn import_nano_moscow(output_name: &str) -> String {
+    let input = common::get_test_path(&["osm", "nano_moscow.osm"]);
+    let output = common::get_test_path(&["osm", output_name]);
+    import(&input, &output).unwrap();
+    output
+}
+
+#[test]
+fn test_lookup_by_id() {
+    let geodata = import_nano_moscow("nano_moscow_lookup.bin");
+    let reader = GeodataReader::load(&geodata).unwrap();
+
+    let tile = tile::coords_to_max_zoom_tile(&(55.7535, 37.6141));
+    let tile = tile::Tile {
+        x: tile.x >> 3,
+        y: tile.y >> 3,
+        zoom: 15,
+    };
+    let entities = reader.get_entities_in_tile_with_neighbors(&tile, &None);
+    assert!(!entities.nodes.is_empty());
+    assert!(!entities.ways.is_empty());
+    assert!(!entities.multipolygons.is_empty());
+
+    for node in &entities.nodes {
+        let found = reader.get_node_by_id(node.global_id()).unwrap();
+        assert_eq!(found.global_id(), node.global_id());
+        assert_eq!((found.lat(), found.lon()), (node.lat(), node.lon()));
+    }
+    for way in &entities.ways {
+        let found = reader.get_way_by_id(way.global_id()).unwrap();
+        assert_eq!(found.global_id(), way.global_id());
+        assert_eq!(found.node_count(), way.node_count());
+        assert_eq!(found.tags().get_by_key("name"), way.tags().get_by_key("name"));
+    }
+    for multipolygon in &entities.multipolygons {
+        let found = reader.get_multipolygon_by_id(multipolygon.global_id()).unwrap();
+        assert_eq!(found.global_id(), multipolygon.global_id());
+        assert_eq!(found.polygon_count(), multipolygon.polygon_count());
+    }
+
+    assert!(reader.get_node_by_id(0).is_none());
+    assert!(reader.get_way_by_id(u64::MAX).is_none());
+    assert!(reader.get_multipolygon_by_id(entities.nodes[0].global_id()).is_none());
+}
+
+#[test]
+fn test_bbox_query() {
+    let geodata = import_nano_moscow("nano_moscow_bbox.bin");
+    let reader = GeodataReader::load(&geodata).unwrap();
+
+    let (min_lat, min_lon, max_lat, max_lon) = (55.752, 37.611, 55.754, 37.615);
+    let entities = reader.get_entities_in_bbox(min_lat, min_lon, max_lat, max_lon);
+    let is_inside = |lat, lon| min_lat <= lat && lat <= max_lat && min_lon <= lon && lon <= max_lon;
+
+    assert!(!entities.nodes.is_empty());
+    assert!(entities.nodes.iter().all(|n| is_inside(n.lat(), n.lon())));
+
+    let mut node_ids = entities.nodes.iter().map(|n| n.global_id()).collect::<Vec<_>>();
+    let mut way_ids = entities.ways.iter().map(|w| w.global_id()).collect::<Vec<_>>();
+    let node_count = node_ids.len();
+    let way_count = way_ids.len();
+    node_ids.sort();
+    node_ids.dedup();
+    way_ids.sort();
+    way_ids.dedup();
+    assert_eq!(node_ids.len(), node_count);
+    assert_eq!(way_ids.len(), way_count);
+
+    // Everything that a larger query finds inside the box must be found by the bbox query too.
+    let tile = tile::coords_to_max_zoom_tile(&(55.753, 37.613));
+    let tile = tile::Tile {
+        x: tile.x >> 3,
+        y: tile.y >> 3,
+        zoom: 15,
+    };
+    let surrounding = reader.get_entities_in_tile_with_neighbors(&tile, &None);
+    for node in surrounding.nodes.iter().filter(|n| is_inside(n.lat(), n.lon())) {
+        assert!(node_ids.binary_search(&node.global_id()).is_ok());
+    }
+    for way in &surrounding.ways {
+        if (0..way.node_count()).any(|idx| is_inside(way.get_node(idx).lat(), way.get_node(idx).lon())) {
+            assert!(way_ids.binary_search(&way.global_id()).is_ok());
+        }
+    }
+    assert!(surrounding.nodes.len() > entities.nodes.len());
+
+    let empty = reader.get_entities_in_bbox(max_lat, min_lon, min_lat, max_lon);
+    assert!(empty.nodes.is_empty() && empty.ways.is_empty() && empty.multipolygons.is_empty());
+}
+
+#[test]
+fn test_index_zoom() {
+    let full_geodata = import_nano_moscow("nano_moscow_index_default.bin");
+    let full_reader = GeodataReader::load(&full_geodata).unwrap();
+
+    let input = common::get_test_path(&["osm", "nano_moscow.osm"]);
+    let coarse_geodata = common::get_test_path(&["osm", "nano_moscow_index_14.bin"]);
+    let options = ImportOptions {
+        index_zoom: Some(14),
+        ..Default::default()
+    };
+    import_with_options(&input, &coarse_geodata, &options).unwrap();
+    let coarse_reader = GeodataReader::load(&coarse_geodata).unwrap();
+
+    let file_size = |path: &str| std::fs::metadata(path).unwrap().len();
+    assert!(file_size(&coarse_geodata) < file_size(&full_geodata));
+
+    let ids = |entities: &[Way<'_>]| {
+        let mut ids = entities.iter().map(|w| w.global_id()).collect::<Vec<_>>();
+        ids.sort();
+        ids.dedup();
+        ids
+    };
+    let center = tile::coords_to_max_zoom_tile(&(55.7535, 37.6141));
+    for zoom in &[12, 15, 18] {
+        let shift = tile::MAX_ZOOM - zoom;
+        let tile = tile::Tile {
+            x: center.x >> shift,
+            y: center.y >> shift,
+            zoom: *zoom,
+        };
+        let full = full_reader.get_entities_in_tile_with_neighbors(&tile, &None);
+        let coarse = coarse_reader.get_entities_in_tile_with_neighbors(&tile, &None);
+        let coarse_ids = ids(&coarse.ways);
+        assert!(!full.ways.is_empty());
+        assert!(ids(&full.ways).iter().all(|id| coarse_ids.binary_search(id).is_ok()));
+        assert!(coarse.nodes.len() >= full.nodes.len());
+    }
+
+    let everything = |reader: &GeodataReader<'_>| {
+        let entities = reader.get_entities_in_bbox(-85.0, -180.0, 85.0, 180.0);
+        (entities.nodes.len(), ids(&entities.ways), entities.multipolygons.len())
+    };
+    assert_eq!(everything(&full_reader), everything(&coarse_reader));
+
+    let options = ImportOptions {
+        index_zoom: Some(tile::MAX_ZOOM + 1),
+        ..Default::default()
+    };
+    let error = import_with_options(&input, &coarse_geodata, &options).unwrap_err();
+    assert!(error.to_string().contains("index zoom"), "{}", error);
+}
+
+#[test]
+fn test_simplified_geometry() {
+    let geodata = import_nano_moscow("nano_moscow_simplified.bin");
+    let reader = GeodataReader::load(&geodata).unwrap();
+    let entities = reader.get_entities_in_bbox(55.74, 37.59, 55.77, 37.64);
+
+    let node_ids = |way: &Way<'_>| {
+        (0..way.node_count())
+            .map(|idx| way.get_node(idx).global_id())
+            .collect::<Vec<_>>()
+    };
+    let mut original_node_count = 0;
+    let mut simplified_node_count = 0;
+    for way in &entities.ways {
+        let original = node_ids(way);
+        let simplified = node_ids(&way.simplified_for_zoom(11));
+        assert_eq!(node_ids(&way.simplified_for_zoom(16)), original);
+        assert!(simplified.len() <= node_ids(&way.simplified_for_zoom(13)).len());
+        assert_eq!(simplified.first(), original.first());
+        assert_eq!(simplified.last(), original.last());
+        assert!(simplified.iter().all(|id| original.contains(id)));
+        original_node_count += original.len();
+        simplified_node_count += simplified.len();
+    }
+    assert!(simplified_node_count < original_node_count);
+
+    for multipolygon in &entities.multipolygons {
+        for idx in 0..multipolygon.polygon_count() {
+            let polygon = multipolygon.get_polygon(idx);
+            assert!(polygon.simplified_for_zoom(11).node_count() <= polygon.node_count());
+            assert_eq!(polygon.simplified_for_zoom(18).node_count(), polygon.node_count());
+        }
+    }
+}
+
+#[test]
+fn test_min_zoom_culling() {
+    let full_geodata = import_nano_moscow("nano_moscow_not_culled.bin");
+    let full_reader = GeodataReader::load(&full_geodata).unwrap();
+
+    let culled_geodata = common::get_test_path(&["osm", "nano_moscow_culled.bin"]);
+    let options = ImportOptions {
+        stylesheet: Some(common::get_test_path(&["mapcss", "mapnik.mapcss"])),
+        ..Default::default()
+    };
+    import_with_options(
+        &common::get_test_path(&["osm", "nano_moscow.osm"]),
+        &culled_geodata,
+        &options,
+    )
+    .unwrap();
+    let culled_reader = GeodataReader::load(&culled_geodata).unwrap();
+
+    let styler = Styler::new(
+        parse_file(Path::new(&common::get_test_path(&["mapcss"])), "mapnik.mapcss").unwrap(),
+        &StyleType::Josm,
+        None,
+    );
+    let styled_ids = |entities: &[Way<'_>], zoom| {
+        let mut ids = styler
+            .style_entities(entities.iter(), zoom, false)
+            .iter()
+            .filter(|(_, s)| {
+                s.color.is_some()
+                    || s.fill_color.is_some()
+                    || s.width.is_some()
+                    || s.casing_width.is_some()
+                    || s.icon_image.is_some()
+                    || s.fill_image.is_some()
+                    || s.text_style.is_some()
+            })
+            .map(|(w, _)| w.global_id())
+            .collect::<Vec<_>>();
+        ids.dedup();
+        ids
+    };
+
+    let max_zoom_tile = tile::coords_to_max_zoom_tile(&(55.7535, 37.6141));
+    for zoom in &[10, 12, 15] {
+        let shift = tile::MAX_ZOOM - zoom;
+        let tile = tile::Tile {
+            x: max_zoom_tile.x >> shift,
+            y: max_zoom_tile.y >> shift,
+            zoom: *zoom,
+        };
+        let full = full_reader.get_entities_in_tile_with_neighbors(&tile, &None);
+        let culled = culled_reader.get_entities_in_tile_with_neighbors(&tile, &None);
+
+        assert!(culled.ways.len() <= full.ways.len());
+        assert_eq!(styled_ids(&culled.ways, *zoom), styled_ids(&full.ways, *zoom));
+        if *zoom < 15 {
+            assert!(culled.nodes.len() < full.nodes.len());
+            assert!(culled.ways.len() < full.ways.len());
+        }
+    }
+}
+
+#[test]
+fn test_relations() {
+    let input = common::get_test_path(&["osm", "relations.osm"]);
+    let geodata = common::get_test_path(&["osm", "relations.bin"]);
+    import(&input, &geodata).unwrap();
+    let reader = GeodataReader::load(&geodata).unwrap();
+
+    let members = |relation: &Relation<'_>| {
+        relation
+            .members()
+            .map(|m| {
+                let member = match m.member {
+                    Member::Node(n) => format!("node {}", n.global_id()),
+                    Member::Way(w) => format!("way {}", w.global_id()),
+                    Member::Relation(r) => format!("relation {}", r.global_id()),
+                };
+                (m.role.to_string(), member)
+            })
+            .collect::<Vec<_>>()
+    };
+    let expected = |members: &[(&str, &str)]| {
+        members
+            .iter()
+            .map(|(role, member)| (role.to_string(), member.to_string()))
+            .collect::<Vec<_>>()
+    };
+
+    let route = reader.get_relation_by_id(100).unwrap();
+    assert_eq!(route.tags().get_by_key("route"), Some("bus"));
+    assert_eq!(
+        members(&route),
+        expected(&[("stop", "node 5"), ("", "way 10"), ("", "way 11")])
+    );
+
+    let boundary = reader.get_relation_by_id(101).unwrap();
+    assert_eq!(
+        members(&boundary),
+        expected(&[("outer", "way 10"), ("subarea", "relation 102")])
+    );
+
+    let street = reader.get_relation_by_id(102).unwrap();
+    assert_eq!(members(&street), expected(&[("street", "way 11"), ("house", "node 6")]));
+    // The untagged node is only used by the relation, but it's still there.
+    assert!(reader.get_node_by_id(6).is_some());
+
+    assert!(reader.get_relation_by_id(103).is_none());
+
+    let parents = |parents: &mut dyn Iterator<Item = Parent<'_>>| {
+        parents
+            .map(|p| {
+                let parent = match p.parent {
+                    Member::Node(n) => format!("node {}", n.global_id()),
+                    Member::Way(w) => format!("way {}", w.global_id()),
+                    Member::Relation(r) => format!("relation {}", r.global_id()),
+                };
+                (p.role.to_string(), parent)
+            })
+            .collect::<Vec<_>>()
+    };
+    let stop = reader.get_node_by_id(5).unwrap();
+    assert_eq!(
+        parents(&mut stop.parents()),
+        expected(&[("", "way 10"), ("stop", "relation 100")])
+    );
+    let side_street = reader.get_way_by_id(11).unwrap();
+    assert_eq!(
+        parents(&mut side_street.parents()),
+        expected(&[("", "relation 100"), ("street", "relation 102")])
+    );
+    assert_eq!(parents(&mut street.parents()), expected(&[("subarea", "relation 101")]));
+    assert!(route.parents().next().is_none());
+
+    let tile = tile::coords_to_max_zoom_tile(&(55.7505, 37.601));
+    let entities = reader.get_entities_in_tile_with_neighbors(&tile, &None);
+    let mut relation_ids = entities.relations.iter().map(|r| r.global_id()).collect::<Vec<_>>();
+    relation_ids.sort();
+    assert_eq!(relation_ids, vec![100, 101]);
+
+    let entities = reader.get_entities_in_bbox(55.749, 37.599, 55.754, 37.607);
+    let mut relation_ids = entities.relations.iter().map(|r| r.global_id()).collect::<Vec<_>>();
+    relation_ids.sort();
+    assert_eq!(relation_ids, vec![100, 101, 102]);
+}
+
+#[test]
+fn test_reading_from_memory() {
+    let input = File::open(common::get_test_path(&["osm", "nano_moscow.osm"])).unwrap();
+    let mut output = Cursor::new(Vec::new());
+    import_from_reader(
+        BufReader::new(input),
+        InputFormat::Xml,
+        &mut output,
+        &ImportOptions::default(),
+    )
+    .unwrap();
+    let geodata = output.into_inner();
+
+    let ids = |reader: &GeodataReader<'_>| {
+        let entities = reader.get_entities_in_bbox(55.750, 37.607, 55.757, 37.621);
+        (
+            entities.nodes.iter().map(|n| n.global_id()).collect::<Vec<_>>(),
+            entities.ways.iter().map(|w| w.global_id()).collect::<Vec<_>>(),
+            entities.multipolygons.iter().map(|m| m.global_id()).collect::<Vec<_>>(),
+        )
+    };
+    let file_reader = GeodataReader::load(&import_nano_moscow("nano_moscow_in_memory.bin")).unwrap();
+    let expected_ids = ids(&file_reader);
+    assert!(!expected_ids.1.is_empty());
+
+    assert_eq!(ids(&GeodataReader::from_bytes(&geodata[..]).unwrap()), expected_ids);
+    // A buffer that is not aligned to 4 bytes is copied.
+    let mut shifted = vec![0];
+    shifted.extend_from_slice(&geodata);
+    assert_eq!(ids(&GeodataReader::from_bytes(&shifted[1..]).unwrap()), expected_ids);
+    assert_eq!(ids(&GeodataReader::from_bytes(geodata.clone()).unwrap()), expected_ids);
+
+    assert!(GeodataReader::from_bytes(&geodata[..geodata.len() / 2]).is_err());
+}
@@ -5,11 +5,12 @@ mod common;
 
 use renderer::draw::png_writer::rgb_triples_to_png;
 use renderer::draw::tile_pixels::{RgbTriples, TilePixels};
+use renderer::geodata::importer::{import_from_reader, ImportOptions, InputFormat};
 use renderer::mapcss::parser::parse_file;
 use renderer::mapcss::styler::{StyleType, Styler};
 use std::collections::BTreeMap;
 use std::fs::File;
-use std::io::Write;
+use std::io::{BufReader, Cursor, Write};
 use std::path::Path;
 
 const RED_PIXEL: (u8, u8, u8) = (255, 0, 0);
@@ -69,9 +70,16 @@ fn compare_png_outputs(zoom: u8, suffix: &str) {
 }
 
 fn test_rendering_zoom(zoom: u8, min_x: u32, max_x: u32, min_y: u32, max_y: u32, scale: usize) {
-    let bin_file = common::get_test_path(&["osm", &format!("nano_moscow_{}.bin", zoom)]);
-    renderer::geodata::importer::import(&common::get_test_path(&["osm", "nano_moscow.osm"]), &bin_file).unwrap();
-    let reader = renderer::geodata::reader::GeodataReader::load(&bin_file).unwrap();
+    let input = File::open(common::get_test_path(&["osm", "nano_moscow.osm"])).unwrap();
+    let mut geodata = Cursor::new(Vec::new());
+    import_from_reader(
+        BufReader::new(input),
+        InputFormat::Xml,
+        &mut geodata,
+        &ImportOptions::default(),
+    )
+    .unwrap();
+    let reader = renderer::geodata::reader::GeodataReader::from_bytes(geodata.into_inner()).unwrap();
     let base_path = common::get_test_path(&["mapcss"]);
     let styler = Styler::new(
         parse_file(Path::new(&base_path), "mapnik.mapcss").unwrap(),